@@ -4,9 +4,13 @@
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
 
-use crate::{CollectorConfig, Config, ConfigResolver, Schedule, Sender, SenderCommand};
+use crate::{
+    AggregateRule, Alerts, CollectorConfig, Config, ConfigResolver, Limits, Schedule, Sender,
+    SenderCommand,
+};
 use common::{AgentError, AgentResult, Label, Labels};
 use gethostname::gethostname;
+use relabel::RelabelRuleset;
 use std::collections::{HashMap, HashSet};
 use tokio::{
     runtime::Runtime,
@@ -25,6 +29,8 @@ pub struct Agent {
     dump_metrics: bool,
     default_interval: u64,
     mode: AgentMode,
+    // Hash of the running alerts configuration
+    alerts_hash: Option<u64>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -91,6 +97,7 @@ impl AgentBuilder {
             dump_metrics: self.dump_metrics,
             default_interval: AGENT_DEFAULT_INTERVAL,
             mode: self.mode.clone(),
+            alerts_hash: None,
         }
     }
 }
@@ -206,6 +213,52 @@ impl Agent {
             if let Err(e) = tx.send(SenderCommand::SetAgentLabels(labels)).await {
                 log::error!("Failed to set labels: {}", e);
             }
+            // Configure limits
+            let limits = match &cfg.agent.limits {
+                Some(v) => Some(Limits::try_from(v)?),
+                None => None,
+            };
+            if let Err(e) = tx.send(SenderCommand::SetAgentLimits(limits)).await {
+                log::error!("Failed to set limits: {}", e);
+            }
+            // Configure aggregation rules
+            let rules = match &cfg.agent.aggregate {
+                Some(v) => v
+                    .iter()
+                    .map(AggregateRule::try_from)
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => Vec::new(),
+            };
+            if let Err(e) = tx.send(SenderCommand::SetAgentAggregate(rules)).await {
+                log::error!("Failed to set aggregation rules: {}", e);
+            }
+            // Configure relabeling
+            let relabel = match &cfg.agent.relabel {
+                Some(v) => Some(RelabelRuleset::try_from(v)?),
+                None => None,
+            };
+            if let Err(e) = tx.send(SenderCommand::SetAgentRelabel(relabel)).await {
+                log::error!("Failed to set relabeling rules: {}", e);
+            }
+            // Configure alerts, keep running engine when not changed
+            let alerts_cfg = cfg.agent.alerts.as_ref().map(|v| {
+                let mut v = v.clone();
+                if v.interval.is_none() {
+                    v.interval = Some(self.default_interval);
+                }
+                v
+            });
+            let alerts_hash = alerts_cfg.as_ref().map(|v| v.get_hash());
+            if alerts_hash != self.alerts_hash {
+                let alerts = match &alerts_cfg {
+                    Some(v) => Some(Alerts::try_from(v)?),
+                    None => None,
+                };
+                match tx.send(SenderCommand::SetAgentAlerts(alerts)).await {
+                    Ok(_) => self.alerts_hash = alerts_hash,
+                    Err(e) => log::error!("Failed to set alerts: {}", e),
+                }
+            }
         }
         Ok(())
     }
@@ -240,6 +293,12 @@ impl Agent {
         log::debug!("[{}] Stopping", collector_id);
         if let Some(item) = self.running.remove(collector_id) {
             item.abort();
+            // Release collector's series
+            if let Some(tx) = &self.sender_tx {
+                tx.send(SenderCommand::RemoveCollector(collector_id.to_string()))
+                    .await
+                    .map_err(|e| AgentError::InternalError(e.to_string()))?;
+            }
         }
         Ok(())
     }
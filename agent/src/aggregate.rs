@@ -0,0 +1,186 @@
+// --------------------------------------------------------------------
+// Gufo Agent: Local aggregation rules
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use crate::AggregateConfig;
+use common::{AgentError, Label, Labels, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct AggregateRule {
+    metric: String,
+    op: AggregateOp,
+    by: Vec<String>,
+    name: Option<String>,
+    keep_source: bool,
+}
+
+// Running state of the single aggregated series
+#[derive(Debug)]
+pub(crate) struct Accumulator {
+    op: AggregateOp,
+    count: u64,
+    value: f64,
+    // All source values are integer
+    integer: bool,
+    // All source values are counters
+    counter: bool,
+    ts: u64,
+}
+
+impl TryFrom<&AggregateConfig> for AggregateRule {
+    type Error = AgentError;
+
+    fn try_from(value: &AggregateConfig) -> Result<Self, Self::Error> {
+        if value.metric.is_empty() {
+            return Err(AgentError::ConfigurationError(
+                "aggregate: metric must be set".into(),
+            ));
+        }
+        let op = match value.op.as_str() {
+            "sum" => AggregateOp::Sum,
+            "avg" => AggregateOp::Avg,
+            "min" => AggregateOp::Min,
+            "max" => AggregateOp::Max,
+            "count" => AggregateOp::Count,
+            _ => {
+                return Err(AgentError::ConfigurationError(format!(
+                    "invalid aggregate op: {}",
+                    value.op
+                )))
+            }
+        };
+        let mut by = value.by.clone();
+        by.sort();
+        by.dedup();
+        Ok(Self {
+            metric: value.metric.clone(),
+            op,
+            by,
+            name: value.name.clone(),
+            keep_source: value.keep_source,
+        })
+    }
+}
+
+impl AggregateRule {
+    pub fn metric(&self) -> &str {
+        &self.metric
+    }
+    // Name of the aggregated metric, `<family>:<op>` by default
+    pub fn name(&self, family: &str) -> String {
+        match &self.name {
+            Some(x) => x.clone(),
+            None => format!("{}:{}", family, self.op.as_str()),
+        }
+    }
+    pub fn keep_source(&self) -> bool {
+        self.keep_source
+    }
+    pub fn accumulator(&self) -> Accumulator {
+        Accumulator {
+            op: self.op,
+            count: 0,
+            value: 0.0,
+            integer: true,
+            counter: true,
+            ts: 0,
+        }
+    }
+    // Get labels of aggregated series, only `by` labels are kept.
+    pub fn group(&self, labels: &Labels) -> Labels {
+        let r: Vec<Label> = labels
+            .iter()
+            .filter(|x| self.by.contains(&x.key))
+            .cloned()
+            .collect();
+        if r.is_empty() {
+            Labels::default()
+        } else {
+            Labels::new(r)
+        }
+    }
+    pub fn help(&self) -> String {
+        format!(
+            "{} of {} by ({})",
+            self.op.as_str(),
+            self.metric,
+            self.by.join(", ")
+        )
+    }
+}
+
+impl AggregateOp {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AggregateOp::Sum => "sum",
+            AggregateOp::Avg => "avg",
+            AggregateOp::Min => "min",
+            AggregateOp::Max => "max",
+            AggregateOp::Count => "count",
+        }
+    }
+}
+
+impl Accumulator {
+    pub fn push(&mut self, value: &Value, ts: u64) {
+        let (v, integer, counter) = match value {
+            Value::Counter(x) => (*x as f64, true, true),
+            Value::CounterF(x) => (*x as f64, false, true),
+            Value::Gauge(x) => (*x as f64, true, false),
+            Value::GaugeI(x) => (*x as f64, true, false),
+            Value::GaugeF(x) => (*x as f64, false, false),
+        };
+        self.value = if self.count == 0 {
+            v
+        } else {
+            match self.op {
+                AggregateOp::Sum | AggregateOp::Avg => self.value + v,
+                AggregateOp::Min => self.value.min(v),
+                AggregateOp::Max => self.value.max(v),
+                AggregateOp::Count => 0.0,
+            }
+        };
+        self.count += 1;
+        self.integer &= integer;
+        self.counter &= counter;
+        self.ts = self.ts.max(ts);
+    }
+    pub fn ts(&self) -> u64 {
+        self.ts
+    }
+    // Sum of counters remains counter,
+    // all other aggregations produce gauges.
+    pub fn value(&self) -> Value {
+        match self.op {
+            AggregateOp::Count => Value::Gauge(self.count),
+            AggregateOp::Avg => Value::GaugeF((self.value / self.count as f64) as f32),
+            AggregateOp::Sum if self.counter => {
+                if self.integer {
+                    Value::Counter(self.value as u64)
+                } else {
+                    Value::CounterF(self.value as f32)
+                }
+            }
+            _ => {
+                if !self.integer {
+                    Value::GaugeF(self.value as f32)
+                } else if self.value < 0.0 {
+                    Value::GaugeI(self.value as i64)
+                } else {
+                    Value::Gauge(self.value as u64)
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,672 @@
+// --------------------------------------------------------------------
+// Gufo Agent: Local alerting engine
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use crate::{AlertRuleConfig, AlertsConfig, MetricsDb};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use common::{AgentError, Label, Labels};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{interval, Duration, MissedTickBehavior};
+
+const ALERTNAME_LABEL: &str = "alertname";
+const ALERTSTATE_LABEL: &str = "alertstate";
+const RECEIVER: &str = "gufo-agent";
+// Alertmanager's zero time for `endsAt`
+const ZERO_TIME: &str = "0001-01-01T00:00:00Z";
+// Maximal amount of undelivered notifications
+const MAX_QUEUE: usize = 1000;
+const WEBHOOK_TIMEOUT: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug)]
+struct AlertRule {
+    name: String,
+    metric: String,
+    matchers: Vec<Label>,
+    op: CmpOp,
+    value: f64,
+    r#for: u64,
+    labels: BTreeMap<String, String>,
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Debug)]
+struct AlertState {
+    annotations: BTreeMap<String, String>,
+    // Timestamp when the condition was met first
+    active_at: u64,
+    firing: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Alerts {
+    interval: u64,
+    webhook: Option<String>,
+    rules: Vec<AlertRule>,
+    // Alert labels -> state
+    active: HashMap<BTreeMap<String, String>, AlertState>,
+    // Undelivered notifications
+    queue: Vec<Notification>,
+    client: reqwest::Client,
+}
+
+// Running alerts engine
+pub(crate) struct AlertsHandle {
+    handle: JoinHandle<Alerts>,
+    stop: oneshot::Sender<()>,
+}
+
+// Alertmanager-compatible alert
+#[derive(Debug, Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Notification {
+    status: &'static str,
+    labels: BTreeMap<String, String>,
+    annotations: BTreeMap<String, String>,
+    starts_at: String,
+    ends_at: String,
+    #[serde(rename = "generatorURL")]
+    generator_url: String,
+    fingerprint: String,
+}
+
+// Alertmanager-compatible webhook payload
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WebhookMessage<'a> {
+    version: &'static str,
+    group_key: &'static str,
+    truncated_alerts: usize,
+    status: &'static str,
+    receiver: &'static str,
+    group_labels: BTreeMap<String, String>,
+    common_labels: BTreeMap<String, String>,
+    common_annotations: BTreeMap<String, String>,
+    #[serde(rename = "externalURL")]
+    external_url: &'static str,
+    alerts: &'a [Notification],
+}
+
+impl TryFrom<&AlertRuleConfig> for AlertRule {
+    type Error = AgentError;
+
+    fn try_from(value: &AlertRuleConfig) -> Result<Self, Self::Error> {
+        if value.alert.is_empty() {
+            return Err(AgentError::ConfigurationError(
+                "alerts: alert must be set".into(),
+            ));
+        }
+        Ok(Self {
+            name: value.alert.clone(),
+            metric: value.metric.clone(),
+            matchers: match &value.r#match {
+                Some(x) => x.iter().map(|(k, v)| Label::new(k, v)).collect(),
+                None => Vec::new(),
+            },
+            op: match value.op.as_str() {
+                ">" => CmpOp::Gt,
+                ">=" => CmpOp::Ge,
+                "<" => CmpOp::Lt,
+                "<=" => CmpOp::Le,
+                "==" => CmpOp::Eq,
+                "!=" => CmpOp::Ne,
+                _ => {
+                    return Err(AgentError::ConfigurationError(format!(
+                        "invalid alert op: {}",
+                        value.op
+                    )))
+                }
+            },
+            value: value.value,
+            r#for: value.r#for,
+            labels: value.labels.clone().unwrap_or_default(),
+            annotations: value.annotations.clone().unwrap_or_default(),
+        })
+    }
+}
+
+impl TryFrom<&AlertsConfig> for Alerts {
+    type Error = AgentError;
+
+    fn try_from(value: &AlertsConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            interval: match value.interval {
+                Some(0) | None => {
+                    return Err(AgentError::ConfigurationError(
+                        "alerts: invalid interval".into(),
+                    ))
+                }
+                Some(x) => x,
+            },
+            webhook: value.webhook.clone(),
+            rules: value
+                .rules
+                .iter()
+                .map(AlertRule::try_from)
+                .collect::<Result<Vec<_>, _>>()?,
+            active: HashMap::new(),
+            queue: Vec::new(),
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(WEBHOOK_TIMEOUT))
+                .build()
+                .map_err(|e| AgentError::ConfigurationError(e.to_string()))?,
+        })
+    }
+}
+
+impl CmpOp {
+    fn is_match(&self, v: f64, threshold: f64) -> bool {
+        match self {
+            CmpOp::Gt => v > threshold,
+            CmpOp::Ge => v >= threshold,
+            CmpOp::Lt => v < threshold,
+            CmpOp::Le => v <= threshold,
+            CmpOp::Eq => v == threshold,
+            CmpOp::Ne => v != threshold,
+        }
+    }
+}
+
+impl AlertRule {
+    fn is_selected(&self, labels: &Labels) -> bool {
+        self.matchers.iter().all(|m| labels.iter().any(|x| x == m))
+    }
+    // Series labels, overriden by rule labels
+    fn alert_labels(&self, labels: &Labels) -> BTreeMap<String, String> {
+        let mut r = BTreeMap::new();
+        labels.update_map(&mut r);
+        for (k, v) in self.labels.iter() {
+            r.insert(k.clone(), v.clone());
+        }
+        r.insert(ALERTNAME_LABEL.into(), self.name.clone());
+        r
+    }
+    // Expand `{{ $value }}` and `{{ $labels.<name> }}` in annotations
+    fn expand_annotations(
+        &self,
+        labels: &BTreeMap<String, String>,
+        value: f64,
+    ) -> BTreeMap<String, String> {
+        self.annotations
+            .iter()
+            .map(|(k, v)| {
+                let mut r = v.replace("{{ $value }}", &value.to_string());
+                for (name, label_value) in labels.iter() {
+                    r = r.replace(&format!("{{{{ $labels.{} }}}}", name), label_value);
+                }
+                (k.clone(), r)
+            })
+            .collect()
+    }
+}
+
+impl Alerts {
+    // Run engine in the background task
+    pub fn spawn(self, db: MetricsDb) -> AlertsHandle {
+        let (stop, rx) = oneshot::channel();
+        AlertsHandle {
+            handle: tokio::spawn(self.run(db, rx)),
+            stop,
+        }
+    }
+    // Carry over alerts state and undelivered notifications
+    // of the previous engine on reconfiguration.
+    // Alerts of removed or changed rules are resolved on the next evaluation.
+    pub fn inherit(&mut self, prev: Alerts) {
+        self.active = prev.active;
+        self.queue = prev.queue;
+    }
+    // Resolve all active alerts and send the notifications.
+    // Used when alerting is disabled.
+    pub async fn shutdown(mut self) {
+        let ts = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(x) => x.as_secs(),
+            Err(e) => {
+                log::error!("Failed to get timestamp: {}", e);
+                0
+            }
+        };
+        self.resolve_all(ts);
+        self.notify().await;
+    }
+    fn resolve_all(&mut self, ts: u64) {
+        for (labels, state) in self.active.drain() {
+            if state.firing {
+                log::info!("Alert is resolved: {:?}", labels);
+                self.queue
+                    .push(Notification::new(&labels, &state, Some(ts)));
+            }
+        }
+    }
+    async fn run(mut self, mut db: MetricsDb, mut stop: oneshot::Receiver<()>) -> Self {
+        log::info!("Running alerts with {} rules", self.rules.len());
+        let mut ticker = interval(Duration::from_secs(self.interval));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                _ = &mut stop => break,
+            }
+            let ts = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                Ok(x) => x.as_secs(),
+                Err(e) => {
+                    log::error!("Failed to get timestamp: {}", e);
+                    continue;
+                }
+            };
+            self.evaluate(&mut db, ts).await;
+            self.notify().await;
+        }
+        self
+    }
+    // Evaluate all rules, update alerts state and `ALERTS` series.
+    async fn evaluate(&mut self, db: &mut MetricsDb, ts: u64) {
+        let mut seen = HashSet::new();
+        for rule in self.rules.iter() {
+            for (labels, value) in db.query(&rule.metric).await {
+                let value = value.as_f64();
+                if !rule.is_selected(&labels) || !rule.op.is_match(value, rule.value) {
+                    continue;
+                }
+                let alert_labels = rule.alert_labels(&labels);
+                let annotations = rule.expand_annotations(&alert_labels, value);
+                let state = self
+                    .active
+                    .entry(alert_labels.clone())
+                    .or_insert_with(|| AlertState {
+                        annotations: BTreeMap::new(),
+                        active_at: ts,
+                        firing: false,
+                    });
+                state.annotations = annotations;
+                if !state.firing && ts.saturating_sub(state.active_at) >= rule.r#for {
+                    state.firing = true;
+                    log::info!("Alert {} is firing: {:?}", rule.name, alert_labels);
+                    self.queue
+                        .push(Notification::new(&alert_labels, state, None));
+                }
+                seen.insert(alert_labels);
+            }
+        }
+        // Resolve alerts which conditions are not met anymore
+        let resolved: Vec<BTreeMap<String, String>> = self
+            .active
+            .keys()
+            .filter(|k| !seen.contains(*k))
+            .cloned()
+            .collect();
+        for labels in resolved.into_iter() {
+            if let Some(state) = self.active.remove(&labels) {
+                if state.firing {
+                    log::info!("Alert is resolved: {:?}", labels);
+                    self.queue
+                        .push(Notification::new(&labels, &state, Some(ts)));
+                }
+            }
+        }
+        if self.queue.len() > MAX_QUEUE {
+            let n = self.queue.len() - MAX_QUEUE;
+            log::error!("Notification queue is full, dropping {} notifications", n);
+            self.queue.drain(..n);
+        }
+        // Update ALERTS
+        let alerts = self
+            .active
+            .iter()
+            .map(|(labels, state)| {
+                let mut r: Vec<Label> = labels.iter().map(|(k, v)| Label::new(k, v)).collect();
+                r.push(Label::new(
+                    ALERTSTATE_LABEL,
+                    if state.firing { "firing" } else { "pending" },
+                ));
+                r.sort();
+                Labels::new(r)
+            })
+            .collect();
+        db.set_alerts(alerts, ts).await;
+    }
+    // Send queued notifications to webhook.
+    // Notifications are kept in queue until delivered.
+    async fn notify(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+        let url = match &self.webhook {
+            Some(x) => x,
+            None => {
+                self.queue.clear();
+                return;
+            }
+        };
+        let msg = WebhookMessage::new(&self.queue);
+        match self.client.post(url).json(&msg).send().await {
+            Ok(resp) if resp.status().is_success() => {
+                log::debug!("{} notifications sent", self.queue.len());
+                self.queue.clear();
+            }
+            Ok(resp) => log::error!("Failed to send notifications: HTTP {}", resp.status()),
+            Err(e) => log::error!("Failed to send notifications: {}", e),
+        }
+    }
+}
+
+impl AlertsHandle {
+    // Stop engine and get its state back
+    pub async fn stop(self) -> Option<Alerts> {
+        let _ = self.stop.send(());
+        match self.handle.await {
+            Ok(alerts) => Some(alerts),
+            Err(e) => {
+                log::error!("Alerts engine failed: {}", e);
+                None
+            }
+        }
+    }
+}
+
+impl Notification {
+    fn new(
+        labels: &BTreeMap<String, String>,
+        state: &AlertState,
+        resolved_at: Option<u64>,
+    ) -> Self {
+        Self {
+            status: if resolved_at.is_some() {
+                "resolved"
+            } else {
+                "firing"
+            },
+            labels: labels.clone(),
+            annotations: state.annotations.clone(),
+            starts_at: to_rfc3339(state.active_at),
+            ends_at: match resolved_at {
+                Some(ts) => to_rfc3339(ts),
+                None => ZERO_TIME.into(),
+            },
+            generator_url: String::new(),
+            fingerprint: fingerprint(labels),
+        }
+    }
+}
+
+impl<'a> WebhookMessage<'a> {
+    fn new(alerts: &'a [Notification]) -> Self {
+        // Labels and annotations shared by all alerts
+        let common = |f: fn(&Notification) -> &BTreeMap<String, String>| {
+            let mut r = alerts.first().map(|x| f(x).clone()).unwrap_or_default();
+            for alert in alerts.iter().skip(1) {
+                r.retain(|k, v| f(alert).get(k) == Some(v));
+            }
+            r
+        };
+        Self {
+            version: "4",
+            group_key: "{}:{}",
+            truncated_alerts: 0,
+            status: if alerts.iter().any(|x| x.status == "firing") {
+                "firing"
+            } else {
+                "resolved"
+            },
+            receiver: RECEIVER,
+            group_labels: BTreeMap::new(),
+            common_labels: common(|x| &x.labels),
+            common_annotations: common(|x| &x.annotations),
+            external_url: "",
+            alerts,
+        }
+    }
+}
+
+fn to_rfc3339(ts: u64) -> String {
+    match Utc.timestamp_opt(ts as i64, 0).single() {
+        Some(x) => x.to_rfc3339_opts(SecondsFormat::Secs, true),
+        None => ZERO_TIME.into(),
+    }
+}
+
+// Prometheus-compatible label set fingerprint (FNV-1a 64)
+fn fingerprint(labels: &BTreeMap<String, String>) -> String {
+    const OFFSET: u64 = 14695981039346656037;
+    const PRIME: u64 = 1099511628211;
+    const SEPARATOR: u8 = 0xff;
+    let mut h = OFFSET;
+    for (k, v) in labels.iter() {
+        for b in k
+            .bytes()
+            .chain([SEPARATOR])
+            .chain(v.bytes())
+            .chain([SEPARATOR])
+        {
+            h ^= b as u64;
+            h = h.wrapping_mul(PRIME);
+        }
+    }
+    format!("{:016x}", h)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fingerprint, Alerts};
+    use crate::{AlertRuleConfig, AlertsConfig, MetricsData, MetricsDb};
+    use common::{Label, Labels, Measure, Value};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn alerts(webhook: Option<String>) -> Alerts {
+        Alerts::try_from(&AlertsConfig {
+            interval: Some(10),
+            webhook,
+            rules: vec![AlertRuleConfig {
+                alert: "HighCpu".into(),
+                metric: "cpu_user".into(),
+                r#match: Some(BTreeMap::from([("cpu".into(), "0".into())])),
+                op: ">".into(),
+                value: 90.0,
+                r#for: 20,
+                labels: Some(BTreeMap::from([("severity".into(), "warning".into())])),
+                annotations: Some(BTreeMap::from([(
+                    "summary".into(),
+                    "CPU {{ $labels.cpu }} usage is {{ $value }}".into(),
+                )])),
+            }],
+        })
+        .unwrap()
+    }
+
+    async fn set_cpu(db: &mut MetricsDb, values: &[(&str, u64)]) {
+        db.apply_data(&MetricsData {
+            collector: "cpu",
+            id: "cpu".into(),
+            labels: Arc::new(Labels::default()),
+            relabel: Arc::new(None),
+            limits: Arc::new(None),
+            aggregate: Arc::new(None),
+            rates: false,
+            measures: values
+                .iter()
+                .map(|(cpu, v)| Measure {
+                    name: "cpu_user".into(),
+                    help: "".into(),
+                    value: Value::Gauge(*v),
+                    labels: Labels::new(vec![Label::new("cpu", cpu)]),
+                    timestamp: None,
+                    exemplar: None,
+                })
+                .collect(),
+            ts: 0,
+            ttl: 0,
+        })
+        .await;
+    }
+
+    #[test]
+    fn test_invalid_op() {
+        let r = Alerts::try_from(&AlertsConfig {
+            interval: Some(10),
+            webhook: None,
+            rules: vec![AlertRuleConfig {
+                alert: "A".into(),
+                metric: "m".into(),
+                r#match: None,
+                op: "=~".into(),
+                value: 0.0,
+                r#for: 0,
+                labels: None,
+                annotations: None,
+            }],
+        });
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_invalid_interval() {
+        let r = Alerts::try_from(&AlertsConfig {
+            interval: Some(0),
+            webhook: None,
+            rules: Vec::new(),
+        });
+        assert!(r.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_inherit() {
+        let mut db = MetricsDb::default();
+        let mut prev = alerts(None);
+        set_cpu(&mut db, &[("0", 95)]).await;
+        prev.evaluate(&mut db, 100).await;
+        prev.evaluate(&mut db, 120).await;
+        assert_eq!(prev.queue.len(), 1);
+        // Firing state is kept by the new engine
+        let mut alerts = alerts(None);
+        alerts.inherit(prev);
+        alerts.evaluate(&mut db, 130).await;
+        assert_eq!(alerts.queue.len(), 1);
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(out.contains("alertstate=\"firing\""));
+        // Resolved on shutdown
+        alerts.resolve_all(140);
+        assert!(alerts.active.is_empty());
+        assert_eq!(alerts.queue.len(), 2);
+        assert_eq!(alerts.queue[1].status, "resolved");
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        // Empty label set fingerprint is FNV-1a offset basis
+        assert_eq!(fingerprint(&BTreeMap::new()), "cbf29ce484222325");
+        let a = BTreeMap::from([("a".to_string(), "b".to_string())]);
+        let b = BTreeMap::from([("a".to_string(), "c".to_string())]);
+        assert_ne!(fingerprint(&a), fingerprint(&b));
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle() {
+        let mut db = MetricsDb::default();
+        let mut alerts = alerts(None);
+        set_cpu(&mut db, &[("0", 95), ("1", 99)]).await;
+        // Pending
+        alerts.evaluate(&mut db, 100).await;
+        assert!(alerts.queue.is_empty());
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(out.contains(
+            "ALERTS{alertname=\"HighCpu\",alertstate=\"pending\",cpu=\"0\",severity=\"warning\"} 1 100\n"
+        ));
+        assert!(!out.contains("cpu=\"1\",severity"));
+        // Firing after `for`
+        alerts.evaluate(&mut db, 110).await;
+        assert!(alerts.queue.is_empty());
+        alerts.evaluate(&mut db, 120).await;
+        assert_eq!(alerts.queue.len(), 1);
+        let n = &alerts.queue[0];
+        assert_eq!(n.status, "firing");
+        assert_eq!(n.starts_at, "1970-01-01T00:01:40Z");
+        assert_eq!(n.ends_at, "0001-01-01T00:00:00Z");
+        assert_eq!(n.annotations["summary"], "CPU 0 usage is 95");
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(out.contains("alertstate=\"firing\""));
+        // No repeated notifications while firing
+        alerts.evaluate(&mut db, 130).await;
+        assert_eq!(alerts.queue.len(), 1);
+        // Resolved
+        set_cpu(&mut db, &[("0", 10)]).await;
+        alerts.evaluate(&mut db, 140).await;
+        assert_eq!(alerts.queue.len(), 2);
+        assert_eq!(alerts.queue[1].status, "resolved");
+        assert_eq!(alerts.queue[1].ends_at, "1970-01-01T00:02:20Z");
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(!out.contains("ALERTS"));
+        // Without webhook queue is dropped
+        alerts.notify().await;
+        assert!(alerts.queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_webhook() {
+        // Local webhook receiver
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        let receiver = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 4096];
+            loop {
+                let n = sock.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                let req = String::from_utf8_lossy(&buf).to_string();
+                if let Some((head, body)) = req.split_once("\r\n\r\n") {
+                    let len = head
+                        .lines()
+                        .find_map(|x| {
+                            x.to_lowercase()
+                                .strip_prefix("content-length: ")
+                                .map(|v| v.parse::<usize>().unwrap())
+                        })
+                        .unwrap();
+                    if body.len() >= len {
+                        sock.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                            .await
+                            .unwrap();
+                        return (head.to_string(), body.to_string());
+                    }
+                }
+            }
+        });
+        let mut db = MetricsDb::default();
+        let mut alerts = alerts(Some(url));
+        set_cpu(&mut db, &[("0", 95)]).await;
+        alerts.evaluate(&mut db, 100).await;
+        alerts.evaluate(&mut db, 120).await;
+        alerts.notify().await;
+        assert!(alerts.queue.is_empty());
+        let (head, body) = receiver.await.unwrap();
+        assert!(head.starts_with("POST /alerts HTTP/1.1"));
+        let msg: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(msg["version"], "4");
+        assert_eq!(msg["status"], "firing");
+        assert_eq!(msg["receiver"], "gufo-agent");
+        assert_eq!(msg["commonLabels"]["alertname"], "HighCpu");
+        let alert = &msg["alerts"][0];
+        assert_eq!(alert["status"], "firing");
+        assert_eq!(alert["labels"]["severity"], "warning");
+        assert_eq!(alert["startsAt"], "1970-01-01T00:01:40Z");
+        assert_eq!(alert["fingerprint"].as_str().unwrap().len(), 16);
+    }
+}
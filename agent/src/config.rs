@@ -29,6 +29,14 @@ pub struct AgentConfig {
     pub labels: LabelsConfig,
     #[serde(default = "AgentDefaults::default")]
     pub defaults: AgentDefaults,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<LimitsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggregate: Option<Vec<AggregateConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alerts: Option<AlertsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relabel: Option<Vec<RelabelRuleConfig>>,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -72,10 +80,65 @@ pub struct CollectorConfig {
     pub labels: LabelsConfig,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub relabel: Option<Vec<RelabelRuleConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limits: Option<LimitsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aggregate: Option<Vec<AggregateConfig>>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub rates: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub series_ttl: Option<u64>,
     #[serde(flatten)]
     pub config: serde_yaml::Value,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+pub struct LimitsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_series: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_label_length: Option<usize>,
+    #[serde(default = "default_drop_series")]
+    pub action: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+pub struct AggregateConfig {
+    pub metric: String,
+    pub op: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub by: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default = "default_true")]
+    pub keep_source: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash)]
+pub struct AlertsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+    pub rules: Vec<AlertRuleConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AlertRuleConfig {
+    pub alert: String,
+    pub metric: String,
+    #[serde(rename = "match", skip_serializing_if = "Option::is_none")]
+    pub r#match: LabelsConfig,
+    pub op: String,
+    pub value: f64,
+    #[serde(rename = "for", default)]
+    pub r#for: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: LabelsConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: LabelsConfig,
+}
+
 impl Hash for CollectorConfig {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.id.hash(state);
@@ -85,6 +148,10 @@ impl Hash for CollectorConfig {
             labels.hash(state);
         }
         self.disabled.hash(state);
+        self.limits.hash(state);
+        self.aggregate.hash(state);
+        self.rates.hash(state);
+        self.series_ttl.hash(state);
         self.config.hash(state);
     }
 }
@@ -97,6 +164,27 @@ impl CollectorConfig {
     }
 }
 
+impl Hash for AlertRuleConfig {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.alert.hash(state);
+        self.metric.hash(state);
+        self.r#match.hash(state);
+        self.op.hash(state);
+        self.value.to_bits().hash(state);
+        self.r#for.hash(state);
+        self.labels.hash(state);
+        self.annotations.hash(state);
+    }
+}
+
+impl AlertsConfig {
+    pub fn get_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
 impl Default for AgentDefaults {
     fn default() -> Self {
         AgentDefaults {
@@ -133,6 +221,10 @@ fn default_metrics() -> String {
     "/metrics".into()
 }
 
+fn default_drop_series() -> String {
+    "drop_series".into()
+}
+
 fn default_none() -> Option<String> {
     None
 }
@@ -141,6 +233,10 @@ fn default_interval() -> u64 {
     AGENT_DEFAULT_INTERVAL
 }
 
+fn default_true() -> bool {
+    true
+}
+
 fn default_false() -> bool {
     false
 }
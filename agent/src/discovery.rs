@@ -54,6 +54,10 @@ fn config_from_collectors(opts: &ConfigDiscoveryOpts) -> Result<Vec<CollectorCon
                 interval: None,
                 labels: None,
                 relabel: None,
+                limits: None,
+                aggregate: None,
+                rates: false,
+                series_ttl: None,
                 config: cfg.config.clone(),
             });
         }
@@ -0,0 +1,973 @@
+// --------------------------------------------------------------------
+// Gufo Agent: Prometheus config import
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use crate::{AgentConfig, CollectorConfig, Config, SenderConfig};
+use common::AgentError;
+use relabel::RelabelRuleConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// Prometheus defaults
+const DEFAULT_SCRAPE_INTERVAL: &str = "1m";
+const DEFAULT_SEPARATOR: &str = ";";
+const DEFAULT_REGEX: &str = "(.*)";
+const DEFAULT_REPLACEMENT: &str = "$1";
+// Prometheus target labels and their scrape collector counterparts
+const LABEL_MAP: &[(&str, &str)] = &[
+    ("__scheme__", "__meta_sd_schema"),
+    ("__metrics_path__", "__meta_sd_path"),
+];
+// Prometheus target labels with no counterparts
+const UNSUPPORTED_LABELS: &[&str] = &[
+    "__param_",
+    "__scrape_interval__",
+    "__scrape_timeout__",
+    "__meta_dns_",
+];
+
+// Fields unknown to the importer
+type Rest = BTreeMap<String, serde_yaml::Value>;
+
+// Result of the import
+pub struct PrometheusImport {
+    // Resulting config, as YAML
+    pub config: String,
+    // Settings which have not been converted
+    pub unsupported: Vec<String>,
+}
+
+// Prometheus config, only the parts related to scraping
+#[derive(Deserialize)]
+struct PromConfig {
+    #[serde(default)]
+    global: PromGlobalConfig,
+    #[serde(default)]
+    scrape_configs: Vec<PromScrapeConfig>,
+    #[serde(flatten)]
+    rest: Rest,
+}
+
+#[derive(Deserialize, Default)]
+struct PromGlobalConfig {
+    scrape_interval: Option<String>,
+    scrape_timeout: Option<String>,
+    #[serde(flatten)]
+    rest: Rest,
+}
+
+#[derive(Deserialize)]
+struct PromScrapeConfig {
+    job_name: String,
+    scrape_interval: Option<String>,
+    scrape_timeout: Option<String>,
+    metrics_path: Option<String>,
+    scheme: Option<String>,
+    params: Option<BTreeMap<String, Vec<String>>>,
+    honor_labels: Option<bool>,
+    honor_timestamps: Option<bool>,
+    sample_limit: Option<usize>,
+    body_size_limit: Option<String>,
+    #[serde(default)]
+    static_configs: Vec<PromStaticConfig>,
+    #[serde(default)]
+    file_sd_configs: Vec<PromFileSdConfig>,
+    #[serde(default)]
+    consul_sd_configs: Vec<PromConsulSdConfig>,
+    #[serde(default)]
+    dns_sd_configs: Vec<PromDnsSdConfig>,
+    #[serde(default)]
+    relabel_configs: Vec<PromRelabelConfig>,
+    #[serde(default)]
+    metric_relabel_configs: Vec<PromRelabelConfig>,
+    // Must precede `rest`, to consume its fields
+    #[serde(flatten)]
+    client: PromClientConfig,
+    #[serde(flatten)]
+    rest: Rest,
+}
+
+#[derive(Deserialize)]
+struct PromClientConfig {
+    basic_auth: Option<BasicAuthConfig>,
+    authorization: Option<PromAuthorization>,
+    bearer_token: Option<String>,
+    bearer_token_file: Option<String>,
+    tls_config: Option<TlsConfig>,
+    proxy_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PromAuthorization {
+    #[serde(rename = "type")]
+    auth_type: Option<String>,
+    credentials: Option<String>,
+    credentials_file: Option<String>,
+    #[serde(flatten)]
+    rest: Rest,
+}
+
+#[derive(Deserialize)]
+struct PromStaticConfig {
+    #[serde(default)]
+    targets: Vec<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(flatten)]
+    rest: Rest,
+}
+
+#[derive(Deserialize)]
+struct PromFileSdConfig {
+    files: Vec<String>,
+    #[serde(flatten)]
+    rest: Rest,
+}
+
+#[derive(Deserialize)]
+struct PromConsulSdConfig {
+    server: Option<String>,
+    scheme: Option<String>,
+    datacenter: Option<String>,
+    token: Option<String>,
+    services: Option<Vec<String>>,
+    tags: Option<Vec<String>>,
+    node_meta: Option<BTreeMap<String, String>>,
+    filter: Option<String>,
+    tag_separator: Option<String>,
+    refresh_interval: Option<String>,
+    #[serde(flatten)]
+    client: PromClientConfig,
+    #[serde(flatten)]
+    rest: Rest,
+}
+
+#[derive(Deserialize)]
+struct PromDnsSdConfig {
+    names: Vec<String>,
+    #[serde(rename = "type")]
+    query_type: Option<String>,
+    port: Option<u16>,
+    #[serde(flatten)]
+    rest: Rest,
+}
+
+#[derive(Deserialize)]
+struct PromRelabelConfig {
+    source_labels: Option<Vec<String>>,
+    separator: Option<String>,
+    regex: Option<String>,
+    modulus: Option<u64>,
+    target_label: Option<String>,
+    replacement: Option<String>,
+    action: Option<String>,
+    #[serde(flatten)]
+    rest: Rest,
+}
+
+// Shared by Prometheus and the scrape collector
+#[derive(Deserialize, Serialize, Clone)]
+struct BasicAuthConfig {
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password_file: Option<String>,
+    #[serde(flatten, skip_serializing)]
+    rest: Rest,
+}
+
+// Shared by Prometheus and the scrape collector
+#[derive(Deserialize, Serialize, Clone)]
+struct TlsConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ca_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cert_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    insecure_skip_verify: Option<bool>,
+    #[serde(flatten, skip_serializing)]
+    rest: Rest,
+}
+
+// scrape collector config
+#[derive(Serialize)]
+struct ScrapeConfig {
+    service_discovery: SdConfig,
+    trust_timestamps: bool,
+    #[serde(skip_serializing_if = "is_false")]
+    honor_labels: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    params: Option<BTreeMap<String, Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body_size_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metric_relabel: Option<Vec<RelabelRuleConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timeout_ms: Option<u64>,
+    #[serde(flatten)]
+    client: ClientConfig,
+}
+
+#[derive(Serialize)]
+struct SdConfig {
+    #[serde(flatten)]
+    method: SdMethodConfig,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relabel: Option<Vec<RelabelRuleConfig>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schema: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum SdMethodConfig {
+    Consul(Box<ConsulSdConfig>),
+    Dns {
+        query: String,
+        query_type: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        port: Option<u16>,
+    },
+    File {
+        files: Vec<String>,
+    },
+    Static {
+        targets: Vec<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct ConsulSdConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scheme: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    datacenter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    services: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node_meta: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag_separator: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_interval_ms: Option<u64>,
+    #[serde(flatten)]
+    client: ClientConfig,
+}
+
+#[derive(Serialize, Clone)]
+struct ClientConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_config: Option<TlsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    basic_auth: Option<BasicAuthConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bearer_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bearer_token_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxy_url: Option<String>,
+}
+
+// Collects the settings which have not been converted
+struct Report<'a> {
+    scope: String,
+    items: &'a mut Vec<String>,
+}
+
+// Convert `scrape_configs` of Prometheus config to the `scrape` collectors.
+// Every service discovery config of the job becomes a separate collector.
+pub fn import_prometheus(data: &str) -> Result<PrometheusImport, AgentError> {
+    let cfg: PromConfig =
+        serde_yaml::from_str(data).map_err(|e| AgentError::ConfigurationError(e.to_string()))?;
+    let mut unsupported = Vec::new();
+    {
+        let mut report = Report::new("config", &mut unsupported);
+        report.rest("", &cfg.rest);
+        report.rest("global.", &cfg.global.rest);
+    }
+    let mut collectors = Vec::new();
+    for job in cfg.scrape_configs.into_iter() {
+        let mut r = import_job(job, &cfg.global, &mut unsupported)?;
+        collectors.append(&mut r);
+    }
+    let r = Config {
+        version: "1.0".into(),
+        r#type: "zeroconf".into(),
+        agent: AgentConfig::default(),
+        sender: SenderConfig::default(),
+        collectors,
+    };
+    Ok(PrometheusImport {
+        config: serde_yaml::to_string(&r)
+            .map_err(|e| AgentError::ConfigurationError(e.to_string()))?,
+        unsupported,
+    })
+}
+
+fn import_job(
+    job: PromScrapeConfig,
+    global: &PromGlobalConfig,
+    unsupported: &mut Vec<String>,
+) -> Result<Vec<CollectorConfig>, AgentError> {
+    let mut report = Report::new(&format!("job `{}`", job.job_name), unsupported);
+    report.rest("", &job.rest);
+    // Intervals
+    let interval_ms = parse_duration(
+        job.scrape_interval
+            .as_deref()
+            .or(global.scrape_interval.as_deref())
+            .unwrap_or(DEFAULT_SCRAPE_INTERVAL),
+    )?;
+    if interval_ms % 1000 != 0 {
+        report.push("`scrape_interval` is rounded up to seconds".to_string());
+    }
+    let interval = interval_ms.div_ceil(1000).max(1);
+    let timeout_ms = match job
+        .scrape_timeout
+        .as_ref()
+        .or(global.scrape_timeout.as_ref())
+    {
+        Some(x) => Some(parse_duration(x)?),
+        None => None,
+    };
+    // Limits, 0 means no limit
+    let body_size_limit = match &job.body_size_limit {
+        Some(x) => Some(parse_size(x)?).filter(|x| *x > 0),
+        None => None,
+    };
+    let sample_limit = job.sample_limit.filter(|x| *x > 0);
+    // Service discovery
+    let mut methods = Vec::new();
+    for (i, cfg) in job.static_configs.into_iter().enumerate() {
+        report.rest(&format!("static_configs[{}].", i), &cfg.rest);
+        let rules = cfg
+            .labels
+            .iter()
+            .map(|(k, v)| set_label(k, v))
+            .collect::<Vec<_>>();
+        methods.push((
+            SdMethodConfig::Static {
+                targets: cfg.targets,
+            },
+            rules,
+        ));
+    }
+    for (i, mut cfg) in job.file_sd_configs.into_iter().enumerate() {
+        // Files are checked on every run
+        cfg.rest.remove("refresh_interval");
+        report.rest(&format!("file_sd_configs[{}].", i), &cfg.rest);
+        methods.push((SdMethodConfig::File { files: cfg.files }, Vec::new()));
+    }
+    for (i, cfg) in job.consul_sd_configs.into_iter().enumerate() {
+        let path = format!("consul_sd_configs[{}].", i);
+        report.rest(&path, &cfg.rest);
+        let refresh_interval_ms = match &cfg.refresh_interval {
+            Some(x) => Some(parse_duration(x)?),
+            None => None,
+        };
+        methods.push((
+            SdMethodConfig::Consul(Box::new(ConsulSdConfig {
+                server: cfg.server,
+                scheme: cfg.scheme,
+                datacenter: cfg.datacenter,
+                token: cfg.token,
+                services: cfg.services,
+                tags: cfg.tags,
+                node_meta: cfg.node_meta,
+                filter: cfg.filter,
+                tag_separator: cfg.tag_separator,
+                refresh_interval_ms,
+                client: import_client(cfg.client, &path, &mut report),
+            })),
+            Vec::new(),
+        ));
+    }
+    for (i, mut cfg) in job.dns_sd_configs.into_iter().enumerate() {
+        let path = format!("dns_sd_configs[{}].", i);
+        // Names are resolved on every run
+        cfg.rest.remove("refresh_interval");
+        report.rest(&path, &cfg.rest);
+        let query_type = cfg.query_type.unwrap_or_else(|| "SRV".to_string());
+        let port = match query_type.as_str() {
+            "A" => match cfg.port {
+                Some(_) => cfg.port,
+                None => {
+                    return Err(AgentError::ConfigurationError(format!(
+                        "{}: `{}port` must be set for A query",
+                        report.scope, path
+                    )))
+                }
+            },
+            // Port is taken from SRV record
+            "SRV" => None,
+            _ => {
+                report.push(format!("`{}type` {} is not supported", path, query_type));
+                continue;
+            }
+        };
+        for name in cfg.names.into_iter() {
+            methods.push((
+                SdMethodConfig::Dns {
+                    query: name,
+                    query_type: query_type.clone(),
+                    port,
+                },
+                Vec::new(),
+            ));
+        }
+    }
+    if methods.is_empty() {
+        report.push("no supported service discovery, skipped".to_string());
+        return Ok(Vec::new());
+    }
+    let relabel = import_rules(job.relabel_configs, "relabel_configs", &mut report);
+    let metric_relabel = import_rules(
+        job.metric_relabel_configs,
+        "metric_relabel_configs",
+        &mut report,
+    );
+    let client = import_client(job.client, "", &mut report);
+    // Build collectors
+    let mut r = Vec::with_capacity(methods.len());
+    for (n, (method, static_rules)) in methods.into_iter().enumerate() {
+        // Target labels, as set by Prometheus.
+        // `instance` is reset to `__address__` after the relabeling,
+        // unless the rules have changed it.
+        let mut rules = vec![set_label("job", &job.job_name), set_label("instance", "")];
+        rules.extend(static_rules);
+        rules.extend(relabel.iter().cloned());
+        rules.push(RelabelRuleConfig {
+            source_labels: Some(vec!["instance".to_string(), "__address__".to_string()]),
+            regex: Some("^;(.*)$".to_string()),
+            replacement: Some(DEFAULT_REPLACEMENT.to_string()),
+            ..set_label("instance", "")
+        });
+        // Prometheus drops all the labels starting with `__`,
+        // while the collector keeps the ones it does not know.
+        if rules
+            .iter()
+            .filter_map(|x| x.target_label.as_deref())
+            .any(is_temporary)
+        {
+            rules.push(rule("labeldrop", "^__.*"));
+        }
+        let cfg = ScrapeConfig {
+            service_discovery: SdConfig {
+                method,
+                relabel: Some(rules),
+                schema: job.scheme.clone(),
+                path: job.metrics_path.clone(),
+            },
+            trust_timestamps: job.honor_timestamps.unwrap_or(true),
+            honor_labels: job.honor_labels.unwrap_or(false),
+            params: job.params.clone(),
+            body_size_limit,
+            sample_limit,
+            metric_relabel: Some(metric_relabel.clone()).filter(|x| !x.is_empty()),
+            timeout_ms,
+            client: client.clone(),
+        };
+        r.push(CollectorConfig {
+            id: match n {
+                0 => job.job_name.clone(),
+                _ => format!("{} ({})", job.job_name, n + 1),
+            },
+            r#type: "scrape".to_string(),
+            interval: Some(interval),
+            disabled: false,
+            labels: None,
+            relabel: None,
+            limits: None,
+            aggregate: None,
+            rates: false,
+            series_ttl: None,
+            config: serde_yaml::to_value(cfg)
+                .map_err(|e| AgentError::ConfigurationError(e.to_string()))?,
+        });
+    }
+    Ok(r)
+}
+
+fn import_client(cfg: PromClientConfig, path: &str, report: &mut Report) -> ClientConfig {
+    let mut r = ClientConfig {
+        tls_config: cfg.tls_config,
+        basic_auth: cfg.basic_auth,
+        bearer_token: cfg.bearer_token,
+        bearer_token_file: cfg.bearer_token_file,
+        proxy_url: cfg.proxy_url,
+    };
+    if let Some(tls) = &r.tls_config {
+        report.rest(&format!("{}tls_config.", path), &tls.rest);
+    }
+    if let Some(auth) = &r.basic_auth {
+        report.rest(&format!("{}basic_auth.", path), &auth.rest);
+    }
+    if let Some(auth) = cfg.authorization {
+        report.rest(&format!("{}authorization.", path), &auth.rest);
+        match auth.auth_type.as_deref() {
+            None | Some("Bearer") => {
+                r.bearer_token = auth.credentials;
+                r.bearer_token_file = auth.credentials_file;
+            }
+            Some(x) => report.push(format!(
+                "`{}authorization.type` {} is not supported",
+                path, x
+            )),
+        }
+    }
+    r
+}
+
+fn import_rules(
+    rules: Vec<PromRelabelConfig>,
+    path: &str,
+    report: &mut Report,
+) -> Vec<RelabelRuleConfig> {
+    let mut r = Vec::with_capacity(rules.len());
+    for (i, cfg) in rules.into_iter().enumerate() {
+        let path = format!("{}[{}]", path, i);
+        report.rest(&format!("{}.", path), &cfg.rest);
+        let action = cfg
+            .action
+            .map(|x| x.to_lowercase())
+            .unwrap_or_else(|| "replace".to_string());
+        // Prometheus anchors regular expressions
+        let regex = match (&cfg.regex, action.as_str()) {
+            (Some(x), _) => Some(x.as_str()),
+            (None, "labelmap" | "labeldrop" | "labelkeep") => Some(DEFAULT_REGEX),
+            (None, _) => None,
+        }
+        .map(|x| format!("^(?:{})$", x));
+        // Replace rule defaults to the whole value, rather than to the first group
+        let replacement = match (cfg.replacement, action.as_str()) {
+            (None, "replace") if regex.is_some() => Some(DEFAULT_REPLACEMENT.to_string()),
+            (x, _) => x,
+        };
+        let mut map_label = |name: String| {
+            if let Some(x) = UNSUPPORTED_LABELS.iter().find(|x| name.starts_with(*x)) {
+                report.push(format!("{}: `{}` label is not supported", path, x));
+            }
+            match LABEL_MAP.iter().find(|(x, _)| *x == name) {
+                Some((_, x)) => x.to_string(),
+                None => name,
+            }
+        };
+        r.push(RelabelRuleConfig {
+            source_labels: cfg
+                .source_labels
+                .map(|x| x.into_iter().map(&mut map_label).collect()),
+            separator: cfg
+                .separator
+                .unwrap_or_else(|| DEFAULT_SEPARATOR.to_string()),
+            regex,
+            replacement,
+            target_label: cfg.target_label.map(&mut map_label),
+            modulus: cfg.modulus,
+            file: None,
+            value_eq: None,
+            value_gt: None,
+            value_lt: None,
+            is_zero: None,
+            action: Some(action),
+        });
+    }
+    r
+}
+
+// Rule setting the label to the constant value
+fn set_label(name: &str, value: &str) -> RelabelRuleConfig {
+    RelabelRuleConfig {
+        replacement: Some(value.to_string()),
+        target_label: Some(name.to_string()),
+        ..rule("replace", "")
+    }
+}
+
+fn rule(action: &str, regex: &str) -> RelabelRuleConfig {
+    RelabelRuleConfig {
+        source_labels: None,
+        separator: DEFAULT_SEPARATOR.to_string(),
+        regex: Some(regex.to_string()).filter(|x| !x.is_empty()),
+        replacement: None,
+        target_label: None,
+        modulus: None,
+        file: None,
+        value_eq: None,
+        value_gt: None,
+        value_lt: None,
+        is_zero: None,
+        action: Some(action.to_string()),
+    }
+}
+
+impl<'a> Report<'a> {
+    fn new(scope: &str, items: &'a mut Vec<String>) -> Self {
+        Self {
+            scope: scope.to_string(),
+            items,
+        }
+    }
+    fn push(&mut self, msg: String) {
+        self.items.push(format!("{}: {}", self.scope, msg));
+    }
+    fn rest(&mut self, path: &str, rest: &Rest) {
+        for key in rest.keys() {
+            self.push(format!("`{}{}` is not supported", path, key));
+        }
+    }
+}
+
+// Parse Prometheus duration, like `1m30s`, to milliseconds
+fn parse_duration(s: &str) -> Result<u64, AgentError> {
+    let err = || AgentError::ConfigurationError(format!("invalid duration: {}", s));
+    if s.is_empty() {
+        return Err(err());
+    }
+    let mut r = 0u64;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let n = rest.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?;
+        let value: u64 = rest[..n].parse().map_err(|_| err())?;
+        rest = &rest[n..];
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let mult = match &rest[..unit] {
+            "ms" => 1,
+            "s" => 1_000,
+            "m" => 60_000,
+            "h" => 3_600_000,
+            "d" => 86_400_000,
+            "w" => 604_800_000,
+            "y" => 31_536_000_000,
+            _ => return Err(err()),
+        };
+        rest = &rest[unit..];
+        r = value
+            .checked_mul(mult)
+            .and_then(|x| r.checked_add(x))
+            .ok_or_else(err)?;
+    }
+    Ok(r)
+}
+
+// Parse Prometheus size, like `10MB`, to bytes.
+// Units are powers of 2.
+fn parse_size(s: &str) -> Result<usize, AgentError> {
+    let err = || AgentError::ConfigurationError(format!("invalid size: {}", s));
+    let n = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let value: usize = s[..n].parse().map_err(|_| err())?;
+    let mult = match &s[n..] {
+        "" | "B" => 1,
+        "KB" | "KiB" => 1 << 10,
+        "MB" | "MiB" => 1 << 20,
+        "GB" | "GiB" => 1 << 30,
+        "TB" | "TiB" => 1 << 40,
+        _ => return Err(err()),
+    };
+    value.checked_mul(mult).ok_or_else(err)
+}
+
+// Label is dropped by Prometheus, but not by the collector
+fn is_temporary(name: &str) -> bool {
+    name.starts_with("__")
+        && !name.starts_with("__meta_")
+        && name != "__address__"
+        && name != "__name__"
+}
+
+fn is_false(v: &bool) -> bool {
+    !*v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{import_prometheus, parse_duration, parse_size};
+    use crate::Config;
+    use common::Label;
+    use relabel::{ActionResult, ActiveLabels, RelabelRuleConfig, RelabelRuleset, Relabeler};
+
+    const PROMETHEUS: &str = r#"
+global:
+  scrape_interval: 15s
+  evaluation_interval: 15s
+rule_files: [rules.yml]
+scrape_configs:
+  - job_name: node
+    static_configs:
+      - targets: ["10.0.0.1:9100"]
+        labels:
+          env: prod
+      - targets: ["10.0.0.2:9100"]
+    relabel_configs:
+      - source_labels: [__address__]
+        regex: "([^:]+):.*"
+        target_label: ip
+      - source_labels: [__address__]
+        target_label: __tmp_address
+    metric_relabel_configs:
+      - source_labels: [__name__]
+        regex: go_.*
+        action: drop
+  - job_name: federate
+    scrape_interval: 1500ms
+    metrics_path: /federate
+    honor_labels: true
+    params:
+      match[]: ['{job="node"}']
+    oauth2:
+      client_id: x
+    file_sd_configs:
+      - files: [targets/*.json]
+  - job_name: pods
+    kubernetes_sd_configs:
+      - role: pod
+"#;
+
+    fn import() -> (Config, Vec<String>) {
+        let r = import_prometheus(PROMETHEUS).unwrap();
+        (serde_yaml::from_str(&r.config).unwrap(), r.unsupported)
+    }
+
+    // Generated config must be accepted by the scrape collector as is.
+    // Unknown fields are ignored on deserialization, so the config
+    // is serialized back and compared with the generated one.
+    fn check_scrape_config(cfg: &serde_yaml::Value) {
+        let parsed: scrape::Config = serde_yaml::from_value(cfg.clone()).unwrap();
+        let value = serde_yaml::to_value(&parsed).unwrap();
+        assert_subset((cfg, &value), cfg, &value, &[]);
+        if let Err(e) = scrape::Collector::try_from(parsed) {
+            panic!("{}", e);
+        }
+    }
+
+    // `roots` are the generated and the serialized back configs
+    fn assert_subset(
+        roots: (&serde_yaml::Value, &serde_yaml::Value),
+        expected: &serde_yaml::Value,
+        value: &serde_yaml::Value,
+        path: &[&str],
+    ) {
+        let map = match expected.as_mapping() {
+            Some(x) => x,
+            None => return assert_eq!(expected, value, "{}", path.join(".")),
+        };
+        for (k, v) in map.iter() {
+            let mut path = path.to_vec();
+            path.push(k.as_str().unwrap());
+            match value.get(k) {
+                Some(x) => assert_subset(roots, v, x, &path),
+                // Skipped as default value, unless changing it has no effect
+                None => assert!(
+                    is_known(roots, &path),
+                    "{} is dropped by scrape config",
+                    path.join(".")
+                ),
+            }
+        }
+    }
+
+    fn is_known(roots: (&serde_yaml::Value, &serde_yaml::Value), path: &[&str]) -> bool {
+        let mut changed = roots.0.clone();
+        let mut item = &mut changed;
+        for k in path.iter() {
+            item = item.get_mut(k).unwrap();
+        }
+        *item = match item {
+            serde_yaml::Value::Bool(x) => serde_yaml::Value::Bool(!*x),
+            serde_yaml::Value::Number(x) => (x.as_u64().unwrap() + 1).into(),
+            serde_yaml::Value::String(x) => format!("{}_", x).into(),
+            _ => panic!("{}: unexpected value", path.join(".")),
+        };
+        match serde_yaml::from_value::<scrape::Config>(changed) {
+            Ok(x) => serde_yaml::to_value(&x).unwrap() != *roots.1,
+            Err(_) => true,
+        }
+    }
+
+    fn get_rules(cfg: &serde_yaml::Value) -> RelabelRuleset {
+        let rules: Vec<RelabelRuleConfig> =
+            serde_yaml::from_value(cfg["service_discovery"]["relabel"].clone()).unwrap();
+        RelabelRuleset::try_from(&rules).unwrap()
+    }
+
+    #[test]
+    fn test_collectors() {
+        let (cfg, _) = import();
+        let ids: Vec<_> = cfg.collectors.iter().map(|x| x.id.as_str()).collect();
+        assert_eq!(ids, vec!["node", "node (2)", "federate"]);
+        assert!(cfg.collectors.iter().all(|x| x.r#type == "scrape"));
+        for c in cfg.collectors.iter() {
+            check_scrape_config(&c.config);
+        }
+        assert_eq!(cfg.collectors[0].interval, Some(15));
+        assert_eq!(cfg.collectors[2].interval, Some(2));
+        let fed = &cfg.collectors[2].config;
+        assert_eq!(fed["service_discovery"]["type"], "file");
+        assert_eq!(fed["service_discovery"]["path"], "/federate");
+        assert_eq!(fed["honor_labels"], true);
+        assert_eq!(fed["trust_timestamps"], true);
+        assert_eq!(fed["params"]["match[]"][0], "{job=\"node\"}");
+    }
+
+    #[test]
+    fn test_scrape_config() {
+        let data = r#"
+scrape_configs:
+  - job_name: services
+    scheme: https
+    scrape_timeout: 5s
+    body_size_limit: 10MB
+    sample_limit: 1000
+    honor_timestamps: false
+    tls_config:
+      insecure_skip_verify: true
+      server_name: example.com
+    basic_auth:
+      username: user
+      password: secret
+    proxy_url: http://proxy:3128
+    consul_sd_configs:
+      - server: consul:8500
+        scheme: https
+        datacenter: dc1
+        token: t
+        services: [web]
+        tags: [prod]
+        node_meta:
+          rack: r1
+        filter: Service.Meta.metrics == "true"
+        tag_separator: ";"
+        refresh_interval: 10s
+    dns_sd_configs:
+      - names: [_metrics._tcp.example.com]
+      - names: [web.example.com]
+        type: A
+        port: 9100
+"#;
+        let r = import_prometheus(data).unwrap();
+        assert!(r.unsupported.is_empty());
+        let cfg: Config = serde_yaml::from_str(&r.config).unwrap();
+        assert_eq!(cfg.collectors.len(), 3);
+        for c in cfg.collectors.iter() {
+            check_scrape_config(&c.config);
+        }
+        let consul = &cfg.collectors[0].config;
+        assert_eq!(consul["service_discovery"]["type"], "consul");
+        assert_eq!(consul["service_discovery"]["refresh_interval_ms"], 10_000);
+        assert_eq!(consul["timeout_ms"], 5_000);
+        assert_eq!(consul["body_size_limit"], 10_485_760);
+        assert_eq!(cfg.collectors[2].config["service_discovery"]["port"], 9100);
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let (_, unsupported) = import();
+        assert_eq!(
+            unsupported,
+            vec![
+                "config: `rule_files` is not supported",
+                "config: `global.evaluation_interval` is not supported",
+                "job `federate`: `oauth2` is not supported",
+                "job `federate`: `scrape_interval` is rounded up to seconds",
+                "job `pods`: `kubernetes_sd_configs` is not supported",
+                "job `pods`: no supported service discovery, skipped",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_target_labels() {
+        let (cfg, _) = import();
+        let ruleset = get_rules(&cfg.collectors[0].config);
+        let mut labels = ActiveLabels::new(vec![Label::new("__address__", "10.0.0.1:9100")]);
+        assert_eq!(ruleset.apply(&mut labels).unwrap(), ActionResult::Pass);
+        let labels = labels.to_labels();
+        let mut items: Vec<_> = labels
+            .iter()
+            .map(|x| format!("{}={}", x.key, x.value))
+            .collect();
+        items.sort();
+        assert_eq!(
+            items,
+            vec![
+                "env=prod",
+                "instance=10.0.0.1:9100",
+                "ip=10.0.0.1",
+                "job=node"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_instance_set_by_rules() {
+        let data = r#"
+scrape_configs:
+  - job_name: blackbox
+    static_configs:
+      - targets: [example.com]
+    relabel_configs:
+      - source_labels: [__address__]
+        target_label: instance
+      - target_label: __address__
+        replacement: 127.0.0.1:9115
+"#;
+        let r = import_prometheus(data).unwrap();
+        let cfg: Config = serde_yaml::from_str(&r.config).unwrap();
+        let ruleset = get_rules(&cfg.collectors[0].config);
+        let mut labels = ActiveLabels::new(vec![Label::new("__address__", "example.com")]);
+        assert_eq!(ruleset.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("instance").unwrap(), "example.com");
+        assert_eq!(labels.get("__address__").unwrap(), "127.0.0.1:9115");
+    }
+
+    #[test]
+    fn test_metric_relabel() {
+        let (cfg, _) = import();
+        let rules: Vec<RelabelRuleConfig> =
+            serde_yaml::from_value(cfg.collectors[0].config["metric_relabel"].clone()).unwrap();
+        assert_eq!(rules[0].regex.as_deref(), Some("^(?:go_.*)$"));
+        assert_eq!(rules[0].action.as_deref(), Some("drop"));
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("15s").unwrap(), 15_000);
+        assert_eq!(parse_duration("1m30s").unwrap(), 90_000);
+        assert_eq!(parse_duration("500ms").unwrap(), 500);
+        assert_eq!(parse_duration("1h").unwrap(), 3_600_000);
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("15").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("584942418y").is_err());
+        assert!(parse_duration("18446744073709551615ms1ms").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100").unwrap(), 100);
+        assert_eq!(parse_size("10KB").unwrap(), 10_240);
+        assert_eq!(parse_size("1MB").unwrap(), 1_048_576);
+        assert!(parse_size("1XB").is_err());
+        assert!(parse_size("18446744073709551615KB").is_err());
+    }
+}
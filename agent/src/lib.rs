@@ -5,20 +5,33 @@
 // --------------------------------------------------------------------
 
 pub(crate) mod agent;
+pub(crate) mod aggregate;
+pub(crate) mod alerts;
 pub(crate) mod config;
 pub(crate) mod discovery;
+pub(crate) mod import_prometheus;
+pub(crate) mod limits;
 pub(crate) mod mdb;
 pub(crate) mod registry;
+pub(crate) mod relabel_test;
 pub(crate) mod resolver;
 pub(crate) mod schedule;
 pub(crate) mod sender;
 
 pub(crate) use crate::agent::AGENT_DEFAULT_INTERVAL;
 pub use crate::agent::{Agent, AgentBuilder, AgentMode};
-pub(crate) use config::{AgentConfig, CollectorConfig, Config, SenderConfig};
+pub(crate) use aggregate::{Accumulator, AggregateRule};
+pub(crate) use alerts::{Alerts, AlertsHandle};
+pub(crate) use config::{
+    AgentConfig, AggregateConfig, AlertRuleConfig, AlertsConfig, CollectorConfig, Config,
+    LimitsConfig, SenderConfig,
+};
 pub use discovery::config_from_discovery;
+pub use import_prometheus::{import_prometheus, PrometheusImport};
+pub(crate) use limits::{LimitAction, Limits, RejectReason};
 pub(crate) use mdb::{MetricsData, MetricsDb};
 pub use registry::Collectors;
+pub use relabel_test::relabel_test;
 pub(crate) use resolver::ConfigResolver;
 pub(crate) use schedule::Schedule;
 pub(crate) use sender::{Sender, SenderCommand};
@@ -0,0 +1,79 @@
+// --------------------------------------------------------------------
+// Gufo Agent: Series cardinality limits
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use crate::LimitsConfig;
+use common::{AgentError, Measure};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum LimitAction {
+    // Drop only series exceeding limits
+    DropSeries,
+    // Drop the whole collector's batch
+    DropBatch,
+}
+
+#[derive(Debug, Clone, Copy, Ord, PartialOrd, Eq, PartialEq)]
+pub(crate) enum RejectReason {
+    SeriesLimit,
+    LabelLength,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Limits {
+    max_series: Option<usize>,
+    max_label_length: Option<usize>,
+    action: LimitAction,
+}
+
+impl TryFrom<&LimitsConfig> for Limits {
+    type Error = AgentError;
+
+    fn try_from(value: &LimitsConfig) -> Result<Self, Self::Error> {
+        Ok(Self {
+            max_series: value.max_series,
+            max_label_length: value.max_label_length,
+            action: match value.action.as_str() {
+                "drop_series" => LimitAction::DropSeries,
+                "drop_batch" => LimitAction::DropBatch,
+                _ => {
+                    return Err(AgentError::ConfigurationError(format!(
+                        "invalid limits action: {}",
+                        value.action
+                    )))
+                }
+            },
+        })
+    }
+}
+
+impl Limits {
+    pub fn action(&self) -> LimitAction {
+        self.action
+    }
+    // Check if any label value exceeds the limit
+    pub fn is_label_too_long(&self, measure: &Measure) -> bool {
+        match self.max_label_length {
+            Some(max) => measure.labels.iter().any(|x| x.value.chars().count() > max),
+            None => false,
+        }
+    }
+    // Check if adding `new` series to `current` ones exceeds limit
+    pub fn is_exceeded(&self, current: usize, new: usize) -> bool {
+        match self.max_series {
+            Some(max) => current + new > max,
+            None => false,
+        }
+    }
+}
+
+impl RejectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::SeriesLimit => "series_limit",
+            RejectReason::LabelLength => "label_length",
+        }
+    }
+}
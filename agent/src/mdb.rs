@@ -4,18 +4,49 @@
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
 
+use crate::{Accumulator, AggregateRule, LimitAction, Limits, RejectReason};
 use bytes::BytesMut;
-use common::{AgentError, Labels, Measure, Value};
+use common::{
+    escape::{escape_help, sanitize_metric_name},
+    AgentError, Exemplar, Label, Labels, Measure, Value,
+};
 use relabel::{ActiveLabels, RelabelRuleset};
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
 use std::ops::Deref;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+const COUNTER_SUFFIX: &str = "_total";
+const RATE_SUFFIX: &str = "_rate";
+// Self-metric for metric family conflicts
+const CONFLICTS_NAME: &str = "agent_metric_conflicts";
+const CONFLICTS_HELP: &str = "Conflicting type, help, label names, series owner or reserved names";
+// Self-metric for rejected series
+const REJECTED_NAME: &str = "agent_series_rejected";
+const REJECTED_HELP: &str = "Measures rejected due to series limits";
+// Self-metric for active alerts
+const ALERTS_NAME: &str = "ALERTS";
+const ALERTS_HELP: &str = "Pending and firing alerts";
+
 struct _Inner {
-    data: BTreeMap<MetricFamilyKey, MetricFamilyData>,
+    // Metric families, indexed by family name
+    data: BTreeMap<String, MetricFamilyData>,
     labels: Labels,
+    // Global relabeling ruleset
+    relabel: Option<RelabelRuleset>,
+    // Global limits
+    limits: Option<Limits>,
+    // Collector id -> number of series
+    series: HashMap<String, usize>,
+    // Total number of series
+    total_series: usize,
+    // Global aggregation rules
+    aggregate: Vec<AggregateRule>,
+    // Collector id -> aggregation rules
+    collector_aggregate: HashMap<String, Arc<Option<Vec<AggregateRule>>>>,
+    // Registered conflicts: (family name, reason, collector id)
+    conflicts: HashSet<(String, ConflictReason, String)>,
 }
 
 pub(crate) struct MetricsDb(Arc<RwLock<_Inner>>);
@@ -23,26 +54,33 @@ pub(crate) struct MetricsDb(Arc<RwLock<_Inner>>);
 #[derive(Debug)]
 pub(crate) struct MetricsData {
     pub collector: &'static str,
+    // collector id
+    pub id: String,
     // collector labels
     pub labels: Arc<Labels>,
     // Relabeling ruleset
     pub relabel: Arc<Option<RelabelRuleset>>,
+    // Series limits
+    pub limits: Arc<Option<Limits>>,
+    // Aggregation rules
+    pub aggregate: Arc<Option<Vec<AggregateRule>>>,
+    // Emit rates for counters
+    pub rates: bool,
     // collector measures
     pub measures: Vec<Measure>,
     // Timestamp in UNIX format
     pub ts: u64,
-}
-
-#[derive(Ord, PartialOrd, Eq, PartialEq, Debug, Clone)]
-struct MetricFamilyKey {
-    collector: &'static str,
-    name: String,
+    // Expire collector's series not updated for `ttl` seconds.
+    // 0 - never expire
+    pub ttl: u64,
 }
 
 #[derive(Debug)]
 struct MetricFamilyData {
     help: String,
     r#type: ValueType,
+    // Collector which registered the family first
+    collector: &'static str,
     values: HashMap<Labels, MetricValue>,
 }
 
@@ -50,19 +88,34 @@ struct MetricFamilyData {
 struct MetricValue {
     value: Value,
     ts: u64,
+    // Id of the collector produced the series
+    id: Arc<str>,
+    // Timestamp of the last update
+    updated: u64,
+    exemplar: Option<Exemplar>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum ValueType {
     Counter,
     Gauge,
 }
 
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone, Copy, Hash)]
+enum ConflictReason {
+    Type,
+    Help,
+    Label,
+    Owner,
+    Reserved,
+}
+
 #[derive(Ord, PartialOrd, Eq, PartialEq)]
 struct OutputItem<'a> {
     labels: &'a Labels,
     value: String,
     ts: u64,
+    exemplar: Option<String>,
 }
 
 impl ValueType {
@@ -72,6 +125,26 @@ impl ValueType {
             ValueType::Gauge => "gauge",
         }
     }
+    // Get metric family name.
+    // OpenMetrics requires counter samples to have `_total` suffix,
+    // while the family name must not have it.
+    pub fn family_name(&self, name: &str) -> String {
+        let name = sanitize_metric_name(name);
+        match self {
+            ValueType::Counter => match name.strip_suffix(COUNTER_SUFFIX) {
+                Some(family) => family.to_string(),
+                None => name,
+            },
+            ValueType::Gauge => name,
+        }
+    }
+    // Get sample name for the family
+    pub fn sample_name(&self, family: &str) -> String {
+        match self {
+            ValueType::Counter => format!("{}{}", family, COUNTER_SUFFIX),
+            ValueType::Gauge => family.to_string(),
+        }
+    }
 }
 
 impl From<Value> for ValueType {
@@ -86,22 +159,495 @@ impl From<Value> for ValueType {
     }
 }
 
+impl ConflictReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConflictReason::Type => "type",
+            ConflictReason::Help => "help",
+            ConflictReason::Label => "label",
+            ConflictReason::Owner => "owner",
+            ConflictReason::Reserved => "reserved",
+        }
+    }
+}
+
 impl Default for MetricsDb {
     fn default() -> Self {
         Self(Arc::new(RwLock::new(_Inner {
             data: BTreeMap::new(),
             labels: Labels::default(),
+            relabel: None,
+            limits: None,
+            series: HashMap::new(),
+            total_series: 0,
+            aggregate: Vec::new(),
+            collector_aggregate: HashMap::new(),
+            conflicts: HashSet::new(),
         })))
     }
 }
 
+impl _Inner {
+    // Install measure into the metric family.
+    // The first registered type and help are kept,
+    // measures of conflicting type are dropped.
+    // Series are owned by the first collector produced them,
+    // same series from other collectors are dropped.
+    // When `rates` is set, counters produce the derived `<name>_rate` gauges,
+    // which are subject to the series limits too.
+    fn apply_measure(
+        &mut self,
+        collector: &'static str,
+        id: &Arc<str>,
+        measure: Measure,
+        ts: u64,
+        rates: bool,
+        limits: Option<&Limits>,
+    ) {
+        let r#type = ValueType::from(measure.value);
+        let name = r#type.family_name(&measure.name);
+        if name == ALERTS_NAME {
+            self.register_conflict(name, ConflictReason::Reserved, id, ts, || {
+                format!(
+                    "name is reserved for agent's alerts, measure from {} collector dropped",
+                    collector
+                )
+            });
+            return;
+        }
+        let family = self
+            .data
+            .entry(name.clone())
+            .or_insert_with(|| MetricFamilyData {
+                help: measure.help.clone(),
+                r#type: measure.value.into(),
+                collector,
+                values: HashMap::new(),
+            });
+        if family.r#type != r#type {
+            let first = family.collector;
+            let expected = family.r#type.as_str();
+            self.register_conflict(name, ConflictReason::Type, id, ts, || {
+                format!(
+                    "type {} from {} collector, {} expected from {} collector. Measure dropped",
+                    r#type.as_str(),
+                    collector,
+                    expected,
+                    first
+                )
+            });
+            return;
+        }
+        if let Some(owner) = family
+            .values
+            .get(&measure.labels)
+            .map(|v| &v.id)
+            .filter(|owner| *owner != id)
+        {
+            let owner = owner.clone();
+            self.register_conflict(name, ConflictReason::Owner, id, ts, || {
+                format!(
+                    "series {{{}}} from {} collector is already owned by {} collector. Measure dropped",
+                    measure.labels.to_openmetrics(),
+                    id,
+                    owner
+                )
+            });
+            return;
+        }
+        let help_conflict = family.help != measure.help;
+        let first = family.collector;
+        let sample_ts = measure.timestamp.unwrap_or(ts);
+        let rate_labels = if rates && r#type == ValueType::Counter {
+            Some(measure.labels.clone())
+        } else {
+            None
+        };
+        let prev = family.values.insert(
+            measure.labels,
+            MetricValue {
+                value: measure.value,
+                ts: sample_ts,
+                id: id.clone(),
+                updated: ts,
+                exemplar: measure.exemplar,
+            },
+        );
+        let is_new = prev.is_none();
+        if let (Some(labels), Some(prev)) = (rate_labels, prev) {
+            if let Some(rate) = counter_rate(&prev.value, prev.ts, &measure.value, sample_ts) {
+                let rate = Measure {
+                    name: format!("{}{}", name, RATE_SUFFIX),
+                    help: format!("Per-second rate of {}", name),
+                    value: Value::GaugeF(rate),
+                    labels,
+                    timestamp: Some(sample_ts),
+                    exemplar: None,
+                };
+                match self.check_series(id, &rate, limits) {
+                    Some(reason) => self.register_rejection(id, reason, 1, ts),
+                    None => self.apply_measure(collector, id, rate, ts, false, limits),
+                }
+            }
+        }
+        if is_new {
+            *self.series.entry(id.to_string()).or_default() += 1;
+            self.total_series += 1;
+        }
+        if help_conflict {
+            self.register_conflict(name, ConflictReason::Help, id, ts, || {
+                format!(
+                    "help from {} collector differs from {} collector. Using first one",
+                    collector, first
+                )
+            });
+        }
+    }
+    // Check if measure will create a new series
+    fn is_new_series(&self, measure: &Measure) -> bool {
+        let name = ValueType::from(measure.value).family_name(&measure.name);
+        match self.data.get(&name) {
+            Some(family) => !family.values.contains_key(&measure.labels),
+            None => true,
+        }
+    }
+    // Check if counter will create a new derived rate series.
+    // The rate is derived from the existing series only.
+    fn is_new_rate_series(&self, measure: &Measure) -> bool {
+        let r#type = ValueType::from(measure.value);
+        if r#type != ValueType::Counter || self.is_new_series(measure) {
+            return false;
+        }
+        let name = format!("{}{}", r#type.family_name(&measure.name), RATE_SUFFIX);
+        match self.data.get(&name) {
+            Some(family) => !family.values.contains_key(&measure.labels),
+            None => true,
+        }
+    }
+    // Check if the whole batch must be dropped.
+    // Derived rate series are counted when `rates` is set.
+    fn check_batch(
+        &self,
+        id: &str,
+        measures: &[Measure],
+        limits: Option<&Limits>,
+        rates: bool,
+    ) -> Option<RejectReason> {
+        let collector_limits = limits.filter(|x| x.action() == LimitAction::DropBatch);
+        let global_limits = self
+            .limits
+            .as_ref()
+            .filter(|x| x.action() == LimitAction::DropBatch);
+        if collector_limits.is_none() && global_limits.is_none() {
+            return None;
+        }
+        if measures.iter().any(|m| {
+            collector_limits.map(|x| x.is_label_too_long(m)) == Some(true)
+                || global_limits.map(|x| x.is_label_too_long(m)) == Some(true)
+        }) {
+            return Some(RejectReason::LabelLength);
+        }
+        let new_series = measures
+            .iter()
+            .filter(|m| self.is_new_series(m))
+            .map(|m| (ValueType::from(m.value).family_name(&m.name), &m.labels))
+            .chain(
+                measures
+                    .iter()
+                    .filter(|m| rates && self.is_new_rate_series(m))
+                    .map(|m| {
+                        (
+                            format!("{}{}", ValueType::Counter.family_name(&m.name), RATE_SUFFIX),
+                            &m.labels,
+                        )
+                    }),
+            )
+            .collect::<HashSet<_>>()
+            .len();
+        if collector_limits.map(|x| x.is_exceeded(self.collector_series(id), new_series))
+            == Some(true)
+            || global_limits.map(|x| x.is_exceeded(self.total_series, new_series)) == Some(true)
+        {
+            return Some(RejectReason::SeriesLimit);
+        }
+        None
+    }
+    // Check if the single measure must be dropped.
+    fn check_series(
+        &self,
+        id: &str,
+        measure: &Measure,
+        limits: Option<&Limits>,
+    ) -> Option<RejectReason> {
+        let collector_limits = limits.filter(|x| x.action() == LimitAction::DropSeries);
+        let global_limits = self
+            .limits
+            .as_ref()
+            .filter(|x| x.action() == LimitAction::DropSeries);
+        if collector_limits.is_none() && global_limits.is_none() {
+            return None;
+        }
+        if collector_limits.map(|x| x.is_label_too_long(measure)) == Some(true)
+            || global_limits.map(|x| x.is_label_too_long(measure)) == Some(true)
+        {
+            return Some(RejectReason::LabelLength);
+        }
+        if self.is_new_series(measure)
+            && (collector_limits.map(|x| x.is_exceeded(self.collector_series(id), 1)) == Some(true)
+                || global_limits.map(|x| x.is_exceeded(self.total_series, 1)) == Some(true))
+        {
+            return Some(RejectReason::SeriesLimit);
+        }
+        None
+    }
+    fn collector_series(&self, id: &str) -> usize {
+        self.series.get(id).copied().unwrap_or_default()
+    }
+    // Remove collector's series not updated since `threshold`,
+    // or all collector's series when `threshold` is not set.
+    // Empty families are removed too. Returns the number of removed series.
+    fn remove_series(&mut self, id: &str, threshold: Option<u64>) -> usize {
+        let mut removed = 0;
+        self.data.retain(|_, family| {
+            let n = family.values.len();
+            family.values.retain(|_, v| {
+                v.id.as_ref() != id || threshold.map(|t| v.updated >= t).unwrap_or(false)
+            });
+            removed += n - family.values.len();
+            !family.values.is_empty()
+        });
+        if let Some(count) = self.series.get_mut(id) {
+            *count = count.saturating_sub(removed);
+        }
+        self.total_series = self.total_series.saturating_sub(removed);
+        removed
+    }
+    // Remove collector's series not updated since `threshold`.
+    fn expire_series(&mut self, id: &str, threshold: u64) {
+        let removed = self.remove_series(id, Some(threshold));
+        if removed > 0 {
+            log::debug!("[{}] {} stale series expired", id, removed);
+        }
+    }
+    // Forget stopped collector: drop its series, releasing their ownership,
+    // series counters, aggregation rules and registered conflicts.
+    fn remove_collector(&mut self, id: &str) {
+        let removed = self.remove_series(id, None);
+        self.series.remove(id);
+        self.collector_aggregate.remove(id);
+        self.conflicts.retain(|(_, _, x)| x != id);
+        log::debug!("[{}] {} series removed", id, removed);
+    }
+    // Evaluate aggregation rules.
+    // Returns aggregated families and the set of source series
+    // which must not be exposed.
+    fn aggregate(&self) -> (BTreeMap<String, MetricFamilyData>, HashSet<(&str, &Labels)>) {
+        let mut families = BTreeMap::new();
+        let mut hidden = HashSet::new();
+        let rules = self.aggregate.iter().map(|rule| (rule, None)).chain(
+            self.collector_aggregate
+                .iter()
+                .filter_map(|(id, rules)| Option::as_ref(rules).map(|r| (id, r)))
+                .flat_map(|(id, rules)| rules.iter().map(move |rule| (rule, Some(id.as_str())))),
+        );
+        for (rule, scope) in rules {
+            let Some((family_name, family)) = get_family(&self.data, rule.metric()) else {
+                continue;
+            };
+            let mut groups: BTreeMap<Labels, Accumulator> = BTreeMap::new();
+            for (labels, value) in family.values.iter() {
+                if let Some(id) = scope {
+                    if id != value.id.as_ref() {
+                        continue;
+                    }
+                }
+                groups
+                    .entry(rule.group(labels))
+                    .or_insert_with(|| rule.accumulator())
+                    .push(&value.value, value.ts);
+                if !rule.keep_source() {
+                    hidden.insert((family_name.as_str(), labels));
+                }
+            }
+            if groups.is_empty() {
+                continue;
+            }
+            let id: Arc<str> = Arc::from(scope.unwrap_or_default());
+            let values: HashMap<Labels, MetricValue> = groups
+                .into_iter()
+                .map(|(labels, acc)| {
+                    (
+                        labels,
+                        MetricValue {
+                            value: acc.value(),
+                            ts: acc.ts(),
+                            id: id.clone(),
+                            updated: 0,
+                            exemplar: None,
+                        },
+                    )
+                })
+                .collect();
+            // Mixed value types are not possible within the single rule
+            let r#type = match values.values().next() {
+                Some(v) => ValueType::from(v.value),
+                None => continue,
+            };
+            let name = r#type.family_name(&rule.name(family_name));
+            match families.get_mut(&name) {
+                Some(MetricFamilyData {
+                    r#type: t,
+                    values: v,
+                    ..
+                }) if *t == r#type => v.extend(values),
+                Some(_) => {
+                    log::debug!("Aggregated metric {} has conflicting type. Skipping", name);
+                }
+                None => {
+                    families.insert(
+                        name,
+                        MetricFamilyData {
+                            help: rule.help(),
+                            r#type,
+                            collector: "agent",
+                            values,
+                        },
+                    );
+                }
+            }
+        }
+        (families, hidden)
+    }
+    // Count conflict and update self-metric.
+    // Each conflict is counted and logged as an error only once per collector.
+    fn register_conflict<F>(
+        &mut self,
+        name: String,
+        reason: ConflictReason,
+        id: &str,
+        ts: u64,
+        msg: F,
+    ) where
+        F: Fn() -> String,
+    {
+        if !self
+            .conflicts
+            .insert((name.clone(), reason, id.to_string()))
+        {
+            log::debug!("Metric {} conflict: {}", name, msg());
+            return;
+        }
+        self.inc_self_counter(
+            CONFLICTS_NAME,
+            CONFLICTS_HELP,
+            vec![
+                Label::new("name", name.clone()),
+                Label::new("reason", reason.as_str()),
+            ],
+            1,
+            ts,
+        );
+        log::error!("Metric {} conflict: {}", name, msg());
+    }
+    // Count rejected measures and update self-metric.
+    // Log an error on first occurence only.
+    fn register_rejection(&mut self, id: &str, reason: RejectReason, n: u64, ts: u64) {
+        let count = self.inc_self_counter(
+            REJECTED_NAME,
+            REJECTED_HELP,
+            vec![
+                Label::new("collector_id", id),
+                Label::new("reason", reason.as_str()),
+            ],
+            n,
+            ts,
+        );
+        if count == n {
+            log::error!(
+                "[{}] {} measures rejected: {} exceeded",
+                id,
+                n,
+                reason.as_str()
+            );
+        } else {
+            log::debug!(
+                "[{}] {} measures rejected: {} exceeded",
+                id,
+                n,
+                reason.as_str()
+            );
+        }
+    }
+    // Increment agent's self-metric counter and return the new value.
+    fn inc_self_counter(
+        &mut self,
+        name: &str,
+        help: &str,
+        labels: Vec<Label>,
+        delta: u64,
+        ts: u64,
+    ) -> u64 {
+        let labels = Labels::merge_sort2(&self.labels, &Labels::new(labels));
+        let family = self
+            .data
+            .entry(name.to_string())
+            .or_insert_with(|| MetricFamilyData {
+                help: help.to_string(),
+                r#type: ValueType::Counter,
+                collector: "agent",
+                values: HashMap::new(),
+            });
+        let item = family.values.entry(labels).or_insert_with(|| MetricValue {
+            value: Value::Counter(0),
+            ts,
+            id: Arc::from(""),
+            updated: ts,
+            exemplar: None,
+        });
+        let value = match item.value {
+            Value::Counter(x) => x + delta,
+            _ => delta,
+        };
+        item.value = Value::Counter(value);
+        item.ts = ts;
+        value
+    }
+}
+
 impl MetricsDb {
     pub async fn set_labels(&mut self, labels: Labels) {
         let mut db = self.0.write().await;
         db.labels = labels
     }
+    pub async fn set_relabel(&mut self, relabel: Option<RelabelRuleset>) {
+        let mut db = self.0.write().await;
+        db.relabel = relabel
+    }
+    pub async fn set_limits(&mut self, limits: Option<Limits>) {
+        let mut db = self.0.write().await;
+        db.limits = limits
+    }
+    pub async fn set_aggregate(&mut self, rules: Vec<AggregateRule>) {
+        let mut db = self.0.write().await;
+        db.aggregate = rules
+    }
+    pub async fn remove_collector(&mut self, id: &str) {
+        let mut db = self.0.write().await;
+        db.remove_collector(id)
+    }
     pub async fn apply_data(&mut self, data: &MetricsData) {
         let mut db = self.0.write().await;
+        if data.aggregate.is_some() {
+            db.collector_aggregate
+                .insert(data.id.clone(), data.aggregate.clone());
+        } else {
+            db.collector_aggregate.remove(&data.id);
+        }
+        // Expire stale series
+        if data.ttl > 0 {
+            db.expire_series(&data.id, data.ts.saturating_sub(data.ttl));
+        }
+        let mut measures = Vec::with_capacity(data.measures.len());
         for measure in data.measures.iter() {
             // Relabeling
             if let Some(measure) = match Option::as_ref(&data.relabel) {
@@ -123,63 +669,154 @@ impl MetricsDb {
                     }
                 }
             } {
-                // Check for Metric Family
-                let k = MetricFamilyKey {
-                    collector: data.collector,
-                    name: measure.name.clone(),
+                // Global relabeling
+                let mut measure = match &db.relabel {
+                    Some(ruleset) => {
+                        match ruleset.process(&Labels::default(), &Labels::default(), &measure) {
+                            Ok(Some(r)) => r,
+                            Ok(None) => continue,
+                            Err(e) => {
+                                log::error!("Failed to relabel: {}", e);
+                                continue;
+                            }
+                        }
+                    }
+                    None => measure,
                 };
-                // @todo: Use .get()
-                // Wait for map_try_insert feature to stabilize
-                if !db.data.contains_key(&k) {
-                    // Insert metric family info
-                    db.data.insert(
-                        k.clone(),
-                        MetricFamilyData {
-                            help: measure.help.clone(),
-                            r#type: measure.value.into(),
-                            values: HashMap::new(),
-                        },
-                    );
-                }
-                if let Some(family) = db.data.get_mut(&k) {
-                    // Install value if not dropped
-                    family.values.insert(
-                        measure.labels,
-                        MetricValue {
-                            value: measure.value,
-                            ts: measure.timestamp.unwrap_or(data.ts),
-                        },
-                    );
+                // Sanitized label names must be unique
+                for key in measure.labels.sanitize() {
+                    let name = ValueType::from(measure.value).family_name(&measure.name);
+                    db.register_conflict(name, ConflictReason::Label, &data.id, data.ts, || {
+                        format!(
+                            "label {} from {} collector collides with another label after sanitizing. Label dropped",
+                            key, data.collector
+                        )
+                    });
                 }
+                measures.push(measure);
+            }
+        }
+        // Check batch limits
+        let limits = Option::as_ref(&data.limits);
+        if let Some(reason) = db.check_batch(&data.id, &measures, limits, data.rates) {
+            db.register_rejection(&data.id, reason, measures.len() as u64, data.ts);
+            return;
+        }
+        let id: Arc<str> = Arc::from(data.id.as_str());
+        for measure in measures.drain(..) {
+            // Check series limits
+            if let Some(reason) = db.check_series(&data.id, &measure, limits) {
+                db.register_rejection(&data.id, reason, 1, data.ts);
+                continue;
             }
+            db.apply_measure(data.collector, &id, measure, data.ts, data.rates, limits);
         }
     }
+    // Get all series of the metric, including aggregated ones
+    pub async fn query(&self, name: &str) -> Vec<(Labels, Value)> {
+        let db = self.0.read().await;
+        if let Some((_, family)) = get_family(&db.data, name) {
+            return family
+                .values
+                .iter()
+                .map(|(labels, value)| (labels.clone(), value.value))
+                .collect();
+        }
+        let (aggregated, _) = db.aggregate();
+        match get_family(&aggregated, name) {
+            Some((_, family)) => family
+                .values
+                .iter()
+                .map(|(labels, value)| (labels.clone(), value.value))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+    // Replace active alerts series
+    pub async fn set_alerts(&mut self, alerts: Vec<Labels>, ts: u64) {
+        let mut db = self.0.write().await;
+        if alerts.is_empty() {
+            db.data.remove(ALERTS_NAME);
+            return;
+        }
+        let id: Arc<str> = Arc::from("");
+        db.data.insert(
+            ALERTS_NAME.to_string(),
+            MetricFamilyData {
+                help: ALERTS_HELP.to_string(),
+                r#type: ValueType::Gauge,
+                collector: "agent",
+                values: alerts
+                    .into_iter()
+                    .map(|labels| {
+                        (
+                            labels,
+                            MetricValue {
+                                value: Value::Gauge(1),
+                                ts,
+                                id: id.clone(),
+                                updated: ts,
+                                exemplar: None,
+                            },
+                        )
+                    })
+                    .collect(),
+            },
+        );
+    }
     pub async fn write_openmetrics(&self, out: &mut BytesMut) -> Result<(), AgentError> {
         let db = self.0.read().await;
-        for (family, fv) in db.data.iter() {
-            if !fv.help.is_empty() {
-                fmt::write(out, format_args!("# HELP {} {}\n", family.name, fv.help,))?;
-            }
-            fmt::write(
-                out,
-                format_args!("# TYPE {} {}\n", family.name, fv.r#type.as_str(),),
-            )?;
+        let (aggregated, hidden) = db.aggregate();
+        let mut families: Vec<(&String, &MetricFamilyData)> = db
+            .data
+            .iter()
+            .chain(aggregated.iter().filter(|(name, _)| {
+                if db.data.contains_key(*name) {
+                    log::debug!("Aggregated metric {} conflicts with existing one", name);
+                    false
+                } else {
+                    true
+                }
+            }))
+            .collect();
+        families.sort_by(|a, b| a.0.cmp(b.0));
+        for (family_name, fv) in families {
             let mut items: Vec<OutputItem> = fv
                 .values
                 .iter()
+                .filter(|(labels, _)| !hidden.contains(&(family_name.as_str(), *labels)))
                 .map(|(labels, value)| OutputItem {
                     labels,
                     value: value.value.to_string(),
                     ts: value.ts,
+                    // Exemplars are allowed only for counters
+                    exemplar: match fv.r#type {
+                        ValueType::Counter => value.exemplar.as_ref().map(|x| x.to_openmetrics()),
+                        ValueType::Gauge => None,
+                    },
                 })
                 .collect();
+            if items.is_empty() {
+                continue;
+            }
             items.sort();
+            let sample_name = fv.r#type.sample_name(family_name);
+            if !fv.help.is_empty() {
+                fmt::write(
+                    out,
+                    format_args!("# HELP {} {}\n", family_name, escape_help(&fv.help)),
+                )?;
+            }
+            fmt::write(
+                out,
+                format_args!("# TYPE {} {}\n", family_name, fv.r#type.as_str(),),
+            )?;
             for item in items.iter() {
                 fmt::write(
                     out,
                     format_args!(
-                        "{}{} {}{}\n",
-                        family.name,
+                        "{}{} {}{}{}\n",
+                        sample_name,
                         if item.labels.is_empty() {
                             "".into()
                         } else {
@@ -191,6 +828,7 @@ impl MetricsDb {
                         } else {
                             "".to_string()
                         },
+                        item.exemplar.as_deref().unwrap_or_default(),
                     ),
                 )?;
             }
@@ -205,8 +843,880 @@ impl MetricsDb {
     }
 }
 
+// Calculate per-second rate between two counter samples.
+// Decreasing value is considered as counter reset,
+// so the counter is started from zero.
+fn counter_rate(prev: &Value, prev_ts: u64, value: &Value, ts: u64) -> Option<f32> {
+    if ts <= prev_ts {
+        return None;
+    }
+    let delta = match (prev, value) {
+        (Value::Counter(x0), Value::Counter(x1)) => {
+            if x1 >= x0 {
+                (x1 - x0) as f64
+            } else {
+                *x1 as f64
+            }
+        }
+        (Value::CounterF(x0), Value::CounterF(x1)) => {
+            if x1 >= x0 {
+                (x1 - x0) as f64
+            } else {
+                *x1 as f64
+            }
+        }
+        _ => return None,
+    };
+    Some((delta / (ts - prev_ts) as f64) as f32)
+}
+
+// Get metric family.
+// Metric may be referred either by family or by sample name
+fn get_family<'a>(
+    data: &'a BTreeMap<String, MetricFamilyData>,
+    name: &str,
+) -> Option<(&'a String, &'a MetricFamilyData)> {
+    data.get_key_value(name).or_else(|| {
+        name.strip_suffix(COUNTER_SUFFIX)
+            .and_then(|name| data.get_key_value(name))
+            .filter(|(_, family)| family.r#type == ValueType::Counter)
+    })
+}
+
 impl Clone for MetricsDb {
     fn clone(&self) -> Self {
         MetricsDb(Arc::clone(&self.0))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{MetricsData, MetricsDb, ValueType};
+    use crate::{AggregateConfig, AggregateRule, Limits, LimitsConfig};
+    use common::{Exemplar, Label, Labels, Measure, Value};
+    use openmetrics::{parse, ParseConfig};
+    use relabel::{RelabelRuleConfig, RelabelRuleset};
+    use std::sync::Arc;
+
+    fn measure(name: &str, help: &str, value: Value, labels: Vec<Label>) -> Measure {
+        Measure {
+            name: name.into(),
+            help: help.into(),
+            value,
+            labels: if labels.is_empty() {
+                Labels::default()
+            } else {
+                Labels::new(labels)
+            },
+            timestamp: None,
+            exemplar: None,
+        }
+    }
+
+    async fn apply(db: &mut MetricsDb, collector: &'static str, measures: Vec<Measure>) {
+        db.apply_data(&MetricsData {
+            collector,
+            id: collector.to_string(),
+            labels: Arc::new(Labels::new(vec![Label::new("collector", collector)])),
+            relabel: Arc::new(None),
+            limits: Arc::new(None),
+            aggregate: Arc::new(None),
+            rates: false,
+            measures,
+            ts: 0,
+            ttl: 0,
+        })
+        .await;
+    }
+
+    // Apply `jobs` gauge without labels on behalf of collector `id`
+    async fn apply_jobs(db: &mut MetricsDb, id: &str, value: u64) {
+        db.apply_data(&MetricsData {
+            collector: "exec",
+            id: id.to_string(),
+            labels: Arc::new(Labels::default()),
+            relabel: Arc::new(None),
+            limits: Arc::new(None),
+            aggregate: Arc::new(None),
+            rates: false,
+            measures: vec![measure("jobs", "Jobs", Value::Gauge(value), vec![])],
+            ts: 0,
+            ttl: 0,
+        })
+        .await;
+    }
+
+    fn limits(max_series: Option<usize>, max_label_length: Option<usize>, action: &str) -> Limits {
+        Limits::try_from(&LimitsConfig {
+            max_series,
+            max_label_length,
+            action: action.into(),
+        })
+        .unwrap()
+    }
+
+    async fn apply_limited(db: &mut MetricsDb, limits: Limits, measures: Vec<Measure>) {
+        db.apply_data(&MetricsData {
+            collector: "test",
+            id: "test".to_string(),
+            labels: Arc::new(Labels::default()),
+            relabel: Arc::new(None),
+            limits: Arc::new(Some(limits)),
+            aggregate: Arc::new(None),
+            rates: false,
+            measures,
+            ts: 0,
+            ttl: 0,
+        })
+        .await;
+    }
+
+    fn rule(metric: &str, op: &str, by: &[&str], keep_source: bool) -> AggregateRule {
+        AggregateRule::try_from(&AggregateConfig {
+            metric: metric.into(),
+            op: op.into(),
+            by: by.iter().map(|x| x.to_string()).collect(),
+            name: None,
+            keep_source,
+        })
+        .unwrap()
+    }
+
+    async fn apply_aggregated(
+        db: &mut MetricsDb,
+        collector: &'static str,
+        rules: Vec<AggregateRule>,
+        measures: Vec<Measure>,
+    ) {
+        db.apply_data(&MetricsData {
+            collector,
+            id: collector.to_string(),
+            labels: Arc::new(Labels::default()),
+            relabel: Arc::new(None),
+            limits: Arc::new(None),
+            aggregate: Arc::new(Some(rules)),
+            rates: false,
+            measures,
+            ts: 0,
+            ttl: 0,
+        })
+        .await;
+    }
+
+    async fn apply_rates(db: &mut MetricsDb, ts: u64, measures: Vec<Measure>) {
+        db.apply_data(&MetricsData {
+            collector: "network",
+            id: "network".to_string(),
+            labels: Arc::new(Labels::default()),
+            relabel: Arc::new(None),
+            limits: Arc::new(None),
+            aggregate: Arc::new(None),
+            rates: true,
+            measures,
+            ts,
+            ttl: 0,
+        })
+        .await;
+    }
+
+    fn processes() -> Vec<Measure> {
+        [
+            ("root", "init", 1),
+            ("root", "sshd", 2),
+            ("www", "nginx", 4),
+        ]
+        .iter()
+        .map(|(user, process, value)| {
+            measure(
+                "ps_cpu_usage",
+                "CPU usage",
+                Value::Gauge(*value),
+                vec![Label::new("process", process), Label::new("user", user)],
+            )
+        })
+        .collect()
+    }
+
+    fn series(n: usize) -> Vec<Measure> {
+        (0..n)
+            .map(|i| measure("m", "", Value::Gauge(i as u64), vec![Label::new("i", i)]))
+            .collect()
+    }
+
+    async fn expose(measures: Vec<Measure>) -> String {
+        let mut db = MetricsDb::default();
+        db.apply_data(&MetricsData {
+            collector: "test",
+            id: "test".to_string(),
+            labels: Arc::new(Labels::default()),
+            relabel: Arc::new(None),
+            limits: Arc::new(None),
+            aggregate: Arc::new(None),
+            rates: false,
+            measures,
+            ts: 0,
+            ttl: 0,
+        })
+        .await;
+        db.to_openmetrics_string().await.unwrap()
+    }
+
+    // Expose and parse back, checking the sample names are preserved
+    async fn round_trip(measures: Vec<Measure>) -> Vec<Measure> {
+        let mut names: Vec<String> = measures
+            .iter()
+            .map(|m| {
+                let t = ValueType::from(m.value);
+                t.sample_name(&t.family_name(&m.name))
+            })
+            .collect();
+        let out = expose(measures).await;
+        let parsed = parse(&out, &ParseConfig::default()).unwrap();
+        let mut parsed_names: Vec<String> = parsed.iter().map(|m| m.name.clone()).collect();
+        names.sort();
+        parsed_names.sort();
+        assert_eq!(parsed_names, names);
+        parsed
+    }
+
+    #[tokio::test]
+    async fn test_eof() {
+        assert_eq!(expose(vec![]).await, "# EOF\n");
+        let out = expose(vec![measure("m", "help", Value::Gauge(1), vec![])]).await;
+        assert!(out.ends_with("\n# EOF\n"));
+    }
+
+    #[tokio::test]
+    async fn test_label_value_escaping() {
+        let cmd = "/bin/sh -c \"echo \\\"quoted\\\"\"\nsecond line";
+        let out = expose(vec![measure(
+            "ps_cpu",
+            "CPU usage",
+            Value::Gauge(1),
+            vec![Label::new("cmd", cmd)],
+        )])
+        .await;
+        assert_eq!(
+            out,
+            "# HELP ps_cpu CPU usage\n# TYPE ps_cpu gauge\nps_cpu{cmd=\"/bin/sh -c \\\"echo \\\\\\\"quoted\\\\\\\"\\\"\\nsecond line\"} 1\n# EOF\n"
+        );
+        let parsed = round_trip(vec![measure(
+            "ps_cpu",
+            "CPU usage",
+            Value::Gauge(1),
+            vec![Label::new("cmd", cmd)],
+        )])
+        .await;
+        assert_eq!(
+            parsed,
+            vec![measure(
+                "ps_cpu",
+                "CPU usage",
+                Value::Gauge(1),
+                vec![Label::new("cmd", cmd)],
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_label_name_sanitizing() {
+        let parsed = round_trip(vec![measure(
+            "query_result",
+            "Query result",
+            Value::GaugeI(-5),
+            vec![Label::new("column-name", "x"), Label::new("1st", "y")],
+        )])
+        .await;
+        assert_eq!(
+            parsed,
+            vec![measure(
+                "query_result",
+                "Query result",
+                Value::GaugeI(-5),
+                vec![Label::new("_1st", "y"), Label::new("column_name", "x")],
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_label_name_collision() {
+        let out = expose(vec![measure(
+            "m",
+            "",
+            Value::Gauge(1),
+            vec![
+                Label::new("a-b", "x"),
+                Label::new("a_b", "y"),
+                Label::new("c.d", "z"),
+            ],
+        )])
+        .await;
+        assert_eq!(
+            out,
+            "# HELP agent_metric_conflicts Conflicting type, help, label names, series owner or reserved names\n# TYPE agent_metric_conflicts counter\nagent_metric_conflicts_total{name=\"m\",reason=\"label\"} 1\n# TYPE m gauge\nm{a_b=\"y\",c_d=\"z\"} 1\n# EOF\n"
+        );
+        let names: Vec<String> = parse(&out, &ParseConfig::default())
+            .unwrap()
+            .into_iter()
+            .map(|m| m.name)
+            .collect();
+        assert_eq!(names, vec!["agent_metric_conflicts_total", "m"]);
+    }
+
+    #[tokio::test]
+    async fn test_help_escaping() {
+        let help = "Path C:\\dir\nand \"quotes\"";
+        let out = expose(vec![measure("m", help, Value::Gauge(1), vec![])]).await;
+        assert!(out.starts_with("# HELP m Path C:\\\\dir\\nand \"quotes\"\n"));
+        let parsed = round_trip(vec![measure("m", help, Value::Gauge(1), vec![])]).await;
+        assert_eq!(parsed, vec![measure("m", help, Value::Gauge(1), vec![])]);
+    }
+
+    #[tokio::test]
+    async fn test_counter_suffix() {
+        let out = expose(vec![
+            measure("requests", "Requests", Value::Counter(3), vec![]),
+            measure("bytes_total", "Bytes", Value::Counter(5), vec![]),
+        ])
+        .await;
+        assert_eq!(
+            out,
+            "# HELP bytes Bytes\n# TYPE bytes counter\nbytes_total 5\n# HELP requests Requests\n# TYPE requests counter\nrequests_total 3\n# EOF\n"
+        );
+        let parsed = round_trip(vec![
+            measure("requests", "Requests", Value::Counter(3), vec![]),
+            measure("bytes_total", "Bytes", Value::Counter(5), vec![]),
+        ])
+        .await;
+        assert_eq!(
+            parsed,
+            vec![
+                measure("bytes_total", "Bytes", Value::Counter(5), vec![]),
+                measure("requests_total", "Requests", Value::Counter(3), vec![]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_metric_name_sanitizing() {
+        let out = expose(vec![measure("cpu.usage", "", Value::Gauge(1), vec![])]).await;
+        assert_eq!(out, "# TYPE cpu_usage gauge\ncpu_usage 1\n# EOF\n");
+        let parsed = round_trip(vec![
+            measure("cpu.usage", "", Value::Gauge(1), vec![]),
+            measure("io-bytes", "", Value::Counter(2), vec![]),
+        ])
+        .await;
+        assert_eq!(parsed[0].name, "cpu_usage");
+        assert_eq!(parsed[1].name, "io_bytes_total");
+    }
+
+    #[tokio::test]
+    async fn test_float_special_values() {
+        let out = expose(vec![
+            measure("a", "", Value::GaugeF(f32::INFINITY), vec![]),
+            measure("b", "", Value::GaugeF(f32::NEG_INFINITY), vec![]),
+            measure("c", "", Value::GaugeF(f32::NAN), vec![]),
+        ])
+        .await;
+        assert_eq!(
+            out,
+            "# TYPE a gauge\na +Inf\n# TYPE b gauge\nb -Inf\n# TYPE c gauge\nc NaN\n# EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_families() {
+        let mut db = MetricsDb::default();
+        apply(
+            &mut db,
+            "exec",
+            vec![measure("jobs", "Jobs", Value::Gauge(1), vec![])],
+        )
+        .await;
+        apply(
+            &mut db,
+            "spool",
+            vec![measure("jobs", "Jobs", Value::Gauge(2), vec![])],
+        )
+        .await;
+        assert_eq!(
+            db.to_openmetrics_string().await.unwrap(),
+            "# HELP jobs Jobs\n# TYPE jobs gauge\njobs{collector=\"exec\"} 1\njobs{collector=\"spool\"} 2\n# EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_merge_counter_suffix() {
+        let mut db = MetricsDb::default();
+        apply(
+            &mut db,
+            "exec",
+            vec![measure("requests", "Requests", Value::Counter(1), vec![])],
+        )
+        .await;
+        apply(
+            &mut db,
+            "spool",
+            vec![measure(
+                "requests_total",
+                "Requests",
+                Value::Counter(2),
+                vec![],
+            )],
+        )
+        .await;
+        assert_eq!(
+            db.to_openmetrics_string().await.unwrap(),
+            "# HELP requests Requests\n# TYPE requests counter\nrequests_total{collector=\"exec\"} 1\nrequests_total{collector=\"spool\"} 2\n# EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_type_conflict() {
+        let mut db = MetricsDb::default();
+        apply(
+            &mut db,
+            "exec",
+            vec![measure("jobs", "Jobs", Value::Gauge(1), vec![])],
+        )
+        .await;
+        for _ in 0..2 {
+            apply(
+                &mut db,
+                "spool",
+                vec![measure("jobs_total", "Jobs", Value::Counter(2), vec![])],
+            )
+            .await;
+        }
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert_eq!(
+            out,
+            "# HELP agent_metric_conflicts Conflicting type, help, label names, series owner or reserved names\n# TYPE agent_metric_conflicts counter\nagent_metric_conflicts_total{name=\"jobs\",reason=\"type\"} 1\n# HELP jobs Jobs\n# TYPE jobs gauge\njobs{collector=\"exec\"} 1\n# EOF\n"
+        );
+        assert!(parse(&out, &ParseConfig::default()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_help_conflict() {
+        let mut db = MetricsDb::default();
+        apply(
+            &mut db,
+            "exec",
+            vec![measure("jobs", "Jobs", Value::Gauge(1), vec![])],
+        )
+        .await;
+        // Conflict is counted once
+        for _ in 0..2 {
+            apply(
+                &mut db,
+                "spool",
+                vec![measure("jobs", "Spool jobs", Value::Gauge(2), vec![])],
+            )
+            .await;
+        }
+        assert_eq!(
+            db.to_openmetrics_string().await.unwrap(),
+            "# HELP agent_metric_conflicts Conflicting type, help, label names, series owner or reserved names\n# TYPE agent_metric_conflicts counter\nagent_metric_conflicts_total{name=\"jobs\",reason=\"help\"} 1\n# HELP jobs Jobs\n# TYPE jobs gauge\njobs{collector=\"exec\"} 1\njobs{collector=\"spool\"} 2\n# EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_owner_conflict() {
+        let mut db = MetricsDb::default();
+        for (id, value) in [("exec1", 1), ("exec2", 2), ("exec2", 3), ("exec1", 4)] {
+            apply_jobs(&mut db, id, value).await;
+        }
+        assert_eq!(
+            db.to_openmetrics_string().await.unwrap(),
+            "# HELP agent_metric_conflicts Conflicting type, help, label names, series owner or reserved names\n# TYPE agent_metric_conflicts counter\nagent_metric_conflicts_total{name=\"jobs\",reason=\"owner\"} 1\n# HELP jobs Jobs\n# TYPE jobs gauge\njobs 4\n# EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_collector() {
+        let mut db = MetricsDb::default();
+        db.set_limits(Some(limits(Some(1), None, "drop_series")))
+            .await;
+        apply_jobs(&mut db, "exec1", 1).await;
+        apply_jobs(&mut db, "exec2", 2).await;
+        db.remove_collector("exec1").await;
+        apply_jobs(&mut db, "exec2", 3).await;
+        // Series ownership and counters are released
+        assert_eq!(
+            db.to_openmetrics_string().await.unwrap(),
+            "# HELP agent_metric_conflicts Conflicting type, help, label names, series owner or reserved names\n# TYPE agent_metric_conflicts counter\nagent_metric_conflicts_total{name=\"jobs\",reason=\"owner\"} 1\n# HELP jobs Jobs\n# TYPE jobs gauge\njobs 3\n# EOF\n"
+        );
+        let inner = db.0.read().await;
+        assert_eq!(inner.total_series, 1);
+        assert!(!inner.series.contains_key("exec1"));
+    }
+
+    #[tokio::test]
+    async fn test_reserved_alerts() {
+        let mut db = MetricsDb::default();
+        db.set_alerts(vec![Labels::new(vec![Label::new("alertname", "A")])], 0)
+            .await;
+        apply(
+            &mut db,
+            "exec",
+            vec![measure("ALERTS", "", Value::Gauge(0), vec![])],
+        )
+        .await;
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(
+            out.contains("agent_metric_conflicts_total{name=\"ALERTS\",reason=\"reserved\"} 1\n")
+        );
+        assert!(out.contains("\nALERTS{alertname=\"A\"} 1\n"));
+        assert!(!out.contains("collector=\"exec\""));
+    }
+
+    #[test]
+    fn test_invalid_limits_action() {
+        assert!(Limits::try_from(&LimitsConfig {
+            max_series: None,
+            max_label_length: None,
+            action: "drop_everything".into(),
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drop_series() {
+        let mut db = MetricsDb::default();
+        apply_limited(&mut db, limits(Some(2), None, "drop_series"), series(3)).await;
+        // Update existing series
+        apply_limited(
+            &mut db,
+            limits(Some(2), None, "drop_series"),
+            vec![measure("m", "", Value::Gauge(5), vec![Label::new("i", 0)])],
+        )
+        .await;
+        assert_eq!(
+            db.to_openmetrics_string().await.unwrap(),
+            "# HELP agent_series_rejected Measures rejected due to series limits\n# TYPE agent_series_rejected counter\nagent_series_rejected_total{collector_id=\"test\",reason=\"series_limit\"} 1\n# TYPE m gauge\nm{i=\"0\"} 5\nm{i=\"1\"} 1\n# EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drop_batch() {
+        let mut db = MetricsDb::default();
+        apply_limited(&mut db, limits(Some(2), None, "drop_batch"), series(2)).await;
+        apply_limited(&mut db, limits(Some(2), None, "drop_batch"), series(3)).await;
+        assert_eq!(
+            db.to_openmetrics_string().await.unwrap(),
+            "# HELP agent_series_rejected Measures rejected due to series limits\n# TYPE agent_series_rejected counter\nagent_series_rejected_total{collector_id=\"test\",reason=\"series_limit\"} 3\n# TYPE m gauge\nm{i=\"0\"} 0\nm{i=\"1\"} 1\n# EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_label_length() {
+        let mut db = MetricsDb::default();
+        apply_limited(
+            &mut db,
+            limits(None, Some(4), "drop_series"),
+            vec![
+                measure("m", "", Value::Gauge(1), vec![Label::new("cmd", "ls")]),
+                measure("m", "", Value::Gauge(2), vec![Label::new("cmd", "ls -la")]),
+            ],
+        )
+        .await;
+        assert_eq!(
+            db.to_openmetrics_string().await.unwrap(),
+            "# HELP agent_series_rejected Measures rejected due to series limits\n# TYPE agent_series_rejected counter\nagent_series_rejected_total{collector_id=\"test\",reason=\"label_length\"} 1\n# TYPE m gauge\nm{cmd=\"ls\"} 1\n# EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_global_limits() {
+        let mut db = MetricsDb::default();
+        db.set_limits(Some(limits(Some(3), None, "drop_series")))
+            .await;
+        apply(&mut db, "exec", series(2)).await;
+        apply(&mut db, "spool", series(2)).await;
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(out.contains(
+            "agent_series_rejected_total{collector_id=\"spool\",reason=\"series_limit\"} 1\n"
+        ));
+        assert_eq!(out.matches("\nm{").count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_expire_series() {
+        let mut db = MetricsDb::default();
+        for (ts, measures) in [
+            (10, series(2)),
+            (40, series(1)),
+            (
+                41,
+                vec![measure("m", "", Value::Gauge(5), vec![Label::new("i", 5)])],
+            ),
+        ] {
+            db.apply_data(&MetricsData {
+                collector: "test",
+                id: "test".to_string(),
+                labels: Arc::new(Labels::default()),
+                relabel: Arc::new(None),
+                limits: Arc::new(Some(limits(Some(2), None, "drop_series"))),
+                aggregate: Arc::new(None),
+                rates: false,
+                measures,
+                ts,
+                ttl: 20,
+            })
+            .await;
+        }
+        // Expired series are not counted against limits
+        assert_eq!(
+            db.to_openmetrics_string().await.unwrap(),
+            "# TYPE m gauge\nm{i=\"0\"} 0 40\nm{i=\"5\"} 5 41\n# EOF\n"
+        );
+    }
+
+    #[test]
+    fn test_invalid_aggregate_op() {
+        let r = AggregateRule::try_from(&AggregateConfig {
+            metric: "m".into(),
+            op: "median".into(),
+            by: vec![],
+            name: None,
+            keep_source: true,
+        });
+        assert!(r.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_ops() {
+        for (op, expected) in [
+            ("sum", "7"),
+            ("avg", "2.3333333"),
+            ("min", "1"),
+            ("max", "4"),
+            ("count", "3"),
+        ] {
+            let mut db = MetricsDb::default();
+            apply_aggregated(
+                &mut db,
+                "ps",
+                vec![rule("ps_cpu_usage", op, &[], true)],
+                processes(),
+            )
+            .await;
+            let out = db.to_openmetrics_string().await.unwrap();
+            assert!(
+                out.contains(&format!("\nps_cpu_usage:{} {}\n", op, expected)),
+                "{}: {}",
+                op,
+                out
+            );
+            // Source series are kept
+            assert_eq!(out.matches("\nps_cpu_usage{").count(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_by() {
+        let mut db = MetricsDb::default();
+        apply_aggregated(
+            &mut db,
+            "ps",
+            vec![rule("ps_cpu_usage", "sum", &["user"], false)],
+            processes(),
+        )
+        .await;
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert_eq!(
+            out,
+            "# HELP ps_cpu_usage:sum sum of ps_cpu_usage by (user)\n\
+             # TYPE ps_cpu_usage:sum gauge\n\
+             ps_cpu_usage:sum{user=\"root\"} 3\n\
+             ps_cpu_usage:sum{user=\"www\"} 4\n\
+             # EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_counter() {
+        let mut db = MetricsDb::default();
+        apply_aggregated(
+            &mut db,
+            "net",
+            vec![rule("net_rx_octets_total", "sum", &[], true)],
+            vec![
+                measure(
+                    "net_rx_octets",
+                    "",
+                    Value::Counter(10),
+                    vec![Label::new("iface", "eth0")],
+                ),
+                measure(
+                    "net_rx_octets",
+                    "",
+                    Value::Counter(20),
+                    vec![Label::new("iface", "eth1")],
+                ),
+            ],
+        )
+        .await;
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(out.contains("# TYPE net_rx_octets:sum counter\n"));
+        assert!(out.contains("\nnet_rx_octets:sum_total 30\n"));
+        parse(&out, &ParseConfig::default()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_scope() {
+        let mut db = MetricsDb::default();
+        // Collector rule applies to own series only
+        apply_aggregated(
+            &mut db,
+            "ps",
+            vec![rule("ps_cpu_usage", "count", &[], false)],
+            processes(),
+        )
+        .await;
+        apply(&mut db, "other", processes().drain(..1).collect()).await;
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(out.contains("\nps_cpu_usage:count 3\n"));
+        assert_eq!(out.matches("\nps_cpu_usage{").count(), 1);
+        // Global rule applies to all collectors
+        db.set_aggregate(vec![rule("ps_cpu_usage", "max", &[], true)])
+            .await;
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(out.contains("\nps_cpu_usage:max 4\n"));
+    }
+
+    #[tokio::test]
+    async fn test_rates() {
+        let octets = |v| {
+            vec![
+                measure(
+                    "net_rx_octets",
+                    "Received",
+                    Value::Counter(v),
+                    vec![Label::new("iface", "eth0")],
+                ),
+                measure("net_mtu", "MTU", Value::Gauge(1500), vec![]),
+            ]
+        };
+        let mut db = MetricsDb::default();
+        // No rate on first sample
+        apply_rates(&mut db, 10, octets(100)).await;
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(!out.contains("_rate"));
+        apply_rates(&mut db, 20, octets(160)).await;
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(out.contains(
+            "# HELP net_rx_octets_rate Per-second rate of net_rx_octets\n\
+             # TYPE net_rx_octets_rate gauge\n\
+             net_rx_octets_rate{iface=\"eth0\"} 6 20\n"
+        ));
+        assert!(!out.contains("net_mtu_rate"));
+        // Counter reset
+        apply_rates(&mut db, 30, octets(30)).await;
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert!(out.contains("net_rx_octets_rate{iface=\"eth0\"} 3 30\n"));
+        parse(&out, &ParseConfig::default()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits() {
+        for (action, expected) in [
+            ("drop_series", "net_rx_octets_total 160 20\n"),
+            ("drop_batch", "net_rx_octets_total 100 10\n"),
+        ] {
+            let mut db = MetricsDb::default();
+            for (ts, value) in [(10, 100), (20, 160)] {
+                db.apply_data(&MetricsData {
+                    collector: "network",
+                    id: "network".to_string(),
+                    labels: Arc::new(Labels::default()),
+                    relabel: Arc::new(None),
+                    limits: Arc::new(Some(limits(Some(1), None, action))),
+                    aggregate: Arc::new(None),
+                    rates: true,
+                    measures: vec![measure("net_rx_octets", "", Value::Counter(value), vec![])],
+                    ts,
+                    ttl: 0,
+                })
+                .await;
+            }
+            let out = db.to_openmetrics_string().await.unwrap();
+            assert!(out.contains(expected), "{}: {}", action, out);
+            assert!(!out.contains("_rate"), "{}: {}", action, out);
+            assert!(
+                out.contains("reason=\"series_limit\"} 1 "),
+                "{}: {}",
+                action,
+                out
+            );
+        }
+    }
+
+    fn ruleset(yaml: &str) -> RelabelRuleset {
+        let cfg = serde_yaml::from_str::<Vec<RelabelRuleConfig>>(yaml).unwrap();
+        RelabelRuleset::try_from(&cfg).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_global_relabel() {
+        let mut db = MetricsDb::default();
+        db.set_relabel(Some(ruleset(
+            r#"
+- source_labels: [__name__]
+  regex: "^mem_direct_map_.+"
+  action: drop
+- source_labels: [collector]
+  target_label: source
+"#,
+        )))
+        .await;
+        // Collector rules are applied first
+        db.apply_data(&MetricsData {
+            collector: "memory",
+            id: "memory".to_string(),
+            labels: Arc::new(Labels::new(vec![Label::new("collector", "memory")])),
+            relabel: Arc::new(Some(ruleset(
+                r#"
+- source_labels: [__name__]
+  regex: "mem_total"
+  replacement: mem_direct_map_total
+  target_label: __name__
+"#,
+            ))),
+            limits: Arc::new(None),
+            aggregate: Arc::new(None),
+            rates: false,
+            measures: vec![
+                measure("mem_direct_map_4k", "", Value::Gauge(1), vec![]),
+                measure("mem_total", "", Value::Gauge(2), vec![]),
+                measure("mem_free", "", Value::Gauge(3), vec![]),
+            ],
+            ts: 0,
+            ttl: 0,
+        })
+        .await;
+        let out = db.to_openmetrics_string().await.unwrap();
+        assert_eq!(
+            out,
+            "# TYPE mem_free gauge\nmem_free{collector=\"memory\",source=\"memory\"} 3\n# EOF\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_exemplar() {
+        let exemplar = Some(Exemplar {
+            labels: Labels::new(vec![Label::new("trace_id", "abc")]),
+            value: 0.67,
+            timestamp: None,
+        });
+        let mut requests = measure("requests", "Requests", Value::Counter(3), vec![]);
+        requests.exemplar = exemplar.clone();
+        let mut temp = measure("temp", "Temperature", Value::Gauge(20), vec![]);
+        temp.exemplar = exemplar;
+        let out = expose(vec![requests, temp]).await;
+        assert_eq!(
+            out,
+            "# HELP requests Requests\n# TYPE requests counter\nrequests_total 3 # {trace_id=\"abc\"} 0.67\n# HELP temp Temperature\n# TYPE temp gauge\ntemp 20\n# EOF\n"
+        );
+    }
+}
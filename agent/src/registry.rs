@@ -162,6 +162,37 @@ impl Collectors {
         }
     }
 
+    pub fn is_expire_series(&self) -> bool {
+        match self {
+            // @@@{{{
+            // | Collectors::{ename}(_) => {name}::Collector::is_expire_series(),
+            Collectors::BlockIo(_) => block_io::Collector::is_expire_series(),
+            Collectors::Cpu(_) => cpu::Collector::is_expire_series(),
+            Collectors::Dns(_) => dns::Collector::is_expire_series(),
+            Collectors::Exec(_) => exec::Collector::is_expire_series(),
+            Collectors::Fs(_) => fs::Collector::is_expire_series(),
+            Collectors::Http(_) => http::Collector::is_expire_series(),
+            Collectors::Memory(_) => memory::Collector::is_expire_series(),
+            Collectors::ModbusRtu(_) => modbus_rtu::Collector::is_expire_series(),
+            Collectors::ModbusTcp(_) => modbus_tcp::Collector::is_expire_series(),
+            Collectors::Mysql(_) => mysql::Collector::is_expire_series(),
+            Collectors::MysqlQuery(_) => mysql_query::Collector::is_expire_series(),
+            Collectors::Network(_) => network::Collector::is_expire_series(),
+            Collectors::Pgbouncer(_) => pgbouncer::Collector::is_expire_series(),
+            Collectors::Postgres(_) => postgres::Collector::is_expire_series(),
+            Collectors::PostgresQuery(_) => postgres_query::Collector::is_expire_series(),
+            Collectors::Procstat(_) => procstat::Collector::is_expire_series(),
+            Collectors::Redis(_) => redis::Collector::is_expire_series(),
+            Collectors::Scrape(_) => scrape::Collector::is_expire_series(),
+            Collectors::Sockets(_) => sockets::Collector::is_expire_series(),
+            Collectors::Spool(_) => spool::Collector::is_expire_series(),
+            Collectors::TwampReflector(_) => twamp_reflector::Collector::is_expire_series(),
+            Collectors::TwampSender(_) => twamp_sender::Collector::is_expire_series(),
+            Collectors::Uptime(_) => uptime::Collector::is_expire_series(),
+            // @@@}}}
+        }
+    }
+
     pub async fn collect(&mut self) -> Result<Vec<Measure>, AgentError> {
         match self {
             // @@@{{{
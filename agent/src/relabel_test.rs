@@ -0,0 +1,200 @@
+// --------------------------------------------------------------------
+// Gufo Agent: Relabel rules testing
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use common::{AgentError, Label, Labels};
+use openmetrics::{parse, parse_series, ParseConfig};
+use relabel::{ActionResult, ActiveLabels, LabelChange, RelabelRuleConfig, RelabelRuleset};
+use std::fmt::Write;
+
+const NAME_LABEL: &str = "__name__";
+
+// Input series
+struct Series {
+    name: Option<String>,
+    labels: Labels,
+    value: Option<String>,
+    fvalue: Option<f64>,
+}
+
+// Apply ruleset to the input series and build per-rule trace report.
+// `rules` is a YAML list of relabel rules.
+// `input` is either OpenMetrics text or a list of label sets, one per line.
+pub fn relabel_test(rules: &str, input: &str) -> Result<String, AgentError> {
+    let cfg: Vec<RelabelRuleConfig> =
+        serde_yaml::from_str(rules).map_err(|e| AgentError::ConfigurationError(e.to_string()))?;
+    let ruleset = RelabelRuleset::try_from(&cfg)?;
+    let mut r = String::new();
+    for series in parse_input(input)?.into_iter() {
+        let mut labels = ActiveLabels::try_from(&series.labels)?;
+        if let Some(name) = &series.name {
+            labels.insert(Label::new(NAME_LABEL, name));
+        }
+        if let Some(v) = series.fvalue {
+            labels.set_value(v);
+        }
+        let _ = writeln!(r, "{}", series);
+        let trace = ruleset.trace(&mut labels)?;
+        for step in trace.iter() {
+            let _ = writeln!(
+                r,
+                "  #{} {}: {}",
+                step.rule,
+                step.action,
+                match step.result {
+                    ActionResult::Pass => "pass",
+                    ActionResult::Drop => "drop",
+                }
+            );
+            for change in step.changes.iter() {
+                let _ = match change {
+                    LabelChange::Added { name, value } => {
+                        writeln!(r, "    + {}=\"{}\"", name, value)
+                    }
+                    LabelChange::Changed { name, old, new } => {
+                        writeln!(r, "    ~ {}=\"{}\" -> \"{}\"", name, old, new)
+                    }
+                    LabelChange::Removed { name, value } => {
+                        writeln!(r, "    - {}=\"{}\"", name, value)
+                    }
+                };
+            }
+        }
+        let _ = match trace.last() {
+            Some(step) if step.result == ActionResult::Drop => {
+                writeln!(r, "  => dropped by rule #{}", step.rule)
+            }
+            _ => writeln!(
+                r,
+                "  => kept: {}",
+                Series {
+                    name: labels.get(NAME_LABEL).cloned(),
+                    labels: labels.to_labels(),
+                    value: series.value.clone(),
+                    fvalue: None,
+                }
+            ),
+        };
+    }
+    Ok(r)
+}
+
+// Parse label sets or OpenMetrics text, depending on the first line
+fn parse_input(input: &str) -> Result<Vec<Series>, AgentError> {
+    let mut lines = input
+        .lines()
+        .enumerate()
+        .map(|(n, line)| (n, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .peekable();
+    match lines.peek() {
+        None => return Ok(Vec::new()),
+        Some((_, line)) if parse_series(line).is_ok() => {}
+        Some(_) => {
+            let measures = parse(input, &ParseConfig::default())?;
+            if measures.is_empty() {
+                return Err(AgentError::ParseError("invalid input".to_string()));
+            }
+            return Ok(measures
+                .into_iter()
+                .map(|m| Series {
+                    name: Some(m.name),
+                    labels: m.labels,
+                    value: Some(m.value.to_string()),
+                    fvalue: Some(m.value.as_f64()),
+                })
+                .collect());
+        }
+    }
+    let mut r = Vec::new();
+    for (n, line) in lines {
+        let (name, labels) = parse_series(line)
+            .map_err(|e| AgentError::ParseError(format!("line {}: {}", n + 1, e)))?;
+        r.push(Series {
+            name,
+            labels,
+            value: None,
+            fvalue: None,
+        });
+    }
+    Ok(r)
+}
+
+impl std::fmt::Display for Series {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{}", name)?;
+        }
+        write!(f, "{{{}}}", self.labels.to_openmetrics())?;
+        if let Some(value) = &self.value {
+            write!(f, " {}", value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::relabel_test;
+
+    const RULES: &str = r#"
+- source_labels: [zone]
+  target_label: region
+- action: labeldrop
+  regex: zone
+- action: drop
+  is_zero: true
+"#;
+
+    #[test]
+    fn test_openmetrics() {
+        let input = r#"# HELP ps_io_read Read operations
+# TYPE ps_io_read gauge
+ps_io_read{zone="tiger"} 0
+ps_io_read{zone="lion"} 10
+# EOF"#;
+        let r = relabel_test(RULES, input).unwrap();
+        assert_eq!(
+            r,
+            r#"ps_io_read{zone="tiger"} 0
+  #1 replace: pass
+    + region="tiger"
+  #2 labeldrop: pass
+    - zone="tiger"
+  #3 drop: drop
+  => dropped by rule #3
+ps_io_read{zone="lion"} 10
+  #1 replace: pass
+    + region="lion"
+  #2 labeldrop: pass
+    - zone="lion"
+  #3 drop: pass
+  => kept: ps_io_read{region="lion"} 10
+"#
+        );
+    }
+
+    #[test]
+    fn test_label_sets() {
+        let input = "{__address__=\"10.0.0.1:9100\",zone=\"tiger\"}\n";
+        let r = relabel_test(RULES, input).unwrap();
+        assert_eq!(
+            r,
+            r#"{__address__="10.0.0.1:9100",zone="tiger"}
+  #1 replace: pass
+    + region="tiger"
+  #2 labeldrop: pass
+    - zone="tiger"
+  #3 drop: pass
+  => kept: {region="tiger"}
+"#
+        );
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert!(relabel_test(RULES, "{zone=tiger}").is_err());
+    }
+}
@@ -4,7 +4,7 @@
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
 
-use crate::{CollectorConfig, Collectors, MetricsData, SenderCommand};
+use crate::{AggregateRule, CollectorConfig, Collectors, Limits, MetricsData, SenderCommand};
 use common::{AgentError, Label, Labels, Measure};
 use rand::Rng;
 use relabel::RelabelRuleset;
@@ -13,11 +13,20 @@ use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
 use tokio::time::Duration;
 
+// Default number of collection intervals without updates
+// after which the series are expired
+const SERIES_TTL_INTERVALS: u64 = 5;
+
 pub(crate) struct Schedule {
     id: String,
     interval: u64,
     labels: Arc<Labels>,
     relabel: Arc<Option<RelabelRuleset>>,
+    limits: Arc<Option<Limits>>,
+    aggregate: Arc<Option<Vec<AggregateRule>>>,
+    rates: bool,
+    // Series TTL, in collection intervals. 0 - never expire
+    series_ttl: u64,
     collector: Collectors,
     sender_tx: Option<mpsc::Sender<SenderCommand>>,
 }
@@ -37,6 +46,20 @@ impl TryFrom<CollectorConfig> for Schedule {
                 Some(v) => Some(RelabelRuleset::try_from(v)?),
                 None => None,
             }),
+            limits: Arc::new(match &value.limits {
+                Some(v) => Some(Limits::try_from(v)?),
+                None => None,
+            }),
+            aggregate: Arc::new(match &value.aggregate {
+                Some(v) => Some(
+                    v.iter()
+                        .map(AggregateRule::try_from)
+                        .collect::<Result<Vec<_>, _>>()?,
+                ),
+                None => None,
+            }),
+            rates: value.rates,
+            series_ttl: value.series_ttl.unwrap_or(SERIES_TTL_INTERVALS),
             collector: Collectors::try_from(value)?,
             sender_tx: None,
         })
@@ -134,10 +157,19 @@ impl Schedule {
         if let Some(tx) = &self.sender_tx {
             tx.send(SenderCommand::Data(MetricsData {
                 collector: collector_name,
+                id: self.id.clone(),
                 labels: self.labels.clone(),
                 relabel: self.relabel.clone(),
+                limits: self.limits.clone(),
+                aggregate: self.aggregate.clone(),
+                rates: self.rates,
                 measures,
                 ts,
+                ttl: if self.collector.is_expire_series() {
+                    self.interval * self.series_ttl
+                } else {
+                    0
+                },
             }))
             .await
             .map_err(|e| AgentError::InternalError(e.to_string()))?;
@@ -4,8 +4,9 @@
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
 
-use crate::{MetricsData, MetricsDb, SenderConfig};
+use crate::{AggregateRule, Alerts, AlertsHandle, Limits, MetricsData, MetricsDb, SenderConfig};
 use common::{AgentError, Labels};
+use relabel::RelabelRuleset;
 use std::convert::Infallible;
 use std::fs;
 use std::net::SocketAddrV4;
@@ -16,7 +17,12 @@ const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset
 
 pub(crate) enum SenderCommand {
     Data(MetricsData),
+    RemoveCollector(String),
     SetAgentLabels(Labels),
+    SetAgentLimits(Option<Limits>),
+    SetAgentAggregate(Vec<AggregateRule>),
+    SetAgentAlerts(Option<Alerts>),
+    SetAgentRelabel(Option<RelabelRuleset>),
     Dump,
     Shutdown,
 }
@@ -40,6 +46,8 @@ pub(crate) struct Sender {
     http: Option<SenderHttp>,
     https: Option<SenderHttps>,
     path: String,
+    // Running alerts engine
+    alerts: Option<AlertsHandle>,
 }
 
 const SENDER_CHANNEL_BUFFER: usize = 10_000;
@@ -151,6 +159,7 @@ impl TryFrom<&SenderConfig> for Sender {
             dump_metrics: false,
             http,
             https,
+            alerts: None,
         })
     }
 }
@@ -179,10 +188,45 @@ impl Sender {
                         }
                     }
                 }
+                SenderCommand::RemoveCollector(id) => {
+                    self.db.remove_collector(&id).await;
+                }
                 SenderCommand::SetAgentLabels(labels) => {
                     log::debug!("Set labels to: {:?}", labels);
                     self.db.set_labels(labels).await;
                 }
+                SenderCommand::SetAgentLimits(limits) => {
+                    log::debug!("Set limits to: {:?}", limits);
+                    self.db.set_limits(limits).await;
+                }
+                SenderCommand::SetAgentAggregate(rules) => {
+                    log::debug!("Set aggregation rules to: {:?}", rules);
+                    self.db.set_aggregate(rules).await;
+                }
+                SenderCommand::SetAgentRelabel(ruleset) => {
+                    log::debug!("Set relabeling rules to: {:?}", ruleset);
+                    self.db.set_relabel(ruleset).await;
+                }
+                SenderCommand::SetAgentAlerts(alerts) => {
+                    let prev = match self.alerts.take() {
+                        Some(handle) => handle.stop().await,
+                        None => None,
+                    };
+                    match alerts {
+                        Some(mut alerts) => {
+                            if let Some(prev) = prev {
+                                alerts.inherit(prev);
+                            }
+                            self.alerts = Some(alerts.spawn(self.db.clone()));
+                        }
+                        None => {
+                            self.db.set_alerts(Vec::new(), 0).await;
+                            if let Some(prev) = prev {
+                                tokio::spawn(prev.shutdown());
+                            }
+                        }
+                    }
+                }
                 SenderCommand::Dump => {
                     if let Ok(data) = self.db.to_openmetrics_string().await {
                         println!("{}", data)
@@ -6,11 +6,13 @@
 
 use async_trait::async_trait;
 use common::{counter, AgentError, Collectable, Measure};
-use openmetrics::{parse, ParseConfig};
+use openmetrics::{Format, ParseConfig, Parser};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::process::Stdio;
-use tokio::process::Command;
+use tokio::{io::AsyncReadExt, process::Command};
+
+const BUFFER_SIZE: usize = 65536;
 
 // Collector config
 #[derive(Deserialize, Serialize)]
@@ -20,6 +22,12 @@ pub struct Config {
     env: Option<HashMap<String, String>>,
     #[serde(default = "default_false")]
     trust_timestamps: bool,
+    #[serde(default = "default_false")]
+    strict: bool,
+    #[serde(default)]
+    format: Format,
+    body_size_limit: Option<usize>,
+    sample_limit: Option<usize>,
 }
 
 // Collector structure
@@ -51,6 +59,10 @@ impl TryFrom<Config> for Collector {
             env: value.env,
             parse_cfg: ParseConfig {
                 trust_timestamps: value.trust_timestamps,
+                strict: value.strict,
+                format: value.format,
+                body_size_limit: value.body_size_limit,
+                sample_limit: value.sample_limit,
             },
             // Stats
             exec_parsed: 0,
@@ -80,16 +92,32 @@ impl Collectable for Collector {
             cmd = cmd.envs(env.clone().into_iter());
         }
         // Detach stdio
-        cmd = cmd.stdin(Stdio::null());
+        cmd = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
         // Run command
-        let output = cmd
-            .output()
-            .await
+        let mut child = cmd
+            .spawn()
             .map_err(|e| AgentError::InternalError(e.to_string()))?;
-        // Parse stdout
-        let out = String::from_utf8(output.stdout)
-            .map_err(|e| AgentError::InternalError(e.to_string()))?;
-        let mut parsed = parse(out.as_str(), &self.parse_cfg)?;
+        let mut stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| AgentError::InternalError("stdout is not captured".to_string()))?;
+        // Parse stdout as it arrives.
+        // Process is killed on the error.
+        let mut parser = Parser::new(&self.parse_cfg);
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let n = stdout.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            parser.feed(&buf[..n])?;
+        }
+        child.wait().await?;
+        let mut parsed = parser.finish()?;
         self.exec_parsed += parsed.len() as u64;
         // Push result
         let mut r = Vec::new();
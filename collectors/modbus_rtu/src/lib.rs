@@ -209,6 +209,7 @@ impl Collectable for Collector {
                 labels: item.labels.clone(),
                 value,
                 timestamp: None,
+                exemplar: None,
             });
         }
         Ok(r)
@@ -151,6 +151,7 @@ impl Collectable for Collector {
                 labels: item.labels.clone(),
                 value,
                 timestamp: None,
+                exemplar: None,
             });
         }
         // Push result
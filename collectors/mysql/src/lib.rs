@@ -83,6 +83,7 @@ impl Collectable for Collector {
                     value: common::Value::Gauge(v),
                     labels: Labels::default(),
                     timestamp: None,
+                    exemplar: None,
                 })
             }
         }
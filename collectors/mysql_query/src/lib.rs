@@ -280,6 +280,7 @@ impl RowFields {
             labels: Labels::merge_sort2(&self.labels, &column_labels),
             value,
             timestamp: None,
+            exemplar: None,
         })
     }
 }
@@ -0,0 +1,262 @@
+// --------------------------------------------------------------------
+// Gufo Agent: scrape HTTP client
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use crate::tls::TlsConfig;
+use common::{AgentError, AgentResult};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+
+// Job-wide HTTP settings, flattened into the collector config
+#[derive(Deserialize, Serialize)]
+pub(crate) struct ClientConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tls_config: Option<TlsConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    basic_auth: Option<BasicAuthConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bearer_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bearer_token_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<HashMap<String, String>>,
+    #[serde(
+        default = "default_timeout_ms",
+        skip_serializing_if = "is_default_timeout_ms"
+    )]
+    timeout_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    proxy_url: Option<String>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct BasicAuthConfig {
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password_file: Option<String>,
+}
+
+// Client, shared between all the collector's targets
+pub(crate) struct HttpClient {
+    client: reqwest::Client,
+    auth: Auth,
+    timeout: Duration,
+}
+
+pub(crate) enum Auth {
+    None,
+    Basic {
+        username: String,
+        password: Option<String>,
+        password_file: Option<String>,
+    },
+    Bearer(String),
+    BearerFile(String),
+}
+
+impl TryFrom<ClientConfig> for HttpClient {
+    type Error = AgentError;
+
+    fn try_from(value: ClientConfig) -> Result<Self, Self::Error> {
+        let auth = match (
+            value.basic_auth,
+            value.bearer_token,
+            value.bearer_token_file,
+        ) {
+            (None, None, None) => Auth::None,
+            (Some(cfg), None, None) => {
+                if cfg.password.is_some() && cfg.password_file.is_some() {
+                    return Err(AgentError::ConfigurationError(
+                        "password and password_file are mutually exclusive".to_string(),
+                    ));
+                }
+                Auth::Basic {
+                    username: cfg.username,
+                    password: cfg.password,
+                    password_file: cfg.password_file,
+                }
+            }
+            (None, Some(token), None) => Auth::Bearer(token),
+            (None, None, Some(path)) => Auth::BearerFile(path),
+            _ => {
+                return Err(AgentError::ConfigurationError(
+                    "basic_auth, bearer_token and bearer_token_file are mutually exclusive"
+                        .to_string(),
+                ))
+            }
+        };
+        let mut builder = reqwest::Client::builder().gzip(true);
+        if let Some(tls) = &value.tls_config {
+            builder = builder.use_preconfigured_tls(tls.client_config()?);
+        }
+        if let Some(headers) = &value.headers {
+            let mut map = HeaderMap::with_capacity(headers.len());
+            for (name, value) in headers.iter() {
+                map.insert(
+                    HeaderName::from_bytes(name.as_bytes())
+                        .map_err(|e| AgentError::ConfigurationError(format!("{}: {}", name, e)))?,
+                    HeaderValue::from_str(value)
+                        .map_err(|e| AgentError::ConfigurationError(format!("{}: {}", name, e)))?,
+                );
+            }
+            builder = builder.default_headers(map);
+        }
+        if let Some(url) = &value.proxy_url {
+            builder = builder.proxy(
+                reqwest::Proxy::all(url)
+                    .map_err(|e| AgentError::ConfigurationError(e.to_string()))?,
+            );
+        }
+        Self::from_builder(builder, auth, Duration::from_millis(value.timeout_ms))
+    }
+}
+
+impl HttpClient {
+    // Client with TLS and authentication set up by caller
+    pub(crate) fn new(tls: Option<rustls::ClientConfig>, auth: Auth) -> AgentResult<Self> {
+        let mut builder = reqwest::Client::builder().gzip(true);
+        if let Some(tls) = tls {
+            builder = builder.use_preconfigured_tls(tls);
+        }
+        Self::from_builder(builder, auth, Duration::from_millis(DEFAULT_TIMEOUT_MS))
+    }
+    fn from_builder(
+        builder: reqwest::ClientBuilder,
+        auth: Auth,
+        timeout: Duration,
+    ) -> AgentResult<Self> {
+        Ok(Self {
+            client: builder
+                .build()
+                .map_err(|e| AgentError::ConfigurationError(e.to_string()))?,
+            auth,
+            timeout,
+        })
+    }
+    pub(crate) fn timeout(&self) -> Duration {
+        self.timeout
+    }
+    // Build authenticated GET request.
+    // Secret files are read on every request to follow the rotation.
+    pub(crate) async fn get(&self, url: &str) -> AgentResult<reqwest::RequestBuilder> {
+        let req = self.client.get(url).timeout(self.timeout);
+        Ok(match &self.auth {
+            Auth::None => req,
+            Auth::Basic {
+                username,
+                password,
+                password_file,
+            } => {
+                let password = match password_file {
+                    Some(path) => Some(read_secret(path).await?),
+                    None => password.clone(),
+                };
+                req.basic_auth(username, password)
+            }
+            Auth::Bearer(token) => req.bearer_auth(token),
+            Auth::BearerFile(path) => req.bearer_auth(read_secret(path).await?),
+        })
+    }
+}
+
+pub(crate) async fn read_secret(path: &str) -> AgentResult<String> {
+    Ok(tokio::fs::read_to_string(path)
+        .await?
+        .trim_end_matches(['\r', '\n'])
+        .to_string())
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_TIMEOUT_MS
+}
+
+fn is_default_timeout_ms(v: &u64) -> bool {
+    *v == DEFAULT_TIMEOUT_MS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientConfig, HttpClient};
+    use common::{AgentError, AgentResult};
+    use reqwest::header::AUTHORIZATION;
+
+    fn client(yaml: &str) -> AgentResult<HttpClient> {
+        HttpClient::try_from(serde_yaml::from_str::<ClientConfig>(yaml).unwrap())
+    }
+
+    fn is_config_error(r: AgentResult<HttpClient>) -> bool {
+        matches!(r, Err(AgentError::ConfigurationError(_)))
+    }
+
+    #[test]
+    fn test_mutually_exclusive_auth() {
+        for yaml in [
+            "basic_auth: {username: u, password: p}\nbearer_token: t\n",
+            "basic_auth: {username: u}\nbearer_token_file: /etc/token\n",
+            "bearer_token: t\nbearer_token_file: /etc/token\n",
+            "basic_auth: {username: u, password: p, password_file: /etc/password}\n",
+        ] {
+            assert!(is_config_error(client(yaml)), "{}", yaml);
+        }
+    }
+    #[test]
+    fn test_invalid_header() {
+        assert!(is_config_error(client("headers: {\"bad header\": x}\n")));
+    }
+    #[tokio::test]
+    async fn test_auth() {
+        let get_auth = |yaml: &'static str| async move {
+            let req = client(yaml)
+                .ok()
+                .unwrap()
+                .get("http://127.0.0.1/metrics")
+                .await
+                .ok()
+                .unwrap()
+                .build()
+                .unwrap();
+            req.headers()
+                .get(AUTHORIZATION)
+                .map(|x| x.to_str().unwrap().to_string())
+        };
+        assert_eq!(get_auth("{}").await, None);
+        assert_eq!(
+            get_auth("bearer_token: secret\n").await.as_deref(),
+            Some("Bearer secret")
+        );
+        assert_eq!(
+            get_auth("basic_auth: {username: user, password: pass}\n")
+                .await
+                .as_deref(),
+            Some("Basic dXNlcjpwYXNz")
+        );
+    }
+    #[tokio::test]
+    async fn test_bearer_token_file() {
+        let path = std::env::temp_dir().join(format!("scrape-token-{}", std::process::id()));
+        std::fs::write(&path, "secret\n").unwrap();
+        let client = client(&format!("bearer_token_file: {}\n", path.display()))
+            .ok()
+            .unwrap();
+        let req = client
+            .get("http://127.0.0.1/metrics")
+            .await
+            .ok()
+            .unwrap()
+            .build()
+            .unwrap();
+        // Trailing newline is stripped
+        assert_eq!(req.headers()[AUTHORIZATION], "Bearer secret");
+        std::fs::remove_file(&path).unwrap();
+        // Missed file
+        assert!(client.get("http://127.0.0.1/metrics").await.is_err());
+    }
+}
@@ -4,39 +4,111 @@
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
 
+mod client;
 mod sd;
+mod tls;
 
 use async_trait::async_trait;
-use common::{AgentError, AgentResult, Collectable, Label, Measure};
-use openmetrics::{parse, ParseConfig};
+use client::{ClientConfig, HttpClient};
+use common::{gauge, gauge_f, AgentError, AgentResult, Collectable, Label, Labels, Measure};
+use openmetrics::{Format, ParseConfig, Parser};
+use relabel::{RelabelRuleConfig, RelabelRuleset};
 use sd::{Sd, SdConfig, ServiceDiscovery, LABEL_ADDRESS};
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::{sync::Semaphore, task::JoinSet};
 
 const DEFAULT_CONCURRENCY: usize = 10;
+// Prefer OpenMetrics, fallback to Prometheus text format
+const ACCEPT: &str =
+    "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1";
 
 // Collector config
 #[derive(Deserialize, Serialize)]
 pub struct Config {
     service_discovery: SdConfig,
-    #[serde(default = "default_false")]
+    #[serde(default = "default_false", alias = "honor_timestamps")]
     trust_timestamps: bool,
+    // Keep scraped labels on conflict with the target labels
+    #[serde(default = "default_false")]
+    honor_labels: bool,
+    // Request query parameters, like `match[]` for federation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    params: Option<BTreeMap<String, Vec<String>>>,
+    #[serde(default = "default_false")]
+    strict: bool,
+    // Detect by Content-Type, if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<Format>,
+    body_size_limit: Option<usize>,
+    sample_limit: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metric_relabel: Option<Vec<RelabelRuleConfig>>,
     #[serde(
         default = "default_concurrency",
         skip_serializing_if = "is_default_concurrency"
     )]
     concurrency: usize,
+    #[serde(flatten)]
+    client: ClientConfig,
 }
 
 // Collector structure
 pub struct Collector {
     sd: Sd,
     parse_cfg: ParseConfig,
+    format: Option<Format>,
+    honor_labels: bool,
+    params: Arc<Vec<(String, String)>>,
+    metric_relabel: Arc<Option<RelabelRuleset>>,
     concurrency: usize,
+    client: Arc<HttpClient>,
+    // Target url -> series hashes of the last successful scrape
+    series: HashMap<String, HashSet<u64>>,
+}
+
+// Scrape result of the single target
+struct Target {
+    url: String,
+    address_label: Label,
+    // Non-virtual labels left by the service discovery
+    target_labels: Labels,
+    duration: f32,
+    samples_scraped: usize,
+    // Measures after the metric relabeling
+    measures: AgentResult<Vec<Measure>>,
 }
 
 // Generated metrics
+gauge!(
+    up,
+    "1 if the target has been scraped successfully, 0 otherwise",
+    Labels
+);
+gauge_f!(
+    scrape_duration_seconds,
+    "Duration of the target scrape",
+    Labels
+);
+gauge!(
+    scrape_samples_scraped,
+    "Number of samples the target exposed",
+    Labels
+);
+gauge!(
+    scrape_samples_post_metric_relabeling,
+    "Number of samples remaining after metric relabeling",
+    Labels
+);
+gauge!(
+    scrape_series_added,
+    "Number of series not seen in the previous scrape",
+    Labels
+);
 
 // Instantiate collector from given config
 impl TryFrom<Config> for Collector {
@@ -47,8 +119,28 @@ impl TryFrom<Config> for Collector {
             sd: Sd::try_from(value.service_discovery)?,
             parse_cfg: ParseConfig {
                 trust_timestamps: value.trust_timestamps,
+                strict: value.strict,
+                body_size_limit: value.body_size_limit,
+                sample_limit: value.sample_limit,
+                ..Default::default()
             },
+            format: value.format,
+            honor_labels: value.honor_labels,
+            params: Arc::new(
+                value
+                    .params
+                    .unwrap_or_default()
+                    .into_iter()
+                    .flat_map(|(k, vs)| vs.into_iter().map(move |v| (k.clone(), v)))
+                    .collect(),
+            ),
+            metric_relabel: Arc::new(match &value.metric_relabel {
+                Some(v) => Some(RelabelRuleset::try_from(v)?),
+                None => None,
+            }),
             concurrency: value.concurrency,
+            client: Arc::new(HttpClient::try_from(value.client)?),
+            series: HashMap::new(),
         })
     }
 }
@@ -72,56 +164,85 @@ impl Collectable for Collector {
         for labels in services.iter() {
             let url = self.sd.get_url(labels);
             let address_label = Label::new(LABEL_ADDRESS, self.sd.get_address(labels).to_owned());
+            let target_labels = labels.to_labels();
             let parse_cfg = self.parse_cfg.clone();
+            let format = self.format;
+            let params = self.params.clone();
+            let metric_relabel = self.metric_relabel.clone();
+            let client = self.client.clone();
             let sem = semaphore.clone();
             tasks.spawn(async move {
                 // Limit run, drops at the return
-                let _permit = sem
-                    .acquire()
+                let _permit = sem.acquire().await;
+                let start = Instant::now();
+                let mut samples_scraped = 0;
+                let measures = scrape(&client, &url, &params, parse_cfg, format)
                     .await
-                    .map_err(|e| AgentError::InternalError(e.to_string()))?;
-                // Fetch
-                let client = reqwest::Client::builder() // @todo: move into
-                    .gzip(true)
-                    .build()
-                    .map_err(|e| AgentError::InternalError(e.to_string()))?;
-                match client.get(&url).send().await {
-                    Ok(resp) => match resp.text().await {
-                        Ok(data) => match parse(data.as_str(), &parse_cfg) {
-                            Ok(mut parsed) => {
-                                // Install virtual labels
-                                for item in parsed.iter_mut() {
-                                    item.labels.push(address_label.to_owned());
-                                }
-                                Ok(parsed)
-                            }
-                            Err(e) => Err(AgentError::InternalError(format!(
-                                "Failed to parse from {}: {}",
-                                url, e
-                            ))),
-                        },
-                        Err(e) => Err(AgentError::InternalError(format!(
-                            "Failed to fetch {}: {}",
-                            url, e
-                        ))),
-                    },
-                    Err(e) => Err(AgentError::InternalError(format!(
-                        "Failed to fetch {}: {}",
-                        url, e
-                    ))),
+                    .and_then(|parsed| {
+                        samples_scraped = parsed.len();
+                        apply_metric_relabel(Option::as_ref(&metric_relabel), parsed)
+                    });
+                Target {
+                    url,
+                    address_label,
+                    target_labels,
+                    duration: start.elapsed().as_secs_f32(),
+                    samples_scraped,
+                    measures,
                 }
             });
         }
         // Join and fetch results
         let mut r = Vec::default();
+        let mut series = HashMap::with_capacity(self.series.len());
         while let Some(rs) = tasks.join_next().await {
-            if let Ok(irs) = rs {
-                match irs {
-                    Ok(mut ms) => r.append(&mut ms),
-                    Err(e) => log::error!("{}", e),
+            let target = match rs {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            let (is_up, samples_post_relabel, series_added) = match target.measures {
+                Ok(mut ms) => {
+                    let seen: HashSet<u64> = ms.iter().map(series_hash).collect();
+                    let series_added = match self.series.get(&target.url) {
+                        Some(prev) => seen.difference(prev).count(),
+                        None => seen.len(),
+                    };
+                    series.insert(target.url.clone(), seen);
+                    let n = ms.len();
+                    // Install target and virtual labels
+                    for item in ms.iter_mut() {
+                        item.labels = merge_target_labels(
+                            std::mem::take(&mut item.labels),
+                            &target.target_labels,
+                            self.honor_labels,
+                        );
+                        item.labels.push(target.address_label.to_owned());
+                    }
+                    r.append(&mut ms);
+                    (true, n, series_added)
                 }
-            }
+                Err(e) => {
+                    log::error!("Failed to scrape {}: {}", target.url, e);
+                    (false, 0, 0)
+                }
+            };
+            // Target health
+            let mut labels = target.target_labels;
+            labels.push(target.address_label);
+            r.push(up(is_up as u64, labels.clone()));
+            r.push(scrape_duration_seconds(target.duration, labels.clone()));
+            r.push(scrape_samples_scraped(
+                target.samples_scraped as u64,
+                labels.clone(),
+            ));
+            r.push(scrape_samples_post_metric_relabeling(
+                samples_post_relabel as u64,
+                labels.clone(),
+            ));
+            r.push(scrape_series_added(series_added as u64, labels));
         }
+        // Forget vanished and failed targets
+        self.series = series;
         // Push result
         Ok(r)
     }
@@ -132,6 +253,98 @@ impl Collectable for Collector {
     // }
 }
 
+// Fetch and parse the endpoint.
+// The response body is parsed as it arrives.
+async fn scrape(
+    client: &HttpClient,
+    url: &str,
+    params: &[(String, String)],
+    mut parse_cfg: ParseConfig,
+    format: Option<Format>,
+) -> AgentResult<Vec<Measure>> {
+    let mut resp = client
+        .get(url)
+        .await?
+        .header(reqwest::header::ACCEPT, ACCEPT)
+        .query(params)
+        .send()
+        .await
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| AgentError::NetworkError(e.to_string()))?;
+    parse_cfg.format = format.unwrap_or_else(|| {
+        Format::from_content_type(
+            resp.headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|x| x.to_str().ok())
+                .unwrap_or_default(),
+        )
+    });
+    let mut parser = Parser::new(&parse_cfg);
+    while let Some(chunk) = resp
+        .chunk()
+        .await
+        .map_err(|e| AgentError::NetworkError(e.to_string()))?
+    {
+        parser.feed(&chunk)?;
+    }
+    parser.finish()
+}
+
+// Apply scrape's metric relabeling rules
+fn apply_metric_relabel(
+    ruleset: Option<&RelabelRuleset>,
+    measures: Vec<Measure>,
+) -> AgentResult<Vec<Measure>> {
+    let ruleset = match ruleset {
+        Some(x) => x,
+        None => return Ok(measures),
+    };
+    let empty = Labels::default();
+    let mut r = Vec::with_capacity(measures.len());
+    for measure in measures.iter() {
+        if let Some(m) = ruleset.process(&empty, &empty, measure)? {
+            r.push(m);
+        }
+    }
+    Ok(r)
+}
+
+// Add target labels to the scraped ones.
+// On conflict, the scraped label is kept if `honor_labels` is set.
+// Otherwise, it is renamed to `exported_<name>`, as Prometheus does.
+fn merge_target_labels(scraped: Labels, target: &Labels, honor_labels: bool) -> Labels {
+    if target.is_empty() {
+        return scraped;
+    }
+    let has = |labels: &Labels, key: &str| labels.iter().any(|x| x.key == key);
+    let mut r = Vec::with_capacity(scraped.len() + target.len());
+    for label in scraped.iter() {
+        if honor_labels || !has(target, &label.key) {
+            r.push(label.to_owned());
+            continue;
+        }
+        let mut key = format!("exported_{}", label.key);
+        while has(target, &key) || has(&scraped, &key) {
+            key = format!("exported_{}", key);
+        }
+        r.push(Label::new(key, &label.value));
+    }
+    for label in target.iter() {
+        if !(honor_labels && has(&scraped, &label.key)) {
+            r.push(label.to_owned());
+        }
+    }
+    Labels::new(r)
+}
+
+// Series identity, to track the added ones
+fn series_hash(measure: &Measure) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    measure.name.hash(&mut hasher);
+    measure.labels.hash(&mut hasher);
+    hasher.finish()
+}
+
 fn default_false() -> bool {
     false
 }
@@ -143,3 +356,82 @@ fn default_concurrency() -> usize {
 fn is_default_concurrency(v: &usize) -> bool {
     *v == DEFAULT_CONCURRENCY
 }
+
+#[cfg(test)]
+mod tests {
+    use super::merge_target_labels;
+    use common::{Label, Labels};
+    use std::collections::BTreeMap;
+
+    fn labels(v: &[(&str, &str)]) -> Labels {
+        Labels::new(v.iter().map(|(k, v)| Label::new(k, v)).collect())
+    }
+
+    fn to_map(labels: &Labels) -> BTreeMap<String, String> {
+        let mut map = BTreeMap::new();
+        labels.update_map(&mut map);
+        map
+    }
+
+    fn map(v: &[(&str, &str)]) -> BTreeMap<String, String> {
+        v.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_merge_no_target() {
+        let scraped = labels(&[("job", "app")]);
+        let r = merge_target_labels(scraped, &Labels::default(), false);
+        assert_eq!(to_map(&r), map(&[("job", "app")]));
+    }
+    #[test]
+    fn test_merge_no_conflict() {
+        let scraped = labels(&[("path", "/")]);
+        let target = labels(&[("job", "node"), ("instance", "host:9100")]);
+        let r = merge_target_labels(scraped, &target, false);
+        assert_eq!(r.len(), 3);
+        assert_eq!(
+            to_map(&r),
+            map(&[("path", "/"), ("job", "node"), ("instance", "host:9100")])
+        );
+    }
+    #[test]
+    fn test_merge_exported() {
+        let scraped = labels(&[("job", "app"), ("path", "/")]);
+        let target = labels(&[("job", "node")]);
+        let r = merge_target_labels(scraped, &target, false);
+        assert_eq!(
+            to_map(&r),
+            map(&[("exported_job", "app"), ("path", "/"), ("job", "node")])
+        );
+    }
+    #[test]
+    fn test_merge_exported_chain() {
+        // exported_job is taken by the scraped labels, exported_exported_job by the target
+        let scraped = labels(&[("job", "app"), ("exported_job", "old")]);
+        let target = labels(&[("job", "node"), ("exported_exported_job", "x")]);
+        let r = merge_target_labels(scraped, &target, false);
+        assert_eq!(r.len(), 4);
+        assert_eq!(
+            to_map(&r),
+            map(&[
+                ("exported_exported_exported_job", "app"),
+                ("exported_job", "old"),
+                ("job", "node"),
+                ("exported_exported_job", "x")
+            ])
+        );
+    }
+    #[test]
+    fn test_merge_honor_labels() {
+        let scraped = labels(&[("job", "app"), ("path", "/")]);
+        let target = labels(&[("job", "node"), ("instance", "host:9100")]);
+        let r = merge_target_labels(scraped, &target, true);
+        assert_eq!(r.len(), 3);
+        assert_eq!(
+            to_map(&r),
+            map(&[("job", "app"), ("path", "/"), ("instance", "host:9100")])
+        );
+    }
+}
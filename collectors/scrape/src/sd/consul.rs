@@ -5,25 +5,59 @@
 // --------------------------------------------------------------------
 
 use super::{ServiceDiscovery, LABEL_ADDRESS};
+use crate::client::{read_secret, ClientConfig, HttpClient};
 use async_trait::async_trait;
-use common::{AgentError, AgentResult, Label};
+use common::{escape::sanitize_label_name, AgentError, AgentResult, Label};
 use relabel::ActiveLabels;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::{Notify, OnceCell};
+use tokio::task::JoinHandle;
 
+const LABEL_CONSUL_ADDRESS: &str = "__meta_consul_address";
 const LABEL_CONSUL_DC: &str = "__meta_consul_dc";
+const LABEL_CONSUL_NODE: &str = "__meta_consul_node";
+const LABEL_CONSUL_HEALTH: &str = "__meta_consul_health";
 const LABEL_CONSUL_SERVICE: &str = "__meta_consul_service";
 const LABEL_CONSUL_SERVICE_ID: &str = "__meta_consul_service_id";
 const LABEL_CONSUL_SERVICE_ADDRESS: &str = "__meta_consul_service_address";
 const LABEL_CONSUL_SERVICE_PORT: &str = "__meta_consul_service_port";
 const LABEL_CONSUL_TAGS: &str = "__meta_consul_tags";
 
+const DEFAULT_SERVER: &str = "127.0.0.1:8500";
+const DEFAULT_SCHEME: &str = "http";
 const DEFAULT_TAG_SEPARATOR: &str = ",";
+const DEFAULT_REFRESH_INTERVAL_MS: u64 = 30_000;
+const TOKEN_HEADER: &str = "X-Consul-Token";
+const INDEX_HEADER: &str = "X-Consul-Index";
+// Delay before the retry after error
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Serialize, Deserialize)]
 pub(crate) struct ConsulSdConfig {
-    #[serde(default = "default_server")]
+    #[serde(default = "default_server", skip_serializing_if = "is_default_server")]
     server: String,
+    #[serde(default = "default_scheme", skip_serializing_if = "is_default_scheme")]
+    scheme: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    datacenter: Option<String>,
+    // ACL token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_file: Option<String>,
+    // Service names, all services if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    services: Option<Vec<String>>,
+    // Services must have all the tags
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<Vec<String>>,
+    // Nodes must have all the metadata
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node_meta: Option<BTreeMap<String, String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     filter: Option<String>,
     #[serde(
@@ -31,113 +65,487 @@ pub(crate) struct ConsulSdConfig {
         skip_serializing_if = "is_default_tag_separator"
     )]
     tag_separator: String,
+    // Blocking query wait time
+    #[serde(
+        default = "default_refresh_interval_ms",
+        skip_serializing_if = "is_default_refresh_interval_ms"
+    )]
+    refresh_interval_ms: u64,
+    #[serde(flatten)]
+    client: ClientConfig,
 }
 
 pub(crate) struct ConsulSd {
-    server: String,
-    filter: Option<String>,
+    api: Arc<Api>,
+    state: Arc<State>,
+    // Catalog watcher, started on first request
+    watcher: OnceCell<Task>,
+}
+
+// Consul HTTP API client
+struct Api {
+    url: String,
+    client: HttpClient,
+    token: Token,
+    wait: Duration,
+    // Delay before the retry after error or unchanged index
+    retry_interval: Duration,
+    // Common query parameters
+    query: Vec<(String, String)>,
+    // Health query parameters
+    service_query: Vec<(String, String)>,
+    services: Option<Vec<String>>,
+    tags: Vec<String>,
     tag_separator: String,
 }
 
+enum Token {
+    None,
+    Value(String),
+    File(String),
+}
+
+// Discovered targets, shared with the background tasks
+#[derive(Default)]
+struct State {
+    inner: Mutex<StateInner>,
+    // Notified on every change
+    changed: Notify,
+}
+
+#[derive(Default)]
+struct StateInner {
+    // Catalog is fetched at least once
+    synced: bool,
+    // Catalog and all its services are fetched at least once
+    ready: bool,
+    // Service name -> watcher, targets. Targets are None until fetched
+    services: HashMap<String, (Task, Option<Vec<ActiveLabels>>)>,
+}
+
+// Background task, stopped on drop
+struct Task(JoinHandle<()>);
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
 impl TryFrom<ConsulSdConfig> for ConsulSd {
     type Error = AgentError;
 
     fn try_from(value: ConsulSdConfig) -> Result<Self, Self::Error> {
+        if value.scheme != "http" && value.scheme != "https" {
+            return Err(AgentError::ConfigurationError(format!(
+                "invalid scheme: {}",
+                value.scheme
+            )));
+        }
+        let token = match (value.token, value.token_file) {
+            (None, None) => Token::None,
+            (Some(token), None) => Token::Value(token),
+            (None, Some(path)) => Token::File(path),
+            (Some(_), Some(_)) => {
+                return Err(AgentError::ConfigurationError(
+                    "token and token_file are mutually exclusive".to_string(),
+                ))
+            }
+        };
+        let mut query = Vec::new();
+        if let Some(dc) = value.datacenter {
+            query.push(("dc".to_string(), dc));
+        }
+        if let Some(node_meta) = value.node_meta {
+            for (k, v) in node_meta.iter() {
+                query.push(("node-meta".to_string(), format!("{}:{}", k, v)));
+            }
+        }
+        let mut service_query = Vec::new();
+        for tag in value.tags.iter().flatten() {
+            service_query.push(("tag".to_string(), tag.clone()));
+        }
+        if let Some(filter) = value.filter {
+            service_query.push(("filter".to_string(), filter));
+        }
         Ok(Self {
-            server: value.server,
-            filter: value.filter,
-            tag_separator: value.tag_separator,
+            api: Arc::new(Api {
+                url: format!("{}://{}", value.scheme, value.server),
+                client: HttpClient::try_from(value.client)?,
+                token,
+                wait: Duration::from_millis(value.refresh_interval_ms),
+                retry_interval: RETRY_INTERVAL,
+                query,
+                service_query,
+                services: value.services,
+                tags: value.tags.unwrap_or_default(),
+                tag_separator: value.tag_separator,
+            }),
+            state: Arc::new(State::default()),
+            watcher: OnceCell::new(),
         })
     }
 }
 
 #[async_trait]
 impl ServiceDiscovery for ConsulSd {
+    // Catalog and services are followed in background via blocking queries.
+    // First request waits until all the services are fetched,
+    // later the services are returned as soon as fetched.
+    // Services not fetched in time are skipped until fetched.
     async fn get_services(&self) -> AgentResult<Vec<ActiveLabels>> {
-        let mut query = Vec::default();
-        if let Some(filter) = &self.filter {
-            query.push(format!("filter={}", filter))
+        self.watcher
+            .get_or_init(|| async {
+                Task(tokio::spawn(watch_catalog(
+                    self.api.clone(),
+                    self.state.clone(),
+                )))
+            })
+            .await;
+        let wait = tokio::time::sleep(self.api.client.timeout());
+        tokio::pin!(wait);
+        loop {
+            let changed = self.state.changed.notified();
+            {
+                let mut state = self.state.lock()?;
+                if !state.ready {
+                    state.ready = state.synced && state.services.values().all(|(_, x)| x.is_some());
+                }
+                if state.ready {
+                    return Ok(state.targets());
+                }
+            }
+            tokio::select! {
+                _ = changed => {}
+                _ = &mut wait => {
+                    let mut state = self.state.lock()?;
+                    if !state.synced {
+                        return Err(AgentError::NetworkError(
+                            "timed out waiting for consul catalog".to_string(),
+                        ));
+                    }
+                    log::error!("Timed out waiting for consul services, using fetched ones");
+                    state.ready = true;
+                    return Ok(state.targets());
+                }
+            }
         }
-        let q = if query.is_empty() {
-            "".into()
-        } else {
-            format!("?{}", query.join("&"))
+    }
+}
+
+impl State {
+    fn lock(&self) -> AgentResult<MutexGuard<'_, StateInner>> {
+        self.inner
+            .lock()
+            .map_err(|e| AgentError::InternalError(e.to_string()))
+    }
+}
+
+impl StateInner {
+    // Targets of the fetched services
+    fn targets(&self) -> Vec<ActiveLabels> {
+        self.services
+            .values()
+            .filter_map(|(_, x)| x.as_ref())
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+// Follow the list of services, start and stop service watchers
+async fn watch_catalog(api: Arc<Api>, state: Arc<State>) {
+    let mut index = 0;
+    loop {
+        let catalog: HashMap<String, Vec<String>> =
+            match api.query("/v1/catalog/services", &[], index).await {
+                Ok((x, new_index)) => {
+                    index = new_index;
+                    x
+                }
+                Err(e) => {
+                    log::error!("Failed to fetch consul catalog: {}", e);
+                    tokio::time::sleep(api.retry_interval).await;
+                    continue;
+                }
+            };
+        {
+            let mut inner = match state.lock() {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+            let names: Vec<&String> = catalog
+                .iter()
+                .filter(|(name, tags)| api.is_matched(name, tags))
+                .map(|(name, _)| name)
+                .collect();
+            // Stop removed
+            inner.services.retain(|name, _| names.contains(&name));
+            // Start new
+            for name in names.into_iter() {
+                if !inner.services.contains_key(name) {
+                    log::debug!("Watching consul service {}", name);
+                    let task = Task(tokio::spawn(watch_service(
+                        api.clone(),
+                        state.clone(),
+                        name.clone(),
+                    )));
+                    inner.services.insert(name.clone(), (task, None));
+                }
+            }
+            inner.synced = true;
+        }
+        state.changed.notify_waiters();
+    }
+}
+
+// Follow the service's instances
+async fn watch_service(api: Arc<Api>, state: Arc<State>, name: String) {
+    let path = format!("/v1/health/service/{}", name);
+    let mut index = 0;
+    loop {
+        let entries: Vec<ServiceEntry> = match api.query(&path, &api.service_query, index).await {
+            Ok((x, new_index)) => {
+                index = new_index;
+                x
+            }
+            Err(e) => {
+                log::error!("Failed to fetch consul service {}: {}", name, e);
+                tokio::time::sleep(api.retry_interval).await;
+                continue;
+            }
         };
-        let url = format!("http://{}/v1/agent/services{}", self.server, q);
+        let targets = entries.iter().map(|x| api.to_labels(x)).collect();
+        {
+            let mut inner = match state.lock() {
+                Ok(x) => x,
+                Err(_) => return,
+            };
+            if let Some(item) = inner.services.get_mut(&name) {
+                item.1 = Some(targets);
+            }
+        }
+        state.changed.notify_waiters();
+    }
+}
+
+impl Api {
+    // Blocking query. Returns the result and the new index.
+    // Waits for changes since `index`, unless it is zero.
+    async fn query<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        params: &[(String, String)],
+        index: u64,
+    ) -> AgentResult<(T, u64)> {
+        let mut query: Vec<(&str, String)> = self
+            .query
+            .iter()
+            .chain(params.iter())
+            .map(|(k, v)| (k.as_str(), v.clone()))
+            .collect();
+        if index > 0 {
+            query.push(("index", index.to_string()));
+            query.push(("wait", format!("{}ms", self.wait.as_millis())));
+        }
+        let mut url = reqwest::Url::parse(&format!("{}{}", self.url, path))
+            .map_err(|e| AgentError::ConfigurationError(e.to_string()))?;
+        // Avoid the empty query string
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
         log::debug!("Requesting {}", url);
-        let client = reqwest::Client::builder()
-            .gzip(true)
-            .build()
-            .map_err(|e| AgentError::InternalError(e.to_string()))?;
-        let resp = client
-            .get(&url)
+        let mut req = self
+            .client
+            .get(url.as_str())
+            .await?
+            // Consul adds up to wait/16 of jitter
+            .timeout(self.wait + self.wait / 16 + self.client.timeout());
+        req = match &self.token {
+            Token::None => req,
+            Token::Value(token) => req.header(TOKEN_HEADER, token),
+            Token::File(path) => req.header(TOKEN_HEADER, read_secret(path).await?),
+        };
+        let resp = req
             .send()
             .await
-            .map_err(|e| AgentError::InternalError(e.to_string()))?;
-        let out: HashMap<String, AgentService> = resp
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| AgentError::NetworkError(e.to_string()))?;
+        let new_index = resp
+            .headers()
+            .get(INDEX_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse::<u64>().ok())
+            .unwrap_or_default();
+        let data = resp
             .json()
             .await
             .map_err(|e| AgentError::ParseError(e.to_string()))?;
-        let mut r = Vec::with_capacity(out.len());
-        for svc in out.values() {
-            let mut items = Vec::with_capacity(7 + svc.meta.len());
-            // __address__
+        // Missing or unchanged index turns the next query
+        // into the immediate one, so rate limit the loop
+        if new_index == 0 || new_index == index {
+            log::debug!("{}: index is not advanced, delaying", path);
+            tokio::time::sleep(self.retry_interval).await;
+        }
+        // Reset the index if it goes backwards
+        Ok((data, if new_index < index { 0 } else { new_index }))
+    }
+    // Check service name and tags against the filters
+    fn is_matched(&self, name: &str, tags: &[String]) -> bool {
+        if let Some(services) = &self.services {
+            if !services.iter().any(|x| x == name) {
+                return false;
+            }
+        }
+        self.tags.iter().all(|t| tags.contains(t))
+    }
+    fn to_labels(&self, entry: &ServiceEntry) -> ActiveLabels {
+        let node = &entry.node;
+        let svc = &entry.service;
+        let mut items = Vec::with_capacity(10 + node.meta.len() + 2 * svc.meta.len());
+        // __address__
+        let address = if svc.address.is_empty() {
+            &node.address
+        } else {
+            &svc.address
+        };
+        items.push(Label::new(
+            LABEL_ADDRESS,
+            format!("{}:{}", address, svc.port),
+        ));
+        // __meta_consul_address
+        items.push(Label::new(LABEL_CONSUL_ADDRESS, &node.address));
+        // __meta_consul_dc
+        items.push(Label::new(LABEL_CONSUL_DC, &node.datacenter));
+        // __meta_consul_node
+        items.push(Label::new(LABEL_CONSUL_NODE, &node.node));
+        // __meta_consul_health
+        items.push(Label::new(LABEL_CONSUL_HEALTH, health(&entry.checks)));
+        // __meta_consul_service
+        items.push(Label::new(LABEL_CONSUL_SERVICE, &svc.service));
+        // __meta_consul_service_id
+        items.push(Label::new(LABEL_CONSUL_SERVICE_ID, &svc.id));
+        // __meta_consul_service_address
+        items.push(Label::new(LABEL_CONSUL_SERVICE_ADDRESS, &svc.address));
+        // __meta_consul_service_port
+        items.push(Label::new(LABEL_CONSUL_SERVICE_PORT, svc.port));
+        // __meta_consul_tags
+        // Wrapped with separators, so `.*,tag,.*` matches any position
+        items.push(Label::new(
+            LABEL_CONSUL_TAGS,
+            format!(
+                "{sep}{}{sep}",
+                svc.tags.join(&self.tag_separator),
+                sep = self.tag_separator
+            ),
+        ));
+        // __meta_consul_metadata_XXX
+        for (k, v) in node.meta.iter() {
             items.push(Label::new(
-                LABEL_ADDRESS,
-                format!("{}:{}", svc.address, svc.port),
+                format!("__meta_consul_metadata_{}", sanitize_label_name(k)),
+                v,
             ));
-            // __meta_consul_dc
-            items.push(Label::new(LABEL_CONSUL_DC, svc.datacenter.to_owned()));
-            // __meta_consul_service
-            items.push(Label::new(LABEL_CONSUL_SERVICE, svc.service.to_owned()));
-            // __meta_consul_service_id
-            items.push(Label::new(LABEL_CONSUL_SERVICE_ID, svc.id.to_owned()));
-            // __meta_consul_service_address
+        }
+        // __meta_consul_tagged_address_XXX
+        for (k, v) in node.tagged_addresses.iter() {
             items.push(Label::new(
-                LABEL_CONSUL_SERVICE_ADDRESS,
-                svc.address.to_owned(),
+                format!("__meta_consul_tagged_address_{}", sanitize_label_name(k)),
+                v,
             ));
-            // __meta_consul_service_port
-            items.push(Label::new(LABEL_CONSUL_SERVICE_PORT, svc.port.to_string()));
-            // __meta_consul_tags
+        }
+        // __meta_consul_meta_XXX, __meta_consul_service_metadata_XXX
+        for (k, v) in svc.meta.iter() {
+            let name = sanitize_label_name(k);
+            items.push(Label::new(format!("__meta_consul_meta_{}", name), v));
             items.push(Label::new(
-                LABEL_CONSUL_TAGS,
-                svc.tags.join(&self.tag_separator),
+                format!("__meta_consul_service_metadata_{}", name),
+                v,
             ));
-            // __meta_consul_meta_XXX
-            for (k, v) in svc.meta.iter() {
-                items.push(Label::new(
-                    format!("__meta_consul_meta_{}", k),
-                    v.to_owned(),
-                ));
-            }
-            // Push labels
-            r.push(ActiveLabels::new(items));
         }
-        Ok(r)
+        ActiveLabels::new(items)
     }
 }
 
+// Aggregated status of the node and the service checks
+fn health(checks: &[HealthCheck]) -> &'static str {
+    if checks.iter().any(|x| x.status == "critical") {
+        "critical"
+    } else if checks.iter().any(|x| x.status == "warning") {
+        "warning"
+    } else {
+        "passing"
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct ServiceEntry {
+    #[serde(rename = "Node")]
+    node: Node,
+    #[serde(rename = "Service")]
+    service: AgentService,
+    #[serde(rename = "Checks", default)]
+    checks: Vec<HealthCheck>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Node {
+    #[serde(rename = "Node")]
+    node: String,
+    #[serde(rename = "Address")]
+    address: String,
+    #[serde(rename = "Datacenter", default)]
+    datacenter: String,
+    #[serde(rename = "TaggedAddresses", default, deserialize_with = "nullable")]
+    tagged_addresses: HashMap<String, String>,
+    #[serde(rename = "Meta", default, deserialize_with = "nullable")]
+    meta: HashMap<String, String>,
+}
+
 #[derive(Deserialize, Debug)]
 struct AgentService {
     #[serde(rename = "ID")]
     id: String,
     #[serde(rename = "Service")]
     service: String,
-    #[serde(rename = "Address")]
+    #[serde(rename = "Address", default)]
     address: String,
     #[serde(rename = "Port")]
     port: u16,
-    #[serde(rename = "Datacenter")]
-    datacenter: String,
-    #[serde(rename = "Tags")]
+    #[serde(rename = "Tags", default, deserialize_with = "nullable")]
     tags: Vec<String>,
-    #[serde(rename = "Meta")]
+    #[serde(rename = "Meta", default, deserialize_with = "nullable")]
     meta: HashMap<String, String>,
 }
 
+#[derive(Deserialize, Debug)]
+struct HealthCheck {
+    #[serde(rename = "Status")]
+    status: String,
+}
+
+// Consul returns null for empty lists and maps
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
 fn default_server() -> String {
-    "127.0.0.1:8500".into()
+    DEFAULT_SERVER.into()
+}
+
+fn is_default_server(v: &String) -> bool {
+    v == DEFAULT_SERVER
+}
+
+fn default_scheme() -> String {
+    DEFAULT_SCHEME.into()
+}
+
+fn is_default_scheme(v: &String) -> bool {
+    v == DEFAULT_SCHEME
 }
 
 fn default_tag_separator() -> String {
@@ -147,3 +555,293 @@ fn default_tag_separator() -> String {
 fn is_default_tag_separator(v: &String) -> bool {
     v == DEFAULT_TAG_SEPARATOR
 }
+
+fn default_refresh_interval_ms() -> u64 {
+    DEFAULT_REFRESH_INTERVAL_MS
+}
+
+fn is_default_refresh_interval_ms(v: &u64) -> bool {
+    *v == DEFAULT_REFRESH_INTERVAL_MS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::stub::{sd_from_yaml, temp_path, Reply, Routes};
+    use super::{
+        ConsulSd, ConsulSdConfig, ServiceDiscovery, ServiceEntry, INDEX_HEADER,
+        LABEL_CONSUL_HEALTH, LABEL_CONSUL_TAGS,
+    };
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    const CATALOG: &str = "/v1/catalog/services";
+    const RETRY: Duration = Duration::from_millis(200);
+
+    type Catalog = HashMap<String, Vec<String>>;
+
+    fn sd(cfg: &str) -> ConsulSd {
+        sd_from_yaml::<ConsulSdConfig, _>(cfg)
+    }
+
+    // Discovery querying the stand-in server, retries are shortened
+    fn sd_at(routes: &Routes, extra: &str) -> ConsulSd {
+        let server = routes.url.trim_start_matches("http://");
+        let mut sd = sd(&format!("server: {}\n{}", server, extra));
+        Arc::get_mut(&mut sd.api).unwrap().retry_interval = RETRY;
+        sd
+    }
+
+    fn reply(body: &str, index: u64) -> Reply {
+        Reply::json(body).header(INDEX_HEADER, index)
+    }
+
+    fn entries(service: &str, addresses: &[&str]) -> String {
+        let items: Vec<String> = addresses
+            .iter()
+            .enumerate()
+            .map(|(i, address)| {
+                format!(
+                    r#"{{"Node": {{"Node": "node-{i}", "Address": "{address}"}}, "Service": {{"ID": "{service}-{i}", "Service": "{service}", "Port": 9100}}}}"#
+                )
+            })
+            .collect();
+        format!("[{}]", items.join(","))
+    }
+
+    fn has_header(head: &str, header: &str) -> bool {
+        head.lines().any(|x| x.eq_ignore_ascii_case(header))
+    }
+
+    async fn addresses(sd: &ConsulSd) -> Vec<String> {
+        let mut r: Vec<String> = sd
+            .get_services()
+            .await
+            .unwrap()
+            .iter()
+            .map(|x| x.get("__address__").unwrap().to_owned())
+            .collect();
+        r.sort();
+        r
+    }
+
+    // Wait until background watchers catch up with the changes
+    async fn wait_addresses(sd: &ConsulSd, expected: &[&str]) -> Vec<String> {
+        let t0 = Instant::now();
+        loop {
+            let r = addresses(sd).await;
+            if r == expected || t0.elapsed() > Duration::from_secs(5) {
+                return r;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn tags(v: &[&str]) -> Vec<String> {
+        v.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_is_matched() {
+        let filtered = sd("services: [web, db]\ntags: [prod, metrics]\n");
+        assert!(filtered
+            .api
+            .is_matched("web", &tags(&["prod", "metrics", "v1"])));
+        assert!(!filtered.api.is_matched("web", &tags(&["prod"])));
+        assert!(!filtered
+            .api
+            .is_matched("cache", &tags(&["prod", "metrics"])));
+        // No filters
+        let any = sd("{}");
+        assert!(any.api.is_matched("cache", &[]));
+    }
+    #[test]
+    fn test_to_labels() {
+        let sd = sd("{}");
+        let entry: ServiceEntry = serde_json::from_str(
+            r#"{
+                "Node": {
+                    "Node": "node-1",
+                    "Address": "10.0.0.1",
+                    "Datacenter": "dc1",
+                    "TaggedAddresses": {"lan": "10.0.0.1"},
+                    "Meta": {"rack-id": "r1"}
+                },
+                "Service": {
+                    "ID": "web-1",
+                    "Service": "web",
+                    "Address": "",
+                    "Port": 9100,
+                    "Tags": ["prod", "metrics"],
+                    "Meta": null
+                },
+                "Checks": [{"Status": "passing"}, {"Status": "warning"}]
+            }"#,
+        )
+        .unwrap();
+        let labels = sd.api.to_labels(&entry);
+        // Node address is used when the service address is empty
+        assert_eq!(labels.get("__address__").unwrap(), "10.0.0.1:9100");
+        assert_eq!(labels.get("__meta_consul_dc").unwrap(), "dc1");
+        assert_eq!(labels.get("__meta_consul_node").unwrap(), "node-1");
+        assert_eq!(labels.get("__meta_consul_service").unwrap(), "web");
+        assert_eq!(labels.get("__meta_consul_service_id").unwrap(), "web-1");
+        assert_eq!(labels.get(LABEL_CONSUL_HEALTH).unwrap(), "warning");
+        assert_eq!(labels.get(LABEL_CONSUL_TAGS).unwrap(), ",prod,metrics,");
+        assert_eq!(labels.get("__meta_consul_metadata_rack_id").unwrap(), "r1");
+        assert_eq!(
+            labels.get("__meta_consul_tagged_address_lan").unwrap(),
+            "10.0.0.1"
+        );
+    }
+    #[test]
+    fn test_to_labels_service_address() {
+        let sd = sd("tag_separator: \";\"\n");
+        let entry: ServiceEntry = serde_json::from_str(
+            r#"{
+                "Node": {"Node": "node-1", "Address": "10.0.0.1"},
+                "Service": {
+                    "ID": "db-1",
+                    "Service": "db",
+                    "Address": "10.0.1.5",
+                    "Port": 5432,
+                    "Tags": null,
+                    "Meta": {"version": "15"}
+                },
+                "Checks": [{"Status": "critical"}]
+            }"#,
+        )
+        .unwrap();
+        let labels = sd.api.to_labels(&entry);
+        assert_eq!(labels.get("__address__").unwrap(), "10.0.1.5:5432");
+        assert_eq!(labels.get(LABEL_CONSUL_HEALTH).unwrap(), "critical");
+        assert_eq!(labels.get(LABEL_CONSUL_TAGS).unwrap(), ";;");
+        assert_eq!(labels.get("__meta_consul_meta_version").unwrap(), "15");
+        assert_eq!(
+            labels
+                .get("__meta_consul_service_metadata_version")
+                .unwrap(),
+            "15"
+        );
+    }
+    #[tokio::test]
+    async fn test_query_index() {
+        let routes = Routes::serve(vec![
+            (CATALOG, reply(r#"{"web": []}"#, 10)),
+            (CATALOG, reply(r#"{"web": []}"#, 10)),
+            (CATALOG, reply(r#"{"web": []}"#, 5)),
+        ])
+        .await;
+        let sd = sd_at(&routes, "refresh_interval_ms: 1000\n");
+        // Initial query is not blocking
+        let (catalog, index) = sd.api.query::<Catalog>(CATALOG, &[], 0).await.unwrap();
+        assert!(catalog.contains_key("web"));
+        assert_eq!(index, 10);
+        // Unchanged index delays the next query
+        let t0 = Instant::now();
+        let (_, index) = sd.api.query::<Catalog>(CATALOG, &[], 10).await.unwrap();
+        assert_eq!(index, 10);
+        assert!(t0.elapsed() >= RETRY);
+        // Index going backwards is reset
+        let (_, index) = sd.api.query::<Catalog>(CATALOG, &[], 10).await.unwrap();
+        assert_eq!(index, 0);
+        let heads = routes.heads();
+        assert!(heads[0].starts_with("GET /v1/catalog/services HTTP/1.1"));
+        assert!(heads[1].starts_with("GET /v1/catalog/services?index=10&wait=1000ms HTTP/1.1"));
+        assert!(heads[2].starts_with("GET /v1/catalog/services?index=10&wait=1000ms HTTP/1.1"));
+    }
+    #[tokio::test]
+    async fn test_token() {
+        let path = temp_path("consul-token");
+        std::fs::write(&path, "file-secret\n").unwrap();
+        let routes = Routes::serve(vec![
+            (CATALOG, reply("{}", 1)),
+            (CATALOG, reply("{}", 1)),
+            (CATALOG, reply("{}", 1)),
+        ])
+        .await;
+        for extra in [
+            "token: secret\n".to_string(),
+            format!("token_file: {}\n", path.display()),
+            String::new(),
+        ] {
+            sd_at(&routes, &extra)
+                .api
+                .query::<Catalog>(CATALOG, &[], 0)
+                .await
+                .unwrap();
+        }
+        let heads = routes.heads();
+        assert!(has_header(&heads[0], "x-consul-token: secret"));
+        assert!(has_header(&heads[1], "x-consul-token: file-secret"));
+        assert!(!heads[2].to_lowercase().contains("x-consul-token"));
+        std::fs::remove_file(path).unwrap();
+    }
+    #[tokio::test]
+    async fn test_query_params() {
+        let routes = Routes::serve(vec![
+            (CATALOG, reply(r#"{"web": ["prod"], "db": ["prod"]}"#, 1)),
+            (
+                "/v1/health/service/web",
+                reply(&entries("web", &["10.0.0.1"]), 1),
+            ),
+        ])
+        .await;
+        let sd = sd_at(
+            &routes,
+            "datacenter: dc1\nservices: [web]\ntags: [prod]\nnode_meta:\n  rack: r1\n",
+        );
+        assert_eq!(addresses(&sd).await, vec!["10.0.0.1:9100"]);
+        let heads = routes.heads();
+        // Node metadata is applied to all queries, tags to the service queries only
+        assert!(
+            heads[0].starts_with("GET /v1/catalog/services?dc=dc1&node-meta=rack%3Ar1 HTTP/1.1")
+        );
+        assert!(heads.iter().any(|x| x.starts_with(
+            "GET /v1/health/service/web?dc=dc1&node-meta=rack%3Ar1&tag=prod HTTP/1.1"
+        )));
+        // Filtered out service is not watched
+        assert!(!heads.iter().any(|x| x.contains("/v1/health/service/db")));
+    }
+    #[tokio::test]
+    async fn test_watch() {
+        let routes = Routes::serve(vec![
+            (CATALOG, reply(r#"{"web": [], "db": []}"#, 1)),
+            (
+                "/v1/health/service/web",
+                reply(&entries("web", &["10.0.0.1"]), 1),
+            ),
+            (
+                "/v1/health/service/db",
+                reply(&entries("db", &["10.0.0.2"]), 1),
+            ),
+        ])
+        .await;
+        let sd = sd_at(&routes, "");
+        assert_eq!(addresses(&sd).await, vec!["10.0.0.1:9100", "10.0.0.2:9100"]);
+        // Service change is followed
+        routes.push(
+            "/v1/health/service/web",
+            reply(&entries("web", &["10.0.0.1", "10.0.0.3"]), 2),
+        );
+        assert_eq!(
+            wait_addresses(&sd, &["10.0.0.1:9100", "10.0.0.2:9100", "10.0.0.3:9100"]).await,
+            vec!["10.0.0.1:9100", "10.0.0.2:9100", "10.0.0.3:9100"]
+        );
+        // Service left the catalog, its watcher is dropped
+        routes.push(CATALOG, reply(r#"{"web": []}"#, 2));
+        assert_eq!(
+            wait_addresses(&sd, &["10.0.0.1:9100", "10.0.0.3:9100"]).await,
+            vec!["10.0.0.1:9100", "10.0.0.3:9100"]
+        );
+        assert!(!sd.state.lock().unwrap().services.contains_key("db"));
+        // Watchers wait for the changes since the last index
+        let heads = routes.heads();
+        assert!(heads
+            .iter()
+            .any(|x| x.starts_with("GET /v1/catalog/services?index=1&wait=30000ms HTTP/1.1")));
+        assert!(heads
+            .iter()
+            .any(|x| x.starts_with("GET /v1/health/service/web?index=2&wait=30000ms HTTP/1.1")));
+    }
+}
@@ -0,0 +1,372 @@
+// --------------------------------------------------------------------
+// Gufo Agent: docker service discovery
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use super::{ServiceDiscovery, LABEL_ADDRESS};
+use async_trait::async_trait;
+use common::{escape::sanitize_label_name, AgentError, AgentResult, Label};
+use hyperlocal::UnixConnector;
+use relabel::ActiveLabels;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+const LABEL_DOCKER_CONTAINER_ID: &str = "__meta_docker_container_id";
+const LABEL_DOCKER_CONTAINER_NAME: &str = "__meta_docker_container_name";
+const LABEL_DOCKER_CONTAINER_NETWORK_MODE: &str = "__meta_docker_container_network_mode";
+const LABEL_DOCKER_NETWORK_ID: &str = "__meta_docker_network_id";
+const LABEL_DOCKER_NETWORK_NAME: &str = "__meta_docker_network_name";
+const LABEL_DOCKER_NETWORK_IP: &str = "__meta_docker_network_ip";
+const LABEL_DOCKER_PORT_PRIVATE: &str = "__meta_docker_port_private";
+const LABEL_DOCKER_PORT_PUBLIC: &str = "__meta_docker_port_public";
+const LABEL_DOCKER_PORT_PUBLIC_IP: &str = "__meta_docker_port_public_ip";
+
+const UNIX_PREFIX: &str = "unix://";
+const DEFAULT_HOST: &str = "unix:///var/run/docker.sock";
+const DEFAULT_PORT: u16 = 80;
+const DEFAULT_HOST_NETWORKING_HOST: &str = "localhost";
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct DockerSdConfig {
+    #[serde(default = "default_host", skip_serializing_if = "is_default_host")]
+    host: String,
+    // Port for the containers without exposed ports
+    #[serde(default = "default_port", skip_serializing_if = "is_default_port")]
+    port: u16,
+    #[serde(
+        default = "default_host_networking_host",
+        skip_serializing_if = "is_default_host_networking_host"
+    )]
+    host_networking_host: String,
+    // Docker API filters, i.e. `label: ["prometheus.scrape=true"]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    filters: Option<BTreeMap<String, Vec<String>>>,
+    #[serde(
+        default = "default_timeout_ms",
+        skip_serializing_if = "is_default_timeout_ms"
+    )]
+    timeout_ms: u64,
+}
+
+pub(crate) struct DockerSd {
+    socket: String,
+    port: u16,
+    host_networking_host: String,
+    // Request path with query
+    path: String,
+    timeout: Duration,
+}
+
+impl TryFrom<DockerSdConfig> for DockerSd {
+    type Error = AgentError;
+
+    fn try_from(value: DockerSdConfig) -> Result<Self, Self::Error> {
+        let socket = match value.host.strip_prefix(UNIX_PREFIX) {
+            Some(x) if !x.is_empty() => x.to_string(),
+            _ => {
+                return Err(AgentError::ConfigurationError(format!(
+                    "{}: host must be unix://<path>",
+                    value.host
+                )))
+            }
+        };
+        let path = match &value.filters {
+            Some(filters) => {
+                let filters = serde_json::to_string(filters)
+                    .map_err(|e| AgentError::ConfigurationError(e.to_string()))?;
+                let mut url = reqwest::Url::parse("http://docker/containers/json")
+                    .map_err(|e| AgentError::InternalError(e.to_string()))?;
+                url.query_pairs_mut().append_pair("filters", &filters);
+                format!("{}?{}", url.path(), url.query().unwrap_or_default())
+            }
+            None => "/containers/json".to_string(),
+        };
+        Ok(Self {
+            socket,
+            port: value.port,
+            host_networking_host: value.host_networking_host,
+            path,
+            timeout: Duration::from_millis(value.timeout_ms),
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for DockerSd {
+    // One target per container, network and private tcp port.
+    // Containers without exposed ports get the configured port.
+    async fn get_services(&self) -> AgentResult<Vec<ActiveLabels>> {
+        let mut r = Vec::new();
+        for container in self.get_containers().await?.iter() {
+            let mut common = Vec::with_capacity(3 + container.labels.len());
+            // __meta_docker_container_id
+            common.push(Label::new(LABEL_DOCKER_CONTAINER_ID, &container.id));
+            // __meta_docker_container_name
+            if let Some(name) = container.names.first() {
+                common.push(Label::new(LABEL_DOCKER_CONTAINER_NAME, name));
+            }
+            // __meta_docker_container_network_mode
+            common.push(Label::new(
+                LABEL_DOCKER_CONTAINER_NETWORK_MODE,
+                &container.host_config.network_mode,
+            ));
+            // __meta_docker_container_label_XXX
+            for (k, v) in container.labels.iter() {
+                common.push(Label::new(
+                    format!("__meta_docker_container_label_{}", sanitize_label_name(k)),
+                    v,
+                ));
+            }
+            let is_host = container.host_config.network_mode == "host";
+            for (net_name, net) in container.network_settings.networks.iter() {
+                let mut items = common.clone();
+                // __meta_docker_network_id
+                items.push(Label::new(LABEL_DOCKER_NETWORK_ID, &net.network_id));
+                // __meta_docker_network_name
+                items.push(Label::new(LABEL_DOCKER_NETWORK_NAME, net_name));
+                // __meta_docker_network_ip
+                items.push(Label::new(LABEL_DOCKER_NETWORK_IP, &net.ip_address));
+                let host = if is_host {
+                    self.host_networking_host.as_str()
+                } else {
+                    net.ip_address.as_str()
+                };
+                let mut seen = HashSet::new();
+                for port in container.ports.iter() {
+                    // Ports published both on IPv4 and IPv6 are listed twice
+                    if port.r#type != "tcp" || !seen.insert(port.private_port) {
+                        continue;
+                    }
+                    let mut port_items = items.clone();
+                    // __address__
+                    port_items.push(Label::new(
+                        LABEL_ADDRESS,
+                        format!("{}:{}", host, port.private_port),
+                    ));
+                    // __meta_docker_port_private
+                    port_items.push(Label::new(LABEL_DOCKER_PORT_PRIVATE, port.private_port));
+                    if let Some(public_port) = port.public_port {
+                        // __meta_docker_port_public
+                        port_items.push(Label::new(LABEL_DOCKER_PORT_PUBLIC, public_port));
+                        // __meta_docker_port_public_ip
+                        if let Some(ip) = &port.ip {
+                            port_items.push(Label::new(LABEL_DOCKER_PORT_PUBLIC_IP, ip));
+                        }
+                    }
+                    r.push(ActiveLabels::new(port_items));
+                }
+                if seen.is_empty() {
+                    // __address__
+                    items.push(Label::new(LABEL_ADDRESS, format!("{}:{}", host, self.port)));
+                    r.push(ActiveLabels::new(items));
+                }
+            }
+        }
+        Ok(r)
+    }
+}
+
+impl DockerSd {
+    // List running containers via Docker Engine API
+    async fn get_containers(&self) -> AgentResult<Vec<Container>> {
+        log::debug!("Requesting {}{}", self.socket, self.path);
+        let client: hyper::Client<UnixConnector> = hyper::Client::builder().build(UnixConnector);
+        let (status, body) = tokio::time::timeout(self.timeout, async {
+            let resp = client
+                .get(hyperlocal::Uri::new(&self.socket, &self.path).into())
+                .await
+                .map_err(|e| AgentError::NetworkError(e.to_string()))?;
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body())
+                .await
+                .map_err(|e| AgentError::NetworkError(e.to_string()))?;
+            Ok::<_, AgentError>((status, body))
+        })
+        .await
+        .map_err(|_| AgentError::NetworkError(format!("{}: request timed out", self.socket)))??;
+        if !status.is_success() {
+            return Err(AgentError::NetworkError(format!(
+                "{}: {}",
+                status,
+                String::from_utf8_lossy(&body).trim()
+            )));
+        }
+        serde_json::from_slice(&body).map_err(|e| AgentError::ParseError(e.to_string()))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Container {
+    #[serde(rename = "Id")]
+    id: String,
+    #[serde(rename = "Names", default)]
+    names: Vec<String>,
+    #[serde(rename = "Labels", default)]
+    labels: HashMap<String, String>,
+    #[serde(rename = "Ports", default)]
+    ports: Vec<ContainerPort>,
+    #[serde(rename = "HostConfig")]
+    host_config: HostConfig,
+    #[serde(rename = "NetworkSettings")]
+    network_settings: NetworkSettings,
+}
+
+#[derive(Deserialize, Debug)]
+struct ContainerPort {
+    #[serde(rename = "IP")]
+    ip: Option<String>,
+    #[serde(rename = "PrivatePort")]
+    private_port: u16,
+    #[serde(rename = "PublicPort")]
+    public_port: Option<u16>,
+    #[serde(rename = "Type")]
+    r#type: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct HostConfig {
+    #[serde(rename = "NetworkMode", default)]
+    network_mode: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct NetworkSettings {
+    #[serde(rename = "Networks", default)]
+    networks: BTreeMap<String, Network>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Network {
+    #[serde(rename = "NetworkID", default)]
+    network_id: String,
+    #[serde(rename = "IPAddress", default)]
+    ip_address: String,
+}
+
+fn default_host() -> String {
+    DEFAULT_HOST.into()
+}
+
+fn is_default_host(v: &String) -> bool {
+    v == DEFAULT_HOST
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+fn is_default_port(v: &u16) -> bool {
+    *v == DEFAULT_PORT
+}
+
+fn default_host_networking_host() -> String {
+    DEFAULT_HOST_NETWORKING_HOST.into()
+}
+
+fn is_default_host_networking_host(v: &String) -> bool {
+    v == DEFAULT_HOST_NETWORKING_HOST
+}
+
+fn default_timeout_ms() -> u64 {
+    DEFAULT_TIMEOUT_MS
+}
+
+fn is_default_timeout_ms(v: &u64) -> bool {
+    *v == DEFAULT_TIMEOUT_MS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::stub::{sd_from_yaml, serve_unix, temp_path, Reply};
+    use super::{
+        DockerSd, DockerSdConfig, ServiceDiscovery, LABEL_DOCKER_CONTAINER_NAME,
+        LABEL_DOCKER_NETWORK_NAME, LABEL_DOCKER_PORT_PUBLIC, LABEL_DOCKER_PORT_PUBLIC_IP,
+    };
+
+    const CONTAINERS: &str = r#"[
+        {
+            "Id": "a1",
+            "Names": ["/web"],
+            "Labels": {"com.example.role": "web"},
+            "Ports": [
+                {"IP": "0.0.0.0", "PrivatePort": 9100, "PublicPort": 19100, "Type": "tcp"},
+                {"IP": "::", "PrivatePort": 9100, "PublicPort": 19100, "Type": "tcp"},
+                {"PrivatePort": 9200, "Type": "tcp"},
+                {"PrivatePort": 53, "Type": "udp"}
+            ],
+            "HostConfig": {"NetworkMode": "bridge"},
+            "NetworkSettings": {"Networks": {"bridge": {"NetworkID": "n1", "IPAddress": "172.17.0.2"}}}
+        },
+        {
+            "Id": "b2",
+            "Names": ["/node"],
+            "HostConfig": {"NetworkMode": "host"},
+            "NetworkSettings": {"Networks": {"host": {"NetworkID": "n2", "IPAddress": ""}}}
+        }
+    ]"#;
+
+    fn sd(path: &std::path::Path, extra: &str) -> DockerSd {
+        sd_from_yaml::<DockerSdConfig, _>(&format!("host: unix://{}\n{}", path.display(), extra))
+    }
+
+    #[test]
+    fn test_invalid_host() {
+        let cfg: DockerSdConfig = serde_yaml::from_str("host: tcp://127.0.0.1:2375").unwrap();
+        assert!(DockerSd::try_from(cfg).is_err());
+    }
+    #[tokio::test]
+    async fn test_get_services() {
+        let path = temp_path("docker.sock");
+        let server = serve_unix(&path, vec![Reply::json(CONTAINERS)]);
+        let sd = sd(
+            &path,
+            "port: 8080\nhost_networking_host: 10.0.0.1\nfilters:\n  label: [\"scrape=true\"]\n",
+        );
+        let services = sd.get_services().await.unwrap();
+        // Per-port targets, duplicated and udp ports are skipped
+        assert_eq!(services.len(), 3);
+        assert_eq!(services[0].get("__address__").unwrap(), "172.17.0.2:9100");
+        assert_eq!(
+            services[0].get(LABEL_DOCKER_CONTAINER_NAME).unwrap(),
+            "/web"
+        );
+        assert_eq!(
+            services[0].get(LABEL_DOCKER_NETWORK_NAME).unwrap(),
+            "bridge"
+        );
+        assert_eq!(services[0].get(LABEL_DOCKER_PORT_PUBLIC).unwrap(), "19100");
+        assert_eq!(
+            services[0].get(LABEL_DOCKER_PORT_PUBLIC_IP).unwrap(),
+            "0.0.0.0"
+        );
+        assert_eq!(
+            services[0]
+                .get("__meta_docker_container_label_com_example_role")
+                .unwrap(),
+            "web"
+        );
+        assert_eq!(services[1].get("__address__").unwrap(), "172.17.0.2:9200");
+        assert!(services[1].get(LABEL_DOCKER_PORT_PUBLIC).is_none());
+        // Host network without exposed ports
+        assert_eq!(services[2].get("__address__").unwrap(), "10.0.0.1:8080");
+        let heads = server.await.unwrap();
+        assert!(heads[0].starts_with(
+            "GET /containers/json?filters=%7B%22label%22%3A%5B%22scrape%3Dtrue%22%5D%7D HTTP/1.1"
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+    #[tokio::test]
+    async fn test_timeout() {
+        let path = temp_path("docker-timeout.sock");
+        // Accepts connections but never replies
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let sd = sd(&path, "timeout_ms: 100\n");
+        assert!(sd.get_services().await.is_err());
+        drop(listener);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
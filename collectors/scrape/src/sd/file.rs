@@ -0,0 +1,179 @@
+// --------------------------------------------------------------------
+// Gufo Agent: file service discovery
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use super::{ServiceDiscovery, TargetGroup};
+use async_trait::async_trait;
+use common::{AgentError, AgentResult, Label};
+use relabel::ActiveLabels;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{metadata, read_to_string};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+const LABEL_FILEPATH: &str = "__meta_filepath";
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct FileSdConfig {
+    // Glob patterns
+    files: Vec<String>,
+}
+
+pub(crate) struct FileSd {
+    files: Vec<String>,
+    // Last successfully parsed files
+    cache: Mutex<HashMap<PathBuf, CachedFile>>,
+}
+
+struct CachedFile {
+    modified: Option<SystemTime>,
+    len: u64,
+    services: Vec<ActiveLabels>,
+}
+
+impl TryFrom<FileSdConfig> for FileSd {
+    type Error = AgentError;
+
+    fn try_from(value: FileSdConfig) -> Result<Self, Self::Error> {
+        if value.files.is_empty() {
+            return Err(AgentError::ConfigurationError(
+                "files must not be empty".to_string(),
+            ));
+        }
+        for pattern in value.files.iter() {
+            glob::Pattern::new(pattern)
+                .map_err(|e| AgentError::ConfigurationError(format!("{}: {}", pattern, e)))?;
+        }
+        Ok(Self {
+            files: value.files,
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for FileSd {
+    // Files are polled on every call and re-read when modification time or size changes.
+    // Broken or unreadable file contributes the targets parsed before,
+    // until it is fixed or removed.
+    async fn get_services(&self) -> AgentResult<Vec<ActiveLabels>> {
+        let mut paths = Vec::new();
+        for pattern in self.files.iter() {
+            let entries =
+                glob::glob(pattern).map_err(|e| AgentError::ConfigurationError(e.to_string()))?;
+            for entry in entries {
+                match entry {
+                    Ok(path) => paths.push(path),
+                    Err(e) => log::error!("Cannot list {}: {}", pattern, e),
+                }
+            }
+        }
+        paths.sort();
+        paths.dedup();
+        let mut cache = self
+            .cache
+            .lock()
+            .map_err(|e| AgentError::InternalError(e.to_string()))?;
+        // Forget removed files
+        cache.retain(|path, _| paths.contains(path));
+        let mut r = Vec::new();
+        for path in paths.iter() {
+            let meta = match metadata(path) {
+                Ok(x) => x,
+                Err(e) => {
+                    log::error!("Cannot stat {}: {}", path.display(), e);
+                    if let Some(cached) = cache.get(path) {
+                        r.extend(cached.services.iter().cloned());
+                    }
+                    continue;
+                }
+            };
+            let modified = meta.modified().ok();
+            let len = meta.len();
+            match cache.get(path) {
+                Some(cached) if cached.modified == modified && cached.len == len => {}
+                _ => match read_file(path) {
+                    Ok(services) => {
+                        log::debug!("{}: {} targets", path.display(), services.len());
+                        cache.insert(
+                            path.clone(),
+                            CachedFile {
+                                modified,
+                                len,
+                                services,
+                            },
+                        );
+                    }
+                    Err(e) => log::error!("Failed to read {}: {}", path.display(), e),
+                },
+            }
+            if let Some(cached) = cache.get(path) {
+                r.extend(cached.services.iter().cloned());
+            }
+        }
+        Ok(r)
+    }
+}
+
+// Parse JSON or YAML file, depending on extension
+fn read_file(path: &Path) -> AgentResult<Vec<ActiveLabels>> {
+    let data = read_to_string(path)?;
+    let groups: Vec<TargetGroup> = match path.extension().and_then(|x| x.to_str()) {
+        Some("json") => serde_json::from_str(&data).map_err(|e| e.to_string()),
+        Some("yml") | Some("yaml") => serde_yaml::from_str(&data).map_err(|e| e.to_string()),
+        _ => Err("unknown file extension".to_string()),
+    }
+    .map_err(AgentError::ParseError)?;
+    Ok(TargetGroup::to_services(
+        &groups,
+        Label::new(LABEL_FILEPATH, path.to_string_lossy()),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::stub::temp_path;
+    use super::{FileSd, FileSdConfig, ServiceDiscovery, LABEL_FILEPATH};
+    use std::fs;
+
+    #[test]
+    fn test_empty_files() {
+        assert!(FileSd::try_from(FileSdConfig { files: vec![] }).is_err());
+    }
+    #[tokio::test]
+    async fn test_get_services() {
+        let dir = temp_path("file-sd");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("targets.json");
+        fs::write(
+            &path,
+            r#"[{"targets": ["10.0.0.1:9100", "10.0.0.2:9100"], "labels": {"env": "prod"}}]"#,
+        )
+        .unwrap();
+        let sd = FileSd::try_from(FileSdConfig {
+            files: vec![format!("{}/*.json", dir.display())],
+        })
+        .unwrap();
+        let services = sd.get_services().await.unwrap();
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0].get("__address__").unwrap(), "10.0.0.1:9100");
+        assert_eq!(services[1].get("env").unwrap(), "prod");
+        assert_eq!(
+            services[0].get(LABEL_FILEPATH).unwrap(),
+            &path.to_string_lossy()
+        );
+        // Broken file, last good list is kept
+        fs::write(&path, "[{\"targets\": ").unwrap();
+        let services = sd.get_services().await.unwrap();
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[1].get("__address__").unwrap(), "10.0.0.2:9100");
+        // Removed file is forgotten
+        fs::remove_file(&path).unwrap();
+        assert!(sd.get_services().await.unwrap().is_empty());
+        fs::remove_dir(&dir).unwrap();
+    }
+}
@@ -0,0 +1,190 @@
+// --------------------------------------------------------------------
+// Gufo Agent: http service discovery
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use super::{ServiceDiscovery, TargetGroup};
+use crate::client::{ClientConfig, HttpClient};
+use async_trait::async_trait;
+use common::{AgentError, AgentResult, Label};
+use relabel::ActiveLabels;
+use serde::{Deserialize, Serialize};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+const LABEL_URL: &str = "__meta_url";
+const DEFAULT_REFRESH_INTERVAL_MS: u64 = 60_000;
+const REFRESH_INTERVAL_HEADER: &str = "X-Prometheus-Refresh-Interval-Seconds";
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct HttpSdConfig {
+    url: String,
+    #[serde(
+        default = "default_refresh_interval_ms",
+        skip_serializing_if = "is_default_refresh_interval_ms"
+    )]
+    refresh_interval_ms: u64,
+    #[serde(flatten)]
+    client: ClientConfig,
+}
+
+// Last successful response and the time it was fetched
+type Cache = Option<(Instant, Vec<ActiveLabels>)>;
+
+pub(crate) struct HttpSd {
+    url: String,
+    refresh_interval: Duration,
+    client: HttpClient,
+    cache: Mutex<Cache>,
+}
+
+impl TryFrom<HttpSdConfig> for HttpSd {
+    type Error = AgentError;
+
+    fn try_from(value: HttpSdConfig) -> Result<Self, Self::Error> {
+        let url = reqwest::Url::parse(&value.url)
+            .map_err(|e| AgentError::ConfigurationError(format!("{}: {}", value.url, e)))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(AgentError::ConfigurationError(format!(
+                "{}: schema must be http or https",
+                value.url
+            )));
+        }
+        Ok(Self {
+            url: value.url,
+            refresh_interval: Duration::from_millis(value.refresh_interval_ms),
+            client: HttpClient::try_from(value.client)?,
+            cache: Mutex::new(None),
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for HttpSd {
+    // Endpoint is requested not more often than once per refresh interval.
+    // Failed request falls back to the previous response. The error is returned
+    // only until the endpoint responds successfully once.
+    async fn get_services(&self) -> AgentResult<Vec<ActiveLabels>> {
+        if let Some((fetched, services)) = &*self.lock()? {
+            if fetched.elapsed() < self.refresh_interval {
+                return Ok(services.clone());
+            }
+        }
+        match self.fetch().await {
+            Ok(services) => {
+                log::debug!("{}: {} targets", self.url, services.len());
+                *self.lock()? = Some((Instant::now(), services.clone()));
+                Ok(services)
+            }
+            Err(e) => match &*self.lock()? {
+                Some((_, services)) => {
+                    log::error!("Failed to request {}: {}", self.url, e);
+                    Ok(services.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+impl HttpSd {
+    fn lock(&self) -> AgentResult<MutexGuard<'_, Cache>> {
+        self.cache
+            .lock()
+            .map_err(|e| AgentError::InternalError(e.to_string()))
+    }
+    async fn fetch(&self) -> AgentResult<Vec<ActiveLabels>> {
+        log::debug!("Requesting {}", self.url);
+        let resp = self
+            .client
+            .get(&self.url)
+            .await?
+            .header(
+                REFRESH_INTERVAL_HEADER,
+                self.refresh_interval.as_secs().to_string(),
+            )
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| AgentError::NetworkError(e.to_string()))?;
+        match resp
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+        {
+            Some(ct) if ct.starts_with("application/json") => {}
+            ct => {
+                return Err(AgentError::ParseError(format!(
+                    "unexpected Content-Type: {}",
+                    ct.unwrap_or("none")
+                )))
+            }
+        }
+        let groups: Vec<TargetGroup> = resp
+            .json()
+            .await
+            .map_err(|e| AgentError::ParseError(e.to_string()))?;
+        Ok(TargetGroup::to_services(
+            &groups,
+            Label::new(LABEL_URL, &self.url),
+        ))
+    }
+}
+
+fn default_refresh_interval_ms() -> u64 {
+    DEFAULT_REFRESH_INTERVAL_MS
+}
+
+fn is_default_refresh_interval_ms(v: &u64) -> bool {
+    *v == DEFAULT_REFRESH_INTERVAL_MS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::stub::{sd_from_yaml, serve_tcp, Reply};
+    use super::{HttpSd, HttpSdConfig, ServiceDiscovery, LABEL_URL};
+
+    fn sd(url: &str) -> HttpSd {
+        sd_from_yaml::<HttpSdConfig, _>(&format!("url: {}\nrefresh_interval_ms: 0\n", url))
+    }
+
+    #[tokio::test]
+    async fn test_get_services() {
+        let (base, server) = serve_tcp(vec![
+            Reply::json(
+                r#"[{"targets": ["10.0.0.1:9100", "10.0.0.2:9100"], "labels": {"env": "prod"}}]"#,
+            ),
+            Reply::new("200 OK", "text/plain", "10.0.0.3:9100"),
+            Reply::new("500 Internal Server Error", "application/json", "[]"),
+        ])
+        .await;
+        let url = format!("{}/targets", base);
+        let sd = sd(&url);
+        let services = sd.get_services().await.unwrap();
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[0].get("__address__").unwrap(), "10.0.0.1:9100");
+        assert_eq!(services[1].get("env").unwrap(), "prod");
+        assert_eq!(services[1].get(LABEL_URL).unwrap(), &url);
+        // Unexpected Content-Type, last good list is kept
+        let services = sd.get_services().await.unwrap();
+        assert_eq!(services.len(), 2);
+        assert_eq!(services[1].get("__address__").unwrap(), "10.0.0.2:9100");
+        // Server error, last good list is kept
+        assert_eq!(sd.get_services().await.unwrap().len(), 2);
+        let heads = server.await.unwrap();
+        assert_eq!(heads.len(), 3);
+        assert!(heads[0].starts_with("GET /targets HTTP/1.1"));
+        assert!(heads[0]
+            .to_lowercase()
+            .contains("x-prometheus-refresh-interval-seconds: 0"));
+    }
+    #[tokio::test]
+    async fn test_content_type() {
+        let (base, server) =
+            serve_tcp(vec![Reply::new("200 OK", "text/plain", "10.0.0.1:9100")]).await;
+        // No previous list to fall back to
+        assert!(sd(&base).get_services().await.is_err());
+        server.await.unwrap();
+    }
+}
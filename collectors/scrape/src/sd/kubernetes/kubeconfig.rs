@@ -0,0 +1,198 @@
+// --------------------------------------------------------------------
+// Gufo Agent: kubernetes client credentials
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use crate::client::{Auth, HttpClient};
+use crate::tls::{client_config, Pem};
+use base64::Engine;
+use common::{AgentError, AgentResult};
+use serde::Deserialize;
+use std::path::Path;
+
+const SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+// Subset of kubeconfig file
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Kubeconfig {
+    #[serde(default)]
+    current_context: String,
+    #[serde(default)]
+    clusters: Vec<Named<Cluster>>,
+    #[serde(default)]
+    users: Vec<Named<User>>,
+    #[serde(default)]
+    contexts: Vec<Named<Context>>,
+}
+
+#[derive(Deserialize)]
+struct Named<T> {
+    name: String,
+    #[serde(alias = "cluster", alias = "user", alias = "context")]
+    item: T,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Cluster {
+    server: String,
+    certificate_authority: Option<String>,
+    certificate_authority_data: Option<String>,
+    #[serde(default)]
+    insecure_skip_tls_verify: bool,
+    tls_server_name: Option<String>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(rename_all = "kebab-case", default)]
+struct User {
+    client_certificate: Option<String>,
+    client_certificate_data: Option<String>,
+    client_key: Option<String>,
+    client_key_data: Option<String>,
+    token: Option<String>,
+    #[serde(rename = "tokenFile")]
+    token_file: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    exec: Option<serde_yaml::Value>,
+    auth_provider: Option<serde_yaml::Value>,
+}
+
+#[derive(Deserialize)]
+struct Context {
+    cluster: String,
+    #[serde(default)]
+    user: String,
+}
+
+// Build API server url and client for the current context of kubeconfig
+pub(crate) fn from_kubeconfig(path: &str) -> AgentResult<(String, HttpClient)> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| AgentError::ConfigurationError(format!("{}: {}", path, e)))?;
+    let cfg: Kubeconfig = serde_yaml::from_str(&data)
+        .map_err(|e| AgentError::ConfigurationError(format!("{}: {}", path, e)))?;
+    // Relative paths are resolved against the kubeconfig directory
+    let base = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let resolve = |p: &str| base.join(p).to_string_lossy().to_string();
+    let context = find(&cfg.contexts, &cfg.current_context, "context")?;
+    let cluster = find(&cfg.clusters, &context.cluster, "cluster")?;
+    let user = if context.user.is_empty() {
+        User::default()
+    } else {
+        find(&cfg.users, &context.user, "user")?.clone()
+    };
+    if user.exec.is_some() || user.auth_provider.is_some() {
+        return Err(AgentError::ConfigurationError(format!(
+            "{}: exec and auth-provider credentials are not supported",
+            path
+        )));
+    }
+    let ca = pem(
+        &cluster.certificate_authority,
+        &cluster.certificate_authority_data,
+        "certificate-authority-data",
+        &resolve,
+    )?;
+    let cert = pem(
+        &user.client_certificate,
+        &user.client_certificate_data,
+        "client-certificate-data",
+        &resolve,
+    )?;
+    let key = pem(
+        &user.client_key,
+        &user.client_key_data,
+        "client-key-data",
+        &resolve,
+    )?;
+    let identity = match (cert, key) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => {
+            return Err(AgentError::ConfigurationError(format!(
+                "{}: client certificate and key must be set together",
+                path
+            )))
+        }
+    };
+    let tls = client_config(
+        ca.as_ref(),
+        identity.as_ref(),
+        cluster.tls_server_name.as_deref(),
+        cluster.insecure_skip_tls_verify,
+    )?;
+    let auth = match (user.token, user.token_file, user.username) {
+        (Some(token), _, _) => Auth::Bearer(token),
+        (None, Some(token_file), _) => Auth::BearerFile(resolve(&token_file)),
+        (None, None, Some(username)) => Auth::Basic {
+            username,
+            password: user.password,
+            password_file: None,
+        },
+        (None, None, None) => Auth::None,
+    };
+    Ok((
+        cluster.server.trim_end_matches('/').to_string(),
+        HttpClient::new(Some(tls), auth)?,
+    ))
+}
+
+// Build API server url and client from the pod's service account
+pub(crate) fn in_cluster() -> AgentResult<(String, HttpClient)> {
+    let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+        AgentError::ConfigurationError(
+            "KUBERNETES_SERVICE_HOST is not set, api_server or kubeconfig_file must be configured"
+                .to_string(),
+        )
+    })?;
+    let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+    let host = if host.contains(':') {
+        // IPv6
+        format!("[{}]", host)
+    } else {
+        host
+    };
+    let ca = Pem::from_file(&format!("{}/ca.crt", SERVICE_ACCOUNT_DIR))?;
+    let tls = client_config(Some(&ca), None, None, false)?;
+    Ok((
+        format!("https://{}:{}", host, port),
+        HttpClient::new(
+            Some(tls),
+            // Token is rotated, so it is re-read on every request
+            Auth::BearerFile(format!("{}/token", SERVICE_ACCOUNT_DIR)),
+        )?,
+    ))
+}
+
+fn find<'a, T>(items: &'a [Named<T>], name: &str, what: &str) -> AgentResult<&'a T> {
+    items
+        .iter()
+        .find(|x| x.name == name)
+        .map(|x| &x.item)
+        .ok_or_else(|| AgentError::ConfigurationError(format!("{} {} is not found", what, name)))
+}
+
+// Read PEM either from file or from base64-encoded data
+fn pem<F>(
+    path: &Option<String>,
+    data: &Option<String>,
+    source: &str,
+    resolve: &F,
+) -> AgentResult<Option<Pem>>
+where
+    F: Fn(&str) -> String,
+{
+    Ok(match (path, data) {
+        (_, Some(data)) => Some(Pem::new(
+            source,
+            base64::engine::general_purpose::STANDARD
+                .decode(data.trim())
+                .map_err(|e| AgentError::ConfigurationError(format!("{}: {}", source, e)))?,
+        )),
+        (Some(path), None) => Some(Pem::from_file(&resolve(path))?),
+        (None, None) => None,
+    })
+}
@@ -0,0 +1,782 @@
+// --------------------------------------------------------------------
+// Gufo Agent: kubernetes service discovery
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+mod kubeconfig;
+mod types;
+mod watch;
+
+use super::{ServiceDiscovery, LABEL_ADDRESS};
+use crate::client::{ClientConfig, HttpClient};
+use async_trait::async_trait;
+use common::{escape::sanitize_label_name, AgentError, AgentResult, Label};
+use relabel::ActiveLabels;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+use types::{key, Endpoints, Node, ObjectMeta, Pod, Service};
+use watch::Watcher;
+
+const LABEL_NAMESPACE: &str = "__meta_kubernetes_namespace";
+const LABEL_INSTANCE: &str = "instance";
+// Node addresses, in order of preference
+const NODE_ADDRESS_TYPES: [&str; 6] = [
+    "InternalIP",
+    "InternalDNS",
+    "ExternalIP",
+    "ExternalDNS",
+    "LegacyHostIP",
+    "Hostname",
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    Pod,
+    Service,
+    Endpoints,
+    Node,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct KubernetesSdConfig {
+    role: Role,
+    // API server url. In-cluster configuration is used,
+    // unless `api_server` or `kubeconfig_file` is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_server: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kubeconfig_file: Option<String>,
+    // Namespaces to watch, all namespaces if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespaces: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    label_selector: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field_selector: Option<String>,
+    // Restrict pods to the agent's node
+    #[serde(default = "default_false", skip_serializing_if = "is_false")]
+    local_node: bool,
+    // HTTP client settings, used along with `api_server`
+    #[serde(flatten)]
+    client: ClientConfig,
+}
+
+pub(crate) struct KubernetesSd {
+    role: Role,
+    server: String,
+    client: Arc<HttpClient>,
+    // None stands for all namespaces
+    namespaces: Vec<Option<String>>,
+    label_selector: Option<String>,
+    field_selector: Option<String>,
+    // Started on first request
+    watchers: OnceCell<AgentResult<Watchers>>,
+}
+
+// Per-namespace watchers
+struct Watchers {
+    pods: Vec<Watcher<Pod>>,
+    services: Vec<Watcher<Service>>,
+    endpoints: Vec<Watcher<Endpoints>>,
+    nodes: Vec<Watcher<Node>>,
+}
+
+impl TryFrom<KubernetesSdConfig> for KubernetesSd {
+    type Error = AgentError;
+
+    fn try_from(value: KubernetesSdConfig) -> Result<Self, Self::Error> {
+        let (server, client) = match (&value.api_server, &value.kubeconfig_file) {
+            (Some(server), None) => (
+                server.trim_end_matches('/').to_string(),
+                HttpClient::try_from(value.client)?,
+            ),
+            (None, Some(path)) => kubeconfig::from_kubeconfig(path)?,
+            (None, None) => kubeconfig::in_cluster()?,
+            (Some(_), Some(_)) => {
+                return Err(AgentError::ConfigurationError(
+                    "api_server and kubeconfig_file are mutually exclusive".to_string(),
+                ))
+            }
+        };
+        let mut field_selector = value.field_selector;
+        if value.local_node {
+            let field = match value.role {
+                Role::Pod => "spec.nodeName",
+                Role::Node => "metadata.name",
+                _ => {
+                    return Err(AgentError::ConfigurationError(
+                        "local_node is supported only for pod and node roles".to_string(),
+                    ))
+                }
+            };
+            let node = local_node_name();
+            log::debug!("Restricting to node {}", node);
+            let selector = format!("{}={}", field, node);
+            field_selector = Some(match field_selector {
+                Some(x) => format!("{},{}", x, selector),
+                None => selector,
+            });
+        }
+        Ok(Self {
+            role: value.role,
+            server,
+            client: Arc::new(client),
+            namespaces: match value.namespaces {
+                Some(x) if !x.is_empty() => x.into_iter().map(Some).collect(),
+                _ => vec![None],
+            },
+            label_selector: value.label_selector,
+            field_selector,
+            watchers: OnceCell::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceDiscovery for KubernetesSd {
+    // Resources are watched in background,
+    // the targets are built from the current state.
+    async fn get_services(&self) -> AgentResult<Vec<ActiveLabels>> {
+        let watchers = match self.watchers.get_or_init(|| async { self.spawn() }).await {
+            Ok(x) => x,
+            Err(e) => return Err(AgentError::ConfigurationError(e.to_string())),
+        };
+        let timeout = self.client.timeout();
+        for w in watchers.pods.iter() {
+            w.wait_synced(timeout).await?;
+        }
+        for w in watchers.services.iter() {
+            w.wait_synced(timeout).await?;
+        }
+        for w in watchers.endpoints.iter() {
+            w.wait_synced(timeout).await?;
+        }
+        for w in watchers.nodes.iter() {
+            w.wait_synced(timeout).await?;
+        }
+        let mut r = Vec::new();
+        match self.role {
+            Role::Pod => {
+                for w in watchers.pods.iter() {
+                    for pod in w.lock()?.values() {
+                        r.extend(pod_targets(pod));
+                    }
+                }
+            }
+            Role::Service => {
+                for w in watchers.services.iter() {
+                    for svc in w.lock()?.values() {
+                        r.extend(service_targets(svc));
+                    }
+                }
+            }
+            Role::Endpoints => {
+                for ((ew, pw), sw) in watchers
+                    .endpoints
+                    .iter()
+                    .zip(watchers.pods.iter())
+                    .zip(watchers.services.iter())
+                {
+                    let pods = pw.lock()?;
+                    let services = sw.lock()?;
+                    for ep in ew.lock()?.values() {
+                        r.extend(endpoints_targets(ep, &pods, &services));
+                    }
+                }
+            }
+            Role::Node => {
+                for w in watchers.nodes.iter() {
+                    for node in w.lock()?.values() {
+                        r.extend(node_target(node));
+                    }
+                }
+            }
+        }
+        Ok(r)
+    }
+}
+
+impl KubernetesSd {
+    fn spawn(&self) -> AgentResult<Watchers> {
+        let mut watchers = Watchers {
+            pods: Vec::new(),
+            services: Vec::new(),
+            endpoints: Vec::new(),
+            nodes: Vec::new(),
+        };
+        let label_selector = self.label_selector.as_deref();
+        let field_selector = self.field_selector.as_deref();
+        for ns in self.namespaces.iter() {
+            let ns = ns.as_deref();
+            match self.role {
+                Role::Pod => watchers.pods.push(Watcher::spawn(
+                    self.client.clone(),
+                    &self.server,
+                    ns,
+                    label_selector,
+                    field_selector,
+                )?),
+                Role::Service => watchers.services.push(Watcher::spawn(
+                    self.client.clone(),
+                    &self.server,
+                    ns,
+                    label_selector,
+                    field_selector,
+                )?),
+                Role::Endpoints => {
+                    // Selectors are applied to endpoints only,
+                    // pods and services are used for metadata
+                    watchers.endpoints.push(Watcher::spawn(
+                        self.client.clone(),
+                        &self.server,
+                        ns,
+                        label_selector,
+                        field_selector,
+                    )?);
+                    watchers.pods.push(Watcher::spawn(
+                        self.client.clone(),
+                        &self.server,
+                        ns,
+                        None,
+                        None,
+                    )?);
+                    watchers.services.push(Watcher::spawn(
+                        self.client.clone(),
+                        &self.server,
+                        ns,
+                        None,
+                        None,
+                    )?);
+                }
+                Role::Node => {
+                    // Cluster-wide
+                    watchers.nodes.push(Watcher::spawn(
+                        self.client.clone(),
+                        &self.server,
+                        None,
+                        label_selector,
+                        field_selector,
+                    )?);
+                    break;
+                }
+            }
+        }
+        Ok(watchers)
+    }
+}
+
+// __meta_kubernetes_<kind>_label_<name>, __meta_kubernetes_<kind>_labelpresent_<name>,
+// __meta_kubernetes_<kind>_annotation_<name>, __meta_kubernetes_<kind>_annotationpresent_<name>
+fn push_meta(items: &mut Vec<Label>, kind: &str, meta: &ObjectMeta) {
+    for (k, v) in meta.labels.iter() {
+        let name = sanitize_label_name(k);
+        items.push(Label::new(
+            format!("__meta_kubernetes_{}_label_{}", kind, name),
+            v,
+        ));
+        items.push(Label::new(
+            format!("__meta_kubernetes_{}_labelpresent_{}", kind, name),
+            "true",
+        ));
+    }
+    for (k, v) in meta.annotations.iter() {
+        let name = sanitize_label_name(k);
+        items.push(Label::new(
+            format!("__meta_kubernetes_{}_annotation_{}", kind, name),
+            v,
+        ));
+        items.push(Label::new(
+            format!("__meta_kubernetes_{}_annotationpresent_{}", kind, name),
+            "true",
+        ));
+    }
+}
+
+// Pod-wide labels
+fn pod_labels(pod: &Pod) -> Vec<Label> {
+    let mut items = vec![
+        Label::new(LABEL_NAMESPACE, &pod.metadata.namespace),
+        Label::new("__meta_kubernetes_pod_name", &pod.metadata.name),
+        Label::new("__meta_kubernetes_pod_ip", &pod.status.pod_ip),
+        Label::new("__meta_kubernetes_pod_ready", pod_ready(pod)),
+        Label::new("__meta_kubernetes_pod_phase", &pod.status.phase),
+        Label::new("__meta_kubernetes_pod_node_name", &pod.spec.node_name),
+        Label::new("__meta_kubernetes_pod_host_ip", &pod.status.host_ip),
+        Label::new("__meta_kubernetes_pod_uid", &pod.metadata.uid),
+    ];
+    if let Some(owner) = pod.metadata.owner_references.iter().find(|x| x.controller) {
+        items.push(Label::new(
+            "__meta_kubernetes_pod_controller_kind",
+            &owner.kind,
+        ));
+        items.push(Label::new(
+            "__meta_kubernetes_pod_controller_name",
+            &owner.name,
+        ));
+    }
+    push_meta(&mut items, "pod", &pod.metadata);
+    items
+}
+
+fn pod_ready(pod: &Pod) -> String {
+    match pod
+        .status
+        .conditions
+        .iter()
+        .find(|x| x.condition_type == "Ready")
+    {
+        Some(cond) => cond.status.to_lowercase(),
+        None => "unknown".to_string(),
+    }
+}
+
+// One target per container port, or per container if no ports exposed.
+// Pods without IP are skipped.
+fn pod_targets(pod: &Pod) -> Vec<ActiveLabels> {
+    let mut r = Vec::new();
+    if pod.status.pod_ip.is_empty() {
+        return r;
+    }
+    let common = pod_labels(pod);
+    let containers = pod
+        .spec
+        .containers
+        .iter()
+        .map(|c| (c, false))
+        .chain(pod.spec.init_containers.iter().map(|c| (c, true)));
+    for (container, is_init) in containers {
+        let mut items = common.clone();
+        items.push(Label::new(
+            "__meta_kubernetes_pod_container_name",
+            &container.name,
+        ));
+        items.push(Label::new(
+            "__meta_kubernetes_pod_container_image",
+            &container.image,
+        ));
+        items.push(Label::new("__meta_kubernetes_pod_container_init", is_init));
+        if container.ports.is_empty() {
+            items.push(Label::new(LABEL_ADDRESS, &pod.status.pod_ip));
+            r.push(ActiveLabels::new(items));
+            continue;
+        }
+        for port in container.ports.iter() {
+            let mut port_items = items.clone();
+            port_items.push(Label::new(
+                LABEL_ADDRESS,
+                join_host_port(&pod.status.pod_ip, port.container_port),
+            ));
+            port_items.push(Label::new(
+                "__meta_kubernetes_pod_container_port_name",
+                &port.name,
+            ));
+            port_items.push(Label::new(
+                "__meta_kubernetes_pod_container_port_number",
+                port.container_port,
+            ));
+            port_items.push(Label::new(
+                "__meta_kubernetes_pod_container_port_protocol",
+                protocol(&port.protocol),
+            ));
+            r.push(ActiveLabels::new(port_items));
+        }
+    }
+    r
+}
+
+// Service-wide labels
+fn service_labels(svc: &Service) -> Vec<Label> {
+    let mut items = vec![
+        Label::new(LABEL_NAMESPACE, &svc.metadata.namespace),
+        Label::new("__meta_kubernetes_service_name", &svc.metadata.name),
+        Label::new("__meta_kubernetes_service_type", &svc.spec.service_type),
+    ];
+    if svc.spec.service_type == "ExternalName" {
+        items.push(Label::new(
+            "__meta_kubernetes_service_external_name",
+            &svc.spec.external_name,
+        ));
+    } else {
+        items.push(Label::new(
+            "__meta_kubernetes_service_cluster_ip",
+            &svc.spec.cluster_ip,
+        ));
+    }
+    push_meta(&mut items, "service", &svc.metadata);
+    items
+}
+
+// One target per service port, addressed by service DNS name
+fn service_targets(svc: &Service) -> Vec<ActiveLabels> {
+    let common = service_labels(svc);
+    svc.spec
+        .ports
+        .iter()
+        .map(|port| {
+            let mut items = common.clone();
+            items.push(Label::new(
+                LABEL_ADDRESS,
+                format!(
+                    "{}.{}.svc:{}",
+                    svc.metadata.name, svc.metadata.namespace, port.port
+                ),
+            ));
+            items.push(Label::new(
+                "__meta_kubernetes_service_port_name",
+                &port.name,
+            ));
+            items.push(Label::new(
+                "__meta_kubernetes_service_port_number",
+                port.port,
+            ));
+            items.push(Label::new(
+                "__meta_kubernetes_service_port_protocol",
+                protocol(&port.protocol),
+            ));
+            ActiveLabels::new(items)
+        })
+        .collect()
+}
+
+// One target per endpoint address and port.
+// Labels of the service and of the target pod are attached.
+fn endpoints_targets(
+    ep: &Endpoints,
+    pods: &HashMap<String, Pod>,
+    services: &HashMap<String, Service>,
+) -> Vec<ActiveLabels> {
+    let mut r = Vec::new();
+    let mut common = vec![
+        Label::new(LABEL_NAMESPACE, &ep.metadata.namespace),
+        Label::new("__meta_kubernetes_endpoints_name", &ep.metadata.name),
+    ];
+    push_meta(&mut common, "endpoints", &ep.metadata);
+    if let Some(svc) = services.get(&key(&ep.metadata.namespace, &ep.metadata.name)) {
+        common.extend(service_labels(svc));
+    }
+    for subset in ep.subsets.iter() {
+        let addresses = subset
+            .addresses
+            .iter()
+            .map(|x| (x, true))
+            .chain(subset.not_ready_addresses.iter().map(|x| (x, false)));
+        for (addr, ready) in addresses {
+            let mut items = common.clone();
+            items.push(Label::new("__meta_kubernetes_endpoint_ready", ready));
+            if !addr.hostname.is_empty() {
+                items.push(Label::new(
+                    "__meta_kubernetes_endpoint_hostname",
+                    &addr.hostname,
+                ));
+            }
+            if !addr.node_name.is_empty() {
+                items.push(Label::new(
+                    "__meta_kubernetes_endpoint_node_name",
+                    &addr.node_name,
+                ));
+            }
+            let mut pod = None;
+            if let Some(target) = &addr.target_ref {
+                items.push(Label::new(
+                    "__meta_kubernetes_endpoint_address_target_kind",
+                    &target.kind,
+                ));
+                items.push(Label::new(
+                    "__meta_kubernetes_endpoint_address_target_name",
+                    &target.name,
+                ));
+                if target.kind == "Pod" {
+                    let ns = if target.namespace.is_empty() {
+                        &ep.metadata.namespace
+                    } else {
+                        &target.namespace
+                    };
+                    pod = pods.get(&key(ns, &target.name));
+                }
+            }
+            if let Some(pod) = pod {
+                items.extend(pod_labels(pod));
+            }
+            for port in subset.ports.iter() {
+                let mut port_items = items.clone();
+                port_items.push(Label::new(
+                    LABEL_ADDRESS,
+                    join_host_port(&addr.ip, port.port),
+                ));
+                port_items.push(Label::new(
+                    "__meta_kubernetes_endpoint_port_name",
+                    &port.name,
+                ));
+                port_items.push(Label::new(
+                    "__meta_kubernetes_endpoint_port_protocol",
+                    protocol(&port.protocol),
+                ));
+                // Container exposing the port
+                if let Some(pod) = pod {
+                    if let Some((container, cport)) = pod.spec.containers.iter().find_map(|c| {
+                        c.ports
+                            .iter()
+                            .find(|p| p.container_port == port.port)
+                            .map(|p| (c, p))
+                    }) {
+                        port_items.push(Label::new(
+                            "__meta_kubernetes_pod_container_name",
+                            &container.name,
+                        ));
+                        port_items.push(Label::new(
+                            "__meta_kubernetes_pod_container_image",
+                            &container.image,
+                        ));
+                        port_items.push(Label::new(
+                            "__meta_kubernetes_pod_container_port_name",
+                            &cport.name,
+                        ));
+                        port_items.push(Label::new(
+                            "__meta_kubernetes_pod_container_port_number",
+                            cport.container_port,
+                        ));
+                        port_items.push(Label::new(
+                            "__meta_kubernetes_pod_container_port_protocol",
+                            protocol(&cport.protocol),
+                        ));
+                    }
+                }
+                r.push(ActiveLabels::new(port_items));
+            }
+        }
+    }
+    r
+}
+
+// Node's kubelet endpoint.
+// Nodes without addresses are skipped.
+fn node_target(node: &Node) -> Option<ActiveLabels> {
+    let address = NODE_ADDRESS_TYPES.iter().find_map(|t| {
+        node.status
+            .addresses
+            .iter()
+            .find(|x| x.address_type == *t)
+            .map(|x| &x.address)
+    })?;
+    let mut items = vec![
+        Label::new(
+            LABEL_ADDRESS,
+            join_host_port(address, node.status.daemon_endpoints.kubelet_endpoint.port),
+        ),
+        Label::new(LABEL_INSTANCE, &node.metadata.name),
+        Label::new("__meta_kubernetes_node_name", &node.metadata.name),
+        Label::new("__meta_kubernetes_node_provider_id", &node.spec.provider_id),
+    ];
+    push_meta(&mut items, "node", &node.metadata);
+    // __meta_kubernetes_node_address_<type>, first address of each type
+    let mut seen = Vec::new();
+    for addr in node.status.addresses.iter() {
+        if !seen.contains(&addr.address_type) {
+            seen.push(addr.address_type.clone());
+            items.push(Label::new(
+                format!("__meta_kubernetes_node_address_{}", addr.address_type),
+                &addr.address,
+            ));
+        }
+    }
+    Some(ActiveLabels::new(items))
+}
+
+fn join_host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        // IPv6
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+// Protocol defaults to TCP when omitted
+fn protocol(v: &str) -> &str {
+    if v.is_empty() {
+        "TCP"
+    } else {
+        v
+    }
+}
+
+// Node name from the downward API, or the host name
+fn local_node_name() -> String {
+    match std::env::var("NODE_NAME") {
+        Ok(x) if !x.is_empty() => x,
+        _ => gethostname::gethostname()
+            .into_string()
+            .unwrap_or_else(|_| "localhost".to_string()),
+    }
+}
+
+fn default_false() -> bool {
+    false
+}
+
+fn is_false(v: &bool) -> bool {
+    !*v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::types::{Endpoints, Pod, Service};
+    use super::{endpoints_targets, pod_targets};
+    use std::collections::HashMap;
+
+    fn web_pod() -> Pod {
+        serde_json::from_str(
+            r#"{
+                "metadata": {
+                    "name": "web-1",
+                    "namespace": "default",
+                    "uid": "u1",
+                    "labels": {"app.kubernetes.io/name": "web"},
+                    "ownerReferences": [{"kind": "ReplicaSet", "name": "web-5d", "controller": true}]
+                },
+                "spec": {
+                    "nodeName": "node-1",
+                    "containers": [
+                        {"name": "app", "image": "web:1", "ports": [
+                            {"name": "http", "containerPort": 8080},
+                            {"name": "metrics", "containerPort": 9100, "protocol": "TCP"}
+                        ]},
+                        {"name": "sidecar", "image": "proxy:1"}
+                    ],
+                    "initContainers": [{"name": "init", "image": "busybox"}]
+                },
+                "status": {
+                    "phase": "Running",
+                    "podIP": "10.1.0.5",
+                    "hostIP": "192.168.0.1",
+                    "conditions": [{"type": "Ready", "status": "True"}]
+                }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_pod_targets() {
+        let targets = pod_targets(&web_pod());
+        // Two ports, container without ports, init container
+        assert_eq!(targets.len(), 4);
+        let t = &targets[0];
+        assert_eq!(t.get("__address__").unwrap(), "10.1.0.5:8080");
+        assert_eq!(t.get("__meta_kubernetes_namespace").unwrap(), "default");
+        assert_eq!(t.get("__meta_kubernetes_pod_name").unwrap(), "web-1");
+        assert_eq!(t.get("__meta_kubernetes_pod_ready").unwrap(), "true");
+        assert_eq!(t.get("__meta_kubernetes_pod_node_name").unwrap(), "node-1");
+        assert_eq!(
+            t.get("__meta_kubernetes_pod_controller_kind").unwrap(),
+            "ReplicaSet"
+        );
+        assert_eq!(
+            t.get("__meta_kubernetes_pod_label_app_kubernetes_io_name")
+                .unwrap(),
+            "web"
+        );
+        assert_eq!(
+            t.get("__meta_kubernetes_pod_labelpresent_app_kubernetes_io_name")
+                .unwrap(),
+            "true"
+        );
+        assert_eq!(
+            t.get("__meta_kubernetes_pod_container_port_name").unwrap(),
+            "http"
+        );
+        assert_eq!(
+            t.get("__meta_kubernetes_pod_container_port_protocol")
+                .unwrap(),
+            "TCP"
+        );
+        assert_eq!(targets[1].get("__address__").unwrap(), "10.1.0.5:9100");
+        assert_eq!(targets[2].get("__address__").unwrap(), "10.1.0.5");
+        assert_eq!(
+            targets[2]
+                .get("__meta_kubernetes_pod_container_name")
+                .unwrap(),
+            "sidecar"
+        );
+        assert_eq!(
+            targets[3]
+                .get("__meta_kubernetes_pod_container_init")
+                .unwrap(),
+            "true"
+        );
+    }
+    #[test]
+    fn test_pod_targets_without_ip() {
+        let mut pod = web_pod();
+        pod.status.pod_ip = String::new();
+        assert!(pod_targets(&pod).is_empty());
+    }
+    #[test]
+    fn test_endpoints_targets() {
+        let ep: Endpoints = serde_json::from_str(
+            r#"{
+                "metadata": {"name": "web", "namespace": "default"},
+                "subsets": [{
+                    "addresses": [{
+                        "ip": "10.1.0.5",
+                        "nodeName": "node-1",
+                        "targetRef": {"kind": "Pod", "name": "web-1"}
+                    }],
+                    "notReadyAddresses": [{"ip": "10.1.0.6"}],
+                    "ports": [{"name": "metrics", "port": 9100}]
+                }]
+            }"#,
+        )
+        .unwrap();
+        let svc: Service = serde_json::from_str(
+            r#"{
+                "metadata": {"name": "web", "namespace": "default"},
+                "spec": {"type": "ClusterIP", "clusterIP": "10.96.0.10"}
+            }"#,
+        )
+        .unwrap();
+        let pods = HashMap::from([("default/web-1".to_string(), web_pod())]);
+        let services = HashMap::from([("default/web".to_string(), svc)]);
+        let targets = endpoints_targets(&ep, &pods, &services);
+        assert_eq!(targets.len(), 2);
+        let t = &targets[0];
+        assert_eq!(t.get("__address__").unwrap(), "10.1.0.5:9100");
+        assert_eq!(t.get("__meta_kubernetes_endpoints_name").unwrap(), "web");
+        assert_eq!(t.get("__meta_kubernetes_endpoint_ready").unwrap(), "true");
+        assert_eq!(
+            t.get("__meta_kubernetes_endpoint_node_name").unwrap(),
+            "node-1"
+        );
+        assert_eq!(
+            t.get("__meta_kubernetes_endpoint_port_protocol").unwrap(),
+            "TCP"
+        );
+        // Service labels
+        assert_eq!(
+            t.get("__meta_kubernetes_service_cluster_ip").unwrap(),
+            "10.96.0.10"
+        );
+        // Target pod and container labels
+        assert_eq!(t.get("__meta_kubernetes_pod_name").unwrap(), "web-1");
+        assert_eq!(
+            t.get("__meta_kubernetes_pod_container_name").unwrap(),
+            "app"
+        );
+        assert_eq!(
+            t.get("__meta_kubernetes_pod_container_port_name").unwrap(),
+            "metrics"
+        );
+        // Not ready address without target
+        let t = &targets[1];
+        assert_eq!(t.get("__address__").unwrap(), "10.1.0.6:9100");
+        assert_eq!(t.get("__meta_kubernetes_endpoint_ready").unwrap(), "false");
+        assert!(t.get("__meta_kubernetes_pod_name").is_none());
+        assert!(t
+            .get("__meta_kubernetes_endpoint_address_target_kind")
+            .is_none());
+    }
+}
@@ -0,0 +1,271 @@
+// --------------------------------------------------------------------
+// Gufo Agent: kubernetes API objects
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+// Only the fields used by the service discovery are defined.
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+// Watched resource
+pub(crate) trait Resource: DeserializeOwned + Send + 'static {
+    // Plural name, as used in the API path
+    const PLURAL: &'static str;
+    // Node is the only cluster-wide resource
+    const NAMESPACED: bool = true;
+    fn metadata(&self) -> &ObjectMeta;
+    // Store key
+    fn key(&self) -> String {
+        let meta = self.metadata();
+        key(&meta.namespace, &meta.name)
+    }
+}
+
+pub(crate) fn key(namespace: &str, name: &str) -> String {
+    format!("{}/{}", namespace, name)
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct ObjectMeta {
+    pub name: String,
+    pub namespace: String,
+    pub uid: String,
+    pub labels: BTreeMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+    pub owner_references: Vec<OwnerReference>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct OwnerReference {
+    pub kind: String,
+    pub name: String,
+    pub controller: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct ListMeta {
+    pub resource_version: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct List<T> {
+    #[serde(default)]
+    pub metadata: ListMeta,
+    #[serde(default = "Vec::new")]
+    pub items: Vec<T>,
+}
+
+// Watch stream item
+#[derive(Deserialize)]
+pub(crate) struct WatchEvent {
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub object: serde_json::Value,
+}
+
+// Reported by ERROR watch event
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct Status {
+    pub code: u16,
+    pub message: String,
+}
+
+// Pod
+#[derive(Deserialize)]
+pub(crate) struct Pod {
+    pub metadata: ObjectMeta,
+    #[serde(default)]
+    pub spec: PodSpec,
+    #[serde(default)]
+    pub status: PodStatus,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct PodSpec {
+    pub node_name: String,
+    pub containers: Vec<Container>,
+    pub init_containers: Vec<Container>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct Container {
+    pub name: String,
+    pub image: String,
+    pub ports: Vec<ContainerPort>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct ContainerPort {
+    pub name: String,
+    pub container_port: u16,
+    pub protocol: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct PodStatus {
+    pub phase: String,
+    #[serde(rename = "podIP")]
+    pub pod_ip: String,
+    #[serde(rename = "hostIP")]
+    pub host_ip: String,
+    pub conditions: Vec<PodCondition>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct PodCondition {
+    #[serde(rename = "type")]
+    pub condition_type: String,
+    pub status: String,
+}
+
+impl Resource for Pod {
+    const PLURAL: &'static str = "pods";
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+}
+
+// Service
+#[derive(Deserialize)]
+pub(crate) struct Service {
+    pub metadata: ObjectMeta,
+    #[serde(default)]
+    pub spec: ServiceSpec,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct ServiceSpec {
+    #[serde(rename = "type")]
+    pub service_type: String,
+    #[serde(rename = "clusterIP")]
+    pub cluster_ip: String,
+    pub external_name: String,
+    pub ports: Vec<ServicePort>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct ServicePort {
+    pub name: String,
+    pub port: u16,
+    pub protocol: String,
+}
+
+impl Resource for Service {
+    const PLURAL: &'static str = "services";
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+}
+
+// Endpoints
+#[derive(Deserialize)]
+pub(crate) struct Endpoints {
+    pub metadata: ObjectMeta,
+    #[serde(default)]
+    pub subsets: Vec<EndpointSubset>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct EndpointSubset {
+    pub addresses: Vec<EndpointAddress>,
+    pub not_ready_addresses: Vec<EndpointAddress>,
+    pub ports: Vec<EndpointPort>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct EndpointAddress {
+    pub ip: String,
+    pub hostname: String,
+    pub node_name: String,
+    pub target_ref: Option<ObjectReference>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct ObjectReference {
+    pub kind: String,
+    pub namespace: String,
+    pub name: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct EndpointPort {
+    pub name: String,
+    pub port: u16,
+    pub protocol: String,
+}
+
+impl Resource for Endpoints {
+    const PLURAL: &'static str = "endpoints";
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+}
+
+// Node
+#[derive(Deserialize)]
+pub(crate) struct Node {
+    pub metadata: ObjectMeta,
+    #[serde(default)]
+    pub spec: NodeSpec,
+    #[serde(default)]
+    pub status: NodeStatus,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct NodeSpec {
+    #[serde(rename = "providerID")]
+    pub provider_id: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct NodeStatus {
+    pub addresses: Vec<NodeAddress>,
+    pub daemon_endpoints: NodeDaemonEndpoints,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub(crate) struct NodeAddress {
+    #[serde(rename = "type")]
+    pub address_type: String,
+    pub address: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct NodeDaemonEndpoints {
+    pub kubelet_endpoint: DaemonEndpoint,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase", default)]
+pub(crate) struct DaemonEndpoint {
+    pub port: u16,
+}
+
+impl Resource for Node {
+    const PLURAL: &'static str = "nodes";
+    const NAMESPACED: bool = false;
+    fn metadata(&self) -> &ObjectMeta {
+        &self.metadata
+    }
+}
@@ -0,0 +1,335 @@
+// --------------------------------------------------------------------
+// Gufo Agent: kubernetes resource watcher
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use super::types::{List, Resource, Status, WatchEvent};
+use crate::client::HttpClient;
+use common::{AgentError, AgentResult};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+// Server closes the watch after timeout, then it is restarted
+const WATCH_TIMEOUT: u64 = 300;
+// Delay before the relisting after error
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const STATUS_GONE: u16 = 410;
+
+type Store<T> = Arc<Mutex<HashMap<String, T>>>;
+
+// Keeps the local copy of the resources, updated by the background task.
+// The task lists the resources, then follows the changes
+// via watch API, starting from the list's resource version.
+// The task is stopped when watcher is dropped.
+pub(crate) struct Watcher<T: Resource> {
+    store: Store<T>,
+    synced: watch::Receiver<bool>,
+    handle: JoinHandle<()>,
+}
+
+// Background task context
+struct Task<T: Resource> {
+    client: Arc<HttpClient>,
+    // API url without query
+    url: String,
+    // Selectors query, may be empty
+    query: String,
+    store: Store<T>,
+    synced: watch::Sender<bool>,
+}
+
+impl<T: Resource> Watcher<T> {
+    pub(crate) fn spawn(
+        client: Arc<HttpClient>,
+        server: &str,
+        namespace: Option<&str>,
+        label_selector: Option<&str>,
+        field_selector: Option<&str>,
+    ) -> AgentResult<Self> {
+        let url = match namespace {
+            Some(ns) if T::NAMESPACED => {
+                format!("{}/api/v1/namespaces/{}/{}", server, ns, T::PLURAL)
+            }
+            _ => format!("{}/api/v1/{}", server, T::PLURAL),
+        };
+        let mut query = Vec::new();
+        if let Some(selector) = label_selector {
+            query.push(("labelSelector", selector));
+        }
+        if let Some(selector) = field_selector {
+            query.push(("fieldSelector", selector));
+        }
+        let store = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, rx) = watch::channel(false);
+        let task = Task {
+            client,
+            url,
+            query: encode_query(&query)?,
+            store: store.clone(),
+            synced: tx,
+        };
+        Ok(Self {
+            store,
+            synced: rx,
+            handle: tokio::spawn(task.run()),
+        })
+    }
+    // Wait until the initial list is fetched
+    pub(crate) async fn wait_synced(&self, timeout: Duration) -> AgentResult<()> {
+        let mut synced = self.synced.clone();
+        let r = tokio::time::timeout(timeout, synced.wait_for(|x| *x))
+            .await
+            .map(|x| x.map(|_| ()));
+        match r {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(e)) => Err(AgentError::InternalError(e.to_string())),
+            Err(_) => Err(AgentError::NetworkError(format!(
+                "timed out waiting for {} list",
+                T::PLURAL
+            ))),
+        }
+    }
+    // Current state
+    pub(crate) fn lock(&self) -> AgentResult<MutexGuard<'_, HashMap<String, T>>> {
+        self.store
+            .lock()
+            .map_err(|e| AgentError::InternalError(e.to_string()))
+    }
+}
+
+impl<T: Resource> Drop for Watcher<T> {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+impl<T: Resource> Task<T> {
+    async fn run(self) {
+        loop {
+            if let Err(e) = self.list_and_watch().await {
+                log::error!("Failed to watch {}: {}", self.url, e);
+            }
+            tokio::time::sleep(RETRY_INTERVAL).await;
+        }
+    }
+    async fn list_and_watch(&self) -> AgentResult<()> {
+        let mut version = self.list().await?;
+        loop {
+            version = match self.watch(&version).await? {
+                Some(v) => v,
+                None => {
+                    log::debug!("{}: resource version expired, relisting", self.url);
+                    self.list().await?
+                }
+            };
+        }
+    }
+    // Replace the store content, return resource version
+    async fn list(&self) -> AgentResult<String> {
+        log::debug!("Listing {}", self.url);
+        let resp = self
+            .client
+            .get(&self.url_with(&[])?)
+            .await?
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| AgentError::NetworkError(e.to_string()))?;
+        let list: List<T> = resp
+            .json()
+            .await
+            .map_err(|e| AgentError::ParseError(e.to_string()))?;
+        {
+            let mut store = self.lock()?;
+            store.clear();
+            for item in list.items.into_iter() {
+                store.insert(item.key(), item);
+            }
+        }
+        self.synced.send_replace(true);
+        Ok(list.metadata.resource_version)
+    }
+    // Apply changes since `version`.
+    // Return the last seen resource version when the server closes the stream,
+    // or None when the version is expired and the relisting is required.
+    async fn watch(&self, version: &str) -> AgentResult<Option<String>> {
+        let timeout = WATCH_TIMEOUT.to_string();
+        let url = self.url_with(&[
+            ("watch", "1"),
+            ("resourceVersion", version),
+            ("allowWatchBookmarks", "true"),
+            ("timeoutSeconds", &timeout),
+        ])?;
+        let mut resp = self
+            .client
+            .get(&url)
+            .await?
+            .timeout(Duration::from_secs(WATCH_TIMEOUT) + self.client.timeout())
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| AgentError::NetworkError(e.to_string()))?;
+        let mut version = version.to_string();
+        let mut buf = Vec::new();
+        while let Some(chunk) = resp
+            .chunk()
+            .await
+            .map_err(|e| AgentError::NetworkError(e.to_string()))?
+        {
+            buf.extend_from_slice(&chunk);
+            // Events are newline-delimited JSON
+            while let Some(pos) = buf.iter().position(|&c| c == b'\n') {
+                let line: Vec<u8> = buf.drain(..=pos).collect();
+                if line.iter().all(|c| c.is_ascii_whitespace()) {
+                    continue;
+                }
+                let event: WatchEvent = serde_json::from_slice(&line)
+                    .map_err(|e| AgentError::ParseError(e.to_string()))?;
+                match self.apply(event)? {
+                    Some(v) if !v.is_empty() => version = v,
+                    Some(_) => {}
+                    None => return Ok(None),
+                }
+            }
+        }
+        Ok(Some(version))
+    }
+    // Apply watch event, return new resource version
+    // or None if the version is expired.
+    fn apply(&self, event: WatchEvent) -> AgentResult<Option<String>> {
+        if event.event_type == "ERROR" {
+            let status: Status = serde_json::from_value(event.object)
+                .map_err(|e| AgentError::ParseError(e.to_string()))?;
+            if status.code == STATUS_GONE {
+                return Ok(None);
+            }
+            return Err(AgentError::NetworkError(format!(
+                "{}: {}",
+                status.code, status.message
+            )));
+        }
+        let version = event
+            .object
+            .pointer("/metadata/resourceVersion")
+            .and_then(|x| x.as_str())
+            .unwrap_or_default()
+            .to_string();
+        match event.event_type.as_str() {
+            "ADDED" | "MODIFIED" => {
+                let item: T = serde_json::from_value(event.object)
+                    .map_err(|e| AgentError::ParseError(e.to_string()))?;
+                self.lock()?.insert(item.key(), item);
+            }
+            "DELETED" => {
+                let item: T = serde_json::from_value(event.object)
+                    .map_err(|e| AgentError::ParseError(e.to_string()))?;
+                self.lock()?.remove(&item.key());
+            }
+            // BOOKMARK only advances the version
+            _ => {}
+        }
+        Ok(Some(version))
+    }
+    fn lock(&self) -> AgentResult<MutexGuard<'_, HashMap<String, T>>> {
+        self.store
+            .lock()
+            .map_err(|e| AgentError::InternalError(e.to_string()))
+    }
+    fn url_with(&self, params: &[(&str, &str)]) -> AgentResult<String> {
+        let mut query = self.query.clone();
+        let extra = encode_query(params)?;
+        if !extra.is_empty() {
+            if !query.is_empty() {
+                query.push('&');
+            }
+            query.push_str(&extra);
+        }
+        Ok(if query.is_empty() {
+            self.url.clone()
+        } else {
+            format!("{}?{}", self.url, query)
+        })
+    }
+}
+
+fn encode_query(params: &[(&str, &str)]) -> AgentResult<String> {
+    let url = reqwest::Url::parse_with_params("http://localhost/", params)
+        .map_err(|e| AgentError::InternalError(e.to_string()))?;
+    Ok(url.query().unwrap_or_default().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::stub::{serve_tcp, Reply};
+    use super::super::types::Pod;
+    use super::Watcher;
+    use crate::client::{Auth, HttpClient};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn pod(name: &str, version: &str) -> String {
+        format!(
+            r#"{{"metadata": {{"name": "{}", "namespace": "default", "resourceVersion": "{}"}}}}"#,
+            name, version
+        )
+    }
+
+    fn event(event_type: &str, object: &str) -> String {
+        format!("{{\"type\": \"{}\", \"object\": {}}}\n", event_type, object)
+    }
+
+    #[tokio::test]
+    async fn test_relist() {
+        let (server, handle) = serve_tcp(vec![
+            // Initial list
+            Reply::json(&format!(
+                r#"{{"metadata": {{"resourceVersion": "10"}}, "items": [{}]}}"#,
+                pod("a", "9")
+            )),
+            // Changes, then the version is expired
+            Reply::json(&format!(
+                "{}{}{}",
+                event("ADDED", &pod("b", "11")),
+                event("DELETED", &pod("a", "12")),
+                event(
+                    "ERROR",
+                    r#"{"code": 410, "message": "too old resource version"}"#
+                )
+            )),
+            // Relisting
+            Reply::json(&format!(
+                r#"{{"metadata": {{"resourceVersion": "20"}}, "items": [{}]}}"#,
+                pod("c", "19")
+            )),
+            // Watching from the new version
+            Reply::json(&event(
+                "BOOKMARK",
+                r#"{"metadata": {"resourceVersion": "21"}}"#,
+            )),
+        ])
+        .await;
+        let client = Arc::new(HttpClient::new(None, Auth::None).ok().unwrap());
+        let watcher: Watcher<Pod> =
+            Watcher::spawn(client, &server, Some("default"), Some("app=web"), None)
+                .ok()
+                .unwrap();
+        watcher.wait_synced(Duration::from_secs(5)).await.unwrap();
+        let heads = handle.await.unwrap();
+        assert!(heads[0]
+            .starts_with("GET /api/v1/namespaces/default/pods?labelSelector=app%3Dweb HTTP/1.1"));
+        assert!(heads[1].starts_with(
+            "GET /api/v1/namespaces/default/pods?labelSelector=app%3Dweb&watch=1&resourceVersion=10&"
+        ));
+        assert!(heads[2]
+            .starts_with("GET /api/v1/namespaces/default/pods?labelSelector=app%3Dweb HTTP/1.1"));
+        assert!(heads[3].contains("&resourceVersion=20&"));
+        // Store is replaced by relisting
+        let store = watcher.lock().ok().unwrap();
+        assert_eq!(store.len(), 1);
+        assert!(store.contains_key("default/c"));
+    }
+}
@@ -6,15 +6,26 @@
 
 pub(crate) mod consul;
 pub(crate) mod dns;
+pub(crate) mod docker;
+pub(crate) mod file;
+pub(crate) mod http;
+pub(crate) mod kubernetes;
 pub(crate) mod r#static;
+#[cfg(test)]
+mod stub;
 
 use async_trait::async_trait;
 use common::{AgentError, AgentResult, Label};
 use consul::{ConsulSd, ConsulSdConfig};
 use dns::{DnsSd, DnsSdConfig};
+use docker::{DockerSd, DockerSdConfig};
+use file::{FileSd, FileSdConfig};
+use http::{HttpSd, HttpSdConfig};
+use kubernetes::{KubernetesSd, KubernetesSdConfig};
 use r#static::{StaticSd, StaticSdConfig};
 use relabel::{ActionResult, ActiveLabels, RelabelRuleConfig, RelabelRuleset, Relabeler};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 const DEFAULT_SCHEMA: &str = "http";
 const DEFAULT_PATH: &str = "/metrics";
@@ -39,9 +50,17 @@ pub(crate) struct SdConfig {
 #[serde(tag = "type")]
 pub(crate) enum SdMethodConfig {
     #[serde(rename = "consul")]
-    Consul(ConsulSdConfig),
+    Consul(Box<ConsulSdConfig>),
     #[serde(rename = "dns")]
     Dns(DnsSdConfig),
+    #[serde(rename = "docker")]
+    Docker(DockerSdConfig),
+    #[serde(rename = "file")]
+    File(FileSdConfig),
+    #[serde(rename = "http")]
+    Http(Box<HttpSdConfig>),
+    #[serde(rename = "kubernetes")]
+    Kubernetes(Box<KubernetesSdConfig>),
     #[serde(rename = "static")]
     Static(StaticSdConfig),
 }
@@ -56,6 +75,10 @@ pub(crate) struct Sd {
 pub(crate) enum SdMethod {
     Consul(ConsulSd),
     Dns(DnsSd),
+    Docker(DockerSd),
+    File(FileSd),
+    Http(HttpSd),
+    Kubernetes(KubernetesSd),
     Static(StaticSd),
 }
 
@@ -64,15 +87,47 @@ pub(crate) trait ServiceDiscovery {
     async fn get_services(&self) -> AgentResult<Vec<ActiveLabels>>;
 }
 
+// Prometheus' target group, used by file and http discovery
+#[derive(Deserialize)]
+pub(crate) struct TargetGroup {
+    targets: Vec<String>,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+}
+
+impl TargetGroup {
+    // Expand groups to the targets' labels.
+    // `source` label is attached to each target.
+    pub(crate) fn to_services(groups: &[TargetGroup], source: Label) -> Vec<ActiveLabels> {
+        let mut r = Vec::new();
+        for group in groups.iter() {
+            for target in group.targets.iter() {
+                let mut labels =
+                    ActiveLabels::new(group.labels.iter().map(|(k, v)| Label::new(k, v)).collect());
+                labels.insert(Label::new(LABEL_ADDRESS, target));
+                labels.insert(source.clone());
+                r.push(labels);
+            }
+        }
+        r
+    }
+}
+
 impl TryFrom<SdConfig> for Sd {
     type Error = AgentError;
 
     fn try_from(value: SdConfig) -> Result<Self, Self::Error> {
         Ok(Self {
             method: match value.method {
-                SdMethodConfig::Consul(cfg) => SdMethod::Consul(ConsulSd::try_from(cfg)?),
+                SdMethodConfig::Consul(cfg) => SdMethod::Consul(ConsulSd::try_from(*cfg)?),
                 SdMethodConfig::Static(cfg) => SdMethod::Static(StaticSd::try_from(cfg)?),
                 SdMethodConfig::Dns(cfg) => SdMethod::Dns(DnsSd::try_from(cfg)?),
+                SdMethodConfig::Docker(cfg) => SdMethod::Docker(DockerSd::try_from(cfg)?),
+                SdMethodConfig::File(cfg) => SdMethod::File(FileSd::try_from(cfg)?),
+                SdMethodConfig::Http(cfg) => SdMethod::Http(HttpSd::try_from(*cfg)?),
+                SdMethodConfig::Kubernetes(cfg) => {
+                    SdMethod::Kubernetes(KubernetesSd::try_from(*cfg)?)
+                }
             },
             relabel: match &value.relabel {
                 Some(v) => Some(RelabelRuleset::try_from(v)?),
@@ -90,28 +145,34 @@ impl ServiceDiscovery for Sd {
         let mut services = match &self.method {
             SdMethod::Consul(sd) => sd.get_services().await?,
             SdMethod::Dns(sd) => sd.get_services().await?,
+            SdMethod::Docker(sd) => sd.get_services().await?,
+            SdMethod::File(sd) => sd.get_services().await?,
+            SdMethod::Http(sd) => sd.get_services().await?,
+            SdMethod::Kubernetes(sd) => sd.get_services().await?,
             SdMethod::Static(sd) => sd.get_services().await?,
         };
+        // Set defaults, unless set by service discovery
+        for labels in services.iter_mut() {
+            if labels.get(LABEL_SCHEMA).is_none() {
+                labels.insert(Label::new(LABEL_SCHEMA, self.default_schema.to_owned()));
+            }
+            if labels.get(LABEL_PATH).is_none() {
+                labels.insert(Label::new(LABEL_PATH, self.default_path.to_owned()));
+            }
+        }
         Ok(match &self.relabel {
             Some(ruleset) => {
-                if services.is_empty() {
-                    services
-                } else {
-                    let mut r = Vec::with_capacity(services.len());
-                    // Apply relabeling
-                    for mut labels in services.drain(..) {
-                        labels.insert(Label::new(LABEL_SCHEMA, self.default_schema.to_owned()));
-                        labels.insert(Label::new(LABEL_PATH, self.default_path.to_owned()));
-                        match ruleset.apply(&mut labels)? {
-                            ActionResult::Pass => r.push(labels),
-                            ActionResult::Drop => log::debug!(
-                                "Target {} is dropped by rule",
-                                self.get_address(&labels)
-                            ),
+                let mut r = Vec::with_capacity(services.len());
+                // Apply relabeling
+                for mut labels in services.drain(..) {
+                    match ruleset.apply(&mut labels)? {
+                        ActionResult::Pass => r.push(labels),
+                        ActionResult::Drop => {
+                            log::debug!("Target {} is dropped by rule", self.get_address(&labels))
                         }
                     }
-                    r
                 }
+                r
             }
             None => services,
         })
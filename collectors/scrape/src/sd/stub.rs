@@ -0,0 +1,193 @@
+// --------------------------------------------------------------------
+// Gufo Agent: stand-in servers and helpers for service discovery tests
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+// Build service discovery from YAML config.
+// Config type must be set explicitly: `sd_from_yaml::<HttpSdConfig, _>(...)`
+pub(crate) fn sd_from_yaml<C: DeserializeOwned, S: TryFrom<C>>(yaml: &str) -> S {
+    let cfg: C = serde_yaml::from_str(yaml).unwrap();
+    S::try_from(cfg).ok().unwrap()
+}
+
+// Path in temporary directory, unique for test process
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("scrape-{}-{}", std::process::id(), name))
+}
+
+// Canned response
+pub(crate) struct Reply {
+    status: &'static str,
+    content_type: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Reply {
+    pub(crate) fn new(status: &'static str, content_type: &'static str, body: &str) -> Self {
+        Self {
+            status,
+            content_type,
+            headers: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+    pub(crate) fn json(body: &str) -> Self {
+        Self::new("200 OK", "application/json", body)
+    }
+    pub(crate) fn header<T: ToString>(mut self, name: &'static str, value: T) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+// Stand-in for the long polling API.
+// Replies are matched by the request path prefix and each is sent once.
+// Requests without the matching reply are held until the one is pushed.
+pub(crate) struct Routes {
+    // Base url
+    pub url: String,
+    state: Arc<Mutex<RoutesState>>,
+    changed: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct RoutesState {
+    replies: Vec<(&'static str, Reply)>,
+    // Request heads, in order of arrival
+    heads: Vec<String>,
+}
+
+impl Routes {
+    pub(crate) async fn serve(replies: Vec<(&'static str, Reply)>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let routes = Self {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            state: Arc::new(Mutex::new(RoutesState {
+                replies,
+                heads: Vec::new(),
+            })),
+            changed: Arc::new(Notify::new()),
+        };
+        let state = routes.state.clone();
+        let changed = routes.changed.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let state = state.clone();
+                let changed = changed.clone();
+                tokio::spawn(async move {
+                    let head = read_head(&mut sock).await;
+                    let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+                    state.lock().unwrap().heads.push(head);
+                    loop {
+                        let notified = changed.notified();
+                        let reply = {
+                            let mut state = state.lock().unwrap();
+                            state
+                                .replies
+                                .iter()
+                                .position(|(prefix, _)| path.starts_with(prefix))
+                                .map(|i| state.replies.remove(i).1)
+                        };
+                        if let Some(r) = reply {
+                            write_reply(&mut sock, &r).await;
+                            return;
+                        }
+                        notified.await;
+                    }
+                });
+            }
+        });
+        routes
+    }
+    // Add reply, releasing the held request
+    pub(crate) fn push(&self, prefix: &'static str, reply: Reply) {
+        self.state.lock().unwrap().replies.push((prefix, reply));
+        self.changed.notify_waiters();
+    }
+    // Request heads received so far
+    pub(crate) fn heads(&self) -> Vec<String> {
+        self.state.lock().unwrap().heads.clone()
+    }
+}
+
+// Serve replies on the local tcp port, one per connection.
+// Returns base url and the handle resolving to request heads.
+pub(crate) async fn serve_tcp(replies: Vec<Reply>) -> (String, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let handle = tokio::spawn(async move {
+        let mut heads = Vec::with_capacity(replies.len());
+        for r in replies.iter() {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            heads.push(reply(&mut sock, r).await);
+        }
+        heads
+    });
+    (url, handle)
+}
+
+// Serve replies on the unix socket, one per connection.
+pub(crate) fn serve_unix(path: &Path, replies: Vec<Reply>) -> JoinHandle<Vec<String>> {
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path).unwrap();
+    tokio::spawn(async move {
+        let mut heads = Vec::with_capacity(replies.len());
+        for r in replies.iter() {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            heads.push(reply(&mut sock, r).await);
+        }
+        heads
+    })
+}
+
+// Read request head and write the reply, closing the connection
+async fn reply<S: AsyncRead + AsyncWrite + Unpin>(sock: &mut S, r: &Reply) -> String {
+    let head = read_head(sock).await;
+    write_reply(sock, r).await;
+    head
+}
+
+async fn read_head<S: AsyncRead + Unpin>(sock: &mut S) -> String {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = sock.read(&mut chunk).await.unwrap();
+        buf.extend_from_slice(&chunk[..n]);
+        let req = String::from_utf8_lossy(&buf).to_string();
+        if let Some((head, _)) = req.split_once("\r\n\r\n") {
+            break head.to_string();
+        }
+        if n == 0 {
+            break req;
+        }
+    }
+}
+
+async fn write_reply<S: AsyncWrite + Unpin>(sock: &mut S, r: &Reply) {
+    let headers: String = r
+        .headers
+        .iter()
+        .map(|(k, v)| format!("{}: {}\r\n", k, v))
+        .collect();
+    let resp = format!(
+        "HTTP/1.1 {}\r\ncontent-type: {}\r\ncontent-length: {}\r\n{}connection: close\r\n\r\n{}",
+        r.status,
+        r.content_type,
+        r.body.len(),
+        headers,
+        r.body
+    );
+    sock.write_all(resp.as_bytes()).await.unwrap();
+    sock.shutdown().await.unwrap();
+}
@@ -0,0 +1,202 @@
+// --------------------------------------------------------------------
+// Gufo Agent: scrape TLS configuration
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use common::{AgentError, AgentResult};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use serde::{Deserialize, Serialize};
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[derive(Deserialize, Serialize)]
+pub(crate) struct TlsConfig {
+    // CA certificates to verify the server, PEM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ca_file: Option<String>,
+    // Client certificate and key for mTLS, PEM
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cert_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_file: Option<String>,
+    // Name to verify the server certificate against
+    #[serde(skip_serializing_if = "Option::is_none")]
+    server_name: Option<String>,
+    #[serde(default = "default_false", skip_serializing_if = "is_false")]
+    insecure_skip_verify: bool,
+}
+
+// Verifies the server certificate against the configured name,
+// or skips the verification at all.
+struct TlsVerifier {
+    inner: WebPkiVerifier,
+    server_name: Option<ServerName>,
+    insecure_skip_verify: bool,
+}
+
+impl ServerCertVerifier for TlsVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self.insecure_skip_verify {
+            return Ok(ServerCertVerified::assertion());
+        }
+        self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            self.server_name.as_ref().unwrap_or(server_name),
+            scts,
+            ocsp_response,
+            now,
+        )
+    }
+}
+
+// PEM-encoded certificates or key, with the origin for the error messages
+pub(crate) struct Pem {
+    source: String,
+    data: Vec<u8>,
+}
+
+impl Pem {
+    pub(crate) fn new(source: &str, data: Vec<u8>) -> Self {
+        Self {
+            source: source.to_string(),
+            data,
+        }
+    }
+    pub(crate) fn from_file(path: &str) -> AgentResult<Self> {
+        Ok(Self::new(
+            path,
+            std::fs::read(path).map_err(|e| config_error(path, e.to_string()))?,
+        ))
+    }
+    fn certs(&self) -> AgentResult<Vec<Certificate>> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(self.data.as_slice()))
+            .map_err(|e| config_error(&self.source, e.to_string()))?;
+        if certs.is_empty() {
+            return Err(config_error(
+                &self.source,
+                "no certificates found".to_string(),
+            ));
+        }
+        Ok(certs.into_iter().map(Certificate).collect())
+    }
+    fn key(&self) -> AgentResult<PrivateKey> {
+        let mut reader = BufReader::new(self.data.as_slice());
+        loop {
+            match rustls_pemfile::read_one(&mut reader)
+                .map_err(|e| config_error(&self.source, e.to_string()))?
+            {
+                Some(rustls_pemfile::Item::RSAKey(key))
+                | Some(rustls_pemfile::Item::PKCS8Key(key))
+                | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+                Some(_) => continue,
+                None => {
+                    return Err(config_error(
+                        &self.source,
+                        "no private key found".to_string(),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+impl TlsConfig {
+    // Build rustls configuration
+    pub(crate) fn client_config(&self) -> AgentResult<ClientConfig> {
+        let ca = match &self.ca_file {
+            Some(path) => Some(Pem::from_file(path)?),
+            None => None,
+        };
+        let identity = match (&self.cert_file, &self.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                Some((Pem::from_file(cert_file)?, Pem::from_file(key_file)?))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(AgentError::ConfigurationError(
+                    "cert_file and key_file must be set together".to_string(),
+                ))
+            }
+        };
+        client_config(
+            ca.as_ref(),
+            identity.as_ref(),
+            self.server_name.as_deref(),
+            self.insecure_skip_verify,
+        )
+    }
+}
+
+// Build rustls configuration from PEM data.
+// System roots are used when `ca` is not set.
+// `identity` is the client certificate and the key.
+pub(crate) fn client_config(
+    ca: Option<&Pem>,
+    identity: Option<&(Pem, Pem)>,
+    server_name: Option<&str>,
+    insecure_skip_verify: bool,
+) -> AgentResult<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(pem) => {
+            for cert in pem.certs()?.iter() {
+                roots
+                    .add(cert)
+                    .map_err(|e| config_error(&pem.source, e.to_string()))?;
+            }
+        }
+        None => roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(
+                ta.subject,
+                ta.spki,
+                ta.name_constraints,
+            )
+        })),
+    }
+    let server_name = match server_name {
+        Some(name) => Some(
+            ServerName::try_from(name)
+                .map_err(|e| AgentError::ConfigurationError(e.to_string()))?,
+        ),
+        None => None,
+    };
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(TlsVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            server_name,
+            insecure_skip_verify,
+        }));
+    let mut cfg = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(cert.certs()?, key.key()?)
+            .map_err(|e| config_error(&cert.source, e.to_string()))?,
+        None => builder.with_no_client_auth(),
+    };
+    cfg.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(cfg)
+}
+
+fn config_error(source: &str, msg: String) -> AgentError {
+    AgentError::ConfigurationError(format!("{}: {}", source, msg))
+}
+
+fn default_false() -> bool {
+    false
+}
+
+fn is_false(v: &bool) -> bool {
+    !*v
+}
@@ -6,10 +6,15 @@
 
 use async_trait::async_trait;
 use common::{counter, AgentError, Collectable, Measure};
-use openmetrics::{parse, ParseConfig};
+use openmetrics::{Format, ParseConfig, Parser};
 use serde::{Deserialize, Serialize};
-use std::fs::{metadata, read_dir, read_to_string, remove_file};
-use std::path::Path;
+use std::fs::{metadata, read_dir, remove_file, rename, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+const BUFFER_SIZE: usize = 65536;
+// Extension appended to the files failed to parse
+const BAD_EXTENSION: &str = "bad";
 
 // Collector config
 #[derive(Deserialize, Serialize)]
@@ -19,6 +24,12 @@ pub struct Config {
     dry_run: bool,
     #[serde(default = "default_false")]
     trust_timestamps: bool,
+    #[serde(default = "default_false")]
+    strict: bool,
+    #[serde(default)]
+    format: Format,
+    body_size_limit: Option<usize>,
+    sample_limit: Option<usize>,
 }
 
 // Collector structure
@@ -53,6 +64,10 @@ impl TryFrom<Config> for Collector {
             dry_run: value.dry_run,
             parse_cfg: ParseConfig {
                 trust_timestamps: value.trust_timestamps,
+                strict: value.strict,
+                format: value.format,
+                body_size_limit: value.body_size_limit,
+                sample_limit: value.sample_limit,
             },
             spool_jobs: 0,
             spool_jobs_success: 0,
@@ -66,6 +81,7 @@ impl TryFrom<Config> for Collector {
 #[async_trait]
 impl Collectable for Collector {
     const NAME: &'static str = "spool";
+    const EXPIRE_SERIES: bool = false; // Files are processed only once
     type Config = Config;
 
     async fn collect(&mut self) -> Result<Vec<Measure>, AgentError> {
@@ -87,6 +103,10 @@ impl Collectable for Collector {
         //
         let mut r = vec![];
         for entry in paths {
+            // Skip files rejected before
+            if is_bad(entry.path().as_path()) {
+                continue;
+            }
             self.spool_jobs += 1;
             match self.process_file(entry.path().as_path()) {
                 Ok(mut sr) => {
@@ -122,8 +142,19 @@ impl Collector {
             return Err(AgentError::InternalError("not a regular file".to_string()));
         }
         //
-        let data = read_to_string(path)?;
-        let parsed = parse(data.as_str(), &self.parse_cfg)?;
+        let mut file = File::open(path)?;
+        let mut parser = Parser::new(&self.parse_cfg);
+        let mut buf = vec![0u8; BUFFER_SIZE];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            if let Err(e) = parser.feed(&buf[..n]) {
+                return Err(self.reject(path, e));
+            }
+        }
+        let parsed = parser.finish().map_err(|e| self.reject(path, e))?;
         if !self.dry_run {
             log::debug!("Removing {:?}", path);
             if let Err(e) = remove_file(path) {
@@ -132,8 +163,99 @@ impl Collector {
         }
         Ok(parsed)
     }
+    // Move aside the file which cannot be parsed or exceeds the limits,
+    // so it is not processed again on the next run.
+    fn reject(&self, path: &Path, e: AgentError) -> AgentError {
+        if !self.dry_run {
+            let bad = bad_path(path);
+            log::debug!("Moving {:?} to {:?}", path, bad);
+            if let Err(e) = rename(path, &bad) {
+                log::error!("Cannot move file {:?} to {:?}: {}", path, bad, e);
+            }
+        }
+        e
+    }
+}
+
+fn bad_path(path: &Path) -> PathBuf {
+    let mut r = path.as_os_str().to_owned();
+    r.push(".");
+    r.push(BAD_EXTENSION);
+    PathBuf::from(r)
+}
+
+fn is_bad(path: &Path) -> bool {
+    path.extension()
+        .map(|x| x == BAD_EXTENSION)
+        .unwrap_or(false)
 }
 
 fn default_false() -> bool {
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{bad_path, is_bad, Collector, Config};
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("spool-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn get_collector(path: &Path, dry_run: bool) -> Collector {
+        Collector::try_from(Config {
+            path: path.display().to_string(),
+            dry_run,
+            trust_timestamps: false,
+            strict: true,
+            format: Default::default(),
+            body_size_limit: None,
+            sample_limit: Some(1),
+        })
+        .ok()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_processed() {
+        let dir = spool_dir("processed");
+        let path = dir.join("job1");
+        fs::write(&path, "# TYPE job1 gauge\njob1 15\n# EOF\n").unwrap();
+        let parsed = get_collector(&dir, false).process_file(&path).unwrap();
+        assert_eq!(parsed.len(), 1);
+        assert!(!path.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_rejected() {
+        let dir = spool_dir("rejected");
+        let collector = get_collector(&dir, false);
+        for (name, data) in [
+            ("broken", "# TYPE job1 gauge\njob1 x\n# EOF\n"),
+            ("limited", "# TYPE job1 gauge\njob1 1\njob2 2\n# EOF\n"),
+        ] {
+            let path = dir.join(name);
+            fs::write(&path, data).unwrap();
+            assert!(collector.process_file(&path).is_err());
+            assert!(!path.exists());
+            let bad = bad_path(&path);
+            assert!(is_bad(&bad));
+            assert_eq!(fs::read_to_string(&bad).unwrap(), data);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn test_rejected_dry_run() {
+        let dir = spool_dir("rejected-dry-run");
+        let path = dir.join("broken");
+        fs::write(&path, "job1 x\n").unwrap();
+        assert!(get_collector(&dir, true).process_file(&path).is_err());
+        assert!(path.exists());
+        assert!(!bad_path(&path).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
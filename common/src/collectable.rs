@@ -15,14 +15,82 @@ pub enum Value {
     GaugeF(f32),
 }
 
+impl Value {
+    // Get numeric value as float
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Value::Counter(x) => *x as f64,
+            Value::CounterF(x) => *x as f64,
+            Value::Gauge(x) => *x as f64,
+            Value::GaugeI(x) => *x as f64,
+            Value::GaugeF(x) => *x as f64,
+        }
+    }
+}
+
 impl ToString for Value {
     fn to_string(&self) -> String {
         match self {
             Value::Counter(x) => x.to_string(),
-            Value::CounterF(x) => x.to_string(),
+            Value::CounterF(x) => float_to_string(*x),
             Value::Gauge(x) => x.to_string(),
             Value::GaugeI(x) => x.to_string(),
-            Value::GaugeF(x) => x.to_string(),
+            Value::GaugeF(x) => float_to_string(*x),
+        }
+    }
+}
+
+// OpenMetrics-compatible float formatting:
+// number =/ [SIGN] ("inf" / "infinity")
+// number =/ "nan"
+fn float_to_string(x: f32) -> String {
+    if x.is_nan() {
+        "NaN".into()
+    } else if x.is_infinite() {
+        if x.is_sign_positive() {
+            "+Inf".into()
+        } else {
+            "-Inf".into()
+        }
+    } else {
+        x.to_string()
+    }
+}
+
+fn f64_to_string(x: f64) -> String {
+    if x.is_finite() {
+        x.to_string()
+    } else {
+        // NaN and Inf are kept on conversion
+        float_to_string(x as f32)
+    }
+}
+
+// Reference to the example data point, i.e. trace,
+// which is attached to the sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exemplar {
+    pub labels: Labels,
+    pub value: f64,
+    pub timestamp: Option<f64>,
+}
+
+impl Exemplar {
+    // Format as OpenMetrics exemplar:
+    // exemplar = SP HASH SP labels SP number [SP timestamp]
+    pub fn to_openmetrics(&self) -> String {
+        match self.timestamp {
+            Some(ts) => format!(
+                " # {{{}}} {} {}",
+                self.labels.to_openmetrics(),
+                f64_to_string(self.value),
+                f64_to_string(ts)
+            ),
+            None => format!(
+                " # {{{}}} {}",
+                self.labels.to_openmetrics(),
+                f64_to_string(self.value)
+            ),
         }
     }
 }
@@ -34,6 +102,7 @@ pub struct Measure {
     pub value: Value,
     pub labels: Labels,
     pub timestamp: Option<u64>,
+    pub exemplar: Option<Exemplar>,
 }
 
 #[async_trait]
@@ -43,6 +112,7 @@ where
 {
     const NAME: &'static str;
     const RANDOM_OFFSET: bool = true;
+    const EXPIRE_SERIES: bool = true;
     type Config;
 
     fn get_name() -> &'static str {
@@ -51,6 +121,9 @@ where
     fn is_random_offset() -> bool {
         Self::RANDOM_OFFSET
     }
+    fn is_expire_series() -> bool {
+        Self::EXPIRE_SERIES
+    }
     async fn collect(&mut self) -> Result<Vec<Measure>, AgentError>;
     #[allow(unused_variables)]
     fn discover_config(opts: &ConfigDiscoveryOpts) -> Result<Vec<ConfigItem>, AgentError> {
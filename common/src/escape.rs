@@ -0,0 +1,169 @@
+// --------------------------------------------------------------------
+// Gufo Agent: OpenMetrics escaping and name validation
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+// Escape label value:
+// escaped-char =/ BS ("n" / DQUOTE / BS)
+pub fn escape_label_value(value: &str) -> String {
+    let mut r = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => r.push_str("\\\\"),
+            '"' => r.push_str("\\\""),
+            '\n' => r.push_str("\\n"),
+            _ => r.push(c),
+        }
+    }
+    r
+}
+
+// Escape HELP text. Double quotes are left as is.
+pub fn escape_help(value: &str) -> String {
+    let mut r = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => r.push_str("\\\\"),
+            '\n' => r.push_str("\\n"),
+            _ => r.push(c),
+        }
+    }
+    r
+}
+
+// Reverse `escape_label_value` and `escape_help`.
+// Unknown escape sequences are kept verbatim.
+pub fn unescape(value: &str) -> String {
+    let mut r = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            r.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => r.push('\n'),
+            Some('"') => r.push('"'),
+            Some('\\') => r.push('\\'),
+            Some(x) => {
+                r.push('\\');
+                r.push(x);
+            }
+            None => r.push('\\'),
+        }
+    }
+    r
+}
+
+// label-name = label-name-initial-char *label-name-char
+// label-name-char = label-name-initial-char / DIGIT
+// label-name-initial-char = ALPHA / "_"
+pub fn is_valid_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// metricname = metricname-initial-char 0*metricname-char
+// metricname-char = metricname-initial-char / DIGIT
+// metricname-initial-char = ALPHA / "_" / ":"
+pub fn is_valid_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+// Replace all invalid characters with `_`,
+// prepend `_` when name starts with digit.
+pub fn sanitize_label_name(name: &str) -> String {
+    if is_valid_label_name(name) {
+        return name.into();
+    }
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// Replace all invalid characters with `_`,
+// prepend `_` when name starts with digit.
+pub fn sanitize_metric_name(name: &str) -> String {
+    if is_valid_metric_name(name) {
+        return name.into();
+    }
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn sanitize<F>(name: &str, is_valid: F) -> String
+where
+    F: Fn(char) -> bool,
+{
+    let mut r = String::with_capacity(name.len() + 1);
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        r.push('_');
+    }
+    for c in name.chars() {
+        r.push(if is_valid(c) { c } else { '_' });
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        escape_help, escape_label_value, is_valid_label_name, is_valid_metric_name,
+        sanitize_label_name, sanitize_metric_name, unescape,
+    };
+
+    #[test]
+    fn test_escape_label_value() {
+        assert_eq!(escape_label_value("plain"), "plain");
+        assert_eq!(escape_label_value("a\"b"), "a\\\"b");
+        assert_eq!(escape_label_value("c:\\dir"), "c:\\\\dir");
+        assert_eq!(escape_label_value("line1\nline2"), "line1\\nline2");
+    }
+    #[test]
+    fn test_escape_help() {
+        assert_eq!(escape_help("say \"hi\""), "say \"hi\"");
+        assert_eq!(escape_help("a\\b\nc"), "a\\\\b\\nc");
+    }
+    #[test]
+    fn test_unescape() {
+        for s in ["plain", "a\"b", "c:\\dir", "l1\nl2", "\\\"\n\\"] {
+            assert_eq!(unescape(&escape_label_value(s)), s);
+            assert_eq!(unescape(&escape_help(s)), s);
+        }
+        assert_eq!(unescape("\\x"), "\\x");
+        assert_eq!(unescape("trailing\\"), "trailing\\");
+    }
+    #[test]
+    fn test_is_valid_label_name() {
+        assert!(is_valid_label_name("a"));
+        assert!(is_valid_label_name("_a1"));
+        assert!(!is_valid_label_name(""));
+        assert!(!is_valid_label_name("1a"));
+        assert!(!is_valid_label_name("a:b"));
+        assert!(!is_valid_label_name("a-b"));
+    }
+    #[test]
+    fn test_is_valid_metric_name() {
+        assert!(is_valid_metric_name("a:b_c1"));
+        assert!(is_valid_metric_name(":a"));
+        assert!(!is_valid_metric_name("1a"));
+        assert!(!is_valid_metric_name("a.b"));
+    }
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize_label_name("valid_name"), "valid_name");
+        assert_eq!(sanitize_label_name("a-b.c"), "a_b_c");
+        assert_eq!(sanitize_label_name("a:b"), "a_b");
+        assert_eq!(sanitize_label_name("1st"), "_1st");
+        assert_eq!(sanitize_label_name(""), "_");
+        assert_eq!(sanitize_metric_name("a:b"), "a:b");
+        assert_eq!(sanitize_metric_name("cpu.usage%"), "cpu_usage_");
+    }
+}
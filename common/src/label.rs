@@ -4,7 +4,8 @@
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
 
-use std::collections::BTreeMap;
+use crate::escape::{escape_label_value, is_valid_label_name, sanitize_label_name};
+use std::collections::{BTreeMap, HashSet};
 use std::slice::Iter;
 
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Ord, PartialOrd)]
@@ -95,12 +96,57 @@ impl Labels {
         v3.update_map(&mut map);
         Labels::new(map.iter().map(|(k, v)| Label::new(k, v)).collect())
     }
+    // Replace invalid label names with sanitized ones and keep labels sorted.
+    // Valid names take precedence, so the label which sanitized name
+    // collides with the existing one is dropped.
+    // Returns the original names of the dropped labels.
+    pub fn sanitize(&mut self) -> Vec<String> {
+        let Some(labels) = &mut self.0 else {
+            return Vec::new();
+        };
+        if labels.iter().all(|x| is_valid_label_name(&x.key)) {
+            return Vec::new();
+        }
+        let mut seen: HashSet<String> = labels
+            .iter()
+            .filter(|x| is_valid_label_name(&x.key))
+            .map(|x| x.key.clone())
+            .collect();
+        let mut dropped = Vec::new();
+        labels.retain_mut(|x| {
+            if is_valid_label_name(&x.key) {
+                return true;
+            }
+            let name = sanitize_label_name(&x.key);
+            if seen.contains(&name) {
+                dropped.push(x.key.clone());
+                return false;
+            }
+            seen.insert(name.clone());
+            x.key = name;
+            true
+        });
+        labels.sort();
+        dropped
+    }
+    // Format as OpenMetrics label set, without curly braces.
+    // Label names are sanitized, values are escaped.
+    // Labels with duplicated sanitized names are skipped.
     pub fn to_openmetrics(&self) -> String {
         match &self.0 {
             Some(labels) => {
+                let mut seen = HashSet::with_capacity(labels.len());
                 let s: Vec<String> = labels
                     .iter()
-                    .map(|x| format!("{}=\"{}\"", x.key, x.value))
+                    .filter_map(|x| {
+                        let name = sanitize_label_name(&x.key);
+                        if seen.contains(&name) {
+                            return None;
+                        }
+                        let r = format!("{}=\"{}\"", name, escape_label_value(&x.value));
+                        seen.insert(name);
+                        Some(r)
+                    })
                     .collect();
                 s.join(",")
             }
@@ -7,10 +7,11 @@
 pub(crate) mod collectable;
 pub(crate) mod discovery;
 pub(crate) mod error;
+pub mod escape;
 pub(crate) mod label;
 pub mod metrics;
 pub(crate) mod timing;
-pub use collectable::{Collectable, Measure, Value};
+pub use collectable::{Collectable, Exemplar, Measure, Value};
 pub use discovery::{ConfigDiscoveryOpts, ConfigItem};
 pub use error::AgentError;
 pub use label::{Label, Labels, LabelsConfig};
@@ -18,6 +18,7 @@ pub use paste::paste;
 //         value: Value::Counter(v),
 //         labels: Labels::default(),
 //         timestamp: None,
+//         exemplar: None,
 //     }
 // }
 // ```
@@ -32,6 +33,7 @@ pub use paste::paste;
 //         value: Value::Counter(v),
 //         labels: vec![Labels::new("query", l_query)],
 //         timestamp: None,
+//         exemplar: None,
 //     }
 // }
 // ```
@@ -46,6 +48,7 @@ macro_rules! counter {
                 value: common::Value::Counter(v),
                 labels: common::Labels::default(),
                 timestamp: None,
+                exemplar: None,
             }
         }
     };
@@ -58,6 +61,7 @@ macro_rules! counter {
                 value: common::Value::Counter(v),
                 labels,
                 timestamp: None,
+                exemplar: None,
             }
         }
     };
@@ -79,6 +83,7 @@ macro_rules! counter {
                         ]
                     ),
                     timestamp: None,
+                    exemplar: None,
                 }
             }
         }
@@ -97,6 +102,7 @@ macro_rules! counter {
 //         value: Value::CounterF(v),
 //         labels: Labels::default(),
 //         timestamp: None,
+//         exemplar: None,
 //     }
 // }
 // ```
@@ -111,6 +117,7 @@ macro_rules! counter {
 //         value: Value::CounterF(v),
 //         labels: vec![Labels::new("query", l_query)],
 //         timestamp: None,
+//         exemplar: None,
 //     }
 // }
 // ```
@@ -125,6 +132,7 @@ macro_rules! counter_f {
                 value: common::Value::CounterF(v),
                 labels: common::Labels::default(),
                 timestamp: None,
+                exemplar: None,
             }
         }
     };
@@ -137,6 +145,7 @@ macro_rules! counter_f {
                 value: common::Value::CounterF(v),
                 labels,
                 timestamp: None,
+                exemplar: None,
             }
         }
     };
@@ -158,6 +167,7 @@ macro_rules! counter_f {
                         ]
                     ),
                     timestamp: None,
+                    exemplar: None,
                 }
             }
         }
@@ -176,6 +186,7 @@ macro_rules! counter_f {
 //         value: Value::Gauge(v),
 //         labels: Labels::default(),
 //         timestamp: None,
+//         exemplar: None,
 //     }
 // }
 // ```
@@ -190,6 +201,7 @@ macro_rules! counter_f {
 //         value: Value::Gauge(v),
 //         labels: vec![Labels::new("query", l_query)],
 //         timestamp: None,
+//         exemplar: None,
 //     }
 // }
 // ```
@@ -204,6 +216,7 @@ macro_rules! gauge {
                 value: common::Value::Gauge(v),
                 labels: common::Labels::default(),
                 timestamp: None,
+                exemplar: None,
             }
         }
     };
@@ -216,6 +229,7 @@ macro_rules! gauge {
                 value: common::Value::Gauge(v),
                 labels,
                 timestamp: None,
+                exemplar: None,
             }
         }
     };
@@ -236,6 +250,7 @@ macro_rules! gauge {
                         ]
                     ),
                     timestamp: None,
+                    exemplar: None,
                 }
             }
         }
@@ -254,6 +269,7 @@ macro_rules! gauge {
 //         value: Value::GaugeI(v),
 //         labels: Labels::default(),
 //         timestamp: None,
+//         exemplar: None,
 //     }
 // }
 // ```
@@ -268,6 +284,7 @@ macro_rules! gauge {
 //         value: Value::GaugeI(v),
 //         labels: vec![Labels::new("query", l_query)],
 //         timestamp: None,
+//         exemplar: None,
 //     }
 // }
 // ```
@@ -282,6 +299,7 @@ macro_rules! gauge_i {
                 value: common::Value::GaugeI(v),
                 labels: common::Labels::default(),
                 timestamp: None,
+                exemplar: None,
             }
         }
     };
@@ -294,6 +312,7 @@ macro_rules! gauge_i {
                 value: common::Value::GaugeI(v),
                 labels,
                 timestamp: None,
+                exemplar: None,
             }
         }
     };
@@ -314,6 +333,7 @@ macro_rules! gauge_i {
                         ]
                     ),
                     timestamp: None,
+                    exemplar: None,
                 }
             }
         }
@@ -331,6 +351,7 @@ macro_rules! gauge_i {
 //         value: Value::GaugeF(v),
 //         labels: Labels::default(),
 //         timestamp: None,
+//         exemplar: None,
 //     }
 // }
 // ```
@@ -345,6 +366,7 @@ macro_rules! gauge_i {
 //         value: Value::GaugeF(v),
 //         labels: vec![Labels::new("query", l_query)],
 //         timestamp: None,
+//         exemplar: None,
 //     }
 // }
 // ```
@@ -359,6 +381,7 @@ macro_rules! gauge_f {
                 value: common::Value::GaugeF(v),
                 labels: common::Labels::default(),
                 timestamp: None,
+                exemplar: None,
             }
         }
     };
@@ -371,6 +394,7 @@ macro_rules! gauge_f {
                 value: common::Value::GaugeF(v),
                 labels,
                 timestamp: None,
+                exemplar: None,
             }
         }
     };
@@ -391,6 +415,7 @@ macro_rules! gauge_f {
                         ]
                     ),
                     timestamp: None,
+                    exemplar: None,
                 }
             }
         }
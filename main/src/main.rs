@@ -3,10 +3,12 @@
 // --------------------------------------------------------------------
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
-use agent::{config_from_discovery, Agent, AgentMode, Collectors};
-use clap::Parser;
+use agent::{config_from_discovery, import_prometheus, relabel_test, Agent, AgentMode, Collectors};
+use clap::{Parser, Subcommand};
 use common::ConfigDiscoveryOpts;
 use std::env;
+use std::fs;
+use std::io;
 use std::process;
 
 /// The lightweight infrastructure monitoring agent.
@@ -37,6 +39,25 @@ struct Cli {
     pub test: bool,
     #[arg(long)]
     pub check: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Apply relabel rules to the series and print per-rule traces.
+    RelabelTest {
+        /// YAML file with the list of relabel rules
+        #[arg(short, long)]
+        rules: String,
+        /// OpenMetrics text or label sets, one per line. Read stdin if omitted.
+        input: Option<String>,
+    },
+    /// Convert Prometheus scrape configs to the agent's config.
+    ImportPrometheus {
+        /// Prometheus config file
+        config: String,
+    },
 }
 
 const ERR_EX_OTHER: i32 = 1;
@@ -45,6 +66,14 @@ const ERR_EX_OTHER: i32 = 1;
 fn main() {
     // Parse command-line arguments
     let cli = Cli::parse();
+    // Subcommands
+    if let Some(command) = cli.command {
+        if let Err(e) = run_command(command) {
+            println!("Error: {}", e);
+            process::exit(ERR_EX_OTHER);
+        }
+        return;
+    }
     // --list-collectors
     if cli.list_collectors {
         for name in Collectors::to_vec().iter() {
@@ -119,3 +148,27 @@ fn main() {
         process::exit(ERR_EX_OTHER);
     }
 }
+
+fn run_command(command: Command) -> Result<(), String> {
+    match command {
+        Command::RelabelTest { rules, input } => {
+            let rules = fs::read_to_string(&rules).map_err(|e| format!("{}: {}", rules, e))?;
+            let input = match input {
+                Some(path) => fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?,
+                None => io::read_to_string(io::stdin()).map_err(|e| e.to_string())?,
+            };
+            let report = relabel_test(&rules, &input).map_err(|e| format!("{:?}", e))?;
+            print!("{}", report);
+        }
+        Command::ImportPrometheus { config } => {
+            let data = fs::read_to_string(&config).map_err(|e| format!("{}: {}", config, e))?;
+            let r = import_prometheus(&data).map_err(|e| format!("{:?}", e))?;
+            // Keep stdout a valid config
+            for item in r.unsupported.iter() {
+                eprintln!("Warning: {}", item);
+            }
+            print!("{}", r.config);
+        }
+    }
+    Ok(())
+}
@@ -5,17 +5,46 @@
 // See LICENSE for details
 // ---------------------------------------------------------------------
 
-use common::{AgentError, AgentResult, Label, Labels, Measure, Value};
+use common::{escape::unescape, AgentError, AgentResult, Exemplar, Label, Labels, Measure, Value};
 use nom::{
     branch::alt,
-    bytes::complete::{escaped, is_not, tag},
-    character::complete::{alpha1, alphanumeric1, char, line_ending, space0, space1, u64},
-    combinator::{eof, opt, recognize},
-    multi::{many0, many0_count, separated_list0},
+    bytes::complete::{escaped, is_not, tag, tag_no_case},
+    character::complete::{alpha1, alphanumeric1, anychar, digit1, line_ending, space0, space1},
+    combinator::{eof, map, map_res, opt, recognize},
+    multi::{many0_count, separated_list0},
     number::complete::recognize_float_parts,
-    sequence::{pair, tuple},
+    sequence::{pair, preceded, tuple},
     IResult,
 };
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// Maximal combined length of exemplar's label names and values
+const MAX_EXEMPLAR_LABELS_LENGTH: usize = 128;
+
+// Exposition format
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    OpenMetrics,
+    // Prometheus text format 0.0.4
+    Prometheus,
+}
+
+impl Format {
+    // Detect format by HTTP Content-Type.
+    // Everything except `application/openmetrics-text`
+    // is considered as Prometheus text format.
+    pub fn from_content_type(content_type: &str) -> Self {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if media_type.eq_ignore_ascii_case("application/openmetrics-text") {
+            Format::OpenMetrics
+        } else {
+            Format::Prometheus
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub struct Desc {
@@ -46,12 +75,23 @@ enum InternalValue {
     F32(f32),
 }
 
+impl InternalValue {
+    fn as_f64(&self) -> f64 {
+        match self {
+            InternalValue::U64(x) => *x as f64,
+            InternalValue::I64(x) => *x as f64,
+            InternalValue::F32(x) => *x as f64,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Metric {
     metric_name: String,
     labels: Labels,
     value: InternalValue,
     timestamp: Option<u64>,
+    exemplar: Option<Exemplar>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -122,8 +162,9 @@ pub enum Token {
 // normal-char = %x00-09 / %x0B-21 / %x23-5B / %x5D-D7FF / %xE000-10FFFF
 
 // Parse input to a vec of tokens
+#[cfg(test)]
 fn parse_tokens(input: &str) -> IResult<&str, Vec<Token>> {
-    many0(line)(input)
+    nom::multi::many0(line)(input)
 }
 
 // Parse single line and return Token
@@ -170,10 +211,16 @@ fn hash_help(input: &str) -> IResult<&str, Token> {
     let (input, _) = tag("HELP")(input)?;
     let (input, _) = space1(input)?;
     let (input, name) = metric_name(input)?;
-    let (input, _) = space1(input)?;
-    let (input, help) = is_not("\r\n")(input)?;
+    // Help may be empty
+    let (input, help) = opt(preceded(space1, opt(is_not("\r\n"))))(input)?;
     let (input, _) = alt((line_ending, eof))(input)?;
-    Ok((input, Token::DescHelp(Desc::new(name, help))))
+    Ok((
+        input,
+        Token::DescHelp(Desc::new(
+            name,
+            unescape(help.flatten().unwrap_or_default()),
+        )),
+    ))
 }
 // TYPE <metric name> <type>
 fn hash_type(input: &str) -> IResult<&str, Token> {
@@ -182,8 +229,17 @@ fn hash_type(input: &str) -> IResult<&str, Token> {
     let (input, _) = space1(input)?;
     let (input, name) = metric_name(input)?;
     let (input, _) = space1(input)?;
-    let (input, t) = alt((tag("counter"), tag("gauge")))(input)?;
-    let (input, _) = alt((line_ending, eof))(input)?;
+    let (input, t) = alt((
+        tag("counter"),
+        tag("gaugehistogram"),
+        tag("gauge"),
+        tag("histogram"),
+        tag("stateset"),
+        tag("info"),
+        tag("summary"),
+        tag("unknown"),
+    ))(input)?;
+    let (input, _) = tuple((space0, alt((line_ending, eof))))(input)?;
     Ok((input, Token::DescType(Desc::new(name, t))))
 }
 fn hash_unit(input: &str) -> IResult<&str, Token> {
@@ -191,25 +247,84 @@ fn hash_unit(input: &str) -> IResult<&str, Token> {
     let (input, _) = tag("UNIT")(input)?;
     let (input, _) = space1(input)?;
     let (input, name) = metric_name(input)?;
-    let (input, _) = space1(input)?;
-    let (input, unit) = is_not("\r\n")(input)?;
+    // Unit may be empty
+    let (input, unit) = opt(preceded(space1, opt(is_not("\r\n"))))(input)?;
     let (input, _) = alt((line_ending, eof))(input)?;
-    Ok((input, Token::DescUnit(Desc::new(name, unit))))
+    Ok((
+        input,
+        Token::DescUnit(Desc::new(name, unit.flatten().unwrap_or_default())),
+    ))
 }
-//
+// number = realnumber
+// number =/ [SIGN] ("inf" / "infinity")
+// number =/ "nan"
 fn recognize_value(input: &str) -> IResult<&str, InternalValue> {
-    let (input, parts) = recognize_float_parts(input)?;
-    let value = match parts {
-        (true, i, "", 0) => InternalValue::U64(i.parse().unwrap_or(0)),
-        (false, i, "", 0) => InternalValue::I64(-i.parse().unwrap_or(0)),
-        (true, i, f, e) => InternalValue::F32(format!("{}.{}e{}", i, f, e).parse().unwrap_or(0.0)),
-        (false, i, f, e) => {
-            InternalValue::F32(format!("-{}.{}e{}", i, f, e).parse().unwrap_or(0.0))
+    alt((special_value, real_value))(input)
+}
+// Case insensitive NaN and Inf
+fn special_value(input: &str) -> IResult<&str, InternalValue> {
+    alt((
+        map(tag_no_case("nan"), |_| InternalValue::F32(f32::NAN)),
+        map(
+            pair(
+                opt(alt((tag("+"), tag("-")))),
+                alt((tag_no_case("infinity"), tag_no_case("inf"))),
+            ),
+            |(sign, _)| match sign {
+                Some("-") => InternalValue::F32(f32::NEG_INFINITY),
+                _ => InternalValue::F32(f32::INFINITY),
+            },
+        ),
+    ))(input)
+}
+// Integers are kept as is, unless they are out of range.
+fn real_value(input: &str) -> IResult<&str, InternalValue> {
+    map_res(recognize(recognize_float_parts), |x: &str| {
+        if !x.contains(['.', 'e', 'E']) {
+            if let Ok(v) = x.parse::<u64>() {
+                return Ok(InternalValue::U64(v));
+            }
+            if let Ok(v) = x.parse::<i64>() {
+                return Ok(InternalValue::I64(v));
+            }
         }
-    };
-    Ok((input, value))
+        x.parse::<f32>().map(InternalValue::F32)
+    })(input)
 }
-// <metric_name>[<labels>] SP <value>[ SP <timestamp>] <LF>
+// realnumber as f64
+fn realnumber(input: &str) -> IResult<&str, f64> {
+    map_res(recognize(recognize_float_parts), |x: &str| x.parse::<f64>())(input)
+}
+// timestamp = realnumber
+// Fractional part is truncated.
+fn timestamp(input: &str) -> IResult<&str, u64> {
+    map_res(realnumber, |x| {
+        if x >= 0.0 {
+            Ok(x as u64)
+        } else {
+            Err("negative timestamp")
+        }
+    })(input)
+}
+// exemplar = SP HASH SP labels SP number [SP timestamp]
+fn exemplar(input: &str) -> IResult<&str, Exemplar> {
+    let (input, _) = tuple((space1, tag("#"), space1))(input)?;
+    // Labels are mandatory
+    let (input, _) = nom::combinator::peek(tag("{"))(input)?;
+    let (input, labels) = labels(input)?;
+    let (input, _) = space1(input)?;
+    let (input, value) = alt((map(special_value, |x| x.as_f64()), realnumber))(input)?;
+    let (input, timestamp) = opt(preceded(space1, realnumber))(input)?;
+    Ok((
+        input,
+        Exemplar {
+            labels,
+            value,
+            timestamp,
+        },
+    ))
+}
+// sample = metricname [labels] SP number [SP timestamp] [exemplar] LF
 fn metric_line(input: &str) -> IResult<&str, Token> {
     // <metric_name>
     let (input, metric_name) = metric_name(input)?;
@@ -220,8 +335,9 @@ fn metric_line(input: &str) -> IResult<&str, Token> {
     // <value>
     let (input, value) = recognize_value(input)?;
     // optional timestamp
-    let (input, ts) = opt(tuple((space1, u64)))(input)?;
-    let timestamp = ts.map(|(_, v)| v);
+    let (input, timestamp) = opt(preceded(space1, timestamp))(input)?;
+    // optional exemplar
+    let (input, exemplar) = opt(exemplar)(input)?;
     // LF
     let (input, _) = alt((line_ending, eof))(input)?;
     // Result
@@ -232,6 +348,7 @@ fn metric_line(input: &str) -> IResult<&str, Token> {
             labels,
             value,
             timestamp,
+            exemplar,
         }),
     ))
 }
@@ -279,11 +396,97 @@ fn label(input: &str) -> IResult<&str, Label> {
     let (input, name) = label_name(input)?;
     // ="
     let (input, _) = tag("=\"")(input)?;
-    // Escaped string
-    let (input, value) = escaped(is_not("\""), '\\', char('"'))(input)?;
+    // Escaped string, may be empty
+    let (input, value) = opt(escaped(is_not("\"\\\n"), '\\', anychar))(input)?;
     // "
     let (input, _) = tag("\"")(input)?;
-    Ok((input, Label::new(name, value)))
+    Ok((input, Label::new(name, unescape(value.unwrap_or_default()))))
+}
+
+// Prometheus text format 0.0.4.
+// Differs from OpenMetrics:
+// * `untyped` type, no `gaugehistogram`, `stateset`, `info` and `unknown`;
+// * no `# UNIT` and `# EOF`, treated as comments;
+// * timestamps are integer milliseconds;
+// * no exemplars;
+// * whitespaces are allowed around the tokens, trailing comma in labels.
+fn prom_line(input: &str) -> IResult<&str, Token> {
+    let (input, _) = space0(input)?;
+    alt((empty_line, prom_hashed_line, prom_metric_line))(input)
+}
+
+// Parse line starting with `#`.
+// May be DescHelp, DescType or Comment
+fn prom_hashed_line(input: &str) -> IResult<&str, Token> {
+    let (input, _) = tag("#")(input)?;
+    match alt((hash_help, prom_type))(input) {
+        Ok(x) => Ok(x),
+        Err(_) => hash_comment(input),
+    }
+}
+
+// TYPE <metric name> <type>
+fn prom_type(input: &str) -> IResult<&str, Token> {
+    let (input, _) = space1(input)?;
+    let (input, _) = tag("TYPE")(input)?;
+    let (input, _) = space1(input)?;
+    let (input, name) = metric_name(input)?;
+    let (input, _) = space1(input)?;
+    let (input, t) = alt((
+        tag("counter"),
+        tag("gauge"),
+        tag("histogram"),
+        tag("summary"),
+        tag("untyped"),
+    ))(input)?;
+    let (input, _) = tuple((space0, alt((line_ending, eof))))(input)?;
+    Ok((input, Token::DescType(Desc::new(name, t))))
+}
+
+// Signed integer milliseconds, converted to seconds.
+// Timestamps before the epoch are not representable and are ignored.
+fn prom_timestamp(input: &str) -> IResult<&str, Option<u64>> {
+    map_res(recognize(pair(opt(tag("-")), digit1)), |x: &str| {
+        x.parse::<i64>()
+            .map(|ms| u64::try_from(ms).ok().map(|ms| ms / 1000))
+    })(input)
+}
+
+// metric_name [labels] value [timestamp]
+fn prom_metric_line(input: &str) -> IResult<&str, Token> {
+    let (input, metric_name) = metric_name(input)?;
+    let (input, labels) = opt(preceded(space0, prom_labels))(input)?;
+    let (input, _) = space1(input)?;
+    let (input, value) = recognize_value(input)?;
+    let (input, timestamp) = opt(preceded(space1, prom_timestamp))(input)?;
+    let (input, _) = tuple((space0, alt((line_ending, eof))))(input)?;
+    Ok((
+        input,
+        Token::Metric(Metric {
+            metric_name: metric_name.into(),
+            labels: labels.unwrap_or_default(),
+            value,
+            timestamp: timestamp.flatten(),
+            exemplar: None,
+        }),
+    ))
+}
+
+// "{" [label *("," label) [","]] "}"
+fn prom_labels(input: &str) -> IResult<&str, Labels> {
+    let (input, _) = pair(tag("{"), space0)(input)?;
+    let (input, r) = separated_list0(tuple((space0, tag(","), space0)), prom_label)(input)?;
+    let (input, _) = tuple((space0, opt(pair(tag(","), space0)), tag("}")))(input)?;
+    Ok((input, Labels::new(r)))
+}
+
+// label-name = "escaped-string"
+fn prom_label(input: &str) -> IResult<&str, Label> {
+    let (input, name) = label_name(input)?;
+    let (input, _) = tuple((space0, tag("="), space0, tag("\"")))(input)?;
+    let (input, value) = opt(escaped(is_not("\"\\\n"), '\\', anychar))(input)?;
+    let (input, _) = tag("\"")(input)?;
+    Ok((input, Label::new(name, unescape(value.unwrap_or_default()))))
 }
 
 #[derive(Default)]
@@ -297,9 +500,51 @@ struct MetricDescriptor {
 enum InternalType {
     Counter,
     Gauge,
+    Histogram,
+    GaugeHistogram,
+    StateSet,
+    Info,
+    Summary,
+    Unknown,
+}
+
+// Suffixes of the samples belonging to the metric family
+const SAMPLE_SUFFIXES: &[&str] = &["_total", "_created"];
+
+impl InternalType {
+    fn suffixes(&self) -> &'static [&'static str] {
+        match self {
+            InternalType::Counter => SAMPLE_SUFFIXES,
+            InternalType::Histogram => &["_bucket", "_count", "_sum", "_created"],
+            InternalType::GaugeHistogram => &["_bucket", "_gcount", "_gsum"],
+            InternalType::Summary => &["_count", "_sum", "_created"],
+            InternalType::Info => &["_info"],
+            InternalType::Gauge | InternalType::StateSet | InternalType::Unknown => &[],
+        }
+    }
 }
 
 impl MetricDescriptor {
+    // Check if the sample name belongs to the current metric family,
+    // i.e. `<family>_total` for counters.
+    pub fn is_family_sample(&self, name: &str) -> bool {
+        match &self.metric_name {
+            Some(family) => self
+                .r#type
+                .as_ref()
+                .map(|t| t.suffixes())
+                .unwrap_or(SAMPLE_SUFFIXES)
+                .iter()
+                .any(|suffix| name.strip_suffix(suffix) == Some(family.as_str())),
+            None => false,
+        }
+    }
+    // Reset descriptor unless sample belongs to the current family
+    pub fn ensure_sample(&mut self, name: &str) {
+        if !self.is_family_sample(name) {
+            self.ensure_name(name.to_string());
+        }
+    }
     pub fn ensure_name(&mut self, name: String) {
         if let Some(x) = &self.metric_name {
             if *x == name {
@@ -320,6 +565,12 @@ impl MetricDescriptor {
         self.ensure_name(desc.metric_name);
         self.r#type = Some(match desc.desc.as_str() {
             "counter" => InternalType::Counter,
+            "histogram" => InternalType::Histogram,
+            "gaugehistogram" => InternalType::GaugeHistogram,
+            "stateset" => InternalType::StateSet,
+            "info" => InternalType::Info,
+            "summary" => InternalType::Summary,
+            "unknown" | "untyped" => InternalType::Unknown,
             _ => InternalType::Gauge,
         });
     }
@@ -330,13 +581,18 @@ impl MetricDescriptor {
     pub fn help(&self) -> String {
         self.help.clone().unwrap_or_else(|| "???".to_string())
     }
-    pub fn value(&self, v: InternalValue) -> Result<Value, AgentError> {
-        Ok(match self.r#type.as_ref().unwrap_or(&InternalType::Gauge) {
-            InternalType::Counter => match v {
+    // Only counter samples are exposed as counters,
+    // all others, including histogram and summary parts, are gauges.
+    pub fn value(&self, name: &str, v: InternalValue) -> Result<Value, &'static str> {
+        let is_counter =
+            matches!(self.r#type, Some(InternalType::Counter)) && !name.ends_with("_created");
+        Ok(match is_counter {
+            true => match v {
                 InternalValue::U64(v) => Value::Counter(v),
-                _ => return Err(AgentError::ParseError("invalid counter".to_string())),
+                InternalValue::F32(v) if v >= 0.0 => Value::CounterF(v),
+                _ => return Err("invalid counter value"),
             },
-            InternalType::Gauge => match v {
+            false => match v {
                 InternalValue::U64(v) => Value::Gauge(v),
                 InternalValue::I64(v) => Value::GaugeI(v),
                 InternalValue::F32(v) => Value::GaugeF(v),
@@ -348,53 +604,233 @@ impl MetricDescriptor {
 #[derive(Debug, Default, Clone)]
 pub struct ParseConfig {
     pub trust_timestamps: bool,
+    // Return positioned error on invalid input,
+    // instead of skipping invalid lines and values.
+    pub strict: bool,
+    pub format: Format,
+    // Maximal input size, in bytes
+    pub body_size_limit: Option<usize>,
+    // Maximal number of samples
+    pub sample_limit: Option<usize>,
+}
+
+// Positioned parsing error
+fn error_at(line: usize, column: Option<usize>, msg: &str) -> AgentError {
+    AgentError::ParseError(match column {
+        Some(column) => format!("line {}, column {}: {}", line, column, msg),
+        None => format!("line {}: {}", line, msg),
+    })
+}
+
+// Parse single line. Returns column of the error.
+fn parse_line(input: &str, format: Format) -> Result<Token, usize> {
+    let column = |rest: &str| input[..input.len() - rest.len()].chars().count() + 1;
+    let r = match format {
+        Format::OpenMetrics => line(input),
+        Format::Prometheus => prom_line(input),
+    };
+    match r {
+        Ok(("", token)) => Ok(token),
+        Ok((rest, _)) => Err(column(rest)),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(column(e.input)),
+        Err(nom::Err::Incomplete(_)) => Err(column("")),
+    }
+}
+
+// Check metric for the semantic errors
+fn check_metric(metric: &Metric) -> Result<(), String> {
+    let mut seen = HashSet::new();
+    for label in metric.labels.iter() {
+        if !seen.insert(&label.key) {
+            return Err(format!("duplicated label {}", label.key));
+        }
+    }
+    if let Some(exemplar) = &metric.exemplar {
+        let size: usize = exemplar
+            .labels
+            .iter()
+            .map(|x| x.key.chars().count() + x.value.chars().count())
+            .sum();
+        if size > MAX_EXEMPLAR_LABELS_LENGTH {
+            return Err("exemplar labels are too long".to_string());
+        }
+    }
+    Ok(())
 }
 
+// Parse complete input
 pub fn parse(value: &str, cfg: &ParseConfig) -> AgentResult<Vec<Measure>> {
-    let (_, mut tokens) = parse_tokens(value).map_err(|e| AgentError::ParseError(e.to_string()))?;
-    if tokens.is_empty() {
-        return Ok(Vec::default());
-    }
-    let mut desc = MetricDescriptor::default();
-    let mut r = Vec::with_capacity(tokens.len());
-    for t in tokens.drain(..) {
-        match t {
-            Token::DescType(d) => desc.set_type(d),
-            Token::DescHelp(d) => desc.set_help(d),
-            Token::DescUnit(d) => desc.set_units(d),
-            Token::Metric(metric) => {
-                desc.ensure_name(metric.metric_name.clone());
-                let value = match desc.value(metric.value) {
+    let mut parser = Parser::new(cfg);
+    parser.feed(value.as_bytes())?;
+    parser.finish()
+}
+
+// Incremental parser.
+// Input is fed by the chunks of arbitrary size,
+// complete lines are parsed as soon as they are available.
+pub struct Parser {
+    cfg: ParseConfig,
+    desc: MetricDescriptor,
+    measures: Vec<Measure>,
+    // Incomplete last line
+    buf: Vec<u8>,
+    lineno: usize,
+    size: usize,
+    eof: bool,
+}
+
+impl Parser {
+    pub fn new(cfg: &ParseConfig) -> Self {
+        Parser {
+            cfg: cfg.clone(),
+            desc: MetricDescriptor::default(),
+            measures: Vec::new(),
+            buf: Vec::new(),
+            lineno: 0,
+            size: 0,
+            eof: false,
+        }
+    }
+    // Feed next chunk of input
+    pub fn feed(&mut self, chunk: &[u8]) -> AgentResult<()> {
+        self.size += chunk.len();
+        if let Some(limit) = self.cfg.body_size_limit {
+            if self.size > limit {
+                return Err(AgentError::ParseError(format!(
+                    "body size limit of {} bytes exceeded",
+                    limit
+                )));
+            }
+        }
+        let mut rest = chunk;
+        while let Some(pos) = rest.iter().position(|c| *c == b'\n') {
+            let (line, tail) = rest.split_at(pos + 1);
+            rest = tail;
+            if self.buf.is_empty() {
+                self.parse_line(line)?;
+            } else {
+                let mut buf = std::mem::take(&mut self.buf);
+                buf.extend_from_slice(line);
+                self.parse_line(&buf)?;
+            }
+        }
+        self.buf.extend_from_slice(rest);
+        Ok(())
+    }
+    // Parse the rest of input and return the result
+    pub fn finish(mut self) -> AgentResult<Vec<Measure>> {
+        if !self.buf.is_empty() {
+            let buf = std::mem::take(&mut self.buf);
+            self.parse_line(&buf)?;
+        }
+        if self.cfg.strict && self.cfg.format == Format::OpenMetrics && !self.eof {
+            return Err(AgentError::ParseError("missing # EOF".to_string()));
+        }
+        Ok(self.measures)
+    }
+    fn parse_line(&mut self, input: &[u8]) -> AgentResult<()> {
+        self.lineno += 1;
+        let strict = self.cfg.strict;
+        let line = match std::str::from_utf8(input) {
+            Ok(x) => x,
+            Err(e) if strict => {
+                let column = String::from_utf8_lossy(&input[..e.valid_up_to()])
+                    .chars()
+                    .count()
+                    + 1;
+                return Err(error_at(self.lineno, Some(column), "invalid UTF-8"));
+            }
+            Err(_) => return Ok(()),
+        };
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+        if self.eof {
+            if strict {
+                return Err(error_at(self.lineno, None, "data after # EOF"));
+            }
+            return Ok(());
+        }
+        let token = match parse_line(line, self.cfg.format) {
+            Ok(x) => x,
+            Err(column) if strict => {
+                return Err(error_at(self.lineno, Some(column), "syntax error"))
+            }
+            Err(_) => return Ok(()),
+        };
+        match token {
+            Token::DescType(d) => self.desc.set_type(d),
+            Token::DescHelp(d) => self.desc.set_help(d),
+            Token::DescUnit(d) => self.desc.set_units(d),
+            Token::Metric(mut metric) => {
+                if let Err(e) = check_metric(&metric) {
+                    if strict {
+                        return Err(error_at(self.lineno, None, &e));
+                    }
+                    metric.exemplar = None;
+                }
+                self.desc.ensure_sample(&metric.metric_name);
+                let value = match self.desc.value(&metric.metric_name, metric.value) {
                     Ok(x) => x,
-                    Err(_) => continue,
+                    Err(e) if strict => return Err(error_at(self.lineno, None, e)),
+                    Err(_) => return Ok(()),
                 };
-                r.push(Measure {
+                if let Some(limit) = self.cfg.sample_limit {
+                    if self.measures.len() >= limit {
+                        return Err(AgentError::ParseError(format!(
+                            "sample limit of {} exceeded",
+                            limit
+                        )));
+                    }
+                }
+                self.measures.push(Measure {
                     name: metric.metric_name,
-                    help: desc.help(),
+                    help: self.desc.help(),
                     value,
                     labels: metric.labels,
-                    timestamp: if cfg.trust_timestamps {
+                    timestamp: if self.cfg.trust_timestamps {
                         metric.timestamp
                     } else {
                         None
                     },
+                    exemplar: metric.exemplar,
                 })
             }
             Token::Comment => {}
-            Token::Eof => {}
+            Token::Eof => self.eof = true,
             Token::EmptyLine => {}
         }
+        Ok(())
     }
-    Ok(r)
+}
+
+// Parse series without value, i.e. `name{label="value",...}`.
+// Metric name is optional, labels may be omitted.
+pub fn parse_series(value: &str) -> AgentResult<(Option<String>, Labels)> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(AgentError::ParseError("empty series".to_string()));
+    }
+    let (_, (name, labels, _)) = tuple((opt(metric_name), labels, eof))(value)
+        .map_err(|e: nom::Err<nom::error::Error<&str>>| AgentError::ParseError(e.to_string()))?;
+    Ok((name.map(|x| x.to_string()), labels))
 }
 
 #[cfg(test)]
 mod tests {
     use super::{
-        empty_line, hash_comment, hash_eof, hash_help, hash_type, hash_unit, hashed_line,
-        metric_name, parse, parse_tokens, Desc, InternalValue, Labels, Measure, Metric,
-        ParseConfig, Token,
+        empty_line, hash_comment, hash_eof, hash_help, hash_type, hash_unit, hashed_line, label,
+        metric_line, metric_name, parse, parse_series, parse_tokens, recognize_value, Desc, Format,
+        InternalValue, Label, Labels, Measure, Metric, ParseConfig, Parser, Token,
     };
+    use common::{Exemplar, Value};
+
+    fn strict() -> ParseConfig {
+        ParseConfig {
+            strict: true,
+            ..Default::default()
+        }
+    }
 
     #[test]
     fn test_metricname() {
@@ -466,7 +902,15 @@ mod tests {
             hash_type(" TYPE mymetric gauge\n"),
             Ok(("", Token::DescType(Desc::new("mymetric", "gauge"))))
         );
-        assert!(hash_type(" TYPE mymetric unknown").is_err());
+        assert_eq!(
+            hash_type(" TYPE mymetric unknown"),
+            Ok(("", Token::DescType(Desc::new("mymetric", "unknown"))))
+        );
+        assert_eq!(
+            hash_type(" TYPE mymetric gaugehistogram"),
+            Ok(("", Token::DescType(Desc::new("mymetric", "gaugehistogram"))))
+        );
+        assert!(hash_type(" TYPE mymetric invalid").is_err());
     }
     #[test]
     fn test_hash_unit() {
@@ -522,7 +966,8 @@ metric2 -15
                         metric_name: "metric1".into(),
                         labels: Labels::default(),
                         value: InternalValue::U64(12),
-                        timestamp: None
+                        timestamp: None,
+                        exemplar: None,
                     }),
                     Token::EmptyLine,
                     Token::DescHelp(Desc::new("metric2", "second metric")),
@@ -532,7 +977,8 @@ metric2 -15
                         metric_name: "metric2".into(),
                         labels: Labels::default(),
                         value: InternalValue::I64(-15),
-                        timestamp: None
+                        timestamp: None,
+                        exemplar: None,
                     }),
                     Token::EmptyLine,
                     Token::Eof,
@@ -566,6 +1012,7 @@ metric2 -15 1686823614
                         labels: Labels::default(),
                         value: InternalValue::U64(12),
                         timestamp: Some(1686823614),
+                        exemplar: None,
                     }),
                     Token::EmptyLine,
                     Token::DescHelp(Desc::new("metric2", "second metric")),
@@ -576,6 +1023,7 @@ metric2 -15 1686823614
                         labels: Labels::default(),
                         value: InternalValue::I64(-15),
                         timestamp: Some(1686823614),
+                        exemplar: None,
                     }),
                     Token::EmptyLine,
                     Token::Eof,
@@ -598,6 +1046,7 @@ metric2 15 1686823614
 # EOF"#;
         let cfg = ParseConfig {
             trust_timestamps: true,
+            ..Default::default()
         };
         assert_eq!(
             parse(input, &cfg).unwrap(),
@@ -608,13 +1057,15 @@ metric2 15 1686823614
                     value: common::Value::Gauge(12),
                     labels: Labels::default(),
                     timestamp: Some(1686823614),
+                    exemplar: None,
                 },
                 Measure {
                     name: "metric2".into(),
                     help: "second metric".into(),
                     value: common::Value::Counter(15),
                     labels: Labels::default(),
-                    timestamp: Some(1686823614)
+                    timestamp: Some(1686823614),
+                    exemplar: None
                 }
             ]
         );
@@ -642,6 +1093,7 @@ metric2 15 1686823614
                     value: common::Value::Gauge(12),
                     labels: Labels::default(),
                     timestamp: None,
+                    exemplar: None,
                 },
                 Measure {
                     name: "metric2".into(),
@@ -649,8 +1101,396 @@ metric2 15 1686823614
                     value: common::Value::Counter(15),
                     labels: Labels::default(),
                     timestamp: None,
+                    exemplar: None,
                 }
             ]
         );
     }
+    #[test]
+    fn test_label_escaped() {
+        assert_eq!(label(r#"a="1""#), Ok(("", Label::new("a", "1"))));
+        assert_eq!(label(r#"a="""#), Ok(("", Label::new("a", ""))));
+        assert_eq!(
+            label(r#"cmd="/bin/sh -c \"echo \\\"""#),
+            Ok(("", Label::new("cmd", r#"/bin/sh -c "echo \""#)))
+        );
+        assert_eq!(
+            label(r#"a="line1\nline2""#),
+            Ok(("", Label::new("a", "line1\nline2")))
+        );
+    }
+    #[test]
+    fn test_hash_help_escaped() {
+        assert_eq!(
+            hash_help(r#" HELP m path C:\\dir\nnext"#),
+            Ok(("", Token::DescHelp(Desc::new("m", "path C:\\dir\nnext"))))
+        );
+    }
+    #[test]
+    fn test_parse_counter_total() {
+        let input = r#"# HELP requests Total requests
+# TYPE requests counter
+requests_total{code="200"} 10
+# EOF"#;
+        let cfg = ParseConfig::default();
+        assert_eq!(
+            parse(input, &cfg).unwrap(),
+            vec![Measure {
+                name: "requests_total".into(),
+                help: "Total requests".into(),
+                value: common::Value::Counter(10),
+                labels: Labels::new(vec![Label::new("code", "200")]),
+                timestamp: None,
+                exemplar: None,
+            }]
+        );
+    }
+    #[test]
+    fn test_parse_series() {
+        assert_eq!(
+            parse_series(r#"ps_cpu{user="scott",zone="tiger"}"#).unwrap(),
+            (
+                Some("ps_cpu".into()),
+                Labels::new(vec![
+                    Label::new("user", "scott"),
+                    Label::new("zone", "tiger")
+                ])
+            )
+        );
+        assert_eq!(
+            parse_series(r#"{user="scott"}"#).unwrap(),
+            (None, Labels::new(vec![Label::new("user", "scott")]))
+        );
+        assert_eq!(
+            parse_series("ps_cpu").unwrap(),
+            (Some("ps_cpu".into()), Labels::default())
+        );
+        assert!(parse_series("").is_err());
+        assert!(parse_series("ps_cpu 1").is_err());
+    }
+    #[test]
+    fn test_special_values() {
+        for (input, expected) in [
+            ("+Inf", f32::INFINITY),
+            ("inf", f32::INFINITY),
+            ("Infinity", f32::INFINITY),
+            ("-Inf", f32::NEG_INFINITY),
+            ("-infinity", f32::NEG_INFINITY),
+        ] {
+            assert_eq!(
+                recognize_value(input),
+                Ok(("", InternalValue::F32(expected)))
+            );
+        }
+        match recognize_value("nan") {
+            Ok(("", InternalValue::F32(x))) => assert!(x.is_nan()),
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+    #[test]
+    fn test_values() {
+        assert_eq!(recognize_value("12"), Ok(("", InternalValue::U64(12))));
+        assert_eq!(recognize_value("-12"), Ok(("", InternalValue::I64(-12))));
+        assert_eq!(recognize_value("1.5"), Ok(("", InternalValue::F32(1.5))));
+        assert_eq!(recognize_value("1e3"), Ok(("", InternalValue::F32(1000.0))));
+        // Out of range integers are not converted to 0
+        assert_eq!(
+            recognize_value("99999999999999999999"),
+            Ok(("", InternalValue::F32(1e20)))
+        );
+        assert!(recognize_value("abc").is_err());
+    }
+    #[test]
+    fn test_exemplar() {
+        assert_eq!(
+            metric_line(
+                r#"requests_total{code="200"} 10 # {trace_id="KOO5S4vxi0o"} 0.67 1520879607.789"#
+            ),
+            Ok((
+                "",
+                Token::Metric(Metric {
+                    metric_name: "requests_total".into(),
+                    labels: Labels::new(vec![Label::new("code", "200")]),
+                    value: InternalValue::U64(10),
+                    timestamp: None,
+                    exemplar: Some(Exemplar {
+                        labels: Labels::new(vec![Label::new("trace_id", "KOO5S4vxi0o")]),
+                        value: 0.67,
+                        timestamp: Some(1520879607.789),
+                    }),
+                })
+            ))
+        );
+        assert_eq!(
+            metric_line(r#"requests_total 10 1520879607.789 # {} 1"#),
+            Ok((
+                "",
+                Token::Metric(Metric {
+                    metric_name: "requests_total".into(),
+                    labels: Labels::default(),
+                    value: InternalValue::U64(10),
+                    timestamp: Some(1520879607),
+                    exemplar: Some(Exemplar {
+                        labels: Labels::new(vec![]),
+                        value: 1.0,
+                        timestamp: None,
+                    }),
+                })
+            ))
+        );
+        // Labels are mandatory
+        assert!(metric_line(r#"requests_total 10 # 1"#).is_err());
+    }
+    #[test]
+    fn test_parse_exemplar() {
+        let input = r#"# TYPE requests counter
+requests_total 10 # {trace_id="a"} 1
+# EOF"#;
+        let r = parse(input, &strict()).unwrap();
+        assert_eq!(
+            r[0].exemplar,
+            Some(Exemplar {
+                labels: Labels::new(vec![Label::new("trace_id", "a")]),
+                value: 1.0,
+                timestamp: None,
+            })
+        );
+    }
+    #[test]
+    fn test_parse_histogram() {
+        let input = r#"# HELP latency Request latency
+# TYPE latency histogram
+# UNIT latency seconds
+latency_bucket{le="0.1"} 5
+latency_bucket{le="+Inf"} 7
+latency_count 7
+latency_sum 0.5
+# HELP empty
+# TYPE empty gauge
+empty NaN
+# EOF
+"#;
+        let r = parse(input, &strict()).unwrap();
+        assert_eq!(r.len(), 5);
+        assert_eq!(r[0].name, "latency_bucket");
+        assert_eq!(r[0].help, "Request latency");
+        assert_eq!(r[0].value, Value::Gauge(5));
+        assert_eq!(r[3].help, "Request latency");
+        assert_eq!(r[3].value, Value::GaugeF(0.5));
+        assert_eq!(r[4].help, "");
+        match r[4].value {
+            Value::GaugeF(x) => assert!(x.is_nan()),
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+    #[test]
+    fn test_parse_counter_float() {
+        let input = r#"# TYPE cpu_seconds counter
+cpu_seconds_total 1.5
+cpu_seconds_created 1520430000.123
+# EOF"#;
+        let r = parse(input, &strict()).unwrap();
+        assert_eq!(r[0].value, Value::CounterF(1.5));
+        assert!(matches!(r[1].value, Value::GaugeF(_)));
+    }
+    #[test]
+    fn test_parse_lenient() {
+        let input = r#"# TYPE requests counter
+requests_total -1
+metric1 12
+metric2{a="1" 3
+metric3 abc
+metric4 4
+"#;
+        let r = parse(input, &ParseConfig::default()).unwrap();
+        assert_eq!(
+            r.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(),
+            vec!["metric1", "metric4"]
+        );
+    }
+    #[test]
+    fn test_parse_strict() {
+        for (input, msg) in [
+            (
+                "metric1 12\nmetric2{a=\"1\" 3\n# EOF\n",
+                "line 2, column 14: syntax error",
+            ),
+            ("metric1 abc\n# EOF\n", "line 1, column 9: syntax error"),
+            (
+                "# TYPE requests counter\nrequests_total -1\n# EOF\n",
+                "line 2: invalid counter value",
+            ),
+            (
+                "metric1{a=\"1\",a=\"2\"} 1\n# EOF\n",
+                "line 1: duplicated label a",
+            ),
+            ("metric1 1\n# EOF\nmetric2 2\n", "line 3: data after # EOF"),
+            ("metric1 1\n", "missing # EOF"),
+        ] {
+            match parse(input, &strict()) {
+                Err(common::AgentError::ParseError(x)) => assert_eq!(x, msg),
+                x => panic!("unexpected {:?}", x),
+            }
+        }
+    }
+    #[test]
+    fn test_format_from_content_type() {
+        for (ct, expected) in [
+            (
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+                Format::OpenMetrics,
+            ),
+            (
+                "text/plain; version=0.0.4; charset=utf-8",
+                Format::Prometheus,
+            ),
+            ("text/html", Format::Prometheus),
+            ("", Format::Prometheus),
+        ] {
+            assert_eq!(Format::from_content_type(ct), expected);
+        }
+    }
+    #[test]
+    fn test_parse_prometheus() {
+        let input = r#"# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200",} 1027 1395066363000
+http_requests_total { method = "post", code = "400" } 3 1395066363000
+# A comment
+  # TYPE rpc_duration_seconds summary
+rpc_duration_seconds{quantile="0.5"} 4773
+rpc_duration_seconds_sum 1.7560473e+07
+rpc_duration_seconds_count 2693
+# TYPE legacy untyped
+legacy -3
+# UNIT legacy seconds
+# EOF
+"#;
+        let cfg = ParseConfig {
+            trust_timestamps: true,
+            strict: true,
+            format: Format::Prometheus,
+            ..Default::default()
+        };
+        let r = parse(input, &cfg).unwrap();
+        assert_eq!(r.len(), 6);
+        assert_eq!(r[0].name, "http_requests_total");
+        assert_eq!(r[0].help, "The total number of HTTP requests.");
+        assert_eq!(r[0].value, Value::Counter(1027));
+        assert_eq!(
+            r[0].labels,
+            Labels::new(vec![
+                Label::new("method", "post"),
+                Label::new("code", "200")
+            ])
+        );
+        assert_eq!(r[0].timestamp, Some(1395066363));
+        assert_eq!(
+            r[1].labels,
+            Labels::new(vec![
+                Label::new("method", "post"),
+                Label::new("code", "400")
+            ])
+        );
+        assert_eq!(r[2].value, Value::Gauge(4773));
+        assert_eq!(r[3].name, "rpc_duration_seconds_sum");
+        assert_eq!(r[4].value, Value::Gauge(2693));
+        assert_eq!(r[5].value, Value::GaugeI(-3));
+    }
+    #[test]
+    fn test_parse_prometheus_strict() {
+        let cfg = ParseConfig {
+            strict: true,
+            format: Format::Prometheus,
+            ..Default::default()
+        };
+        for input in ["m_total 1 # {trace_id=\"1\"} 1\n", "m 1 1.5\n"] {
+            assert!(parse(input, &cfg).is_err(), "{}", input);
+        }
+    }
+    #[test]
+    fn test_parse_prometheus_negative_ts() {
+        let cfg = ParseConfig {
+            trust_timestamps: true,
+            strict: true,
+            format: Format::Prometheus,
+            ..Default::default()
+        };
+        let r = parse("a 1 -1395066363000\nb 2 1395066363000\n", &cfg).unwrap();
+        assert_eq!(r.len(), 2);
+        assert_eq!(r[0].timestamp, None);
+        assert_eq!(r[1].timestamp, Some(1395066363));
+        // Out of i64 range
+        assert!(parse("a 1 -99999999999999999999\n", &cfg).is_err());
+    }
+    #[test]
+    fn test_parser_chunks() {
+        let input = "# HELP m Metric\n# TYPE m gauge\nm{a=\"\u{1F600}\"} 1\nm{a=\"2\"} 2\n# EOF";
+        let expected = parse(input, &strict()).unwrap();
+        assert_eq!(expected.len(), 2);
+        for size in 1..input.len() {
+            let mut parser = Parser::new(&strict());
+            for chunk in input.as_bytes().chunks(size) {
+                parser.feed(chunk).unwrap();
+            }
+            assert_eq!(parser.finish().unwrap(), expected);
+        }
+    }
+    #[test]
+    fn test_parser_invalid_utf8() {
+        let input = b"m 1\nm{a=\"\xff\"} 2\nn 3\n# EOF\n";
+        let mut parser = Parser::new(&ParseConfig::default());
+        parser.feed(input).unwrap();
+        let r = parser.finish().unwrap();
+        assert_eq!(
+            r.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(),
+            vec!["m", "n"]
+        );
+        let mut parser = Parser::new(&strict());
+        match parser.feed(input) {
+            Err(common::AgentError::ParseError(x)) => {
+                assert_eq!(x, "line 2, column 6: invalid UTF-8")
+            }
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+    #[test]
+    fn test_body_size_limit() {
+        let cfg = ParseConfig {
+            body_size_limit: Some(8),
+            ..Default::default()
+        };
+        let mut parser = Parser::new(&cfg);
+        parser.feed(b"m 1\n").unwrap();
+        parser.feed(b"n 2\n").unwrap();
+        match parser.feed(b"o 3\n") {
+            Err(common::AgentError::ParseError(x)) => {
+                assert_eq!(x, "body size limit of 8 bytes exceeded")
+            }
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+    #[test]
+    fn test_sample_limit() {
+        let cfg = ParseConfig {
+            sample_limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(parse("m 1\nn 2\n# EOF\n", &cfg).unwrap().len(), 2);
+        match parse("m 1\nn 2\no 3\n# EOF\n", &cfg) {
+            Err(common::AgentError::ParseError(x)) => assert_eq!(x, "sample limit of 2 exceeded"),
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+    #[test]
+    fn test_exemplar_too_long() {
+        let input = format!(
+            "m_total 1 # {{trace_id=\"{}\"}} 1\n# EOF\n",
+            "x".repeat(128)
+        );
+        assert!(parse(&input, &strict()).is_err());
+        // Exemplar is dropped in lenient mode
+        let r = parse(&input, &ParseConfig::default()).unwrap();
+        assert!(r[0].exemplar.is_none());
+    }
 }
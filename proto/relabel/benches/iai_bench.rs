@@ -0,0 +1,89 @@
+// ------------------------------------------------------------------------
+// Gufo Agent: iai benches for relabeling engine
+// ------------------------------------------------------------------------
+// Copyright (C) 2023, Gufo Labs
+// See LICENSE.md for details
+// ------------------------------------------------------------------------
+
+use common::{Label, Labels, Measure, Value};
+use iai_callgrind::{black_box, main};
+use relabel::{RelabelRuleConfig, RelabelRuleset};
+
+// Number of scrape cycles
+const CYCLES: usize = 5;
+// Number of measures per cycle
+const MEASURES: usize = 100;
+
+const RULES: &str = r#"
+- source_labels: [__name__]
+  regex: "go_.+|process_.+"
+  action: drop
+- source_labels: [__address__]
+  regex: "([^:]+):\\d+"
+  target_label: instance
+- source_labels: [handler]
+  regex: "/api/v(\\d+)/.*"
+  replacement: "v$1"
+  target_label: api_version
+- action: labeldrop
+  regex: "pod_template_hash|controller_revision_hash"
+- source_labels: [code]
+  regex: "(\\d)\\d\\d"
+  replacement: "${1}xx"
+  target_label: code_class
+"#;
+
+#[export_name = "bench::get_ruleset"]
+#[inline(never)]
+pub fn get_ruleset(cache_size: usize) -> RelabelRuleset {
+    let cfg = serde_yaml::from_str::<Vec<RelabelRuleConfig>>(RULES).unwrap();
+    let mut ruleset = RelabelRuleset::try_from(&cfg).unwrap();
+    ruleset.set_cache_size(cache_size);
+    ruleset
+}
+
+#[export_name = "bench::get_measures"]
+#[inline(never)]
+pub fn get_measures() -> Vec<Measure> {
+    (0..MEASURES)
+        .map(|i| Measure {
+            name: "http_requests_total".into(),
+            help: "Total requests".into(),
+            value: Value::Counter(i as u64),
+            labels: Labels::new(vec![
+                Label::new("code", 200 + i % 5),
+                Label::new("handler", format!("/api/v{}/items/{}", i % 3, i)),
+                Label::new("pod_template_hash", "5d8c9f7b6"),
+            ]),
+            timestamp: None,
+            exemplar: None,
+        })
+        .collect()
+}
+
+fn process_measures(cache_size: usize) {
+    let ruleset = get_ruleset(cache_size);
+    let measures = get_measures();
+    let agent_labels = Labels::new(vec![Label::new("host", "node1")]);
+    let collector_labels = Labels::new(vec![Label::new("__address__", "10.0.0.1:9100")]);
+    for _ in 0..CYCLES {
+        for measure in measures.iter() {
+            let _ = black_box(ruleset.process(&agent_labels, &collector_labels, measure));
+        }
+    }
+}
+
+#[inline(never)]
+pub fn process_uncached() {
+    process_measures(0);
+}
+
+#[inline(never)]
+pub fn process_cached() {
+    process_measures(1024);
+}
+
+main!(
+    callgrind_args = "toggle-collect=bench::get_ruleset", "toggle-collect=bench::get_measures";
+    functions = process_uncached, process_cached
+);
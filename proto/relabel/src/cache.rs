@@ -0,0 +1,166 @@
+// --------------------------------------------------------------------
+// Gufo Agent: Relabeling cache
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use common::Labels;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+// Resulting name and labels, None if dropped
+pub(crate) type CacheResult = Option<(String, Labels)>;
+
+// Bounded cache of relabeling results, keyed by the incoming label set.
+// Two generations are kept: when the current generation is full,
+// it becomes the previous one and the oldest one is discarded.
+// Entries hit in the previous generation are promoted to the current one,
+// so the label sets repeating between cycles are kept.
+#[derive(Debug)]
+pub(crate) struct RelabelCache {
+    capacity: usize,
+    current: HashMap<u64, CacheEntry>,
+    previous: HashMap<u64, CacheEntry>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    name: String,
+    agent_labels: Labels,
+    collector_labels: Labels,
+    labels: Labels,
+    result: CacheResult,
+}
+
+// Incoming label set
+pub(crate) struct CacheKey<'a> {
+    pub name: &'a str,
+    pub agent_labels: &'a Labels,
+    pub collector_labels: &'a Labels,
+    pub labels: &'a Labels,
+}
+
+impl CacheKey<'_> {
+    fn hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.name.hash(&mut hasher);
+        self.agent_labels.hash(&mut hasher);
+        self.collector_labels.hash(&mut hasher);
+        self.labels.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl CacheEntry {
+    fn is_match(&self, key: &CacheKey) -> bool {
+        self.name == key.name
+            && self.labels == *key.labels
+            && self.collector_labels == *key.collector_labels
+            && self.agent_labels == *key.agent_labels
+    }
+}
+
+impl RelabelCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        RelabelCache {
+            capacity,
+            current: HashMap::new(),
+            previous: HashMap::new(),
+        }
+    }
+    pub(crate) fn get(&mut self, key: &CacheKey) -> Option<CacheResult> {
+        let hash = key.hash();
+        if let Some(entry) = self.current.get(&hash) {
+            if entry.is_match(key) {
+                return Some(entry.result.clone());
+            }
+            return None;
+        }
+        // Promote from the previous generation
+        let entry = self.previous.remove(&hash)?;
+        if !entry.is_match(key) {
+            return None;
+        }
+        let result = entry.result.clone();
+        self.insert_entry(hash, entry);
+        Some(result)
+    }
+    pub(crate) fn insert(&mut self, key: &CacheKey, result: CacheResult) {
+        self.insert_entry(
+            key.hash(),
+            CacheEntry {
+                name: key.name.to_owned(),
+                agent_labels: key.agent_labels.clone(),
+                collector_labels: key.collector_labels.clone(),
+                labels: key.labels.clone(),
+                result,
+            },
+        );
+    }
+    fn insert_entry(&mut self, hash: u64, entry: CacheEntry) {
+        if self.current.len() >= self.capacity {
+            self.previous = std::mem::take(&mut self.current);
+        }
+        self.current.insert(hash, entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheKey, RelabelCache};
+    use common::{Label, Labels};
+
+    fn key<'a>(name: &'a str, labels: &'a Labels, empty: &'a Labels) -> CacheKey<'a> {
+        CacheKey {
+            name,
+            agent_labels: empty,
+            collector_labels: empty,
+            labels,
+        }
+    }
+
+    #[test]
+    fn test_get() {
+        let empty = Labels::default();
+        let labels = Labels::new(vec![Label::new("a", "1")]);
+        let mut cache = RelabelCache::new(10);
+        assert!(cache.get(&key("m", &labels, &empty)).is_none());
+        cache.insert(&key("m", &labels, &empty), None);
+        assert_eq!(cache.get(&key("m", &labels, &empty)), Some(None));
+        // Other name
+        assert!(cache.get(&key("n", &labels, &empty)).is_none());
+    }
+    #[test]
+    fn test_bounded() {
+        let empty = Labels::default();
+        let sets: Vec<Labels> = (0..5)
+            .map(|i| Labels::new(vec![Label::new("a", i)]))
+            .collect();
+        let mut cache = RelabelCache::new(2);
+        for labels in sets.iter() {
+            cache.insert(&key("m", labels, &empty), None);
+        }
+        // Oldest are discarded
+        assert!(cache.get(&key("m", &sets[0], &empty)).is_none());
+        assert!(cache.get(&key("m", &sets[4], &empty)).is_some());
+    }
+    #[test]
+    fn test_promote() {
+        let empty = Labels::default();
+        let sets: Vec<Labels> = (0..4)
+            .map(|i| Labels::new(vec![Label::new("a", i)]))
+            .collect();
+        let mut cache = RelabelCache::new(2);
+        cache.insert(&key("m", &sets[0], &empty), None);
+        cache.insert(&key("m", &sets[1], &empty), None);
+        // sets[0] and sets[1] are moved to the previous generation
+        cache.insert(&key("m", &sets[2], &empty), None);
+        // Promote sets[0]
+        assert!(cache.get(&key("m", &sets[0], &empty)).is_some());
+        // Rotate again, sets[1] is discarded
+        cache.insert(&key("m", &sets[3], &empty), None);
+        assert!(cache.get(&key("m", &sets[1], &empty)).is_none());
+        assert!(cache.get(&key("m", &sets[0], &empty)).is_some());
+    }
+}
@@ -24,6 +24,18 @@ pub struct RelabelRuleConfig {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub target_label: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub modulus: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_eq: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_gt: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value_lt: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_zero: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub action: Option<String>,
 }
 
@@ -4,13 +4,14 @@
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
 
-use super::{ActionResult, ActiveLabels, Eval, RelabelRuleConfig, Relabeler};
+use super::{ActionResult, ActiveLabels, Eval, RelabelRuleConfig, Relabeler, ValuePredicate};
 use common::{AgentError, AgentResult};
 
 // Drops matched label
 #[derive(Debug)]
 pub(crate) struct DropRule {
     eval: Eval,
+    predicate: Option<ValuePredicate>,
 }
 
 impl TryFrom<&RelabelRuleConfig> for DropRule {
@@ -27,18 +28,37 @@ impl TryFrom<&RelabelRuleConfig> for DropRule {
         }
         // Parse
         let eval = Eval::try_from(value)?;
-        // source_labels must be set
-        eval.require_source_labels()?;
+        let predicate = ValuePredicate::from_config(value);
+        // source_labels must be set, unless matched by value
+        if predicate.is_none() {
+            eval.require_source_labels()?;
+        }
         //
-        Ok(DropRule { eval })
+        Ok(DropRule { eval, predicate })
     }
 }
 
 impl Relabeler for DropRule {
     fn apply(&self, active_labels: &mut ActiveLabels) -> AgentResult<ActionResult> {
-        match self.eval.apply(active_labels) {
-            Some(_) => Ok(ActionResult::Drop),
-            None => Ok(ActionResult::Pass),
+        match self.is_match(active_labels) {
+            true => Ok(ActionResult::Drop),
+            false => Ok(ActionResult::Pass),
+        }
+    }
+}
+
+impl DropRule {
+    pub(crate) fn has_predicate(&self) -> bool {
+        self.predicate.is_some()
+    }
+    // Both labels and value must match
+    fn is_match(&self, active_labels: &ActiveLabels) -> bool {
+        match &self.predicate {
+            Some(predicate) => {
+                predicate.is_match(active_labels)
+                    && (self.eval.is_empty() || self.eval.apply(active_labels).is_some())
+            }
+            None => self.eval.apply(active_labels).is_some(),
         }
     }
 }
@@ -93,4 +113,40 @@ regex: "1;1"
         ]);
         assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
     }
+    #[test]
+    fn test_value() {
+        let yaml = r#"
+action: drop
+is_zero: true
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = DropRule::try_from(&cfg).unwrap();
+        let mut labels = ActiveLabels::new(vec![Label::new("a", "1")]);
+        labels.set_value(0.0);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Drop);
+        labels.set_value(2.0);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        // No value
+        let mut labels = ActiveLabels::new(vec![Label::new("a", "1")]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+    }
+    #[test]
+    fn test_value_and_labels() {
+        let yaml = r#"
+action: drop
+source_labels: [__name__]
+regex: ps_io_.+
+value_gt: 100
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = DropRule::try_from(&cfg).unwrap();
+        let mut labels = ActiveLabels::new(vec![Label::new("__name__", "ps_io_read")]);
+        labels.set_value(101.0);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Drop);
+        labels.set_value(100.0);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        let mut labels = ActiveLabels::new(vec![Label::new("__name__", "ps_cpu")]);
+        labels.set_value(101.0);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+    }
 }
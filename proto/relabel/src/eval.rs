@@ -33,11 +33,7 @@ impl TryFrom<&RelabelRuleConfig> for Eval {
             }
             None => None,
         };
-        // Rewrite $1 -> ${1}
-        let replacement = value.replacement.clone().map(|x| {
-            let cap_rx = CAP_RX.get_or_init(|| Regex::new("(^|[^$])\\$(\\d+)").unwrap());
-            cap_rx.replace_all(&x, "${1}$${${2}}").to_string()
-        });
+        let replacement = value.replacement.as_deref().map(expand_replacement);
         //
         Ok(Eval {
             source_labels,
@@ -48,6 +44,12 @@ impl TryFrom<&RelabelRuleConfig> for Eval {
     }
 }
 
+// Rewrite $1 -> ${1}, so `$1_x` is not considered as named group `1_x`
+pub(crate) fn expand_replacement(x: &str) -> String {
+    let cap_rx = CAP_RX.get_or_init(|| Regex::new("(^|[^$])\\$(\\d+)").unwrap());
+    cap_rx.replace_all(x, "${1}$${${2}}").to_string()
+}
+
 impl Eval {
     pub(crate) fn require_source_labels(&self) -> AgentResult<()> {
         if self.source_labels.is_empty() {
@@ -57,6 +59,10 @@ impl Eval {
         }
         Ok(())
     }
+    // No source labels are set
+    pub(crate) fn is_empty(&self) -> bool {
+        self.source_labels.is_empty()
+    }
     // Resulting string, if match. None otherwise
     pub(crate) fn apply(&self, labels: &ActiveLabels) -> Option<String> {
         if self.source_labels.is_empty() {
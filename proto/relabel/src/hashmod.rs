@@ -0,0 +1,143 @@
+// --------------------------------------------------------------------
+// Gufo Agent: HashMod Rule
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use super::{ActionResult, ActiveLabels, RelabelRuleConfig, Relabeler};
+use common::{AgentError, AgentResult, Label};
+
+// Sets target_label to the modulus of the hash of the source labels.
+// Prometheus-compatible: md5 of the joined values,
+// last 8 bytes as big-endian u64.
+#[derive(Debug)]
+pub(crate) struct HashModRule {
+    source_labels: Vec<String>,
+    separator: String,
+    modulus: u64,
+    target_label: String,
+}
+
+impl TryFrom<&RelabelRuleConfig> for HashModRule {
+    type Error = AgentError;
+
+    fn try_from(value: &RelabelRuleConfig) -> Result<Self, Self::Error> {
+        // action must be `hashmod`
+        if let Some(x) = &value.action {
+            if x != "hashmod" {
+                return Err(AgentError::ConfigurationError(
+                    "'action' must be 'hashmod'".to_string(),
+                ));
+            }
+        }
+        let source_labels = match &value.source_labels {
+            Some(x) if !x.is_empty() => x.clone(),
+            _ => {
+                return Err(AgentError::ConfigurationError(
+                    "'source_labels' must be set".to_string(),
+                ))
+            }
+        };
+        let modulus = match value.modulus {
+            Some(x) if x > 0 => x,
+            _ => {
+                return Err(AgentError::ConfigurationError(
+                    "'modulus' must be set and greater than 0".to_string(),
+                ))
+            }
+        };
+        let target_label = match &value.target_label {
+            Some(x) => x.clone(),
+            None => {
+                return Err(AgentError::ConfigurationError(
+                    "'target_label' must be set".to_string(),
+                ))
+            }
+        };
+        Ok(HashModRule {
+            source_labels,
+            separator: value.separator.clone(),
+            modulus,
+            target_label,
+        })
+    }
+}
+
+impl Relabeler for HashModRule {
+    fn apply(&self, active_labels: &mut ActiveLabels) -> AgentResult<ActionResult> {
+        let value = active_labels.join(&self.source_labels, &self.separator);
+        let digest = md5::compute(value.as_bytes());
+        let mut buf = [0u8; 8];
+        buf.copy_from_slice(&digest[8..]);
+        let r = u64::from_be_bytes(buf) % self.modulus;
+        active_labels.insert(Label::new(self.target_label.clone(), r));
+        Ok(ActionResult::Pass)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActionResult, ActiveLabels, HashModRule, RelabelRuleConfig, Relabeler};
+    use common::Label;
+
+    #[test]
+    fn test_invalid_action() {
+        let yaml = r#"action: drop_something"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        assert!(HashModRule::try_from(&cfg).is_err());
+    }
+    #[test]
+    fn test_no_modulus() {
+        let yaml = r#"
+action: hashmod
+source_labels: [a]
+target_label: b
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        assert!(HashModRule::try_from(&cfg).is_err());
+    }
+    #[test]
+    fn test_no_target_label() {
+        let yaml = r#"
+action: hashmod
+source_labels: [a]
+modulus: 10
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        assert!(HashModRule::try_from(&cfg).is_err());
+    }
+    // Prometheus' relabel_test.go
+    #[test]
+    fn test_hashmod() {
+        let yaml = r#"
+action: hashmod
+source_labels: [c]
+target_label: d
+modulus: 1000
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = HashModRule::try_from(&cfg).unwrap();
+        let mut labels = ActiveLabels::new(vec![
+            Label::new("a", "foo"),
+            Label::new("b", "bar"),
+            Label::new("c", "baz"),
+        ]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("d").unwrap(), "976");
+    }
+    // Prometheus' relabel_test.go
+    #[test]
+    fn test_hashmod_newline() {
+        let yaml = r#"
+action: hashmod
+source_labels: [a]
+target_label: b
+modulus: 1000
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = HashModRule::try_from(&cfg).unwrap();
+        let mut labels = ActiveLabels::new(vec![Label::new("a", "foo\nbar")]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("b").unwrap(), "734");
+    }
+}
@@ -4,13 +4,14 @@
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
 
-use super::{ActionResult, ActiveLabels, Eval, RelabelRuleConfig, Relabeler};
+use super::{ActionResult, ActiveLabels, Eval, RelabelRuleConfig, Relabeler, ValuePredicate};
 use common::{AgentError, AgentResult};
 
 // Keeps matched label
 #[derive(Debug)]
 pub(crate) struct KeepRule {
     eval: Eval,
+    predicate: Option<ValuePredicate>,
 }
 
 impl TryFrom<&RelabelRuleConfig> for KeepRule {
@@ -27,17 +28,36 @@ impl TryFrom<&RelabelRuleConfig> for KeepRule {
         }
         // Parse
         let eval = Eval::try_from(value)?;
-        // source_labels must be set
-        eval.require_source_labels()?;
-        Ok(KeepRule { eval })
+        let predicate = ValuePredicate::from_config(value);
+        // source_labels must be set, unless matched by value
+        if predicate.is_none() {
+            eval.require_source_labels()?;
+        }
+        Ok(KeepRule { eval, predicate })
     }
 }
 
 impl Relabeler for KeepRule {
     fn apply(&self, active_labels: &mut ActiveLabels) -> AgentResult<ActionResult> {
-        match self.eval.apply(active_labels) {
-            Some(_) => Ok(ActionResult::Pass),
-            None => Ok(ActionResult::Drop),
+        match self.is_match(active_labels) {
+            true => Ok(ActionResult::Pass),
+            false => Ok(ActionResult::Drop),
+        }
+    }
+}
+
+impl KeepRule {
+    pub(crate) fn has_predicate(&self) -> bool {
+        self.predicate.is_some()
+    }
+    // Both labels and value must match
+    fn is_match(&self, active_labels: &ActiveLabels) -> bool {
+        match &self.predicate {
+            Some(predicate) => {
+                predicate.is_match(active_labels)
+                    && (self.eval.is_empty() || self.eval.apply(active_labels).is_some())
+            }
+            None => self.eval.apply(active_labels).is_some(),
         }
     }
 }
@@ -92,4 +112,21 @@ regex: "1;1"
         ]);
         assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Drop);
     }
+    #[test]
+    fn test_value() {
+        let yaml = r#"
+action: keep
+value_gt: 0
+value_lt: 1000
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = KeepRule::try_from(&cfg).unwrap();
+        let mut labels = ActiveLabels::new(vec![Label::new("a", "1")]);
+        labels.set_value(10.0);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        labels.set_value(0.0);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Drop);
+        labels.set_value(1000.0);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Drop);
+    }
 }
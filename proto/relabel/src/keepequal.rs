@@ -0,0 +1,123 @@
+// --------------------------------------------------------------------
+// Gufo Agent: keepequal Rule
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use super::{ActionResult, ActiveLabels, RelabelRuleConfig, Relabeler};
+use common::{AgentError, AgentResult};
+
+// Drops measure when target_label differs from the joined source labels
+#[derive(Debug)]
+pub(crate) struct KeepEqualRule {
+    source_labels: Vec<String>,
+    separator: String,
+    target_label: String,
+}
+
+impl TryFrom<&RelabelRuleConfig> for KeepEqualRule {
+    type Error = AgentError;
+
+    fn try_from(value: &RelabelRuleConfig) -> Result<Self, Self::Error> {
+        // action must be `keepequal`
+        if let Some(x) = &value.action {
+            if x != "keepequal" {
+                return Err(AgentError::ConfigurationError(
+                    "'action' must be 'keepequal'".to_string(),
+                ));
+            }
+        }
+        let source_labels = match &value.source_labels {
+            Some(x) if !x.is_empty() => x.clone(),
+            _ => {
+                return Err(AgentError::ConfigurationError(
+                    "'source_labels' must be set".to_string(),
+                ))
+            }
+        };
+        let target_label = match &value.target_label {
+            Some(x) => x.clone(),
+            None => {
+                return Err(AgentError::ConfigurationError(
+                    "'target_label' must be set".to_string(),
+                ))
+            }
+        };
+        Ok(KeepEqualRule {
+            source_labels,
+            separator: value.separator.clone(),
+            target_label,
+        })
+    }
+}
+
+impl Relabeler for KeepEqualRule {
+    fn apply(&self, active_labels: &mut ActiveLabels) -> AgentResult<ActionResult> {
+        let value = active_labels.join(&self.source_labels, &self.separator);
+        let target = active_labels
+            .get(&self.target_label)
+            .map(|x| x.as_str())
+            .unwrap_or_default();
+        Ok(if value == target {
+            ActionResult::Pass
+        } else {
+            ActionResult::Drop
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActionResult, ActiveLabels, KeepEqualRule, RelabelRuleConfig, Relabeler};
+    use common::Label;
+
+    fn get_labels() -> ActiveLabels {
+        ActiveLabels::new(vec![
+            Label::new("__tmp_port", "1234"),
+            Label::new("__port1", "1234"),
+            Label::new("__port2", "5678"),
+        ])
+    }
+
+    #[test]
+    fn test_invalid_action() {
+        let yaml = r#"action: drop_something"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        assert!(KeepEqualRule::try_from(&cfg).is_err());
+    }
+    #[test]
+    fn test_no_target_label() {
+        let yaml = r#"
+action: keepequal
+source_labels: [__tmp_port]
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        assert!(KeepEqualRule::try_from(&cfg).is_err());
+    }
+    // Prometheus' relabel_test.go
+    #[test]
+    fn test_equal() {
+        let yaml = r#"
+action: keepequal
+source_labels: [__tmp_port]
+target_label: __port1
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = KeepEqualRule::try_from(&cfg).unwrap();
+        let mut labels = get_labels();
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+    }
+    // Prometheus' relabel_test.go
+    #[test]
+    fn test_not_equal() {
+        let yaml = r#"
+action: keepequal
+source_labels: [__tmp_port]
+target_label: __port2
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = KeepEqualRule::try_from(&cfg).unwrap();
+        let mut labels = get_labels();
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Drop);
+    }
+}
@@ -4,11 +4,13 @@
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
 
-use super::{ActionResult, ActiveLabels, RelabelRuleConfig, Relabeler};
+use super::{eval::expand_replacement, ActionResult, ActiveLabels, RelabelRuleConfig, Relabeler};
 use common::{AgentError, AgentResult};
 use regex::Regex;
 
-// Keeps matched label
+const DEFAULT_REPLACEMENT: &str = "$1";
+
+// Copies matched labels to the new names
 #[derive(Debug)]
 pub(crate) struct LabelMapRule {
     regex: Regex,
@@ -37,21 +39,15 @@ impl TryFrom<&RelabelRuleConfig> for LabelMapRule {
                 ))
             }
         };
-        let replacement = match &value.replacement {
-            Some(x) => x.clone(),
-            None => {
-                return Err(AgentError::ConfigurationError(
-                    "'replacement' must be set".to_string(),
-                ))
-            }
-        };
+        let replacement =
+            expand_replacement(value.replacement.as_deref().unwrap_or(DEFAULT_REPLACEMENT));
         Ok(LabelMapRule { regex, replacement })
     }
 }
 
 impl Relabeler for LabelMapRule {
     fn apply(&self, active_labels: &mut ActiveLabels) -> AgentResult<ActionResult> {
-        active_labels.rename_if(|k| {
+        active_labels.copy_if(|k| {
             self.regex.captures(k.as_str()).map(|caps| {
                 let mut x = String::new();
                 caps.expand(self.replacement.as_str(), &mut x);
@@ -88,9 +84,64 @@ mod tests {
             Label::new("c", "3"),
         ]);
         assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
-        assert!(labels.get("a").is_none());
+        assert_eq!(labels.get("a").unwrap(), "1");
         assert_eq!(labels.get("b").unwrap(), "2");
         assert_eq!(labels.get("c").unwrap(), "3");
         assert_eq!(labels.get("d").unwrap(), "1");
     }
+    // Prometheus' relabel_test.go
+    #[test]
+    fn test_replacement() {
+        let yaml = r#"
+        action: labelmap
+        regex: (b.*)
+        replacement: bar_${1}
+        "#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = LabelMapRule::try_from(&cfg).unwrap();
+        let mut labels = ActiveLabels::new(vec![
+            Label::new("a", "foo"),
+            Label::new("b", "bar"),
+            Label::new("c", "baz"),
+        ]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("a").unwrap(), "foo");
+        assert_eq!(labels.get("b").unwrap(), "bar");
+        assert_eq!(labels.get("bar_b").unwrap(), "bar");
+        assert_eq!(labels.get("c").unwrap(), "baz");
+    }
+    #[test]
+    fn test_replacement_unbraced() {
+        let yaml = r#"
+        action: labelmap
+        regex: (b.*)
+        replacement: $1_x
+        "#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = LabelMapRule::try_from(&cfg).unwrap();
+        let mut labels = ActiveLabels::new(vec![Label::new("b", "bar")]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("b_x").unwrap(), "bar");
+    }
+    // Prometheus' relabel_test.go
+    #[test]
+    fn test_default_replacement() {
+        let yaml = r#"
+        action: labelmap
+        regex: __meta_(my.*)
+        "#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = LabelMapRule::try_from(&cfg).unwrap();
+        let mut labels = ActiveLabels::new(vec![
+            Label::new("__meta_my_bar", "aaa"),
+            Label::new("__meta_my_baz", "bbb"),
+            Label::new("__meta_other", "ccc"),
+        ]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("my_bar").unwrap(), "aaa");
+        assert_eq!(labels.get("my_baz").unwrap(), "bbb");
+        assert!(labels.get("other").is_none());
+        // Virtual labels are kept
+        assert_eq!(labels.get("__meta_my_bar").unwrap(), "aaa");
+    }
 }
@@ -14,6 +14,8 @@ const META_PREFIX: &str = "__meta_";
 #[derive(Default, Debug, Clone)]
 pub struct ActiveLabels {
     labels: BTreeMap<String, String>,
+    // Measure's value, if relabeling the measure
+    value: Option<f64>,
 }
 
 impl TryFrom<&Labels> for ActiveLabels {
@@ -58,6 +60,14 @@ impl ActiveLabels {
         self.labels.get(&name.to_string())
     }
     #[inline]
+    pub fn set_value(&mut self, value: f64) {
+        self.value = Some(value);
+    }
+    #[inline]
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+    #[inline]
     pub(crate) fn is_virtual(name: &String) -> bool {
         name == NAME_LABEL || name == ADDRESS_LABEL || name.starts_with(META_PREFIX)
     }
@@ -66,21 +76,25 @@ impl ActiveLabels {
             Some(x) => x,
             None => &measure.name, // __name__ has been dropped by rule
         };
-        let labels = Labels::new(
-            self.labels
-                .iter()
-                .filter(|k| !Self::is_virtual(k.0))
-                .map(|(k, v)| Label::new(k, v))
-                .collect(),
-        );
         Measure {
             name: name.into(),
             help: measure.help.to_owned(),
             value: measure.value,
-            labels,
+            labels: self.to_labels(),
             timestamp: measure.timestamp,
+            exemplar: measure.exemplar.clone(),
         }
     }
+    // Resulting labels, without virtual ones
+    pub fn to_labels(&self) -> Labels {
+        Labels::new(
+            self.labels
+                .iter()
+                .filter(|k| !Self::is_virtual(k.0))
+                .map(|(k, v)| Label::new(k, v))
+                .collect(),
+        )
+    }
     // Leave only virtual labels and labels matching function
     pub(crate) fn retain<F>(&mut self, f: F)
     where
@@ -89,38 +103,31 @@ impl ActiveLabels {
         self.labels
             .retain(|name, _| Self::is_virtual(name) || f(name));
     }
-    // Rename label. Keep virtual labels
-    pub(crate) fn rename(&mut self, src: String, dst: String) {
-        if let Some(value) = self.labels.get(&src) {
-            if Self::is_virtual(&src) {
-                // Copy
-                self.labels.insert(dst, value.to_owned());
-            } else {
-                // Move
-                if let Some(v) = self.labels.remove(&src) {
-                    // Always Some because of .get()
-                    // value is owned, so we need no .clone()
-                    self.labels.insert(dst, v);
-                }
-            }
-        }
-    }
-    // Rename label if function returns Some
-    pub(crate) fn rename_if<F>(&mut self, f: F)
+    // Copy label value to the new name if function returns Some.
+    // Source labels are kept
+    pub(crate) fn copy_if<F>(&mut self, f: F)
     where
         F: Fn(&String) -> Option<String>,
     {
-        let mut remap = Vec::with_capacity(self.labels.len());
-        for k in self.labels.keys() {
-            if let Some(new_name) = f(k) {
-                remap.push((k.to_owned(), new_name.to_owned()));
-            }
-        }
-        // Actual renaming
-        for (src, dst) in remap.drain(..) {
-            self.rename(src, dst);
+        let remap: Vec<(String, String)> = self
+            .labels
+            .iter()
+            .filter_map(|(k, v)| f(k).map(|new_name| (new_name, v.to_owned())))
+            .collect();
+        // Actual copying
+        for (dst, value) in remap.into_iter() {
+            self.labels.insert(dst, value);
         }
     }
+    // Join values of labels with separator.
+    // Missed labels are considered empty.
+    pub(crate) fn join(&self, names: &[String], separator: &str) -> String {
+        names
+            .iter()
+            .map(|n| self.labels.get(n).map(|x| x.as_str()).unwrap_or_default())
+            .collect::<Vec<&str>>()
+            .join(separator)
+    }
     pub(crate) fn iter(&self) -> std::collections::btree_map::Iter<String, String> {
         self.labels.iter()
     }
@@ -4,28 +4,48 @@
 // Copyright (C) 2021-2023, Gufo Labs
 // --------------------------------------------------------------------
 
+pub(crate) mod cache;
 pub(crate) mod config;
 pub(crate) mod drop;
 pub(crate) mod drop_if_equal;
+pub(crate) mod dropequal;
 pub(crate) mod dump;
 pub(crate) mod eval;
+pub(crate) mod hashmod;
 pub(crate) mod keep;
+pub(crate) mod keep_if_equal;
+pub(crate) mod keepequal;
 pub(crate) mod labeldrop;
 pub(crate) mod labelkeep;
 pub(crate) mod labelmap;
 pub(crate) mod labels;
+pub(crate) mod lookup;
+pub(crate) mod lowercase;
+pub(crate) mod predicate;
 pub(crate) mod replace;
 pub(crate) mod ruleset;
+pub(crate) mod trace;
+pub(crate) mod uppercase;
 
+pub(crate) use cache::{CacheKey, RelabelCache};
 pub use config::RelabelRuleConfig;
 pub(crate) use drop::DropRule;
 pub(crate) use drop_if_equal::DropIfEqualRule;
+pub(crate) use dropequal::DropEqualRule;
 pub(crate) use dump::DumpRule;
 pub(crate) use eval::Eval;
+pub(crate) use hashmod::HashModRule;
 pub(crate) use keep::KeepRule;
+pub(crate) use keep_if_equal::KeepIfEqualRule;
+pub(crate) use keepequal::KeepEqualRule;
 pub(crate) use labeldrop::LabelDropRule;
 pub(crate) use labelkeep::LabelKeepRule;
 pub(crate) use labelmap::LabelMapRule;
 pub use labels::ActiveLabels;
+pub(crate) use lookup::LookupRule;
+pub(crate) use lowercase::LowercaseRule;
+pub(crate) use predicate::ValuePredicate;
 pub(crate) use replace::ReplaceRule;
 pub use ruleset::{ActionResult, RelabelRuleset, Relabeler};
+pub use trace::{LabelChange, TraceStep};
+pub(crate) use uppercase::UppercaseRule;
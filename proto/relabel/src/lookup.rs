@@ -0,0 +1,404 @@
+// --------------------------------------------------------------------
+// Gufo Agent: Lookup Rule
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use super::{ActionResult, ActiveLabels, RelabelRuleConfig, Relabeler};
+use common::{AgentError, AgentResult, Label};
+use serde_yaml::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// Interval between file change checks
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+type SharedTable = Arc<RwLock<LookupTable>>;
+// File path and target label
+type TableKey = (String, Option<String>);
+
+// Loaded tables.
+// Rules referring to the same file share the table and its reloader.
+static TABLES: OnceLock<Mutex<HashMap<TableKey, Weak<RwLock<LookupTable>>>>> = OnceLock::new();
+
+// Sets labels from the lookup table, matched by the joined source labels.
+// The table is reloaded by the background thread, which exits
+// when the last rule using the table is dropped.
+#[derive(Debug)]
+pub(crate) struct LookupRule {
+    source_labels: Vec<String>,
+    separator: String,
+    table: SharedTable,
+}
+
+#[derive(Debug)]
+struct LookupTable {
+    data: HashMap<String, Vec<Label>>,
+    // File modification time and size
+    version: Option<(SystemTime, u64)>,
+}
+
+impl TryFrom<&RelabelRuleConfig> for LookupRule {
+    type Error = AgentError;
+
+    fn try_from(value: &RelabelRuleConfig) -> Result<Self, Self::Error> {
+        Self::new(value, RELOAD_INTERVAL)
+    }
+}
+
+impl Relabeler for LookupRule {
+    fn apply(&self, active_labels: &mut ActiveLabels) -> AgentResult<ActionResult> {
+        let key = active_labels.join(&self.source_labels, &self.separator);
+        let table = self
+            .table
+            .read()
+            .map_err(|e| AgentError::InternalError(e.to_string()))?;
+        if let Some(labels) = table.data.get(&key) {
+            for label in labels.iter() {
+                active_labels.insert(label.clone());
+            }
+        }
+        Ok(ActionResult::Pass)
+    }
+}
+
+impl LookupRule {
+    fn new(value: &RelabelRuleConfig, reload_interval: Duration) -> AgentResult<Self> {
+        // action must be `lookup`
+        if let Some(x) = &value.action {
+            if x != "lookup" {
+                return Err(AgentError::ConfigurationError(
+                    "'action' must be 'lookup'".to_string(),
+                ));
+            }
+        }
+        let source_labels = match &value.source_labels {
+            Some(x) if !x.is_empty() => x.clone(),
+            _ => {
+                return Err(AgentError::ConfigurationError(
+                    "'source_labels' must be set".to_string(),
+                ))
+            }
+        };
+        let path = match &value.file {
+            Some(x) => x.clone(),
+            None => {
+                return Err(AgentError::ConfigurationError(
+                    "'file' must be set".to_string(),
+                ))
+            }
+        };
+        Ok(LookupRule {
+            source_labels,
+            separator: value.separator.clone(),
+            table: shared_table(path, value.target_label.clone(), reload_interval)?,
+        })
+    }
+}
+
+// Get the table already used by other rules,
+// or load it and start the reloader thread.
+fn shared_table(
+    path: String,
+    target_label: Option<String>,
+    reload_interval: Duration,
+) -> AgentResult<SharedTable> {
+    let mut tables = TABLES
+        .get_or_init(Default::default)
+        .lock()
+        .map_err(|e| AgentError::InternalError(e.to_string()))?;
+    // Forget tables of the dropped rules
+    tables.retain(|_, weak| weak.strong_count() > 0);
+    let key = (path, target_label);
+    if let Some(table) = tables.get(&key).and_then(Weak::upgrade) {
+        // Catch up with changes made since the last check
+        reload_if_changed(&table, &key.0, key.1.as_deref());
+        return Ok(table);
+    }
+    let (path, target_label) = key.clone();
+    let table = Arc::new(RwLock::new(LookupTable {
+        version: file_version(&path),
+        data: load(&path, target_label.as_deref())?,
+    }));
+    let weak = Arc::downgrade(&table);
+    thread::Builder::new()
+        .name("lookup-reload".into())
+        .spawn(move || {
+            while let Some(table) = sleep_upgrade(&weak, reload_interval) {
+                reload_if_changed(&table, &path, target_label.as_deref());
+            }
+        })
+        .map_err(|e| AgentError::InternalError(e.to_string()))?;
+    tables.insert(key, Arc::downgrade(&table));
+    Ok(table)
+}
+
+// Wait for the next check.
+// Returns None when the rule is dropped.
+fn sleep_upgrade(weak: &Weak<RwLock<LookupTable>>, interval: Duration) -> Option<SharedTable> {
+    thread::sleep(interval);
+    weak.upgrade()
+}
+
+// Reload table when file is changed.
+// The file is read without holding the lock, the table is swapped then.
+// Last good table is kept on errors.
+fn reload_if_changed(table: &RwLock<LookupTable>, path: &str, target_label: Option<&str>) {
+    let version = file_version(path);
+    match table.read() {
+        Ok(t) if version.is_some() && version != t.version => {}
+        _ => return,
+    }
+    match load(path, target_label) {
+        Ok(data) => {
+            log::info!("Lookup table {} is reloaded: {} items", path, data.len());
+            if let Ok(mut t) = table.write() {
+                t.data = data;
+                t.version = version;
+            }
+        }
+        Err(e) => log::error!("Failed to reload lookup table {}: {}", path, e),
+    }
+}
+
+fn file_version(path: &str) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+// Load lookup table.
+// CSV files use the first column as the key, other columns
+// are set as labels named by header.
+// YAML files contain mapping of key to the labels,
+// or key to the value of `target_label`.
+fn load(path: &str, target_label: Option<&str>) -> AgentResult<HashMap<String, Vec<Label>>> {
+    let data = fs::read_to_string(path)
+        .map_err(|e| AgentError::ConfigurationError(format!("cannot read {}: {}", path, e)))?;
+    let is_csv = Path::new(path)
+        .extension()
+        .map(|x| x.eq_ignore_ascii_case("csv"))
+        .unwrap_or(false);
+    if is_csv {
+        load_csv(&data)
+    } else {
+        load_yaml(&data, target_label)
+    }
+}
+
+fn load_csv(data: &str) -> AgentResult<HashMap<String, Vec<Label>>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(data.as_bytes());
+    let headers = rdr
+        .headers()
+        .map_err(|e| AgentError::ConfigurationError(e.to_string()))?
+        .clone();
+    if headers.len() < 2 {
+        return Err(AgentError::ConfigurationError(
+            "lookup table must contain at least two columns".to_string(),
+        ));
+    }
+    let mut r = HashMap::new();
+    for record in rdr.records() {
+        let record = record.map_err(|e| AgentError::ConfigurationError(e.to_string()))?;
+        let mut iter = headers.iter().zip(record.iter());
+        if let Some((_, key)) = iter.next() {
+            r.insert(
+                key.to_string(),
+                iter.filter(|(_, v)| !v.is_empty())
+                    .map(|(k, v)| Label::new(k, v))
+                    .collect(),
+            );
+        }
+    }
+    Ok(r)
+}
+
+fn load_yaml(data: &str, target_label: Option<&str>) -> AgentResult<HashMap<String, Vec<Label>>> {
+    let items: BTreeMap<String, Value> =
+        serde_yaml::from_str(data).map_err(|e| AgentError::ConfigurationError(e.to_string()))?;
+    let mut r = HashMap::with_capacity(items.len());
+    for (key, value) in items.into_iter() {
+        let labels = match value {
+            Value::Mapping(map) => map
+                .iter()
+                .filter_map(|(k, v)| Some(Label::new(scalar(k)?, scalar(v)?)))
+                .collect(),
+            v => match (target_label, scalar(&v)) {
+                (Some(name), Some(v)) => vec![Label::new(name, v)],
+                (None, _) => {
+                    return Err(AgentError::ConfigurationError(
+                        "'target_label' must be set for scalar values".to_string(),
+                    ))
+                }
+                (_, None) => {
+                    return Err(AgentError::ConfigurationError(format!(
+                        "invalid value for {}",
+                        key
+                    )))
+                }
+            },
+        };
+        r.insert(key, labels);
+    }
+    Ok(r)
+}
+
+fn scalar(v: &Value) -> Option<String> {
+    match v {
+        Value::String(x) => Some(x.clone()),
+        Value::Number(x) => Some(x.to_string()),
+        Value::Bool(x) => Some(x.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActionResult, ActiveLabels, LookupRule, RelabelRuleConfig, Relabeler};
+    use common::Label;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn temp_file(name: &str, data: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("relabel-{}-{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn get_rule(path: &Path, extra: &str) -> LookupRule {
+        let yaml = format!(
+            "action: lookup\nsource_labels: [__address__]\nfile: {}\n{}",
+            path.display(),
+            extra
+        );
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(&yaml).unwrap();
+        LookupRule::try_from(&cfg).unwrap()
+    }
+
+    #[test]
+    fn test_invalid_action() {
+        let yaml = r#"action: drop_something"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        assert!(LookupRule::try_from(&cfg).is_err());
+    }
+    #[test]
+    fn test_missed_file() {
+        let yaml = r#"
+action: lookup
+source_labels: [__address__]
+file: /nonexistent/lookup.yml
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        assert!(LookupRule::try_from(&cfg).is_err());
+    }
+    #[test]
+    fn test_yaml() {
+        let path = temp_file(
+            "lookup.yml",
+            r#"
+"10.0.0.1:9100":
+  site: msk
+  rack: 12
+"#,
+        );
+        let rule = get_rule(&path, "");
+        let mut labels = ActiveLabels::new(vec![Label::new("__address__", "10.0.0.1:9100")]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("site").unwrap(), "msk");
+        assert_eq!(labels.get("rack").unwrap(), "12");
+        // Not matched
+        let mut labels = ActiveLabels::new(vec![Label::new("__address__", "10.0.0.2:9100")]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert!(labels.get("site").is_none());
+        fs::remove_file(path).unwrap();
+    }
+    #[test]
+    fn test_yaml_scalar() {
+        let path = temp_file("lookup-scalar.yml", "\"10.0.0.1:9100\": web\n");
+        let rule = get_rule(&path, "target_label: role\n");
+        let mut labels = ActiveLabels::new(vec![Label::new("__address__", "10.0.0.1:9100")]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("role").unwrap(), "web");
+        fs::remove_file(path).unwrap();
+    }
+    #[test]
+    fn test_csv() {
+        let path = temp_file(
+            "lookup.csv",
+            "address,site,owner\n10.0.0.1:9100,msk,\"Ops, Team\"\n10.0.0.2:9100,spb,\n",
+        );
+        let rule = get_rule(&path, "");
+        let mut labels = ActiveLabels::new(vec![Label::new("__address__", "10.0.0.1:9100")]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("site").unwrap(), "msk");
+        assert_eq!(labels.get("owner").unwrap(), "Ops, Team");
+        // Empty values are not set
+        let mut labels = ActiveLabels::new(vec![Label::new("__address__", "10.0.0.2:9100")]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("site").unwrap(), "spb");
+        assert!(labels.get("owner").is_none());
+        fs::remove_file(path).unwrap();
+    }
+    #[test]
+    fn test_shared_table() {
+        let path = temp_file("lookup-shared.yml", "\"10.0.0.1:9100\": web\n");
+        let rule1 = get_rule(&path, "target_label: role\n");
+        let rule2 = get_rule(&path, "target_label: role\n");
+        // Same file is loaded and reloaded once
+        assert!(Arc::ptr_eq(&rule1.table, &rule2.table));
+        // Tables for the different target labels are loaded separately
+        let rule3 = get_rule(&path, "target_label: group\n");
+        assert!(!Arc::ptr_eq(&rule1.table, &rule3.table));
+        // Table is loaded again when all rules are dropped
+        let weak = Arc::downgrade(&rule1.table);
+        drop(rule1);
+        drop(rule2);
+        assert!(weak.upgrade().is_none());
+        let rule4 = get_rule(&path, "target_label: role\n");
+        let mut labels = ActiveLabels::new(vec![Label::new("__address__", "10.0.0.1:9100")]);
+        assert_eq!(rule4.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("role").unwrap(), "web");
+        fs::remove_file(path).unwrap();
+    }
+    // Apply rule until the site label is changed or timeout is expired
+    fn wait_site(rule: &LookupRule, prev: &str) -> String {
+        let t0 = Instant::now();
+        loop {
+            let mut labels = ActiveLabels::new(vec![Label::new("__address__", "10.0.0.1:9100")]);
+            rule.apply(&mut labels).unwrap();
+            let site = labels.get("site").unwrap().to_owned();
+            if site != prev || t0.elapsed() > Duration::from_millis(500) {
+                return site;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    #[test]
+    fn test_reload() {
+        let path = temp_file("lookup-reload.csv", "address,site\n10.0.0.1:9100,msk\n");
+        let yaml = format!(
+            "action: lookup\nsource_labels: [__address__]\nfile: {}\n",
+            path.display()
+        );
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(&yaml).unwrap();
+        let rule = LookupRule::new(&cfg, Duration::from_millis(10)).unwrap();
+        let mut labels = ActiveLabels::new(vec![Label::new("__address__", "10.0.0.1:9100")]);
+        rule.apply(&mut labels).unwrap();
+        assert_eq!(labels.get("site").unwrap(), "msk");
+        // Changed file
+        fs::write(&path, "address,site\n10.0.0.1:9100,novosibirsk\n").unwrap();
+        assert_eq!(wait_site(&rule, "msk"), "novosibirsk");
+        // Broken file, last good table is kept
+        fs::write(&path, "address\n").unwrap();
+        assert_eq!(wait_site(&rule, "novosibirsk"), "novosibirsk");
+        fs::remove_file(path).unwrap();
+    }
+}
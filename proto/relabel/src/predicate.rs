@@ -0,0 +1,115 @@
+// --------------------------------------------------------------------
+// Gufo Agent: Value predicates
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use super::{ActiveLabels, RelabelRuleConfig};
+
+// Conditions on the measure's value.
+// All the set conditions must be met.
+#[derive(Debug)]
+pub(crate) struct ValuePredicate {
+    eq: Option<f64>,
+    gt: Option<f64>,
+    lt: Option<f64>,
+    is_zero: Option<bool>,
+}
+
+impl ValuePredicate {
+    // Returns None if no value conditions are set
+    pub(crate) fn from_config(value: &RelabelRuleConfig) -> Option<Self> {
+        if value.value_eq.is_none()
+            && value.value_gt.is_none()
+            && value.value_lt.is_none()
+            && value.is_zero.is_none()
+        {
+            return None;
+        }
+        Some(ValuePredicate {
+            eq: value.value_eq,
+            gt: value.value_gt,
+            lt: value.value_lt,
+            is_zero: value.is_zero,
+        })
+    }
+    // Check measure's value.
+    // Never matches without value. Ruleset skips the rules
+    // with predicates when relabeling the targets.
+    pub(crate) fn is_match(&self, labels: &ActiveLabels) -> bool {
+        let v = match labels.value() {
+            Some(x) => x,
+            None => return false,
+        };
+        if let Some(x) = self.eq {
+            if v != x {
+                return false;
+            }
+        }
+        if let Some(x) = self.gt {
+            if v <= x {
+                return false;
+            }
+        }
+        if let Some(x) = self.lt {
+            if v >= x {
+                return false;
+            }
+        }
+        if let Some(x) = self.is_zero {
+            if (v == 0.0) != x {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActiveLabels, RelabelRuleConfig, ValuePredicate};
+
+    fn get_predicate(yaml: &str) -> Option<ValuePredicate> {
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        ValuePredicate::from_config(&cfg)
+    }
+
+    fn with_value(v: f64) -> ActiveLabels {
+        let mut labels = ActiveLabels::default();
+        labels.set_value(v);
+        labels
+    }
+
+    #[test]
+    fn test_not_set() {
+        assert!(get_predicate("action: drop").is_none());
+    }
+    #[test]
+    fn test_eq() {
+        let p = get_predicate("value_eq: 5").unwrap();
+        assert!(p.is_match(&with_value(5.0)));
+        assert!(!p.is_match(&with_value(5.5)));
+    }
+    #[test]
+    fn test_range() {
+        let p = get_predicate("value_gt: 1\nvalue_lt: 10").unwrap();
+        assert!(!p.is_match(&with_value(1.0)));
+        assert!(p.is_match(&with_value(1.5)));
+        assert!(p.is_match(&with_value(9.0)));
+        assert!(!p.is_match(&with_value(10.0)));
+    }
+    #[test]
+    fn test_is_zero() {
+        let p = get_predicate("is_zero: true").unwrap();
+        assert!(p.is_match(&with_value(0.0)));
+        assert!(!p.is_match(&with_value(1.0)));
+        let p = get_predicate("is_zero: false").unwrap();
+        assert!(!p.is_match(&with_value(0.0)));
+        assert!(p.is_match(&with_value(1.0)));
+    }
+    #[test]
+    fn test_no_value() {
+        let p = get_predicate("is_zero: true").unwrap();
+        assert!(!p.is_match(&ActiveLabels::default()));
+    }
+}
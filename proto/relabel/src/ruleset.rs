@@ -5,10 +5,16 @@
 // --------------------------------------------------------------------
 
 use super::{
-    ActiveLabels, DropIfEqualRule, DropRule, DumpRule, KeepRule, LabelDropRule, LabelKeepRule,
-    LabelMapRule, RelabelRuleConfig, ReplaceRule,
+    ActiveLabels, CacheKey, DropEqualRule, DropIfEqualRule, DropRule, DumpRule, HashModRule,
+    KeepEqualRule, KeepIfEqualRule, KeepRule, LabelChange, LabelDropRule, LabelKeepRule,
+    LabelMapRule, LookupRule, LowercaseRule, RelabelCache, RelabelRuleConfig, ReplaceRule,
+    TraceStep, UppercaseRule,
 };
 use common::{AgentError, AgentResult, Label, Labels, Measure};
+use std::sync::Mutex;
+
+// Default number of the cached label sets, per generation
+const DEFAULT_CACHE_SIZE: usize = 16384;
 
 #[derive(Debug, PartialEq)]
 pub enum ActionResult {
@@ -20,18 +26,29 @@ pub enum ActionResult {
 pub(crate) enum RelabelRule {
     Replace(ReplaceRule),
     Keep(KeepRule),
+    KeepIfEqual(KeepIfEqualRule),
+    KeepEqual(KeepEqualRule),
     Drop(DropRule),
     DropIfEqual(DropIfEqualRule),
+    DropEqual(DropEqualRule),
+    Lookup(LookupRule),
+    Lowercase(LowercaseRule),
+    Uppercase(UppercaseRule),
     Dump(DumpRule),
     LabelKeep(LabelKeepRule),
     LabelDrop(LabelDropRule),
-    // HashMod
+    HashMod(HashModRule),
     LabelMap(LabelMapRule),
 }
 
 #[derive(Debug)]
 pub struct RelabelRuleset {
     rules: Vec<RelabelRule>,
+    // Results of `process`, keyed by the incoming label set.
+    // Ruleset is rebuilt on config change, so the cache is dropped as well.
+    // None if caching is disabled, or the result may depend on the things
+    // other than labels.
+    cache: Option<Mutex<RelabelCache>>,
 }
 
 pub trait Relabeler {
@@ -46,12 +63,19 @@ impl TryFrom<&RelabelRuleConfig> for RelabelRule {
             Some(action) => match action.as_str() {
                 "drop" => RelabelRule::Drop(DropRule::try_from(value)?),
                 "drop_if_equal" => RelabelRule::DropIfEqual(DropIfEqualRule::try_from(value)?),
+                "dropequal" => RelabelRule::DropEqual(DropEqualRule::try_from(value)?),
                 "dump" => RelabelRule::Dump(DumpRule::try_from(value)?),
+                "hashmod" => RelabelRule::HashMod(HashModRule::try_from(value)?),
                 "labeldrop" => RelabelRule::LabelDrop(LabelDropRule::try_from(value)?),
                 "labelkeep" => RelabelRule::LabelKeep(LabelKeepRule::try_from(value)?),
                 "labelmap" => RelabelRule::LabelMap(LabelMapRule::try_from(value)?),
                 "keep" => RelabelRule::Keep(KeepRule::try_from(value)?),
+                "keep_if_equal" => RelabelRule::KeepIfEqual(KeepIfEqualRule::try_from(value)?),
+                "keepequal" => RelabelRule::KeepEqual(KeepEqualRule::try_from(value)?),
+                "lookup" => RelabelRule::Lookup(LookupRule::try_from(value)?),
+                "lowercase" => RelabelRule::Lowercase(LowercaseRule::try_from(value)?),
                 "replace" => RelabelRule::Replace(ReplaceRule::try_from(value)?),
+                "uppercase" => RelabelRule::Uppercase(UppercaseRule::try_from(value)?),
                 _ => {
                     return Err(AgentError::ConfigurationError(format!(
                         "invalid action: {}",
@@ -68,12 +92,15 @@ impl TryFrom<&Vec<RelabelRuleConfig>> for RelabelRuleset {
     type Error = AgentError;
 
     fn try_from(value: &Vec<RelabelRuleConfig>) -> Result<Self, Self::Error> {
-        Ok(RelabelRuleset {
+        let mut r = RelabelRuleset {
             rules: value
                 .iter()
                 .map(RelabelRule::try_from)
                 .collect::<AgentResult<Vec<_>>>()?,
-        })
+            cache: None,
+        };
+        r.set_cache_size(DEFAULT_CACHE_SIZE);
+        Ok(r)
     }
 }
 
@@ -82,19 +109,75 @@ impl Relabeler for RelabelRule {
         match self {
             RelabelRule::Drop(rule) => rule.apply(active_labels),
             RelabelRule::DropIfEqual(rule) => rule.apply(active_labels),
+            RelabelRule::DropEqual(rule) => rule.apply(active_labels),
             RelabelRule::Dump(rule) => rule.apply(active_labels),
+            RelabelRule::HashMod(rule) => rule.apply(active_labels),
             RelabelRule::LabelDrop(rule) => rule.apply(active_labels),
             RelabelRule::LabelKeep(rule) => rule.apply(active_labels),
             RelabelRule::LabelMap(rule) => rule.apply(active_labels),
             RelabelRule::Keep(rule) => rule.apply(active_labels),
+            RelabelRule::KeepIfEqual(rule) => rule.apply(active_labels),
+            RelabelRule::KeepEqual(rule) => rule.apply(active_labels),
+            RelabelRule::Lookup(rule) => rule.apply(active_labels),
+            RelabelRule::Lowercase(rule) => rule.apply(active_labels),
             RelabelRule::Replace(rule) => rule.apply(active_labels),
+            RelabelRule::Uppercase(rule) => rule.apply(active_labels),
+        }
+    }
+}
+
+impl RelabelRule {
+    // Name of the rule's action
+    pub(crate) fn action(&self) -> &'static str {
+        match self {
+            RelabelRule::Drop(_) => "drop",
+            RelabelRule::DropIfEqual(_) => "drop_if_equal",
+            RelabelRule::DropEqual(_) => "dropequal",
+            RelabelRule::Dump(_) => "dump",
+            RelabelRule::HashMod(_) => "hashmod",
+            RelabelRule::LabelDrop(_) => "labeldrop",
+            RelabelRule::LabelKeep(_) => "labelkeep",
+            RelabelRule::LabelMap(_) => "labelmap",
+            RelabelRule::Keep(_) => "keep",
+            RelabelRule::KeepIfEqual(_) => "keep_if_equal",
+            RelabelRule::KeepEqual(_) => "keepequal",
+            RelabelRule::Lookup(_) => "lookup",
+            RelabelRule::Lowercase(_) => "lowercase",
+            RelabelRule::Replace(_) => "replace",
+            RelabelRule::Uppercase(_) => "uppercase",
+        }
+    }
+    // Rule checks the measure's value
+    pub(crate) fn has_value_predicate(&self) -> bool {
+        match self {
+            RelabelRule::Drop(rule) => rule.has_predicate(),
+            RelabelRule::Keep(rule) => rule.has_predicate(),
+            _ => false,
+        }
+    }
+    // Result depends only on the labels
+    pub(crate) fn is_cacheable(&self) -> bool {
+        match self {
+            RelabelRule::Drop(rule) => !rule.has_predicate(),
+            RelabelRule::Keep(rule) => !rule.has_predicate(),
+            // Table may be changed
+            RelabelRule::Lookup(_) => false,
+            // Must be logged every time
+            RelabelRule::Dump(_) => false,
+            _ => true,
         }
     }
 }
 
 impl Relabeler for RelabelRuleset {
+    // Rules with value predicates are passed through
+    // when relabeling is performed without value, i.e. for the targets.
     fn apply(&self, active_labels: &mut ActiveLabels) -> AgentResult<ActionResult> {
+        let has_value = active_labels.value().is_some();
         for rule in self.rules.iter() {
+            if !has_value && rule.has_value_predicate() {
+                continue;
+            }
             match rule.apply(active_labels)? {
                 ActionResult::Drop => return Ok(ActionResult::Drop),
                 ActionResult::Pass => continue,
@@ -105,23 +188,210 @@ impl Relabeler for RelabelRuleset {
 }
 
 impl RelabelRuleset {
+    // Set the number of cached label sets, 0 disables caching.
+    // Caching is always disabled when any of the rules
+    // depends on the things other than labels.
+    pub fn set_cache_size(&mut self, size: usize) {
+        self.cache = if size > 0 && self.rules.iter().all(|x| x.is_cacheable()) {
+            Some(Mutex::new(RelabelCache::new(size)))
+        } else {
+            None
+        };
+    }
     pub fn process(
         &self,
         agent_labels: &Labels,
         collector_labels: &Labels,
         measure: &Measure,
     ) -> AgentResult<Option<Measure>> {
+        let key = CacheKey {
+            name: &measure.name,
+            agent_labels,
+            collector_labels,
+            labels: &measure.labels,
+        };
+        // The lock is not held while evaluating the rules,
+        // so the same label set may be evaluated concurrently.
+        let cached = match &self.cache {
+            Some(cache) => cache.lock().ok().and_then(|mut cache| cache.get(&key)),
+            None => None,
+        };
+        if let Some(r) = cached {
+            return Ok(r.map(|(name, labels)| Measure {
+                name,
+                help: measure.help.to_owned(),
+                value: measure.value,
+                labels,
+                timestamp: measure.timestamp,
+                exemplar: measure.exemplar.clone(),
+            }));
+        }
         let mut labels = ActiveLabels::try_from((agent_labels, collector_labels, &measure.labels))?;
         labels.insert(Label::new("__name__", measure.name.clone()));
-        match self.apply(&mut labels)? {
-            ActionResult::Pass => {
-                // Replace
-                Ok(Some(labels.to_measure(measure)))
-            }
+        labels.set_value(measure.value.as_f64());
+        let r = match self.apply(&mut labels)? {
+            // Replace
+            ActionResult::Pass => Some(labels.to_measure(measure)),
             ActionResult::Drop => {
                 log::debug!("Measure dropped by rule");
-                Ok(None)
+                None
             }
+        };
+        if let Some(Ok(mut cache)) = self.cache.as_ref().map(|x| x.lock()) {
+            cache.insert(&key, r.as_ref().map(|r| (r.name.clone(), r.labels.clone())));
         }
+        Ok(r)
+    }
+    // Apply rules and record the result and label changes
+    // of the every applied rule.
+    pub fn trace(&self, active_labels: &mut ActiveLabels) -> AgentResult<Vec<TraceStep>> {
+        let mut r = Vec::with_capacity(self.rules.len());
+        let has_value = active_labels.value().is_some();
+        for (n, rule) in self.rules.iter().enumerate() {
+            let before = active_labels.clone();
+            let result = if !has_value && rule.has_value_predicate() {
+                ActionResult::Pass
+            } else {
+                rule.apply(active_labels)?
+            };
+            let is_drop = result == ActionResult::Drop;
+            r.push(TraceStep {
+                rule: n + 1,
+                action: rule.action(),
+                result,
+                changes: LabelChange::diff(&before, active_labels),
+            });
+            if is_drop {
+                break;
+            }
+        }
+        Ok(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RelabelRuleset;
+    use crate::{ActionResult, ActiveLabels, RelabelRuleConfig, Relabeler};
+    use common::{Label, Labels, Measure, Value};
+
+    fn get_ruleset(yaml: &str) -> RelabelRuleset {
+        let cfg = serde_yaml::from_str::<Vec<RelabelRuleConfig>>(yaml).unwrap();
+        RelabelRuleset::try_from(&cfg).unwrap()
+    }
+
+    fn measure(zone: &str, value: u64) -> Measure {
+        Measure {
+            name: "ps_cpu".into(),
+            help: "CPU usage".into(),
+            value: Value::Gauge(value),
+            labels: Labels::new(vec![Label::new("zone", zone)]),
+            timestamp: None,
+            exemplar: None,
+        }
+    }
+
+    #[test]
+    fn test_value_predicate_without_value() {
+        let ruleset = get_ruleset(
+            r#"
+- action: keep
+  value_gt: 0
+- action: drop
+  is_zero: false
+- source_labels: [zone]
+  regex: lion
+  action: keep
+"#,
+        );
+        // Targets are relabeled without value
+        let mut labels = ActiveLabels::new(vec![Label::new("zone", "lion")]);
+        assert_eq!(ruleset.apply(&mut labels).unwrap(), ActionResult::Pass);
+        let mut labels = ActiveLabels::new(vec![Label::new("zone", "tiger")]);
+        assert_eq!(ruleset.apply(&mut labels).unwrap(), ActionResult::Drop);
+        // Measures are checked
+        let mut labels = ActiveLabels::new(vec![Label::new("zone", "lion")]);
+        labels.set_value(0.0);
+        assert_eq!(ruleset.apply(&mut labels).unwrap(), ActionResult::Drop);
+    }
+    #[test]
+    fn test_cached() {
+        let ruleset = get_ruleset(
+            r#"
+- source_labels: [zone]
+  target_label: region
+- source_labels: [zone]
+  regex: lion
+  action: drop
+"#,
+        );
+        assert!(ruleset.cache.is_some());
+        let empty = Labels::default();
+        for value in 1..3 {
+            let r = ruleset
+                .process(&empty, &empty, &measure("tiger", value))
+                .unwrap()
+                .unwrap();
+            assert_eq!(r.name, "ps_cpu");
+            assert_eq!(r.value, Value::Gauge(value));
+            assert_eq!(
+                r.labels,
+                Labels::new(vec![
+                    Label::new("region", "tiger"),
+                    Label::new("zone", "tiger")
+                ])
+            );
+            assert!(ruleset
+                .process(&empty, &empty, &measure("lion", value))
+                .unwrap()
+                .is_none());
+        }
+        // Collector labels are the part of the key
+        let collector = Labels::new(vec![Label::new("zone", "lion")]);
+        assert!(ruleset
+            .process(&empty, &collector, &measure("tiger", 1))
+            .unwrap()
+            .is_some());
+        assert!(ruleset
+            .process(
+                &empty,
+                &collector,
+                &Measure {
+                    labels: Labels::default(),
+                    ..measure("tiger", 1)
+                }
+            )
+            .unwrap()
+            .is_none());
+    }
+    #[test]
+    fn test_not_cacheable() {
+        let ruleset = get_ruleset(
+            r#"
+- action: drop
+  is_zero: true
+"#,
+        );
+        assert!(ruleset.cache.is_none());
+        let empty = Labels::default();
+        assert!(ruleset
+            .process(&empty, &empty, &measure("tiger", 0))
+            .unwrap()
+            .is_none());
+        assert!(ruleset
+            .process(&empty, &empty, &measure("tiger", 1))
+            .unwrap()
+            .is_some());
+    }
+    #[test]
+    fn test_disabled() {
+        let mut ruleset = get_ruleset(
+            r#"
+- source_labels: [zone]
+  target_label: region
+"#,
+        );
+        ruleset.set_cache_size(0);
+        assert!(ruleset.cache.is_none());
     }
 }
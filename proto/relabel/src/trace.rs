@@ -0,0 +1,130 @@
+// --------------------------------------------------------------------
+// Gufo Agent: Relabeling trace
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use super::{ActionResult, ActiveLabels};
+use std::collections::BTreeMap;
+
+// Label modification, performed by rule
+#[derive(Debug, PartialEq)]
+pub enum LabelChange {
+    Added {
+        name: String,
+        value: String,
+    },
+    Changed {
+        name: String,
+        old: String,
+        new: String,
+    },
+    Removed {
+        name: String,
+        value: String,
+    },
+}
+
+// Result of the single rule
+#[derive(Debug)]
+pub struct TraceStep {
+    // Rule number, starting from 1
+    pub rule: usize,
+    pub action: &'static str,
+    pub result: ActionResult,
+    pub changes: Vec<LabelChange>,
+}
+
+impl LabelChange {
+    // Compare active labels before and after the rule
+    pub(crate) fn diff(before: &ActiveLabels, after: &ActiveLabels) -> Vec<LabelChange> {
+        let old: BTreeMap<&String, &String> = before.iter().collect();
+        let mut r = Vec::new();
+        for (name, value) in after.iter() {
+            match old.get(name) {
+                Some(x) if *x == value => {}
+                Some(x) => r.push(LabelChange::Changed {
+                    name: name.clone(),
+                    old: x.to_string(),
+                    new: value.clone(),
+                }),
+                None => r.push(LabelChange::Added {
+                    name: name.clone(),
+                    value: value.clone(),
+                }),
+            }
+        }
+        for (name, value) in before.iter() {
+            if after.get(name).is_none() {
+                r.push(LabelChange::Removed {
+                    name: name.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+        r
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActiveLabels, LabelChange};
+    use crate::{ActionResult, RelabelRuleConfig, RelabelRuleset};
+    use common::Label;
+
+    #[test]
+    fn test_trace() {
+        let yaml = r#"
+- source_labels: [zone]
+  target_label: region
+- action: labeldrop
+  regex: zone
+- source_labels: [user]
+  replacement: root
+  target_label: user
+- source_labels: [region]
+  regex: tiger
+  action: drop
+- action: keep
+  source_labels: [__name__]
+"#;
+        let cfg = serde_yaml::from_str::<Vec<RelabelRuleConfig>>(yaml).unwrap();
+        let ruleset = RelabelRuleset::try_from(&cfg).unwrap();
+        let mut labels = ActiveLabels::new(vec![
+            Label::new("__name__", "ps_cpu"),
+            Label::new("user", "scott"),
+            Label::new("zone", "tiger"),
+        ]);
+        let trace = ruleset.trace(&mut labels).unwrap();
+        // Last rule is not reached
+        assert_eq!(trace.len(), 4);
+        assert_eq!(trace[0].rule, 1);
+        assert_eq!(trace[0].action, "replace");
+        assert_eq!(
+            trace[0].changes,
+            vec![LabelChange::Added {
+                name: "region".into(),
+                value: "tiger".into()
+            }]
+        );
+        assert_eq!(trace[1].action, "labeldrop");
+        assert_eq!(
+            trace[1].changes,
+            vec![LabelChange::Removed {
+                name: "zone".into(),
+                value: "tiger".into()
+            }]
+        );
+        assert_eq!(
+            trace[2].changes,
+            vec![LabelChange::Changed {
+                name: "user".into(),
+                old: "scott".into(),
+                new: "root".into()
+            }]
+        );
+        assert_eq!(trace[3].action, "drop");
+        assert_eq!(trace[3].result, ActionResult::Drop);
+        assert!(trace[3].changes.is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+// --------------------------------------------------------------------
+// Gufo Agent: Uppercase Rule
+// --------------------------------------------------------------------
+// Copyright (C) 2021-2023, Gufo Labs
+// --------------------------------------------------------------------
+
+use super::{ActionResult, ActiveLabels, RelabelRuleConfig, Relabeler};
+use common::{AgentError, AgentResult, Label};
+
+// Sets target_label to the uppercased joined source labels
+#[derive(Debug)]
+pub(crate) struct UppercaseRule {
+    source_labels: Vec<String>,
+    separator: String,
+    target_label: String,
+}
+
+impl TryFrom<&RelabelRuleConfig> for UppercaseRule {
+    type Error = AgentError;
+
+    fn try_from(value: &RelabelRuleConfig) -> Result<Self, Self::Error> {
+        // action must be `uppercase`
+        if let Some(x) = &value.action {
+            if x != "uppercase" {
+                return Err(AgentError::ConfigurationError(
+                    "'action' must be 'uppercase'".to_string(),
+                ));
+            }
+        }
+        let source_labels = match &value.source_labels {
+            Some(x) if !x.is_empty() => x.clone(),
+            _ => {
+                return Err(AgentError::ConfigurationError(
+                    "'source_labels' must be set".to_string(),
+                ))
+            }
+        };
+        let target_label = match &value.target_label {
+            Some(x) => x.clone(),
+            None => {
+                return Err(AgentError::ConfigurationError(
+                    "'target_label' must be set".to_string(),
+                ))
+            }
+        };
+        Ok(UppercaseRule {
+            source_labels,
+            separator: value.separator.clone(),
+            target_label,
+        })
+    }
+}
+
+impl Relabeler for UppercaseRule {
+    fn apply(&self, active_labels: &mut ActiveLabels) -> AgentResult<ActionResult> {
+        let value = active_labels
+            .join(&self.source_labels, &self.separator)
+            .to_uppercase();
+        active_labels.insert(Label::new(self.target_label.clone(), value));
+        Ok(ActionResult::Pass)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ActionResult, ActiveLabels, RelabelRuleConfig, Relabeler, UppercaseRule};
+    use common::Label;
+
+    #[test]
+    fn test_invalid_action() {
+        let yaml = r#"action: drop_something"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        assert!(UppercaseRule::try_from(&cfg).is_err());
+    }
+    #[test]
+    fn test_no_target_label() {
+        let yaml = r#"
+action: uppercase
+source_labels: [foo]
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        assert!(UppercaseRule::try_from(&cfg).is_err());
+    }
+    // Prometheus' relabel_test.go
+    #[test]
+    fn test_uppercase() {
+        let yaml = r#"
+action: uppercase
+source_labels: [foo]
+target_label: foo_uppercase
+"#;
+        let cfg = serde_yaml::from_str::<RelabelRuleConfig>(yaml).unwrap();
+        let rule = UppercaseRule::try_from(&cfg).unwrap();
+        let mut labels = ActiveLabels::new(vec![Label::new("foo", "bAr123Foo")]);
+        assert_eq!(rule.apply(&mut labels).unwrap(), ActionResult::Pass);
+        assert_eq!(labels.get("foo").unwrap(), "bAr123Foo");
+        assert_eq!(labels.get("foo_uppercase").unwrap(), "BAR123FOO");
+    }
+}